@@ -0,0 +1,197 @@
+use crate::asm::error::Error;
+
+// Tags for each kind of record in the debug section.
+const LOCATION: u8 = 0;
+const VARIABLE: u8 = 1;
+
+// Maps bytecode back to the source it was compiled from. Records are kept in
+// the order of the code they describe.
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct DebugInfo {
+    pub records: Vec<Record>,
+}
+
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub enum Record {
+    // The code from `pc` on was compiled from the statement at this line and
+    // column, both counted from 1.
+    Location { pc: u32, line: u32, column: u32 },
+    // From `pc` on, the variable is held in the register.
+    Variable { pc: u32, register: u8, name: String },
+}
+
+impl Record {
+    pub fn pc(&self) -> u32 {
+        match self {
+            Record::Location { pc, .. } | Record::Variable { pc, .. } => *pc,
+        }
+    }
+}
+
+impl DebugInfo {
+    pub fn new() -> DebugInfo {
+        DebugInfo { records: vec![] }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.records.is_empty()
+    }
+
+    // The line and column of the statement the instruction at `pc` was
+    // compiled from.
+    pub fn location(&self, pc: usize) -> Option<(u32, u32)> {
+        self.records
+            .iter()
+            .take_while(|r| r.pc() as usize <= pc)
+            .filter_map(|r| match r {
+                Record::Location { line, column, .. } => Some((*line, *column)),
+                Record::Variable { .. } => None,
+            })
+            .last()
+    }
+
+    // The register each variable was last held in, by name.
+    pub fn variables(&self) -> Vec<(&str, u8)> {
+        let mut variables: Vec<(&str, u8)> = vec![];
+        for r in &self.records {
+            if let Record::Variable { register, name, .. } = r {
+                match variables.iter_mut().find(|(n, _)| n == name) {
+                    Some(variable) => variable.1 = *register,
+                    None => variables.push((name, *register)),
+                }
+            }
+        }
+        variables
+    }
+
+    // Each record is its tag and pc, followed by the line and column of a
+    // location, or the register and null-terminated name of a variable.
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut bytes = vec![];
+        for r in &self.records {
+            match r {
+                Record::Location { pc, line, column } => {
+                    bytes.push(LOCATION);
+                    bytes.extend(pc.to_be_bytes());
+                    bytes.extend(line.to_be_bytes());
+                    bytes.extend(column.to_be_bytes());
+                }
+                Record::Variable { pc, register, name } => {
+                    bytes.push(VARIABLE);
+                    bytes.extend(pc.to_be_bytes());
+                    bytes.push(*register);
+                    bytes.extend(name.as_bytes());
+                    bytes.push(0);
+                }
+            }
+        }
+        bytes
+    }
+
+    // `start` is the offset of the section, for reporting errors.
+    pub fn parse(bytes: &[u8], start: usize) -> Result<DebugInfo, Error> {
+        let invalid = |at: usize, reason: &str| Error::InvalidBytecode {
+            offset: start + at,
+            reason: reason.to_string(),
+        };
+        let u32_at = |at: usize| -> Result<u32, Error> {
+            bytes
+                .get(at..at + 4)
+                .map(|b| u32::from_be_bytes(b.try_into().unwrap()))
+                .ok_or_else(|| invalid(at, "Truncated debug info"))
+        };
+
+        let mut records = vec![];
+        let mut at = 0;
+        while at < bytes.len() {
+            let pc = u32_at(at + 1)?;
+            match bytes[at] {
+                LOCATION => {
+                    records.push(Record::Location {
+                        pc,
+                        line: u32_at(at + 5)?,
+                        column: u32_at(at + 9)?,
+                    });
+                    at += 13;
+                }
+                VARIABLE => {
+                    let register = *bytes
+                        .get(at + 5)
+                        .ok_or_else(|| invalid(at, "Truncated debug info"))?;
+                    let name = &bytes[(at + 6).min(bytes.len())..];
+                    let len = name
+                        .iter()
+                        .position(|b| *b == 0)
+                        .ok_or_else(|| invalid(at, "Unterminated variable name"))?;
+                    let name = std::str::from_utf8(&name[..len])
+                        .map_err(|_| invalid(at, "Variable name is not valid UTF-8"))?;
+                    records.push(Record::Variable {
+                        pc,
+                        register,
+                        name: name.to_string(),
+                    });
+                    at += 6 + len + 1;
+                }
+                _ => return Err(invalid(at, "Unknown debug info record")),
+            }
+        }
+        Ok(DebugInfo { records })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn debug_info() -> DebugInfo {
+        DebugInfo {
+            records: vec![
+                Record::Location {
+                    pc: 32,
+                    line: 1,
+                    column: 1,
+                },
+                Record::Variable {
+                    pc: 38,
+                    register: 3,
+                    name: "a".to_string(),
+                },
+                Record::Location {
+                    pc: 38,
+                    line: 2,
+                    column: 5,
+                },
+                Record::Variable {
+                    pc: 44,
+                    register: 4,
+                    name: "a".to_string(),
+                },
+            ],
+        }
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let debug = debug_info();
+        assert_eq!(DebugInfo::parse(&debug.to_bytes(), 0).unwrap(), debug);
+        assert_eq!(DebugInfo::parse(&[], 0).unwrap(), DebugInfo::new());
+    }
+
+    #[test]
+    fn test_invalid() {
+        let bytes = debug_info().to_bytes();
+        assert!(DebugInfo::parse(&bytes[..bytes.len() - 1], 0).is_err());
+        assert!(DebugInfo::parse(&bytes[..5], 0).is_err());
+        assert!(DebugInfo::parse(&[7, 0, 0, 0, 0], 0).is_err());
+    }
+
+    #[test]
+    fn test_location() {
+        let debug = debug_info();
+        assert_eq!(debug.location(20), None);
+        assert_eq!(debug.location(32), Some((1, 1)));
+        assert_eq!(debug.location(37), Some((1, 1)));
+        assert_eq!(debug.location(40), Some((2, 5)));
+        assert_eq!(debug.variables(), vec![("a", 4)]);
+    }
+}
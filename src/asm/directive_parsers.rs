@@ -30,9 +30,9 @@ pub fn directive(i: &str) -> IResult<&str, Instruction> {
             opt(preceded(multispace1, operand)),
             opt(preceded(multispace1, operand)),
         )),
-        |(l, name, o0, _o1, _o2)| -> Result<Instruction, nom::error::Error<&str>> {
+        |(l, name, o0, o1, _o2)| -> Result<Instruction, nom::error::Error<&str>> {
             log::debug!("[asm::directive] success ({:?}, {:?})", l, name);
-            Ok(Instruction::new_directive(name, l, o0))
+            Ok(Instruction::new_directive(name, l, o0, o1))
         },
     )(i)
 }
@@ -70,8 +70,21 @@ mod tests {
             Some(Token::DoString {
                 value: "Hello".to_string(),
             }),
+            None,
         );
 
         assert_eq!(directive, expected);
     }
+
+    #[test]
+    fn test_debug_directives() {
+        let (_, loc) = directive(".loc #3 #5").unwrap();
+        assert_eq!(loc.source_location(), Some((3, 5)));
+
+        let (_, var) = directive(".var 'a' $r2").unwrap();
+        assert_eq!(
+            var.variable(),
+            Some(("a".to_string(), Token::RealRegister { idx: 2 }))
+        );
+    }
 }
@@ -0,0 +1,481 @@
+use crate::asm::error::Error;
+use crate::asm::opcode::Opcode;
+use crate::asm::syscalls::Syscall;
+use crate::asm::{
+    escape, DebugInfo, Header, Record, DO_HEADER_LEN, DO_LABEL_TARGET, DO_SECTION_CODE,
+    DO_SECTION_DEBUG, DO_SECTION_READONLY,
+};
+use crate::vm::register::*;
+
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+use std::convert::TryFrom;
+
+// What each operand of an instruction is encoded as.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    Register,
+    Integer,
+    Real,
+    // An offset into the code.
+    Label,
+    // A register holding an offset into the code, or a label.
+    Target,
+    // A register or the offset of a string, depending on the syscall.
+    SyscallArg,
+}
+
+#[derive(Debug, PartialEq)]
+enum Operand {
+    Register(u8),
+    Integer(i32),
+    Real(f64),
+    Label(usize),
+    SyscallArg(u16),
+}
+
+#[derive(Debug)]
+struct Decoded {
+    offset: usize,
+    opcode: Opcode,
+    operands: Vec<Operand>,
+}
+
+// Turns bytecode back into assembly that assembles to the same bytes.
+pub fn disassemble(bytecode: &[u8]) -> Result<String, Error> {
+    let header = Header::parse(bytecode)?;
+    let code_section = header.section(DO_SECTION_CODE);
+    // The assembler always starts at the start of the code.
+    if header.entry as usize != code_section.start {
+        return Err(invalid(8, "Entry point cannot be written in assembly"));
+    }
+
+    let debug_section = header.section(DO_SECTION_DEBUG);
+    if debug_section.end != bytecode.len() {
+        return Err(invalid(
+            debug_section.end,
+            "Unexpected bytes after the sections",
+        ));
+    }
+    let debug = DebugInfo::parse(&bytecode[debug_section.clone()], debug_section.start)?;
+
+    let strings = strings(&bytecode[header.section(DO_SECTION_READONLY)])?;
+    let mut listing = String::from(".data\n");
+    for (name, value) in strings.values() {
+        listing.push_str(&format!("{}: .str '{}'\n", name, escape(value)));
+    }
+    listing.push_str(".code\n");
+    listing.push_str(&code(
+        &bytecode[..code_section.end],
+        code_section.start,
+        &strings,
+        &debug.records,
+    )?);
+    Ok(listing)
+}
+
+// Lists code without a header, as the repl builds it up.
+pub fn disassemble_code(code_only: &[u8]) -> Result<String, Error> {
+    code(code_only, 0, &BTreeMap::new(), &[])
+}
+
+fn invalid(offset: usize, reason: &str) -> Error {
+    Error::InvalidBytecode {
+        offset,
+        reason: reason.to_string(),
+    }
+}
+
+// Splits the read-only data into its strings, keyed by offset.
+fn strings(ro: &[u8]) -> Result<BTreeMap<usize, (String, String)>, Error> {
+    let mut strings = BTreeMap::new();
+    let mut start = 0;
+    while start < ro.len() {
+        let offset = DO_HEADER_LEN + start;
+        let len = ro[start..]
+            .iter()
+            .position(|b| *b == 0)
+            .ok_or_else(|| invalid(offset, "Unterminated string"))?;
+        let value = std::str::from_utf8(&ro[start..start + len])
+            .map_err(|_| invalid(offset, "String is not valid UTF-8"))?;
+        let name = format!("str_{}", strings.len());
+        strings.insert(start, (name, value.to_string()));
+        start += len + 1;
+    }
+    Ok(strings)
+}
+
+fn code(
+    bytes: &[u8],
+    start: usize,
+    strings: &BTreeMap<usize, (String, String)>,
+    debug: &[Record],
+) -> Result<String, Error> {
+    let mut decoder = Decoder { bytes, pc: start };
+    let mut instructions = vec![];
+    while decoder.pc < bytes.len() {
+        instructions.push(decoder.next_instruction()?);
+    }
+
+    // Every offset jumped or called to gets a label, so each must be the
+    // start of an instruction or the end of the code.
+    let boundaries: BTreeSet<usize> = instructions
+        .iter()
+        .map(|i| i.offset)
+        .chain([bytes.len()])
+        .collect();
+    let mut labels = BTreeMap::new();
+    for i in &instructions {
+        for operand in &i.operands {
+            if let Operand::Label(target) = operand {
+                if !boundaries.contains(target) {
+                    return Err(invalid(
+                        i.offset,
+                        "Target is not the start of an instruction",
+                    ));
+                }
+                labels.insert(*target, String::new());
+            }
+        }
+    }
+    for (idx, name) in labels.values_mut().enumerate() {
+        *name = format!("label_{}", idx);
+    }
+
+    // Integer constants loaded since the last label, to find syscall numbers.
+    let mut constants: HashMap<u8, i32> = HashMap::new();
+    let mut records = debug.iter().peekable();
+    let mut listing = String::new();
+    for i in &instructions {
+        if let Some(name) = labels.get(&i.offset) {
+            listing.push_str(&format!("{}:\n", name));
+            constants.clear();
+        }
+        while let Some(r) = records.next_if(|r| r.pc() as usize <= i.offset) {
+            listing.push_str(&debug_directive(r, i.offset)?);
+        }
+
+        let mut line = i.opcode.to_string();
+        for operand in &i.operands {
+            let text = match operand {
+                Operand::Register(reg) => register_name(*reg),
+                Operand::Integer(value) => format!("#{}", value),
+                // Debug always writes a decimal point or exponent, so the
+                // value is read back as a real.
+                Operand::Real(value) => format!("#{:?}", value),
+                Operand::Label(target) => format!("@{}", labels[target]),
+                Operand::SyscallArg(value) => {
+                    let call = match i.operands.first() {
+                        Some(Operand::Register(reg)) => constants.get(reg),
+                        _ => None,
+                    };
+                    syscall_arg(*value, call, strings)
+                        .ok_or_else(|| invalid(i.offset, "Cannot decode syscall operand"))?
+                }
+            };
+            line.push(' ');
+            line.push_str(&text);
+        }
+        listing.push_str(&line);
+        listing.push('\n');
+
+        if let (Opcode::LOAD, [Operand::Register(reg), Operand::Integer(value)]) =
+            (i.opcode, i.operands.as_slice())
+        {
+            constants.insert(*reg, *value);
+        }
+    }
+    if let Some(name) = labels.get(&bytes.len()) {
+        listing.push_str(&format!("{}:\n", name));
+    }
+    for r in records {
+        listing.push_str(&debug_directive(r, bytes.len())?);
+    }
+    Ok(listing)
+}
+
+// Debug info is written before the instruction at `offset`, so must be
+// recorded against it.
+fn debug_directive(record: &Record, offset: usize) -> Result<String, Error> {
+    let pc = record.pc() as usize;
+    if pc != offset {
+        return Err(invalid(
+            pc,
+            "Debug info is not at the start of an instruction",
+        ));
+    }
+    match record {
+        Record::Location { line, column, .. }
+            if (1..=i32::MAX as u32).contains(line) && (1..=i32::MAX as u32).contains(column) =>
+        {
+            Ok(format!(".loc #{} #{}\n", line, column))
+        }
+        Record::Variable { register, name, .. } if !name.contains(['\'', '\n']) => {
+            Ok(format!(".var '{}' {}\n", name, register_name(*register)))
+        }
+        _ => Err(invalid(pc, "Debug info cannot be written in assembly")),
+    }
+}
+
+// Strings are printed by label and everything else takes a register, which
+// is followed by a padding byte.
+fn syscall_arg(
+    value: u16,
+    call: Option<&i32>,
+    strings: &BTreeMap<usize, (String, String)>,
+) -> Option<String> {
+    let string = strings
+        .get(&(value as usize))
+        .map(|(name, _)| format!("@{}", name));
+    let prints_str = matches!(
+        call.map(|c| Syscall::try_from(*c)),
+        Some(Ok(Syscall::PrintStr | Syscall::PrintStrInline))
+    );
+    if prints_str && string.is_some() {
+        return string;
+    }
+    let [reg, padding] = value.to_be_bytes();
+    if padding == 0 {
+        return Some(register_name(reg));
+    }
+    string
+}
+
+fn register_name(reg: u8) -> String {
+    if is_int_register(reg) {
+        format!("$i{}", idx_from_int_register(reg))
+    } else if is_real_register(reg) {
+        format!("$r{}", idx_from_real_register(reg))
+    } else if is_vector_register(reg) {
+        format!("$v{}", idx_from_vector_register(reg))
+    } else if is_nested_register(reg) {
+        format!("$n{}", idx_from_nested_register(reg))
+    } else {
+        format!("$d{}", idx_from_dict_register(reg))
+    }
+}
+
+// The operands each opcode is written with, other than `load`, which depends
+// on the register being loaded.
+fn operand_kinds(opcode: Opcode) -> &'static [Kind] {
+    use Kind::*;
+    match opcode {
+        Opcode::HLT | Opcode::RET | Opcode::IGL => &[],
+        Opcode::PUSH | Opcode::POP => &[Register],
+        Opcode::COPY
+        | Opcode::LW
+        | Opcode::SW
+        | Opcode::NOT
+        | Opcode::SUM
+        | Opcode::PROD
+        | Opcode::MIN
+        | Opcode::MAX
+        | Opcode::KEYS
+        | Opcode::VALS
+        | Opcode::LEN
+        | Opcode::FLAT => &[Register, Register],
+        Opcode::ADD
+        | Opcode::SUB
+        | Opcode::MUL
+        | Opcode::DIV
+        | Opcode::EQ
+        | Opcode::NEQ
+        | Opcode::GT
+        | Opcode::LT
+        | Opcode::GTE
+        | Opcode::LTE
+        | Opcode::AND
+        | Opcode::OR
+        | Opcode::MASK
+        | Opcode::DICT
+        | Opcode::GET
+        | Opcode::FIRST
+        | Opcode::CAT
+        | Opcode::ZIP
+        | Opcode::AT => &[Register, Register, Register],
+        Opcode::SORT => &[Register, Register, Integer],
+        Opcode::ALLOC | Opcode::NEST => &[Register, Integer],
+        Opcode::CALL => &[Label],
+        Opcode::MAP | Opcode::FOLD => &[Register, Register, Label],
+        Opcode::JMP => &[Target],
+        Opcode::JEQ | Opcode::JNE | Opcode::JGT | Opcode::JLT | Opcode::JGTE | Opcode::JLTE => {
+            &[Target, Register, Register]
+        }
+        Opcode::JZ | Opcode::JNZ => &[Target, Register],
+        Opcode::SYSCALL => &[Register, SyscallArg],
+        Opcode::LOAD => unreachable!(),
+    }
+}
+
+struct Decoder<'a> {
+    bytes: &'a [u8],
+    pc: usize,
+}
+
+impl<'a> Decoder<'a> {
+    fn next_instruction(&mut self) -> Result<Decoded, Error> {
+        let offset = self.pc;
+        let opcode = Opcode::try_from(self.next_bytes::<1>(offset)?[0])
+            .map_err(|_| invalid(offset, "Illegal opcode"))?;
+
+        let kinds = match opcode {
+            Opcode::IGL => return Err(invalid(offset, "Illegal opcode")),
+            Opcode::LOAD => {
+                let reg = *self
+                    .bytes
+                    .get(self.pc)
+                    .ok_or_else(|| invalid(offset, "Truncated instruction"))?;
+                if is_int_register(reg) {
+                    &[Kind::Register, Kind::Integer][..]
+                } else if is_real_register(reg) {
+                    &[Kind::Register, Kind::Real][..]
+                } else if is_vector_register(reg) {
+                    &[Kind::Register, Kind::Register, Kind::Integer][..]
+                } else {
+                    return Err(invalid(offset, "Cannot load into register"));
+                }
+            }
+            _ => operand_kinds(opcode),
+        };
+
+        let mut operands = vec![];
+        for kind in kinds {
+            operands.push(self.next_operand(*kind, offset)?);
+        }
+
+        // Short instructions are padded with zeroes.
+        while self.pc - offset < 4 {
+            if self.next_bytes::<1>(offset)?[0] != 0 {
+                return Err(invalid(offset, "Unexpected bytes after operands"));
+            }
+        }
+
+        Ok(Decoded {
+            offset,
+            opcode,
+            operands,
+        })
+    }
+
+    fn next_operand(&mut self, kind: Kind, offset: usize) -> Result<Operand, Error> {
+        Ok(match kind {
+            Kind::Register => Operand::Register(self.next_bytes::<1>(offset)?[0]),
+            Kind::Integer => Operand::Integer(i32::from_be_bytes(self.next_bytes(offset)?)),
+            Kind::Real => Operand::Real(f64::from_be_bytes(self.next_bytes(offset)?)),
+            Kind::Label => Operand::Label(u32::from_be_bytes(self.next_bytes(offset)?) as usize),
+            Kind::Target => {
+                let reg = self.next_bytes::<1>(offset)?[0];
+                if reg == DO_LABEL_TARGET {
+                    Operand::Label(u32::from_be_bytes(self.next_bytes(offset)?) as usize)
+                } else {
+                    Operand::Register(reg)
+                }
+            }
+            Kind::SyscallArg => Operand::SyscallArg(u16::from_be_bytes(self.next_bytes(offset)?)),
+        })
+    }
+
+    fn next_bytes<const N: usize>(&mut self, offset: usize) -> Result<[u8; N], Error> {
+        let bytes = self
+            .bytes
+            .get(self.pc..self.pc + N)
+            .ok_or_else(|| invalid(offset, "Truncated instruction"))?;
+        self.pc += N;
+        Ok(bytes.try_into().unwrap())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::asm::Assembler;
+    use crate::compiler::Compiler;
+
+    fn round_trip(assembly: &str) -> String {
+        let bytecode = Assembler::new().assemble(assembly).unwrap();
+        let listing = disassemble(&bytecode).unwrap();
+        assert_eq!(Assembler::new().assemble(&listing).unwrap(), bytecode);
+        listing
+    }
+
+    #[test]
+    fn test_disassemble() {
+        let listing = round_trip(
+            ".data\nhello: .str 'Hello there'\nempty: .str ''\nquote: .str 'it\\'s\\n'\n.code\nload $i0 #100\nload $r1 #1.0\nstart: load $i2 #2\nsyscall $i2 @hello\nload $v3 $i0 #16\nadd $r1 $i0 $r1\njlt @start $i0 $i1\njz $i0 $i1\nsyscall $i0 $v3\nhalt\n",
+        );
+        assert_eq!(
+            listing,
+            ".data\nstr_0: .str 'Hello there'\nstr_1: .str ''\nstr_2: .str 'it\\'s\\n'\n.code\nload $i0 #100\nload $r1 #1.0\nlabel_0:\nload $i2 #2\nsyscall $i2 @str_0\nload $v3 $i0 #16\nadd $r1 $i0 $r1\njlt @label_0 $i0 $i1\njz $i0 $i1\nsyscall $i0 $v3\nhalt\n"
+        );
+    }
+
+    #[test]
+    fn test_disassemble_compiled() {
+        let source = "func double(x: real) -> real {\n    return x * 2\n}\n\nc = [1, 2.5, -3]\nd = do(map, double, c)\nt = do(fold, func(acc, x) { return acc + x }, d, 0)\ns = do(sort, |3, 1|, gt)\nif t gt 1\n    do(print, \"big {t}\")\nelse\n    do(write, d)\ndone\n";
+        for level in 0..=2 {
+            for debug_info in [false, true] {
+                let mut compiler = Compiler::new();
+                compiler.set_opt_level(level);
+                compiler.set_debug_info(debug_info);
+                round_trip(&compiler.compile(source).unwrap());
+            }
+        }
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let listing = round_trip(
+            ".data\n.code\n.loc #1 #1\nload $i0 #1\n.var 'a' $i0\n.loc #2 #1\nend:\n.var 'b' $r1\n",
+        );
+        assert_eq!(
+            listing,
+            ".data\n.code\n.loc #1 #1\nload $i0 #1\n.var 'a' $i0\n.loc #2 #1\n.var 'b' $r1\n"
+        );
+    }
+
+    #[test]
+    fn test_label_at_end() {
+        let listing = round_trip(".data\n.code\njmp @end\nend:\n");
+        assert_eq!(listing, ".data\n.code\njmp @label_0\nlabel_0:\n");
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(disassemble(&[]).is_err());
+        assert!(disassemble(b"DOVM").is_err());
+
+        let mut bytecode = Assembler::new()
+            .assemble(".data\n.code\nload $i0 #1\n")
+            .unwrap();
+        bytecode.truncate(bytecode.len() - 1);
+        assert!(disassemble(&bytecode).is_err());
+
+        // Jumps into the middle of the load.
+        let mut bytecode = Assembler::new()
+            .assemble(".data\n.code\nload $i0 #1\njmp @end\nend:\n")
+            .unwrap();
+        let last = bytecode.len() - 1;
+        bytecode[last] = (DO_HEADER_LEN + 2) as u8;
+        assert!(disassemble(&bytecode).is_err());
+
+        let mut bytecode = Assembler::new().assemble(".data\n.code\nret\n").unwrap();
+        bytecode[DO_HEADER_LEN] = 200;
+        assert!(disassemble(&bytecode).is_err());
+    }
+
+    #[test]
+    fn test_disassemble_code() {
+        let code = [
+            Opcode::LOAD.into(),
+            1,
+            0,
+            0,
+            0,
+            7,
+            Opcode::HLT.into(),
+            0,
+            0,
+            0,
+        ];
+        assert_eq!(disassemble_code(&code).unwrap(), "load $i1 #7\nhalt\n");
+    }
+}
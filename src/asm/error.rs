@@ -1,9 +1,10 @@
+use crate::asm::header::DO_VERSION;
 use crate::asm::Token;
 use std::fmt;
 
 #[derive(Debug, Clone)]
 pub enum Error {
-    ParseError { error: String },
+    Parse { error: String },
     NoSectionDecl,
     MissingSection,
     StringConstantWithoutLabel { instr: String },
@@ -12,16 +13,21 @@ pub enum Error {
     UnknownDirective { name: String },
     UnknownSection { name: String },
     UnknownLabel { name: String },
+    LabelOutOfRange { name: String },
     UnexpectedToken { token: Token },
     NotAnOpcode,
     EmptyString,
     UnlabeledString,
+    InvalidBytecode { offset: usize, reason: String },
+    InvalidHeader { reason: String },
+    UnsupportedVersion { version: u16 },
+    InvalidDebugInfo { instr: String },
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match *self {
-            Error::ParseError { ref error } => f.write_str(&format!("Parse error: {}", error)),
+            Error::Parse { ref error } => f.write_str(&format!("Parse error: {}", error)),
             Error::NoSectionDecl => f.write_str("No section declared"),
             Error::MissingSection => f.write_str("Missing section"),
             Error::StringConstantWithoutLabel { ref instr } => f.write_str(&format!(
@@ -41,12 +47,28 @@ impl fmt::Display for Error {
                 f.write_str(&format!("Unknown section: {}", name))
             }
             Error::UnknownLabel { ref name } => f.write_str(&format!("Unknown label: {}", name)),
+            Error::LabelOutOfRange { ref name } => {
+                f.write_str(&format!("Label out of range: {}", name))
+            }
             Error::UnexpectedToken { ref token } => {
                 f.write_str(&format!("Unexpected token {:?} in the bagging area", token))
             }
             Error::NotAnOpcode => f.write_str("Non-opcode found in opcode field"),
             Error::EmptyString => f.write_str("Empty string provided"),
             Error::UnlabeledString => f.write_str("Unlabeled string cannot be referenced"),
+            Error::InvalidBytecode { offset, ref reason } => {
+                f.write_str(&format!("Invalid bytecode at {}: {}", offset, reason))
+            }
+            Error::InvalidHeader { ref reason } => {
+                f.write_str(&format!("Invalid bytecode header: {}", reason))
+            }
+            Error::UnsupportedVersion { version } => f.write_str(&format!(
+                "Unsupported bytecode version {} (expected {})",
+                version, DO_VERSION
+            )),
+            Error::InvalidDebugInfo { ref instr } => {
+                f.write_str(&format!("Invalid debug info: {}", instr))
+            }
         }
     }
 }
@@ -54,7 +76,7 @@ impl fmt::Display for Error {
 impl std::error::Error for Error {
     fn description(&self) -> &str {
         match self {
-            Error::ParseError { .. } => "There was an error parsing the code",
+            Error::Parse { .. } => "There was an error parsing the code",
             Error::NoSectionDecl => "No section declared",
             Error::MissingSection => "Missing section",
             Error::StringConstantWithoutLabel { .. } => "String constant declared without label",
@@ -63,10 +85,15 @@ impl std::error::Error for Error {
             Error::UnknownDirective { .. } => "Unknown directive",
             Error::UnknownSection { .. } => "Unknown section",
             Error::UnknownLabel { .. } => "Unknown label",
+            Error::LabelOutOfRange { .. } => "Label out of range",
             Error::UnexpectedToken { .. } => "Unexpected token",
-            Error::NotAnOpcode { .. } => "Not an opcode",
-            Error::EmptyString { .. } => "Empty string",
-            Error::UnlabeledString { .. } => "Unlabeled string",
+            Error::NotAnOpcode => "Not an opcode",
+            Error::EmptyString => "Empty string",
+            Error::UnlabeledString => "Unlabeled string",
+            Error::InvalidBytecode { .. } => "Invalid bytecode",
+            Error::InvalidHeader { .. } => "Invalid bytecode header",
+            Error::UnsupportedVersion { .. } => "Unsupported bytecode version",
+            Error::InvalidDebugInfo { .. } => "Invalid debug info",
         }
     }
 }
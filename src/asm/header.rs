@@ -0,0 +1,173 @@
+use crate::asm::error::Error;
+use crate::asm::{DO_HEADER_LEN, DO_HEADER_PREFIX};
+
+use std::ops::Range;
+
+// The version of the bytecode format. Bytecode of any other version is
+// rejected rather than misread, so this must change with the format.
+pub const DO_VERSION: u16 = 2;
+
+// No flags are defined yet.
+const DO_KNOWN_FLAGS: u16 = 0;
+
+// Sections follow the header in this order.
+pub const DO_SECTION_READONLY: usize = 0;
+pub const DO_SECTION_CODE: usize = 1;
+// Optional, mapping the code back to its source.
+pub const DO_SECTION_DEBUG: usize = 2;
+const DO_SECTION_COUNT: usize = 5;
+
+// After the prefix come the version, the flags, the offset execution starts
+// at and the length of each section, all big-endian.
+#[derive(Debug, PartialEq, Eq, Clone)]
+pub struct Header {
+    pub version: u16,
+    pub flags: u16,
+    pub entry: u32,
+    pub sections: [u32; DO_SECTION_COUNT],
+}
+
+impl Header {
+    // Starts execution at the start of the code.
+    pub fn new(ro_len: usize, code_len: usize) -> Header {
+        let mut sections = [0; DO_SECTION_COUNT];
+        sections[DO_SECTION_READONLY] = ro_len as u32;
+        sections[DO_SECTION_CODE] = code_len as u32;
+        Header {
+            version: DO_VERSION,
+            flags: 0,
+            entry: (DO_HEADER_LEN + ro_len) as u32,
+            sections,
+        }
+    }
+
+    pub fn parse(bytecode: &[u8]) -> Result<Header, Error> {
+        if !bytecode.starts_with(&DO_HEADER_PREFIX) {
+            return Err(Error::InvalidHeader {
+                reason: "Missing DOVM prefix".to_string(),
+            });
+        }
+        if bytecode.len() < DO_HEADER_LEN {
+            return Err(Error::InvalidHeader {
+                reason: format!(
+                    "Truncated header of {} bytes (expected {})",
+                    bytecode.len(),
+                    DO_HEADER_LEN
+                ),
+            });
+        }
+
+        let u16_at = |at: usize| u16::from_be_bytes([bytecode[at], bytecode[at + 1]]);
+        let u32_at = |at: usize| u32::from_be_bytes(bytecode[at..at + 4].try_into().unwrap());
+
+        let version = u16_at(4);
+        if version != DO_VERSION {
+            return Err(Error::UnsupportedVersion { version });
+        }
+
+        let flags = u16_at(6);
+        if flags & !DO_KNOWN_FLAGS != 0 {
+            return Err(Error::InvalidHeader {
+                reason: format!("Unknown flags {:#06x}", flags),
+            });
+        }
+
+        let mut sections = [0; DO_SECTION_COUNT];
+        for (idx, section) in sections.iter_mut().enumerate() {
+            *section = u32_at(12 + 4 * idx);
+        }
+        let header = Header {
+            version,
+            flags,
+            entry: u32_at(8),
+            sections,
+        };
+
+        if header.section(DO_SECTION_COUNT - 1).end > bytecode.len() {
+            return Err(Error::InvalidHeader {
+                reason: "Sections run past the end of the bytecode".to_string(),
+            });
+        }
+        let code = header.section(DO_SECTION_CODE);
+        if !(code.start..=code.end).contains(&(header.entry as usize)) {
+            return Err(Error::InvalidHeader {
+                reason: format!("Entry point {} is outside the code", header.entry),
+            });
+        }
+        Ok(header)
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut header = DO_HEADER_PREFIX.to_vec();
+        header.extend(self.version.to_be_bytes());
+        header.extend(self.flags.to_be_bytes());
+        header.extend(self.entry.to_be_bytes());
+        for section in &self.sections {
+            header.extend(section.to_be_bytes());
+        }
+        header
+    }
+
+    // The offsets of a section in the bytecode.
+    pub fn section(&self, section: usize) -> Range<usize> {
+        let start = DO_HEADER_LEN
+            + self.sections[..section]
+                .iter()
+                .map(|len| *len as usize)
+                .sum::<usize>();
+        start..start + self.sections[section] as usize
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_round_trip() {
+        let header = Header::new(6, 10);
+        let mut bytecode = header.to_bytes();
+        assert_eq!(bytecode.len(), DO_HEADER_LEN);
+        assert_eq!(bytecode[4..12], [0, 2, 0, 0, 0, 0, 0, 38]);
+
+        bytecode.resize(DO_HEADER_LEN + 16, 0);
+        assert_eq!(Header::parse(&bytecode).unwrap(), header);
+        assert_eq!(header.section(DO_SECTION_READONLY), 32..38);
+        assert_eq!(header.section(DO_SECTION_CODE), 38..48);
+    }
+
+    #[test]
+    fn test_invalid() {
+        let mut bytecode = Header::new(0, 4).to_bytes();
+        bytecode.resize(DO_HEADER_LEN + 4, 0);
+        assert!(Header::parse(&bytecode).is_ok());
+        assert!(Header::parse(&bytecode[..DO_HEADER_LEN + 2]).is_err());
+        assert_eq!(
+            Header::parse(&bytecode[..8]).unwrap_err().to_string(),
+            "Invalid bytecode header: Truncated header of 8 bytes (expected 32)"
+        );
+        assert_eq!(
+            Header::parse(&bytecode[..2]).unwrap_err().to_string(),
+            "Invalid bytecode header: Missing DOVM prefix"
+        );
+
+        let mut wrong = bytecode.clone();
+        wrong[0] = b'X';
+        assert!(Header::parse(&wrong).is_err());
+
+        let mut wrong = bytecode.clone();
+        wrong[5] = 0;
+        assert_eq!(
+            Header::parse(&wrong).unwrap_err().to_string(),
+            "Unsupported bytecode version 0 (expected 2)"
+        );
+
+        let mut wrong = bytecode.clone();
+        wrong[7] = 4;
+        assert!(Header::parse(&wrong).is_err());
+
+        let mut wrong = bytecode;
+        wrong[11] = 40;
+        assert!(Header::parse(&wrong).is_err());
+    }
+}
@@ -7,11 +7,14 @@ use nom::IResult;
 use crate::asm::directive_parsers::*;
 use crate::asm::error::Error;
 use crate::asm::label_parsers::*;
+use crate::asm::opcode::Opcode;
 use crate::asm::opcode_parsers::*;
 use crate::asm::operand_parsers::operand;
 use crate::asm::symbols::*;
-use crate::asm::Token;
-use crate::vm::register::{real_register_to_idx, vector_register_to_idx};
+use crate::asm::{Token, DO_LABEL_TARGET};
+use crate::vm::register::{
+    dict_register_to_idx, nested_register_to_idx, real_register_to_idx, vector_register_to_idx,
+};
 
 use std::fmt;
 
@@ -29,14 +32,15 @@ impl Instruction {
     pub fn new_directive(
         directive: Token,
         label: Option<Token>,
-        operand: Option<Token>,
+        operand0: Option<Token>,
+        operand1: Option<Token>,
     ) -> Instruction {
         Instruction {
             label,
             directive: Some(directive),
             opcode: None,
-            operand0: operand,
-            operand1: None,
+            operand0,
+            operand1,
             operand2: None,
         }
     }
@@ -115,6 +119,83 @@ impl Instruction {
         self.operand0.is_some() || self.operand1.is_some() || self.operand2.is_some()
     }
 
+    // The number of bytes this instruction will take up in the program. This
+    // must agree with `to_bytes`.
+    pub fn byte_len(&self) -> usize {
+        if !self.is_opcode() {
+            return 0;
+        }
+
+        let len = 1 + [&self.operand0, &self.operand1, &self.operand2]
+            .iter()
+            .copied()
+            .flatten()
+            .map(|token| self.operand_len(token))
+            .sum::<usize>();
+
+        len.max(4)
+    }
+
+    fn operand_len(&self, t: &Token) -> usize {
+        match t {
+            Token::IntRegister { .. }
+            | Token::RealRegister { .. }
+            | Token::VectorRegister { .. }
+            | Token::DictRegister { .. }
+            | Token::NestedRegister { .. } => 1,
+            Token::Integer { .. } => 4,
+            Token::Real { .. } => 8,
+            Token::LabelRef { .. } => {
+                if self.loads_label() || self.calls_label() {
+                    4
+                } else if self.jumps_to_label() {
+                    5
+                } else {
+                    2
+                }
+            }
+            _ => 0,
+        }
+    }
+
+    // Loading a label into an integer register loads its offset as a full
+    // integer so it can be used as a jump target.
+    fn loads_label(&self) -> bool {
+        matches!(self.opcode, Some(Token::Op { code: Opcode::LOAD }))
+            && matches!(self.operand0, Some(Token::IntRegister { .. }))
+            && matches!(self.operand1, Some(Token::LabelRef { .. }))
+    }
+
+    // Functions are called at their full offset too, as code can run past
+    // where two bytes reach.
+    fn calls_label(&self) -> bool {
+        matches!(
+            self.opcode,
+            Some(Token::Op {
+                code: Opcode::CALL | Opcode::MAP | Opcode::FOLD
+            })
+        )
+    }
+
+    // Jumping to a label rather than a register marks the target as a label
+    // so the VM knows to read an offset.
+    fn jumps_to_label(&self) -> bool {
+        matches!(
+            self.opcode,
+            Some(Token::Op {
+                code: Opcode::JMP
+                    | Opcode::JEQ
+                    | Opcode::JNE
+                    | Opcode::JGT
+                    | Opcode::JLT
+                    | Opcode::JGTE
+                    | Opcode::JLTE
+                    | Opcode::JZ
+                    | Opcode::JNZ
+            })
+        ) && matches!(self.operand0, Some(Token::LabelRef { .. }))
+    }
+
     pub fn string_constant(&self) -> Option<String> {
         if let Some(Token::DoString { value }) = &self.operand0 {
             Some(value.clone())
@@ -123,6 +204,42 @@ impl Instruction {
         }
     }
 
+    // The line and column given to a `.loc` directive.
+    pub fn source_location(&self) -> Option<(u32, u32)> {
+        match (&self.operand0, &self.operand1) {
+            (Some(Token::Integer { value: line }), Some(Token::Integer { value: column }))
+                if *line > 0 && *column > 0 =>
+            {
+                Some((*line as u32, *column as u32))
+            }
+            _ => None,
+        }
+    }
+
+    // The name and register given to a `.var` directive.
+    pub fn variable(&self) -> Option<(String, Token)> {
+        match (&self.operand0, &self.operand1) {
+            (
+                Some(Token::DoString { value }),
+                Some(
+                    register @ (Token::IntRegister { .. }
+                    | Token::RealRegister { .. }
+                    | Token::VectorRegister { .. }
+                    | Token::DictRegister { .. }
+                    | Token::NestedRegister { .. }),
+                ),
+            ) => Some((value.clone(), register.clone())),
+            _ => None,
+        }
+    }
+
+    // The byte a register is written as.
+    pub fn register_byte(register: &Token) -> Result<u8, Error> {
+        let mut bytes = vec![];
+        Instruction::extract_operand(register, &Table::new(), &mut bytes)?;
+        Ok(bytes[0])
+    }
+
     pub fn to_bytes(&self, symbols: &Table) -> Result<Vec<u8>, Error> {
         let mut results = vec![];
         // println!(".. writing {}", self);
@@ -141,6 +258,18 @@ impl Instruction {
             .copied()
             .flatten()
             .try_for_each(|token| -> Result<(), Error> {
+                if let Token::LabelRef { name } = token {
+                    if self.loads_label() || self.calls_label() || self.jumps_to_label() {
+                        if self.jumps_to_label() {
+                            results.push(DO_LABEL_TARGET);
+                        }
+                        let value = symbols.value(name).ok_or(Error::UnknownLabel {
+                            name: name.to_string(),
+                        })?;
+                        results.extend(value.to_be_bytes());
+                        return Ok(());
+                    }
+                }
                 Instruction::extract_operand(token, symbols, &mut results)?;
                 Ok(())
             })?;
@@ -165,6 +294,14 @@ impl Instruction {
                 let idx = vector_register_to_idx(*idx);
                 results.push(idx);
             }
+            Token::DictRegister { idx } => {
+                let idx = dict_register_to_idx(*idx);
+                results.push(idx);
+            }
+            Token::NestedRegister { idx } => {
+                let idx = nested_register_to_idx(*idx);
+                results.push(idx);
+            }
             Token::Integer { value } => {
                 for b in value.to_be_bytes().iter() {
                     results.push(*b);
@@ -177,10 +314,10 @@ impl Instruction {
             }
             Token::LabelRef { name } => {
                 if let Some(value) = symbols.value(name) {
-                    let lb = value;
-                    let hb = value >> 8;
-                    results.push(hb as u8);
-                    results.push(lb as u8);
+                    let value = u16::try_from(value).map_err(|_| Error::LabelOutOfRange {
+                        name: name.to_string(),
+                    })?;
+                    results.extend(value.to_be_bytes());
                 } else {
                     return Err(Error::UnknownLabel {
                         name: name.to_string(),
@@ -206,7 +343,14 @@ impl fmt::Display for Instruction {
 }
 
 pub fn instruction(i: &str) -> IResult<&str, Instruction> {
-    alt((instruction_comb, directive))(i)
+    alt((instruction_comb, directive, label))(i)
+}
+
+fn label(i: &str) -> IResult<&str, Instruction> {
+    map_res(
+        label_decl,
+        |l| -> Result<Instruction, nom::error::Error<&str>> { Ok(Instruction::new_label(l)) },
+    )(i)
 }
 
 fn instruction_comb(i: &str) -> IResult<&str, Instruction> {
@@ -219,9 +363,12 @@ fn instruction_comb(i: &str) -> IResult<&str, Instruction> {
             opt(preceded(multispace1, operand)),
             opt(preceded(multispace1, operand)),
         )),
-        |(_l, op, o0, o1, o2)| -> Result<Instruction, nom::error::Error<&str>> {
+        |(l, op, o0, o1, o2)| -> Result<Instruction, nom::error::Error<&str>> {
             log::debug!("[asm::instruction] success ({:?})'", op);
-            Ok(Instruction::new_opcode(op, o0, o1, o2))
+            Ok(Instruction {
+                label: l,
+                ..Instruction::new_opcode(op, o0, o1, o2)
+            })
         },
     )(i)
 }
@@ -229,7 +376,6 @@ fn instruction_comb(i: &str) -> IResult<&str, Instruction> {
 #[cfg(test)]
 mod tests {
     use super::*;
-    use crate::asm::opcode::Opcode;
 
     #[test]
     fn test_extract_operand_int_register() {
@@ -323,6 +469,39 @@ mod tests {
         )
     }
 
+    #[test]
+    fn test_parse_label() {
+        let result = instruction("if_0_end:");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Instruction::new_label(Token::LabelDecl {
+                    name: "if_0_end".to_string()
+                })
+            ))
+        )
+    }
+
+    #[test]
+    fn test_byte_len() {
+        let (_, i) = instruction_comb("halt").unwrap();
+        assert_eq!(i.byte_len(), 4);
+        let (_, i) = instruction_comb("load $r0 #1.5").unwrap();
+        assert_eq!(i.byte_len(), 10);
+        let (_, i) = instruction_comb("load $i0 @test").unwrap();
+        assert_eq!(i.byte_len(), 6);
+
+        let mut symbols = Table::new();
+        let mut symbol = Symbol::new("test".to_string(), Type::Label);
+        symbol.set_offset(300);
+        symbols.add(symbol);
+        assert_eq!(
+            i.to_bytes(&symbols).unwrap(),
+            vec![Opcode::LOAD as u8, 0, 0, 0, 1, 44]
+        );
+    }
+
     #[test]
     fn test_parse_instruction_form_two() {
         let result = instruction_comb("halt");
@@ -1,16 +1,22 @@
 use crate::asm::Token;
 
 use nom::{
+    branch::alt,
     bytes::complete::tag,
-    character::complete::{alphanumeric1, multispace0},
-    combinator::{map_res, opt},
-    sequence::{delimited, tuple},
+    character::complete::{alphanumeric1, space0},
+    combinator::{map_res, opt, recognize},
+    multi::many1,
+    sequence::{preceded, tuple},
     IResult,
 };
 
+fn label_name(i: &str) -> IResult<&str, &str> {
+    recognize(many1(alt((alphanumeric1, tag("_")))))(i)
+}
+
 pub fn label_decl(i: &str) -> IResult<&str, Token> {
     map_res(
-        tuple((alphanumeric1, tag(":"), opt(multispace0))),
+        tuple((label_name, tag(":"), opt(space0))),
         |(name, _, _)| -> Result<Token, nom::error::Error<&str>> {
             Ok(Token::LabelDecl {
                 name: String::from(name),
@@ -21,7 +27,7 @@ pub fn label_decl(i: &str) -> IResult<&str, Token> {
 
 pub fn label_ref(i: &str) -> IResult<&str, Token> {
     map_res(
-        delimited(tag("@"), alphanumeric1, multispace0),
+        preceded(tag("@"), label_name),
         |name| -> Result<Token, nom::error::Error<&str>> {
             Ok(Token::LabelRef {
                 name: String::from(name),
@@ -48,6 +54,16 @@ mod tests {
 
         let result = label_decl("test");
         assert!(result.is_err());
+
+        let result = label_decl("func_test_1:");
+        assert!(result.is_ok());
+        let (_, token) = result.unwrap();
+        assert_eq!(
+            token,
+            Token::LabelDecl {
+                name: "func_test_1".to_string()
+            }
+        );
     }
 
     #[test]
@@ -4,8 +4,11 @@ use crate::asm::opcode::Opcode;
 use crate::asm::program_parsers::{program, Program};
 use crate::asm::symbols::{Symbol, Table, Type};
 
+mod debug_info;
 mod directive_parsers;
+mod disassembler;
 mod error;
+mod header;
 mod instruction_parsers;
 mod label_parsers;
 mod opcode_parsers;
@@ -17,15 +20,28 @@ pub mod opcode;
 pub mod program_parsers;
 pub mod syscalls;
 
+pub use self::debug_info::{DebugInfo, Record};
+pub use self::disassembler::{disassemble, disassemble_code};
+pub use self::header::{
+    Header, DO_SECTION_CODE, DO_SECTION_DEBUG, DO_SECTION_READONLY, DO_VERSION,
+};
+pub use self::operand_parsers::escape;
+
 pub const DO_HEADER_PREFIX: [u8; 4] = [68, 79, 86, 77]; // "DOVM"
 pub const DO_HEADER_LEN: usize = 32;
 
+// Written in place of the register holding a jump's target when the target is
+// a label, whose offset follows in the next four bytes.
+pub const DO_LABEL_TARGET: u8 = 0xff;
+
 #[derive(Debug, PartialEq, Clone)]
 pub enum Token {
     Op { code: Opcode },
     IntRegister { idx: u8 },
     RealRegister { idx: u8 },
     VectorRegister { idx: u8 },
+    DictRegister { idx: u8 },
+    NestedRegister { idx: u8 },
     Integer { value: i32 },
     Real { value: f64 },
     LabelDecl { name: String },
@@ -44,6 +60,7 @@ pub enum Phase {
 pub struct Assembler {
     pub symbols: Table,
     readonly: Vec<u8>,
+    debug: DebugInfo,
     phase: Phase,
     sections: Vec<Section>,
     current_section: Option<Section>,
@@ -55,6 +72,7 @@ impl Assembler {
         Assembler {
             symbols: Table::new(),
             readonly: vec![],
+            debug: DebugInfo::new(),
             phase: Phase::First,
             sections: vec![],
             current_section: None,
@@ -81,14 +99,23 @@ impl Assembler {
                     self.errors.push(e);
                     return Err(self.errors.clone());
                 }
+                // Debug info is only checked in the second phase.
+                if !self.errors.is_empty() {
+                    return Err(self.errors.clone());
+                }
 
-                let mut assembled = self.write_header();
+                let mut body = body.unwrap();
+                let mut debug = self.debug.to_bytes();
+                let mut header = Header::new(self.readonly.len(), body.len());
+                header.sections[DO_SECTION_DEBUG] = debug.len() as u32;
+                let mut assembled = header.to_bytes();
                 assembled.append(&mut self.readonly);
-                assembled.append(&mut body.unwrap());
+                assembled.append(&mut body);
+                assembled.append(&mut debug);
                 Ok(assembled)
             }
             Err(e) => {
-                self.errors.push(Error::ParseError {
+                self.errors.push(Error::Parse {
                     error: e.to_string(),
                 });
                 Err(self.errors.clone())
@@ -97,6 +124,7 @@ impl Assembler {
     }
 
     fn process_first(&mut self, p: &Program) {
+        let mut code_offset = 0;
         for i in &p.instructions {
             if i.is_comment() {
                 continue;
@@ -104,20 +132,28 @@ impl Assembler {
 
             if i.is_label() {
                 if self.current_section.is_some() {
-                    self.process_label_decl(i);
+                    self.process_label_decl(i, code_offset);
                 } else {
                     self.errors.push(Error::NoSectionDecl);
                 }
             }
 
             if i.is_directive() {
-                self.process_directive(i);
+                self.process_directive(i, code_offset as usize);
             }
+
+            code_offset += i.byte_len() as u32;
         }
+
+        // Code labels are offsets into the whole bytecode, which starts with
+        // the header and the read-only data.
+        let code_start = (DO_HEADER_LEN + self.readonly.len()) as u32;
+        self.symbols.shift_offsets(Type::Label, code_start);
+
         self.phase = Phase::Second;
     }
 
-    fn process_label_decl(&mut self, i: &Instruction) {
+    fn process_label_decl(&mut self, i: &Instruction, code_offset: u32) {
         let name = match i.label_name() {
             Some(name) => name,
             None => {
@@ -133,7 +169,16 @@ impl Assembler {
             return;
         }
 
-        self.symbols.add(Symbol::new(name, Type::Label));
+        if i.is_directive() {
+            self.symbols.add(Symbol::new(name, Type::IrString));
+            return;
+        }
+
+        let mut symbol = Symbol::new(name, Type::Label);
+        if let Some(Section::Code { .. }) = self.current_section {
+            symbol.set_offset(code_offset);
+        }
+        self.symbols.add(symbol);
     }
 
     // NOTE: public so the repl can do the right thing.
@@ -147,13 +192,15 @@ impl Assembler {
                 program.append(&mut i.to_bytes(&self.symbols)?);
             }
             if i.is_directive() {
-                self.process_directive(i);
+                self.process_directive(i, program.len());
             }
         }
         Ok(program)
     }
 
-    fn process_directive(&mut self, i: &Instruction) {
+    // Debug info is recorded against `code_offset`, the offset into the code
+    // of the next instruction.
+    fn process_directive(&mut self, i: &Instruction, code_offset: usize) {
         let name = match i.directive_name() {
             Some(name) => name,
             None => {
@@ -169,6 +216,9 @@ impl Assembler {
                 "str" => {
                     self.handle_str(i);
                 }
+                "loc" | "var" => {
+                    self.handle_debug_info(i, &name, code_offset);
+                }
                 _ => {
                     self.errors.push(Error::UnknownDirective { name });
                 }
@@ -190,6 +240,33 @@ impl Assembler {
         self.current_section = Some(section);
     }
 
+    fn handle_debug_info(&mut self, i: &Instruction, name: &str, code_offset: usize) {
+        if self.phase != Phase::Second {
+            return;
+        }
+
+        let pc = (DO_HEADER_LEN + self.readonly.len() + code_offset) as u32;
+        let record = match name {
+            "loc" => {
+                i.source_location()
+                    .map(|(line, column)| Record::Location { pc, line, column })
+            }
+            _ => i.variable().and_then(|(name, register)| {
+                Some(Record::Variable {
+                    pc,
+                    register: Instruction::register_byte(&register).ok()?,
+                    name,
+                })
+            }),
+        };
+        match record {
+            Some(record) => self.debug.records.push(record),
+            None => self.errors.push(Error::InvalidDebugInfo {
+                instr: i.to_string(),
+            }),
+        }
+    }
+
     fn handle_str(&mut self, i: &Instruction) {
         if self.phase != Phase::First {
             return;
@@ -217,22 +294,6 @@ impl Assembler {
             }
         }
     }
-
-    fn write_header(&self) -> Vec<u8> {
-        let mut header = vec![];
-        for byte in &DO_HEADER_PREFIX {
-            header.push(*byte);
-        }
-        let ro_len = self.readonly.len() as u32;
-        header.push((ro_len >> 24) as u8);
-        header.push((ro_len >> 16) as u8);
-        header.push((ro_len >> 8) as u8);
-        header.push(ro_len as u8);
-        while header.len() < DO_HEADER_LEN {
-            header.push(0);
-        }
-        header
-    }
 }
 
 impl Default for Assembler {
@@ -241,19 +302,18 @@ impl Default for Assembler {
     }
 }
 
-#[derive(Debug, PartialEq, Eq, Clone)]
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
 pub enum Section {
-    Data { offset: Option<u32> },
-    Code { offset: Option<u32> },
+    Data {
+        offset: Option<u32>,
+    },
+    Code {
+        offset: Option<u32>,
+    },
+    #[default]
     Unknown,
 }
 
-impl Default for Section {
-    fn default() -> Self {
-        Section::Unknown
-    }
-}
-
 impl From<&str> for Section {
     fn from(name: &str) -> Section {
         match name {
@@ -267,6 +327,7 @@ impl From<&str> for Section {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::vm::register::vector_register_to_idx;
 
     #[test]
     fn test_assemble_program() {
@@ -339,12 +400,172 @@ mod tests {
         assert_eq!(asm.errors.len(), 0);
     }
 
+    #[test]
+    fn test_code_label_offsets() {
+        let mut asm = Assembler::new();
+        let test =
+            ".data\nhello: .str 'Hello'\n.code\nload $i0 #100\nstart:\nload $r1 #1.4\nend:\nhalt\n";
+        let program = asm.assemble(test);
+        assert!(program.is_ok());
+        assert_eq!(asm.symbols.value("hello"), Some(0));
+        assert_eq!(
+            asm.symbols.value("start"),
+            Some(DO_HEADER_LEN as u32 + 6 + 6)
+        );
+        assert_eq!(
+            asm.symbols.value("end"),
+            Some(DO_HEADER_LEN as u32 + 6 + 16)
+        );
+    }
+
+    #[test]
+    fn test_label_jumps() {
+        let mut asm = Assembler::new();
+        let test = ".data\n.code\nstart: load $i0 #1\njmp @start\njeq @end $i0 $i0\nend: halt\n";
+        let program = asm.assemble(test).unwrap();
+        let start = DO_HEADER_LEN as u32;
+        let end = start + 6 + 6 + 8;
+        assert_eq!(asm.symbols.value("start"), Some(start));
+        assert_eq!(asm.symbols.value("end"), Some(end));
+
+        let code = &program[DO_HEADER_LEN + 6..];
+        assert_eq!(
+            code[..6],
+            [Opcode::JMP.into(), DO_LABEL_TARGET, 0, 0, 0, start as u8]
+        );
+        assert_eq!(
+            code[6..14],
+            [
+                Opcode::JEQ.into(),
+                DO_LABEL_TARGET,
+                0,
+                0,
+                0,
+                end as u8,
+                0,
+                0
+            ]
+        );
+
+        let mut asm = Assembler::new();
+        let program = asm
+            .assemble(".data\n.code\nstart: jz @start $i1\njgte $i0 $i1 $i2\n")
+            .unwrap();
+        assert_eq!(
+            program[DO_HEADER_LEN..],
+            [
+                Opcode::JZ.into(),
+                DO_LABEL_TARGET,
+                0,
+                0,
+                0,
+                DO_HEADER_LEN as u8,
+                1,
+                Opcode::JGTE.into(),
+                0,
+                1,
+                2
+            ]
+        );
+
+        // past where two bytes reach.
+        let mut test = String::from(".data\n.code\njz @end $i1\n");
+        test.push_str(&"load $i0 #1\n".repeat(12000));
+        test.push_str("end: halt\n");
+        let mut asm = Assembler::new();
+        let program = asm.assemble(&test).unwrap();
+        let end = asm.symbols.value("end").unwrap();
+        assert!(end > u16::MAX as u32);
+        let mut jz = vec![Opcode::JZ.into(), DO_LABEL_TARGET];
+        jz.extend(end.to_be_bytes());
+        jz.push(1);
+        assert_eq!(program[DO_HEADER_LEN..DO_HEADER_LEN + 7], jz);
+
+        let mut asm = Assembler::new();
+        assert!(asm.assemble(".data\n.code\njmp @nowhere\n").is_err());
+    }
+
+    #[test]
+    fn test_far_calls() {
+        // Enough code to put the function past where two bytes reach.
+        let mut test = String::from(".data\n.code\ncall @f\nmap $v0 $v1 @f\n");
+        test.push_str(&"load $i0 #1\n".repeat(12000));
+        test.push_str("f: ret\n");
+        let mut asm = Assembler::new();
+        let program = asm.assemble(&test).unwrap();
+        let f = asm.symbols.value("f").unwrap();
+        assert!(f > u16::MAX as u32);
+
+        let code = &program[DO_HEADER_LEN..];
+        let mut call = vec![Opcode::CALL.into()];
+        call.extend(f.to_be_bytes());
+        assert_eq!(code[..5], call);
+        let mut map = vec![
+            Opcode::MAP.into(),
+            vector_register_to_idx(0),
+            vector_register_to_idx(1),
+        ];
+        map.extend(f.to_be_bytes());
+        assert_eq!(code[5..12], map);
+
+        // strings are still addressed with two bytes.
+        let mut test = format!(".data\nfar: .str '{}'\n", "a".repeat(70000));
+        test.push_str("hello: .str 'hello'\n.code\nload $i0 #3\nsyscall $i0 @hello\n");
+        assert!(Assembler::new().assemble(&test).is_err());
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let mut asm = Assembler::new();
+        let test = ".data\n.code\n.loc #1 #1\nload $i0 #1\n.var 'a' $i0\n.loc #2 #3\nhalt\n";
+        let program = asm.assemble(test).unwrap();
+        let header = Header::parse(&program).unwrap();
+        let debug = DebugInfo::parse(&program[header.section(DO_SECTION_DEBUG)], 0).unwrap();
+        let start = DO_HEADER_LEN as u32;
+        assert_eq!(
+            debug.records,
+            vec![
+                Record::Location {
+                    pc: start,
+                    line: 1,
+                    column: 1
+                },
+                Record::Variable {
+                    pc: start + 6,
+                    register: 0,
+                    name: "a".to_string()
+                },
+                Record::Location {
+                    pc: start + 6,
+                    line: 2,
+                    column: 3
+                },
+            ]
+        );
+
+        // Without any, there's no debug section.
+        let program = Assembler::new().assemble(".data\n.code\nhalt\n").unwrap();
+        assert_eq!(
+            Header::parse(&program).unwrap().sections[DO_SECTION_DEBUG],
+            0
+        );
+
+        let mut asm = Assembler::new();
+        assert!(asm.assemble(".data\n.code\n.loc #1\nhalt\n").is_err());
+        let mut asm = Assembler::new();
+        assert!(asm.assemble(".data\n.code\n.var 'a' #1\nhalt\n").is_err());
+    }
+
     #[test]
     fn test_start_offset_written() {
         let mut asm = Assembler::new();
-        let test = ".data\ntest: .str 'Hello'\n.code\nload $0 #100\nhlt\n";
+        let test = ".data\ntest: .str 'Hello'\n.code\nload $i0 #100\nhalt\n";
         let program = asm.assemble(test);
         assert!(program.is_ok());
-        assert_eq!(program.unwrap()[4..8], [0, 0, 0, 6]);
+        let header = Header::parse(&program.unwrap()).unwrap();
+        assert_eq!(header.version, DO_VERSION);
+        assert_eq!(header.entry, DO_HEADER_LEN as u32 + 6);
+        assert_eq!(header.sections[DO_SECTION_READONLY], 6);
+        assert_eq!(header.sections[DO_SECTION_CODE], 10);
     }
 }
@@ -1,5 +1,7 @@
 use num_enum::{IntoPrimitive, TryFromPrimitive};
 
+use std::fmt;
+
 #[derive(Debug, PartialEq, Eq, TryFromPrimitive, IntoPrimitive)]
 #[repr(u8)]
 pub enum Opcode {
@@ -14,7 +16,8 @@ pub enum Opcode {
     MUL,
     DIV,
     JMP,
-    // TODO: RET
+    CALL,
+    RET,
     EQ,
     NEQ,
     GT,
@@ -26,7 +29,35 @@ pub enum Opcode {
     OR,
     NOT,
     ALLOC,
+    PUSH,
+    POP,
+    MAP,
+    MASK,
+    FOLD,
+    SUM,
+    PROD,
+    MIN,
+    MAX,
+    DICT,
+    GET,
+    KEYS,
+    VALS,
+    SORT,
+    FIRST,
+    CAT,
+    ZIP,
+    LEN,
+    NEST,
+    AT,
     SYSCALL,
+    JNE,
+    JGT,
+    JLT,
+    JGTE,
+    JLTE,
+    JZ,
+    JNZ,
+    FLAT,
     IGL = 255,
 }
 
@@ -44,6 +75,8 @@ impl<'a> From<&'a str> for Opcode {
             "mul" => Opcode::MUL,
             "div" => Opcode::DIV,
             "jmp" => Opcode::JMP,
+            "call" => Opcode::CALL,
+            "ret" => Opcode::RET,
             "eq" => Opcode::EQ,
             "neq" => Opcode::NEQ,
             "gt" => Opcode::GT,
@@ -55,12 +88,101 @@ impl<'a> From<&'a str> for Opcode {
             "or" => Opcode::OR,
             "not" => Opcode::NOT,
             "alloc" => Opcode::ALLOC,
+            "push" => Opcode::PUSH,
+            "pop" => Opcode::POP,
+            "map" => Opcode::MAP,
+            "mask" => Opcode::MASK,
+            "fold" => Opcode::FOLD,
+            "sum" => Opcode::SUM,
+            "prod" => Opcode::PROD,
+            "min" => Opcode::MIN,
+            "max" => Opcode::MAX,
+            "dict" => Opcode::DICT,
+            "get" => Opcode::GET,
+            "keys" => Opcode::KEYS,
+            "vals" => Opcode::VALS,
+            "sort" => Opcode::SORT,
+            "first" => Opcode::FIRST,
+            "cat" => Opcode::CAT,
+            "zip" => Opcode::ZIP,
+            "len" => Opcode::LEN,
+            "nest" => Opcode::NEST,
+            "at" => Opcode::AT,
             "syscall" => Opcode::SYSCALL,
+            "jne" => Opcode::JNE,
+            "jgt" => Opcode::JGT,
+            "jlt" => Opcode::JLT,
+            "jgte" => Opcode::JGTE,
+            "jlte" => Opcode::JLTE,
+            "jz" => Opcode::JZ,
+            "jnz" => Opcode::JNZ,
+            "flat" => Opcode::FLAT,
             _ => Opcode::IGL,
         }
     }
 }
 
+// The name of the opcode as it is written in assembly.
+impl fmt::Display for Opcode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self {
+            Opcode::HLT => "halt",
+            Opcode::LOAD => "load",
+            Opcode::COPY => "copy",
+            Opcode::LW => "lw",
+            Opcode::SW => "sw",
+            Opcode::ADD => "add",
+            Opcode::SUB => "sub",
+            Opcode::MUL => "mul",
+            Opcode::DIV => "div",
+            Opcode::JMP => "jmp",
+            Opcode::CALL => "call",
+            Opcode::RET => "ret",
+            Opcode::EQ => "eq",
+            Opcode::NEQ => "neq",
+            Opcode::GT => "gt",
+            Opcode::LT => "lt",
+            Opcode::GTE => "gte",
+            Opcode::LTE => "lte",
+            Opcode::JEQ => "jeq",
+            Opcode::AND => "and",
+            Opcode::OR => "or",
+            Opcode::NOT => "not",
+            Opcode::ALLOC => "alloc",
+            Opcode::PUSH => "push",
+            Opcode::POP => "pop",
+            Opcode::MAP => "map",
+            Opcode::MASK => "mask",
+            Opcode::FOLD => "fold",
+            Opcode::SUM => "sum",
+            Opcode::PROD => "prod",
+            Opcode::MIN => "min",
+            Opcode::MAX => "max",
+            Opcode::DICT => "dict",
+            Opcode::GET => "get",
+            Opcode::KEYS => "keys",
+            Opcode::VALS => "vals",
+            Opcode::SORT => "sort",
+            Opcode::FIRST => "first",
+            Opcode::CAT => "cat",
+            Opcode::ZIP => "zip",
+            Opcode::LEN => "len",
+            Opcode::NEST => "nest",
+            Opcode::AT => "at",
+            Opcode::SYSCALL => "syscall",
+            Opcode::JNE => "jne",
+            Opcode::JGT => "jgt",
+            Opcode::JLT => "jlt",
+            Opcode::JGTE => "jgte",
+            Opcode::JLTE => "jlte",
+            Opcode::JZ => "jz",
+            Opcode::JNZ => "jnz",
+            Opcode::FLAT => "flat",
+            Opcode::IGL => "igl",
+        })
+    }
+}
+
 impl Copy for Opcode {}
 
 impl Clone for Opcode {
@@ -92,4 +214,15 @@ mod tests {
         assert_eq!(Opcode::from("lOaD"), Opcode::LOAD);
         assert_eq!(Opcode::from("daol"), Opcode::IGL);
     }
+
+    #[test]
+    fn test_to_str() {
+        assert_eq!(Opcode::HLT.to_string(), "halt");
+        assert_eq!(Opcode::JGTE.to_string(), "jgte");
+        for b in 0..=u8::MAX {
+            if let Ok(op) = Opcode::try_from(b) {
+                assert_eq!(Opcode::from(op.to_string().as_str()), op);
+            }
+        }
+    }
 }
@@ -1,6 +1,6 @@
 use nom::branch::alt;
-use nom::bytes::complete::{tag, take_until};
-use nom::combinator::map_res;
+use nom::bytes::complete::{escaped_transform, is_not, tag};
+use nom::combinator::{consumed, map_res, opt, value};
 use nom::number::complete::double;
 use nom::sequence::{delimited, preceded};
 use nom::IResult;
@@ -15,9 +15,10 @@ pub fn operand(i: &str) -> IResult<&str, Token> {
 
 pub fn num_operand(i: &str) -> IResult<&str, Token> {
     map_res(
-        preceded(tag("#"), double),
-        |value| -> Result<Token, nom::error::Error<&str>> {
-            if value == (value as i32) as f64 {
+        preceded(tag("#"), consumed(double)),
+        |(text, value): (&str, f64)| -> Result<Token, nom::error::Error<&str>> {
+            // Written with a decimal point, whole numbers are still reals.
+            if !text.contains(['.', 'e', 'E']) && value == (value as i32) as f64 {
                 Ok(Token::Integer {
                     value: value as i32,
                 })
@@ -28,17 +29,39 @@ pub fn num_operand(i: &str) -> IResult<&str, Token> {
     )(i)
 }
 
+// A string in single quotes, in which a backslash escapes a quote, another
+// backslash or, as `\n`, a newline.
 pub fn string(i: &str) -> IResult<&str, Token> {
     map_res(
-        delimited(tag("'"), take_until("'"), tag("'")),
+        delimited(
+            tag("'"),
+            opt(escaped_transform(
+                is_not("\\'"),
+                '\\',
+                alt((
+                    value("'", tag("'")),
+                    value("\\", tag("\\")),
+                    value("\n", tag("n")),
+                )),
+            )),
+            tag("'"),
+        ),
         |content| -> Result<Token, nom::error::Error<&str>> {
             Ok(Token::DoString {
-                value: String::from(content),
+                value: content.unwrap_or_default(),
             })
         },
     )(i)
 }
 
+// Escapes the string to be written as a string operand.
+pub fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('\'', "\\'")
+        .replace('\n', "\\n")
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -84,6 +107,9 @@ mod tests {
         let (rest, value) = result.unwrap();
         assert_eq!(rest, "");
         assert_eq!(value, Token::Integer { value: 4 });
+
+        let (_, value) = operand("#4.00").unwrap();
+        assert_eq!(value, Token::Real { value: 4.0 });
     }
 
     #[test]
@@ -120,5 +146,33 @@ mod tests {
 
         let result = string("'invalid");
         assert!(result.is_err());
+
+        let result = string("''");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Token::DoString {
+                    value: String::new()
+                }
+            ))
+        );
+
+        // escaped strings round trip.
+        for value in ["it's", "a \\ b", "two\nlines", "'\\'"] {
+            let quoted = format!("'{}'", escape(value));
+            assert_eq!(
+                string(&quoted),
+                Ok((
+                    "",
+                    Token::DoString {
+                        value: value.to_string()
+                    }
+                )),
+                "{}",
+                quoted
+            );
+        }
+        assert_eq!(escape("it's"), "it\\'s");
     }
 }
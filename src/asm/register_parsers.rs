@@ -38,8 +38,30 @@ fn vregister(i: &str) -> IResult<&str, Token> {
     )(i)
 }
 
+fn dregister(i: &str) -> IResult<&str, Token> {
+    map_res(
+        preceded(tag("$d"), digit1),
+        |idx: &str| -> Result<Token, nom::error::Error<&str>> {
+            Ok(Token::DictRegister {
+                idx: idx.parse::<u8>().unwrap(),
+            })
+        },
+    )(i)
+}
+
+fn nregister(i: &str) -> IResult<&str, Token> {
+    map_res(
+        preceded(tag("$n"), digit1),
+        |idx: &str| -> Result<Token, nom::error::Error<&str>> {
+            Ok(Token::NestedRegister {
+                idx: idx.parse::<u8>().unwrap(),
+            })
+        },
+    )(i)
+}
+
 pub fn register(i: &str) -> IResult<&str, Token> {
-    alt((iregister, rregister, vregister))(i)
+    alt((iregister, rregister, vregister, dregister, nregister))(i)
 }
 
 #[cfg(test)]
@@ -69,6 +91,20 @@ mod tests {
         assert_eq!(rest, "");
         assert_eq!(value, Token::VectorRegister { idx: 30 });
 
+        let result = register("$d2");
+        assert!(result.is_ok());
+
+        let (rest, value) = result.unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(value, Token::DictRegister { idx: 2 });
+
+        let result = register("$n3");
+        assert!(result.is_ok());
+
+        let (rest, value) = result.unwrap();
+        assert_eq!(rest, "");
+        assert_eq!(value, Token::NestedRegister { idx: 3 });
+
         let result = register("0");
         assert!(result.is_err());
 
@@ -19,7 +19,7 @@ impl Symbol {
     }
 }
 
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub enum Type {
     Label,
     Integer,
@@ -65,6 +65,17 @@ impl Table {
             }
         }
     }
+
+    // Moves the offsets of all symbols of the given type by `shift`.
+    pub fn shift_offsets(&mut self, symbol_type: Type, shift: u32) {
+        for symbol in &mut self.symbols {
+            if symbol.symbol_type == symbol_type {
+                if let Some(offset) = symbol.offset {
+                    symbol.offset = Some(offset + shift);
+                }
+            }
+        }
+    }
 }
 
 #[cfg(test)]
@@ -84,4 +95,19 @@ mod tests {
         let v = sym.value("not_exist");
         assert!(v.is_none());
     }
+
+    #[test]
+    fn test_shift_offsets() {
+        let mut sym = Table::new();
+        let mut label = Symbol::new("label".to_string(), Type::Label);
+        label.set_offset(12);
+        sym.add(label);
+        let mut string = Symbol::new("string".to_string(), Type::IrString);
+        string.set_offset(4);
+        sym.add(string);
+
+        sym.shift_offsets(Type::Label, 32);
+        assert_eq!(sym.value("label"), Some(44));
+        assert_eq!(sym.value("string"), Some(4));
+    }
 }
@@ -6,4 +6,8 @@ pub enum Syscall {
     PrintReg,
     PrintMem,
     PrintStr,
+    ReadReg,
+    // As PrintReg and PrintStr, without the trailing newline.
+    PrintRegInline,
+    PrintStrInline,
 }
@@ -5,9 +5,22 @@ use nom::error::ErrorKind;
 #[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum Builtin {
     Write,
+    Print,
+    Read,
     Map,
     Filter,
     Fold,
+    Get,
+    Keys,
+    Values,
+    Seq,
+    Coll,
+    Sort,
+    First,
+    Flatten,
+    Length,
+    Zip,
+    At,
 }
 
 impl fmt::Display for Builtin {
@@ -30,9 +43,27 @@ impl<'a> TryFrom<&'a str> for Builtin {
 }
 
 fn iterator() -> impl Iterator<Item = Builtin> {
-    [Builtin::Write, Builtin::Map, Builtin::Filter, Builtin::Fold]
-        .iter()
-        .copied()
+    [
+        Builtin::Write,
+        Builtin::Print,
+        Builtin::Read,
+        Builtin::Map,
+        Builtin::Filter,
+        Builtin::Fold,
+        Builtin::Get,
+        Builtin::Keys,
+        Builtin::Values,
+        Builtin::Seq,
+        Builtin::Coll,
+        Builtin::Sort,
+        Builtin::First,
+        Builtin::Flatten,
+        Builtin::Length,
+        Builtin::Zip,
+        Builtin::At,
+    ]
+    .iter()
+    .copied()
 }
 
 #[cfg(test)]
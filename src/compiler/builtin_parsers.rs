@@ -1,5 +1,9 @@
-use crate::compiler::{builtin::Builtin, expression_parsers::*, tokens::Token};
+use crate::compiler::{
+    builtin::Builtin, expression_parsers::*, function_parser::lambda, operand_parsers::string,
+    tokens::Token,
+};
 
+use nom::branch::alt;
 use nom::bytes::complete::tag;
 use nom::character::complete::{alpha1, multispace0};
 use nom::combinator::{map_res, opt};
@@ -18,7 +22,10 @@ pub fn builtin(i: &str) -> IResult<&str, Token> {
                     alpha1,
                     opt(preceded(
                         delimited(multispace0, tag(","), multispace0),
-                        separated_list1(delimited(multispace0, tag(","), multispace0), rvalue),
+                        separated_list1(
+                            delimited(multispace0, tag(","), multispace0),
+                            alt((lambda, string, rvalue)),
+                        ),
                     )),
                 ),
                 preceded(multispace0, tag(")")),
@@ -103,6 +110,24 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_builtin_lambda_arg() {
+        init();
+
+        let result = builtin("do(map, func(a) { return a }, foo)");
+        assert!(result.is_ok());
+        let (rest, token) = result.unwrap();
+        assert_eq!(rest, "");
+        match token {
+            Token::Builtin { builtin, args } => {
+                assert_eq!(builtin, Builtin::Map);
+                assert!(matches!(args[0], Token::Lambda { .. }));
+                assert!(matches!(args[1], Token::Arith { .. }));
+            }
+            _ => panic!("expected a builtin"),
+        }
+    }
+
     #[test]
     fn test_builtin_multiple_args() {
         init();
@@ -1,19 +1,130 @@
 use std::fmt;
 
+// Where in the source an error was found.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Span {
+    // Both counted from 1.
+    pub line: usize,
+    pub column: usize,
+    // The number of characters to mark, at least one.
+    pub len: usize,
+    // The whole line containing the error.
+    pub text: String,
+}
+
+impl Span {
+    // Finds the span of `len` bytes from `offset` into `source`.  Spans are
+    // cut short at the end of the line.
+    pub fn new(source: &str, offset: usize, len: usize) -> Span {
+        let offset = offset.min(source.len());
+        let line_start = source[..offset].rfind('\n').map_or(0, |i| i + 1);
+        let line_end = source[offset..]
+            .find('\n')
+            .map_or(source.len(), |i| offset + i);
+        let text = &source[line_start..line_end];
+        let column = source[line_start..offset].chars().count() + 1;
+        let len = source[offset..(offset + len).min(line_end)].chars().count();
+        Span {
+            line: source[..offset].matches('\n').count() + 1,
+            column,
+            len: len.max(1),
+            text: text.to_string(),
+        }
+    }
+
+    // Finds the span from a line and column, both counted from 1, to the end
+    // of the line.
+    pub fn at(source: &str, line: usize, column: usize) -> Option<Span> {
+        let start = source
+            .split_inclusive('\n')
+            .take(line.checked_sub(1)?)
+            .map(str::len)
+            .sum::<usize>();
+        let text = source[start..].lines().next()?;
+        let offset = start
+            + text
+                .char_indices()
+                .nth(column.checked_sub(1)?)
+                .map_or(text.len(), |(i, _)| i);
+        Some(Span::new(source, offset, source.len() - offset))
+    }
+}
+
+// Shows the line with the span marked beneath it.
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let gutter = " ".repeat(self.line.to_string().len());
+        write!(
+            f,
+            "{} |\n{} | {}\n{} | {}{}",
+            gutter,
+            self.line,
+            self.text,
+            gutter,
+            " ".repeat(self.column - 1),
+            "^".repeat(self.len)
+        )
+    }
+}
+
 #[derive(Debug)]
 pub struct Error {
     details: String,
+    span: Option<Span>,
+    // Set for errors caused by an earlier one, which aren't worth reporting.
+    cascaded: bool,
 }
 
 impl Error {
     pub fn new(msg: String) -> Error {
-        Error { details: msg }
+        Error {
+            details: msg,
+            span: None,
+            cascaded: false,
+        }
+    }
+
+    pub fn cascaded(msg: String) -> Error {
+        Error {
+            cascaded: true,
+            ..Error::new(msg)
+        }
+    }
+
+    // An error the type checker should already have reported, so one that's
+    // a bug in the compiler rather than in the program.
+    pub fn unchecked(what: impl fmt::Display) -> Error {
+        Error::new(format!(
+            "Internal error: {} got past the type checker",
+            what
+        ))
+    }
+
+    pub fn is_cascaded(&self) -> bool {
+        self.cascaded
+    }
+
+    // Marks where the error was found, unless it's already known.
+    pub fn with_span(mut self, span: Span) -> Error {
+        self.span.get_or_insert(span);
+        self
+    }
+
+    pub fn span(&self) -> Option<&Span> {
+        self.span.as_ref()
     }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "{}", self.details)
+        match &self.span {
+            Some(span) => write!(
+                f,
+                "{}:{}: {}\n{}",
+                span.line, span.column, self.details, span
+            ),
+            None => write!(f, "{}", self.details),
+        }
     }
 }
 
@@ -22,3 +133,45 @@ impl std::error::Error for Error {
         &self.details
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_span() {
+        let source = "a = 1\nb = a + c\n";
+        assert_eq!(
+            Span::new(source, 14, 1),
+            Span {
+                line: 2,
+                column: 9,
+                len: 1,
+                text: "b = a + c".to_string(),
+            }
+        );
+
+        // cut short at the end of the line.
+        assert_eq!(Span::new(source, 0, 100).len, 5);
+        assert_eq!(Span::new(source, source.len(), 1).line, 3);
+
+        assert_eq!(Span::at(source, 2, 5), Some(Span::new(source, 10, 5)));
+        assert_eq!(
+            Span::at(source, 2, 5).unwrap().to_string(),
+            "  |\n2 | b = a + c\n  |     ^^^^^"
+        );
+        assert_eq!(Span::at(source, 3, 1), None);
+    }
+
+    #[test]
+    fn test_display() {
+        let error = Error::new("Unknown variable 'c'".to_string());
+        assert_eq!(error.to_string(), "Unknown variable 'c'");
+
+        let error = error.with_span(Span::new("a = 1\nb = a + c\n", 14, 1));
+        assert_eq!(
+            error.to_string(),
+            "2:9: Unknown variable 'c'\n  |\n2 | b = a + c\n  |         ^"
+        );
+    }
+}
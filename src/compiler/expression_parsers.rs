@@ -1,12 +1,14 @@
 use nom::branch::alt;
 use nom::bytes::complete::{tag, take_until};
-use nom::character::complete::{alpha1, multispace0, newline};
-use nom::combinator::{consumed, map_res, opt};
+use nom::character::complete::{alphanumeric1, multispace0, newline, space1};
+use nom::combinator::{consumed, map_res, not, opt, peek};
 use nom::multi::many0;
 use nom::sequence::{delimited, pair, preceded, terminated, tuple};
 use nom::IResult;
 
 use crate::compiler::builtin_parsers::builtin;
+use crate::compiler::function_parser::call;
+use crate::compiler::operand_parsers::identifier;
 use crate::compiler::operator_parsers::*;
 use crate::compiler::term_parsers::term;
 use crate::compiler::tokens::Token;
@@ -72,7 +74,7 @@ fn assign(i: &str) -> IResult<&str, Token> {
     log::debug!("[assign] parsing '{}'", i);
     map_res(
         tuple((
-            alpha1,
+            identifier,
             delimited(multispace0, tag("="), multispace0),
             rvalue,
         )),
@@ -86,6 +88,27 @@ fn assign(i: &str) -> IResult<&str, Token> {
     )(i)
 }
 
+pub fn return_value(i: &str) -> IResult<&str, Token> {
+    log::debug!("[return] parsing '{}'", i);
+    map_res(
+        preceded(
+            terminated(tag("return"), not(alt((alphanumeric1, tag("_"))))),
+            opt(preceded(space1, condition)),
+        ),
+        |value| -> Result<Token, nom::error::Error<&str>> {
+            log::debug!("[return] success ({:?})", value);
+            Ok(Token::Return {
+                value: value.map(Box::new),
+            })
+        },
+    )(i)
+}
+
+// A call whose result, if any, is unused.
+fn call_statement(i: &str) -> IResult<&str, Token> {
+    terminated(call, peek(newline))(i)
+}
+
 fn comment(i: &str) -> IResult<&str, Token> {
     log::debug!("[comment] parsing '{}'", i);
     map_res(
@@ -104,11 +127,25 @@ pub fn rvalue(i: &str) -> IResult<&str, Token> {
     alt((builtin, arith))(i)
 }
 
+// Anything that can be tested for truth.
+pub fn condition(i: &str) -> IResult<&str, Token> {
+    log::debug!("[condition] parsing '{}'", i);
+    alt((bin_op, unary_op, rvalue))(i)
+}
+
 pub fn expression(i: &str) -> IResult<&str, Option<Token>> {
     log::debug!("[expression] parsing '{}'", i);
     map_res(
         terminated(
-            consumed(opt(alt((comment, assign, bin_op, unary_op, rvalue)))),
+            consumed(opt(alt((
+                comment,
+                return_value,
+                assign,
+                call_statement,
+                bin_op,
+                unary_op,
+                rvalue,
+            )))),
             newline,
         ),
         |(parsed_source, opt_expr)| -> Result<Option<Token>, nom::error::Error<&str>> {
@@ -239,6 +276,46 @@ mod tests {
         );
     }
 
+    #[test]
+    fn test_return() {
+        assert_eq!(
+            return_value("return"),
+            Ok(("", Token::Return { value: None }))
+        );
+
+        let result = return_value("return a gt 2");
+        assert!(result.is_ok());
+        let (_, token) = result.unwrap();
+        assert!(matches!(
+            token,
+            Token::Return {
+                value: Some(ref value)
+            } if matches!(**value, Token::BinOp { .. })
+        ));
+
+        assert!(return_value("returned").is_err());
+    }
+
+    #[test]
+    fn test_call_statement() {
+        let result = expression("foo(1)\n");
+        assert!(result.is_ok());
+        let (_, token) = result.unwrap();
+        assert!(matches!(
+            token,
+            Some(Token::Expression { ref token, .. }) if matches!(**token, Token::Call { .. })
+        ));
+
+        // calls that are part of a larger expression are values.
+        let result = expression("foo(1) gt 2\n");
+        assert!(result.is_ok());
+        let (_, token) = result.unwrap();
+        assert!(matches!(
+            token,
+            Some(Token::Expression { ref token, .. }) if matches!(**token, Token::BinOp { .. })
+        ));
+    }
+
     #[test]
     fn test_comment() {
         let result = comment("; this! is a comment\n");
@@ -5,13 +5,22 @@ use nom::sequence::delimited;
 use nom::IResult;
 
 use crate::compiler::expression_parsers::*;
+use crate::compiler::function_parser::call;
 use crate::compiler::operand_parsers::*;
 use crate::compiler::tokens::Token;
 
 pub fn factor(i: &str) -> IResult<&str, Token> {
     log::debug!("[factor] parsing '{}'", i);
     map_res(
-        alt((num, coll, delimited(tag("("), rvalue, tag(")")), ident)),
+        alt((
+            num,
+            coll,
+            seq,
+            dict,
+            delimited(tag("("), rvalue, tag(")")),
+            call,
+            ident,
+        )),
         |factor| -> Result<Token, nom::error::Error<&str>> {
             log::debug!("[factor] success ({:?})", factor);
             Ok(Token::Factor {
@@ -63,5 +72,26 @@ mod tests {
             }
         );
         assert_eq!(rest, " + foo");
+
+        let result = factor("foo(1)");
+        assert!(result.is_ok());
+        let (_, tree) = result.unwrap();
+        assert_eq!(
+            tree,
+            Token::Factor {
+                value: Box::new(Token::Call {
+                    name: "foo".to_string(),
+                    args: vec![Token::Arith {
+                        left: Box::new(Token::Term {
+                            left: Box::new(Token::Factor {
+                                value: Box::new(Token::Integer { value: 1 })
+                            }),
+                            right: vec![]
+                        }),
+                        right: vec![]
+                    }]
+                })
+            }
+        );
     }
 }
@@ -0,0 +1,475 @@
+use std::iter::zip;
+
+use crate::compiler::tokens::Token;
+
+// A value known when the program is compiled.
+#[derive(Clone, Debug, PartialEq)]
+enum Constant {
+    Integer(i32),
+    Real(f64),
+    Coll(Vec<f64>),
+    Seq(Vec<f64>),
+}
+
+impl Constant {
+    fn scalar(&self) -> Option<f64> {
+        match *self {
+            Constant::Integer(i) => Some(i as f64),
+            Constant::Real(r) => Some(r),
+            _ => None,
+        }
+    }
+
+    // Scalars are converted as they are by the VM, truncating reals.
+    fn integer(&self) -> Option<i32> {
+        match *self {
+            Constant::Integer(i) => Some(i),
+            Constant::Real(r) => Some(r as i32),
+            _ => None,
+        }
+    }
+
+    fn elements(&self) -> Option<&[f64]> {
+        match self {
+            Constant::Coll(values) | Constant::Seq(values) => Some(values),
+            _ => None,
+        }
+    }
+
+    fn into_token(self) -> Token {
+        let reals = |values: Vec<f64>| values.into_iter().map(|value| Token::Real { value });
+        match self {
+            Constant::Integer(value) => Token::Integer { value },
+            Constant::Real(value) => Token::Real { value },
+            Constant::Coll(values) => Token::Coll {
+                values: reals(values).collect(),
+            },
+            Constant::Seq(values) => Token::Seq {
+                values: reals(values).collect(),
+            },
+        }
+    }
+}
+
+// The value of the token, if it is a constant.  Collections of collections
+// aren't folded.
+fn constant(token: &Token) -> Option<Constant> {
+    let elements = |values: &[Token]| -> Option<Vec<f64>> {
+        values
+            .iter()
+            .map(|value| constant(value)?.scalar())
+            .collect()
+    };
+    match token {
+        Token::Integer { value } => Some(Constant::Integer(*value)),
+        Token::Real { value } => Some(Constant::Real(*value)),
+        Token::Coll { values } => Some(Constant::Coll(elements(values)?)),
+        Token::Seq { values } => Some(Constant::Seq(elements(values)?)),
+        Token::Factor { value } => constant(value),
+        Token::Term { left, right } | Token::Arith { left, right } if right.is_empty() => {
+            constant(left)
+        }
+        _ => None,
+    }
+}
+
+fn is_integer(token: &Token, value: i32) -> bool {
+    constant(token) == Some(Constant::Integer(value))
+}
+
+// Applies the arithmetic operator as the VM would, unless that would fail or
+// give something that can't be written as a constant.
+fn arith(op: &Token, a: &Constant, b: &Constant) -> Option<Constant> {
+    let f: fn(f64, f64) -> f64 = match op {
+        Token::AdditionOp => |a, b| a + b,
+        Token::SubtractionOp => |a, b| a - b,
+        Token::MultiplicationOp => |a, b| a * b,
+        Token::DivisionOp => |a, b| a / b,
+        _ => return None,
+    };
+    let result = match (a, b) {
+        (Constant::Integer(a), Constant::Integer(b)) => {
+            return match op {
+                Token::AdditionOp => a.checked_add(*b),
+                Token::SubtractionOp => a.checked_sub(*b),
+                Token::MultiplicationOp => a.checked_mul(*b),
+                _ => a.checked_div(*b),
+            }
+            .map(Constant::Integer)
+        }
+        (Constant::Coll(va), Constant::Coll(vb)) | (Constant::Seq(va), Constant::Seq(vb)) => {
+            if va.len() != vb.len() {
+                return None;
+            }
+            let values = zip(va, vb).map(|(a, b)| f(*a, *b)).collect();
+            match a {
+                Constant::Coll(_) => Constant::Coll(values),
+                _ => Constant::Seq(values),
+            }
+        }
+        (Constant::Coll(va), b) | (Constant::Seq(va), b) => {
+            let b = b.scalar()?;
+            let values = va.iter().map(|a| f(*a, b)).collect();
+            match a {
+                Constant::Coll(_) => Constant::Coll(values),
+                _ => Constant::Seq(values),
+            }
+        }
+        // Only these are broadcast from the left.
+        (a, Constant::Coll(vb)) | (a, Constant::Seq(vb))
+            if matches!(op, Token::AdditionOp | Token::MultiplicationOp) =>
+        {
+            let a = a.scalar()?;
+            let values = vb.iter().map(|b| f(a, *b)).collect();
+            match b {
+                Constant::Coll(_) => Constant::Coll(values),
+                _ => Constant::Seq(values),
+            }
+        }
+        (a, b) => Constant::Real(f(a.scalar()?, b.scalar()?)),
+    };
+
+    let finite = match &result {
+        Constant::Real(r) => r.is_finite(),
+        _ => result.elements()?.iter().all(|v| v.is_finite()),
+    };
+    finite.then_some(result)
+}
+
+// Compares as the VM would, where the right side is converted to the type of
+// the left.
+fn compare(op: &Token, a: &Constant, b: &Constant) -> Option<Constant> {
+    let result = match a {
+        Constant::Integer(a) => {
+            let b = b.integer()?;
+            match op {
+                Token::EqualsOp => *a == b,
+                Token::NotEqualsOp => *a != b,
+                Token::GreaterThanOp => *a > b,
+                Token::GreaterThanEqualsOp => *a >= b,
+                Token::LessThanOp => *a < b,
+                Token::LessThanEqualsOp => *a <= b,
+                _ => return None,
+            }
+        }
+        Constant::Real(a) => {
+            let b = b.scalar()?;
+            match op {
+                Token::EqualsOp => (a - b).abs() < f64::EPSILON,
+                Token::NotEqualsOp => (a - b).abs() > f64::EPSILON,
+                Token::GreaterThanOp => *a > b,
+                Token::GreaterThanEqualsOp => *a >= b,
+                Token::LessThanOp => *a < b,
+                Token::LessThanEqualsOp => *a <= b,
+                _ => return None,
+            }
+        }
+        Constant::Coll(va) | Constant::Seq(va) => {
+            let vb = b.elements()?;
+            match op {
+                Token::EqualsOp => va.as_slice() == vb,
+                Token::NotEqualsOp => va.as_slice() != vb,
+                Token::GreaterThanOp => va.as_slice() > vb,
+                Token::GreaterThanEqualsOp => va.as_slice() >= vb,
+                Token::LessThanOp => va.as_slice() < vb,
+                Token::LessThanEqualsOp => va.as_slice() <= vb,
+                _ => return None,
+            }
+        }
+    };
+    Some(Constant::Integer(result as i32))
+}
+
+// Logical operators work on integers, so reals are truncated first.
+fn logical(op: &Token, a: &Constant, b: Option<&Constant>) -> Option<Constant> {
+    let a = a.integer()? != 0;
+    let result = match (op, b) {
+        (Token::NotOp, None) => !a,
+        (Token::AndOp, Some(b)) => a && b.integer()? != 0,
+        (Token::OrOp, Some(b)) => a || b.integer()? != 0,
+        _ => return None,
+    };
+    Some(Constant::Integer(result as i32))
+}
+
+// Folds a chain of operators from the left, for as long as the values are
+// constant, and drops those that leave the value alone.  The identity is
+// `identity` for each operator in `ops` and may also lead the chain for
+// `ops[0]`.
+fn fold_chain(
+    left: Token,
+    right: Vec<(Token, Token)>,
+    ops: [fn(&Token) -> bool; 2],
+    identity: i32,
+    wrap: fn(Box<Token>, Vec<(Token, Token)>) -> Token,
+) -> Token {
+    let mut left = fold_constants(left);
+    let mut rest: Vec<(Token, Token)> = vec![];
+    for (op, operand) in right {
+        let operand = fold_constants(operand);
+        if rest.is_empty() {
+            if let (Some(a), Some(b)) = (constant(&left), constant(&operand)) {
+                if let Some(value) = arith(&op, &a, &b) {
+                    left = value.into_token();
+                    continue;
+                }
+            }
+        }
+        // The identity keeps the type of the other side, whatever it is.
+        if ops.iter().any(|is_op| is_op(&op)) && is_integer(&operand, identity) {
+            continue;
+        }
+        rest.push((op, operand));
+    }
+
+    if !rest.is_empty() && ops[0](&rest[0].0) && is_integer(&left, identity) {
+        left = rest.remove(0).1;
+    }
+    match rest.is_empty() {
+        true => left,
+        false => wrap(Box::new(left), rest),
+    }
+}
+
+fn fold_all(tokens: Vec<Token>) -> Vec<Token> {
+    tokens.into_iter().map(fold_constants).collect()
+}
+
+fn fold_body(body: Vec<Option<Token>>) -> Vec<Option<Token>> {
+    body.into_iter()
+        .map(|statement| statement.map(fold_constants))
+        .collect()
+}
+
+// Works out the values of expressions that only involve constants, so they
+// don't need to be worked out when the program runs.  Operations that would
+// fail are left for the VM to report.
+pub fn fold_constants(token: Token) -> Token {
+    match token {
+        Token::Factor { value } => match fold_constants(*value) {
+            value if constant(&value).is_some() => value,
+            value => Token::Factor {
+                value: Box::new(value),
+            },
+        },
+        Token::Term { left, right } => fold_chain(
+            *left,
+            right,
+            [
+                |op| matches!(op, Token::MultiplicationOp),
+                |op| matches!(op, Token::DivisionOp),
+            ],
+            1,
+            |left, right| Token::Term { left, right },
+        ),
+        Token::Arith { left, right } => fold_chain(
+            *left,
+            right,
+            [
+                |op| matches!(op, Token::AdditionOp),
+                |op| matches!(op, Token::SubtractionOp),
+            ],
+            0,
+            |left, right| Token::Arith { left, right },
+        ),
+        Token::BinOp { left, op, right } => {
+            let left = fold_constants(*left);
+            let right = fold_constants(*right);
+            let value = match (constant(&left), constant(&right)) {
+                (Some(a), Some(b)) => compare(&op, &a, &b).or_else(|| logical(&op, &a, Some(&b))),
+                _ => None,
+            };
+            match value {
+                Some(value) => value.into_token(),
+                None => Token::BinOp {
+                    left: Box::new(left),
+                    op,
+                    right: Box::new(right),
+                },
+            }
+        }
+        Token::UnaryOp { op, right } => {
+            let right = fold_constants(*right);
+            match constant(&right).and_then(|a| logical(&op, &a, None)) {
+                Some(value) => value.into_token(),
+                None => Token::UnaryOp {
+                    op,
+                    right: Box::new(right),
+                },
+            }
+        }
+
+        Token::Coll { values } => Token::Coll {
+            values: fold_all(values),
+        },
+        Token::Seq { values } => Token::Seq {
+            values: fold_all(values),
+        },
+        Token::Dict { entries } => Token::Dict {
+            entries: entries
+                .into_iter()
+                .map(|(k, v)| (fold_constants(k), fold_constants(v)))
+                .collect(),
+        },
+        Token::Str { parts } => Token::Str {
+            parts: fold_all(parts),
+        },
+
+        Token::Assign { ident, expr } => Token::Assign {
+            ident,
+            expr: Box::new(fold_constants(*expr)),
+        },
+        Token::Builtin { builtin, args } => Token::Builtin {
+            builtin,
+            args: fold_all(args),
+        },
+        Token::Call { name, args } => Token::Call {
+            name,
+            args: fold_all(args),
+        },
+        Token::Return { value } => Token::Return {
+            value: value.map(|value| Box::new(fold_constants(*value))),
+        },
+        Token::Function {
+            name,
+            args,
+            return_type,
+            body,
+        } => Token::Function {
+            name,
+            args,
+            return_type,
+            body: fold_body(body),
+        },
+        Token::Lambda { args, body } => Token::Lambda {
+            args,
+            body: fold_body(body),
+        },
+        Token::If {
+            branches,
+            otherwise,
+        } => Token::If {
+            branches: branches
+                .into_iter()
+                .map(|(cond, body)| (fold_constants(cond), fold_body(body)))
+                .collect(),
+            otherwise: fold_body(otherwise),
+        },
+        Token::While { cond, body } => Token::While {
+            cond: Box::new(fold_constants(*cond)),
+            body: fold_body(body),
+        },
+        Token::Expression { source, token } => Token::Expression {
+            source,
+            token: Box::new(fold_constants(*token)),
+        },
+        Token::Program { statements } => Token::Program {
+            statements: fold_body(statements),
+        },
+
+        token => token,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::compiler::expression_parsers::condition;
+
+    fn fold(source: &str) -> Token {
+        let (rest, token) = condition(source).unwrap();
+        assert!(rest.is_empty());
+        fold_constants(token)
+    }
+
+    fn ident(name: &str) -> Token {
+        Token::Factor {
+            value: Box::new(Token::Identifier {
+                name: name.to_string(),
+            }),
+        }
+    }
+
+    #[test]
+    fn test_fold_arith() {
+        assert_eq!(
+            fold("(3.4 + 1.0) - 2.8"),
+            Token::Real {
+                value: 3.4 + 1.0 - 2.8
+            }
+        );
+        assert_eq!(fold("2 * 3 + 4 / 3"), Token::Integer { value: 7 });
+        assert_eq!(fold("1 + 2.5"), Token::Real { value: 3.5 });
+
+        // folding stops at the first value that isn't constant.
+        assert_eq!(
+            fold("2 * 3 * x * 2"),
+            Token::Term {
+                left: Box::new(Token::Integer { value: 6 }),
+                right: vec![
+                    (Token::MultiplicationOp, ident("x")),
+                    (Token::MultiplicationOp, Token::Integer { value: 2 }),
+                ],
+            }
+        );
+
+        // failures are left for the VM.
+        assert!(matches!(fold("1 / 0"), Token::Term { .. }));
+        assert!(matches!(fold("1.5 / 0"), Token::Term { .. }));
+        assert!(matches!(fold("2147483647 + 1"), Token::Arith { .. }));
+        assert!(matches!(fold("[1, 2] + [1]"), Token::Arith { .. }));
+    }
+
+    #[test]
+    fn test_fold_colls() {
+        assert_eq!(
+            fold("[1, 2] * 2 + [0.5, 1]"),
+            Token::Coll {
+                values: vec![Token::Real { value: 2.5 }, Token::Real { value: 5.0 }]
+            }
+        );
+        assert_eq!(
+            fold("1 + |1, 2 * 2|"),
+            Token::Seq {
+                values: vec![Token::Real { value: 2.0 }, Token::Real { value: 5.0 }]
+            }
+        );
+        assert!(matches!(fold("[[1], [2]] * 2"), Token::Term { .. }));
+    }
+
+    #[test]
+    fn test_fold_compare() {
+        assert_eq!(fold("1 + 1 == 2"), Token::Integer { value: 1 });
+        assert_eq!(fold("2.5 > 3"), Token::Integer { value: 0 });
+        // the right side is converted to the type of the left.
+        assert_eq!(fold("1 < 1.5"), Token::Integer { value: 0 });
+        assert_eq!(fold("[1, 2] == [1, 2]"), Token::Integer { value: 1 });
+        assert_eq!(fold("1 and 0"), Token::Integer { value: 0 });
+        assert_eq!(fold("0.5 or 0"), Token::Integer { value: 0 });
+        assert_eq!(fold("not 0"), Token::Integer { value: 1 });
+        assert!(matches!(fold("x > 1"), Token::BinOp { .. }));
+    }
+
+    #[test]
+    fn test_identities() {
+        for source in [
+            "x * 1",
+            "1 * x",
+            "x / 1",
+            "x + 0",
+            "0 + x",
+            "x - 0",
+            "x * (2 - 1)",
+        ] {
+            assert_eq!(fold(source), ident("x"), "{}", source);
+        }
+        assert_eq!(
+            fold("0 - x"),
+            Token::Arith {
+                left: Box::new(Token::Integer { value: 0 }),
+                right: vec![(Token::SubtractionOp, ident("x"))]
+            }
+        );
+    }
+}
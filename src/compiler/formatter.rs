@@ -0,0 +1,402 @@
+use super::{
+    error::Error, find_statement, program_parser::program, syntax_error, tokens::Token,
+    with_final_newline,
+};
+
+const INDENT: &str = "    ";
+
+// Writes the program out again in the canonical style: blocks indented by four
+// spaces, single spaces around operators and after commas, and no more than
+// one blank line in a row.  Comments are kept, and programs with syntax errors
+// are left alone.
+pub fn format(source: &str) -> Result<String, Vec<Error>> {
+    if source.trim().is_empty() {
+        return Ok(String::new());
+    }
+    let source = &with_final_newline(source);
+    let (rest, tree) = match program(source) {
+        Ok(parsed) => parsed,
+        Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+            return Err(vec![syntax_error(source, e.input)])
+        }
+        Err(nom::Err::Incomplete(_)) => return Err(vec![syntax_error(source, "")]),
+    };
+    let statements = match tree {
+        Token::Program { statements } => statements,
+        _ => return Err(vec![syntax_error(source, source)]),
+    };
+
+    let mut errors = vec![];
+    let mut offset = 0;
+    invalid_statements(&statements, source, &mut offset, &mut errors);
+    if !rest.trim().is_empty() {
+        errors.push(syntax_error(source, rest));
+    }
+    if !errors.is_empty() {
+        return Err(errors);
+    }
+    Ok(block(&statements, 0))
+}
+
+// Finds the statements that didn't parse, in the order they're in the source.
+fn invalid_statements(
+    statements: &[Option<Token>],
+    source: &str,
+    offset: &mut usize,
+    errors: &mut Vec<Error>,
+) {
+    for statement in statements.iter().flatten() {
+        match statement {
+            Token::Invalid { source: invalid } => {
+                let start = find_statement(source, *offset, invalid).unwrap_or(*offset);
+                *offset = start + invalid.len();
+                errors.push(syntax_error(source, &source[start..]));
+            }
+            Token::Function { body, .. } | Token::While { body, .. } => {
+                invalid_statements(body, source, offset, errors)
+            }
+            Token::If {
+                branches,
+                otherwise,
+            } => {
+                for (_, body) in branches {
+                    invalid_statements(body, source, offset, errors);
+                }
+                invalid_statements(otherwise, source, offset, errors);
+            }
+            _ => {}
+        }
+    }
+}
+
+// The statements, each on its own line.  Blank lines are dropped from the
+// start and end, and runs of them are made one.  Blank lines after functions
+// aren't part of the tree, so one is always added.
+fn block(statements: &[Option<Token>], depth: usize) -> String {
+    let mut out = String::new();
+    let mut blank = false;
+    for statement in statements {
+        match statement {
+            None => blank = !out.is_empty(),
+            Some(token) => {
+                if blank {
+                    out.push('\n');
+                }
+                out.push_str(&line(token, depth));
+                blank = matches!(token, Token::Function { .. });
+            }
+        }
+    }
+    out
+}
+
+fn line(statement: &Token, depth: usize) -> String {
+    let indent = INDENT.repeat(depth);
+    match statement {
+        Token::Function {
+            name,
+            args,
+            return_type,
+            body,
+        } => {
+            let return_type = match return_type {
+                Some(typ) => format!(" -> {}", typ.to_string().to_lowercase()),
+                None => String::new(),
+            };
+            format!(
+                "{}func {}({}){} {{\n{}{}}}\n",
+                indent,
+                name,
+                list(args, depth),
+                return_type,
+                block(body, depth + 1),
+                indent
+            )
+        }
+        Token::If {
+            branches,
+            otherwise,
+        } => {
+            let mut out = String::new();
+            for (n, (cond, body)) in branches.iter().enumerate() {
+                let keyword = if n == 0 { "if" } else { "elif" };
+                out.push_str(&format!(
+                    "{}{} {}\n{}",
+                    indent,
+                    keyword,
+                    expr(cond, depth),
+                    block(body, depth + 1)
+                ));
+            }
+            if otherwise.iter().any(Option::is_some) {
+                out.push_str(&format!("{}else\n{}", indent, block(otherwise, depth + 1)));
+            }
+            format!("{}{}done\n", out, indent)
+        }
+        Token::While { cond, body } => format!(
+            "{}while {}\n{}{}done\n",
+            indent,
+            expr(cond, depth),
+            block(body, depth + 1),
+            indent
+        ),
+        Token::Invalid { source } => format!("{}{}\n", indent, source),
+        _ => format!("{}{}\n", indent, expr(statement, depth)),
+    }
+}
+
+fn list(tokens: &[Token], depth: usize) -> String {
+    tokens
+        .iter()
+        .map(|token| expr(token, depth))
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn operations(left: &Token, right: &[(Token, Token)], depth: usize) -> String {
+    right.iter().fold(expr(left, depth), |out, (op, value)| {
+        format!("{} {} {}", out, expr(op, depth), expr(value, depth))
+    })
+}
+
+// An expression on a single line, apart from the bodies of lambdas that
+// aren't a single return.
+fn expr(token: &Token, depth: usize) -> String {
+    match token {
+        Token::Comment { comment } => format!(";{}", comment.trim_end()),
+
+        Token::AdditionOp => "+".to_string(),
+        Token::SubtractionOp => "-".to_string(),
+        Token::MultiplicationOp => "*".to_string(),
+        Token::DivisionOp => "/".to_string(),
+        Token::EqualsOp => "eq".to_string(),
+        Token::NotEqualsOp => "neq".to_string(),
+        Token::GreaterThanOp => "gt".to_string(),
+        Token::GreaterThanEqualsOp => "gte".to_string(),
+        Token::LessThanOp => "lt".to_string(),
+        Token::LessThanEqualsOp => "lte".to_string(),
+        Token::AndOp => "and".to_string(),
+        Token::OrOp => "or".to_string(),
+        Token::NotOp => "not".to_string(),
+
+        Token::UnaryOp { op, right } => format!("{} {}", expr(op, depth), expr(right, depth)),
+        Token::BinOp { left, op, right } => format!(
+            "{} {} {}",
+            expr(left, depth),
+            expr(op, depth),
+            expr(right, depth)
+        ),
+        Token::Assign { ident, expr: value } => format!("{} = {}", ident, expr(value, depth)),
+        Token::Builtin { builtin, args } => {
+            let name = builtin.to_string().to_lowercase();
+            match args.is_empty() {
+                true => format!("do({})", name),
+                false => format!("do({}, {})", name, list(args, depth)),
+            }
+        }
+        Token::Return { value } => match value {
+            Some(value) => format!("return {}", expr(value, depth)),
+            None => "return".to_string(),
+        },
+        Token::Str { parts } => {
+            let parts: String = parts
+                .iter()
+                .map(|part| match part {
+                    Token::Text { value } => value.clone(),
+                    _ => format!("{{{}}}", expr(part, depth)),
+                })
+                .collect();
+            format!("\"{}\"", parts)
+        }
+        Token::Text { value } => value.clone(),
+        Token::Lambda { args, body } => {
+            // The arguments of lambdas are always reals, so have no type.
+            let args: Vec<&str> = args
+                .iter()
+                .filter_map(|arg| match arg {
+                    Token::Arg { ident, .. } => Some(ident.as_str()),
+                    _ => None,
+                })
+                .collect();
+            let args = args.join(", ");
+            let statements: Vec<&Token> = body.iter().flatten().collect();
+            match statements.as_slice() {
+                [Token::Expression { token, .. }] if matches!(**token, Token::Return { .. }) => {
+                    format!("func({}) {{ {} }}", args, expr(token, depth))
+                }
+                _ => format!(
+                    "func({}) {{\n{}{}}}",
+                    args,
+                    block(body, depth + 1),
+                    INDENT.repeat(depth)
+                ),
+            }
+        }
+        Token::Arg { ident, typ } => format!("{}: {}", ident, typ.to_string().to_lowercase()),
+        Token::Call { name, args } => format!("{}({})", name, list(args, depth)),
+        Token::Identifier { name } => name.clone(),
+        Token::Coll { values } => format!("[{}]", list(values, depth)),
+        Token::Seq { values } => format!("|{}|", list(values, depth)),
+        Token::Dict { entries } => {
+            let entries: Vec<String> = entries
+                .iter()
+                .map(|(key, value)| format!("{}: {}", expr(key, depth), expr(value, depth)))
+                .collect();
+            format!("{{{}}}", entries.join(", "))
+        }
+        Token::Integer { value } => value.to_string(),
+        Token::Real { value } => value.to_string(),
+        // Brackets can't have space inside them.
+        Token::Factor { value } => match **value {
+            Token::Arith { .. } | Token::Builtin { .. } => format!("({})", expr(value, depth)),
+            _ => expr(value, depth),
+        },
+        Token::Term { left, right } | Token::Arith { left, right } => {
+            operations(left, right, depth)
+        }
+        Token::Expression { token, .. } => expr(token, depth),
+        _ => line(token, depth).trim().to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Forgets the source of statements and blank lines, which formatting
+    // changes.
+    fn normalise(token: &mut Token) {
+        let block = |body: &mut Vec<Option<Token>>| {
+            body.retain(Option::is_some);
+            body.iter_mut().flatten().for_each(normalise);
+        };
+        match token {
+            Token::Expression { source, token } => {
+                source.clear();
+                normalise(token);
+            }
+            Token::Program { statements: body }
+            | Token::Function { body, .. }
+            | Token::While { body, .. } => block(body),
+            Token::Lambda { body, .. } => block(body),
+            Token::If {
+                branches,
+                otherwise,
+            } => {
+                for (cond, body) in branches {
+                    normalise(cond);
+                    block(body);
+                }
+                block(otherwise);
+            }
+            Token::Assign { expr, .. } => normalise(expr),
+            Token::Return { value: Some(value) } => normalise(value),
+            Token::Builtin { args, .. } | Token::Call { args, .. } => {
+                args.iter_mut().for_each(normalise)
+            }
+            Token::Factor { value } => normalise(value),
+            Token::BinOp { left, right, .. } => {
+                normalise(left);
+                normalise(right);
+            }
+            Token::UnaryOp { right, .. } => normalise(right),
+            Token::Term { left, right } | Token::Arith { left, right } => {
+                normalise(left);
+                right.iter_mut().for_each(|(_, value)| normalise(value));
+            }
+            _ => {}
+        }
+    }
+
+    fn assert_round_trips(source: &str) {
+        let formatted = format(source).unwrap();
+        assert_eq!(format(&formatted).unwrap(), formatted);
+
+        let (rest, mut before) = program(source).unwrap();
+        assert!(rest.is_empty());
+        let (rest, mut after) = program(&formatted).unwrap();
+        assert!(rest.is_empty(), "{}", formatted);
+        normalise(&mut before);
+        normalise(&mut after);
+        assert_eq!(before, after, "{}", formatted);
+    }
+
+    #[test]
+    fn test_format() {
+        assert_eq!(
+            format("a=1+2*3\nb =( a-1 )\n").unwrap_err().len(),
+            1,
+            "brackets can't have space inside"
+        );
+        assert_eq!(
+            format("a=1+2*3\n\n\n\nb =(a-1)/ 2.5\nc=[a,b]\n").unwrap(),
+            "a = 1 + 2 * 3\n\nb = (a - 1) / 2.5\nc = [a, b]\n"
+        );
+        assert_eq!(
+            format("if a>1\n\tb = |1,2|\nelif not a\n\n  b = {1:2}\nelse\ndone\n").unwrap(),
+            "if a gt 1\n    b = |1, 2|\nelif not a\n    b = {1: 2}\ndone\n"
+        );
+        assert_eq!(
+            format("func f( x:real )->real {\n\n  return x*2\n}\nwhile x!=0\n  x = f(x)\ndone\n")
+                .unwrap(),
+            "func f(x: real) -> real {\n    return x * 2\n}\n\nwhile x neq 0\n    x = f(x)\ndone\n"
+        );
+        assert_eq!(
+            format("do(write,\"x is { x }\")\ny = do(map,func(a){return a*a},[1,2])\n").unwrap(),
+            "do(write, \"x is {x}\")\ny = do(map, func(a) { return a * a }, [1, 2])\n"
+        );
+        assert_eq!(
+            format("y = do(map, func(a) {\nb = a * a\n      return b\n  }, c)\n").unwrap(),
+            "y = do(map, func(a) {\n    b = a * a\n    return b\n}, c)\n"
+        );
+        assert_eq!(format("").unwrap(), "");
+    }
+
+    #[test]
+    fn test_comments() {
+        assert_eq!(
+            format(";top   \nfunc f() {\n  ; inside\n  do(write, 1)\n}\n; after\n").unwrap(),
+            ";top\nfunc f() {\n    ; inside\n    do(write, 1)\n}\n\n; after\n"
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_round_trips(include_str!("../../test.do"));
+        assert_round_trips(include_str!("../../old/test/if.do"));
+        assert_round_trips(include_str!("../../old/test/while.do"));
+        assert_round_trips(concat!(
+            "; a comment\n",
+            "func fact(n: integer) -> integer {\n",
+            "  if n lte 1\n",
+            "  return 1\n",
+            "  done\n",
+            "  return n * fact(n - 1)\n",
+            "}\n",
+            "a = -3 * -2.5\n",
+            "b = do(fold, func(acc, x) {\n",
+            "  if x > 2\n",
+            "    return acc + x\n",
+            "  done\n",
+            "  return acc\n",
+            "}, [1, 2, 3], 0)\n",
+            "c = do(length, (do(keys, {1: 2, 3: 4})))\n",
+            "if a and b\n",
+            "  do(print, \"{a} and {b + 1}\")\n",
+            "elif a == 1\n",
+            "  return\n",
+            "done\n",
+        ));
+    }
+
+    #[test]
+    fn test_syntax_errors() {
+        let errors = format("a = 1\nb = a +* 2\nif a\n  c = ]\ndone\n").unwrap_err();
+        assert_eq!(errors.len(), 2);
+        assert!(errors[0].to_string().starts_with("2:7: Invalid syntax"));
+        assert!(errors[1].to_string().starts_with("4:5: Invalid syntax"));
+
+        // the last line needn't end with a newline.
+        assert_eq!(format("a = 1").unwrap(), "a = 1\n");
+    }
+}
@@ -1,18 +1,31 @@
 use nom::{
     branch::alt,
     bytes::complete::tag,
-    character::complete::{alpha1, multispace0, multispace1},
-    combinator::map_res,
-    multi::{many1, separated_list1},
+    character::complete::{multispace0, multispace1},
+    combinator::{consumed, map, map_res, opt},
+    multi::{many1, separated_list0},
     sequence::{delimited, preceded, terminated, tuple},
     IResult,
 };
 
-use super::{expression_parsers::expression, r#type::Type, tokens::Token};
+use super::{
+    expression_parsers::{return_value, rvalue},
+    operand_parsers::identifier,
+    program_parser::{block_statement, statement},
+    r#type::Type,
+    tokens::Token,
+};
 
 fn type_ident(i: &str) -> IResult<&str, Type> {
     map_res(
-        alt((tag("real"), tag("integer"), tag("coll"))),
+        alt((
+            tag("real"),
+            tag("integer"),
+            tag("coll"),
+            tag("seq"),
+            tag("dict"),
+            tag("nested"),
+        )),
         |t| -> Result<Type, nom::error::Error<&str>> { Type::try_from(t) },
     )(i)
 }
@@ -20,7 +33,7 @@ fn type_ident(i: &str) -> IResult<&str, Type> {
 fn arg(i: &str) -> IResult<&str, Token> {
     map_res(
         tuple((
-            alpha1,
+            identifier,
             delimited(multispace0, tag(":"), multispace0),
             type_ident,
         )),
@@ -36,28 +49,101 @@ fn arg(i: &str) -> IResult<&str, Token> {
 pub fn function(i: &str) -> IResult<&str, Option<Token>> {
     map_res(
         tuple((
-            preceded(terminated(tag("func"), multispace1), alpha1),
+            preceded(terminated(tag("func"), multispace1), identifier),
             delimited(
                 delimited(multispace0, tag("("), multispace0),
-                separated_list1(delimited(multispace0, tag(","), multispace0), arg),
+                separated_list0(delimited(multispace0, tag(","), multispace0), arg),
                 delimited(multispace0, tag(")"), multispace0),
             ),
+            opt(delimited(
+                terminated(tag("->"), multispace0),
+                type_ident,
+                multispace0,
+            )),
             delimited(
                 delimited(multispace0, tag("{"), multispace0),
-                many1(expression),
+                many1(block_statement),
                 delimited(multispace0, tag("}"), multispace0),
             ),
         )),
-        |(name, args, body)| -> Result<Option<Token>, nom::error::Error<&str>> {
+        |(name, args, return_type, body)| -> Result<Option<Token>, nom::error::Error<&str>> {
             Ok(Some(Token::Function {
                 name: String::from(name),
                 args,
+                return_type,
                 body,
             }))
         },
     )(i)
 }
 
+// The body of a lambda may be a single return on the same line as its braces.
+fn inline_return(i: &str) -> IResult<&str, Vec<Option<Token>>> {
+    map(consumed(return_value), |(source, token)| {
+        vec![Some(Token::Expression {
+            source: String::from(source),
+            token: Box::new(token),
+        })]
+    })(i)
+}
+
+// An anonymous function, such as `func(a) { return a * a }`.  Elements of
+// collections are reals, as are the arguments.
+pub fn lambda(i: &str) -> IResult<&str, Token> {
+    log::debug!("[lambda] parsing '{}'", i);
+    map_res(
+        tuple((
+            preceded(
+                tag("func"),
+                delimited(
+                    delimited(multispace0, tag("("), multispace0),
+                    separated_list0(delimited(multispace0, tag(","), multispace0), identifier),
+                    delimited(multispace0, tag(")"), multispace0),
+                ),
+            ),
+            delimited(
+                terminated(tag("{"), multispace0),
+                alt((many1(statement), inline_return)),
+                preceded(multispace0, tag("}")),
+            ),
+        )),
+        |(args, body)| -> Result<Token, nom::error::Error<&str>> {
+            log::debug!("[lambda] success ({:?}, {:?})", args, body);
+            Ok(Token::Lambda {
+                args: args
+                    .into_iter()
+                    .map(|ident| Token::Arg {
+                        ident: String::from(ident),
+                        typ: Type::Real,
+                    })
+                    .collect(),
+                body,
+            })
+        },
+    )(i)
+}
+
+pub fn call(i: &str) -> IResult<&str, Token> {
+    log::debug!("[call] parsing '{}'", i);
+    map_res(
+        tuple((
+            identifier,
+            delimited(
+                delimited(multispace0, tag("("), multispace0),
+                separated_list0(delimited(multispace0, tag(","), multispace0), rvalue),
+                preceded(multispace0, tag(")")),
+            ),
+        )),
+        |(name, args)| -> Result<Token, nom::error::Error<&str>> {
+            log::debug!("[call] success ({:?}, {:?})", name, args);
+            Ok(Token::Call {
+                name: String::from(name),
+                args,
+            })
+        },
+    )(i)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -98,6 +184,7 @@ baz = baz + 1
                         ident: String::from("baz"),
                         typ: Type::Real
                     }],
+                    return_type: None,
                     body: vec![Some(Token::Expression {
                         source: String::from("baz = baz + 1"),
                         token: Box::new(Token::Assign {
@@ -127,4 +214,127 @@ baz = baz + 1
             ))
         );
     }
+
+    #[test]
+    fn test_function_return_type() {
+        let test_function = "func pi() -> real {
+return 2.5
+}";
+
+        assert_eq!(
+            function(test_function),
+            Ok((
+                "",
+                Some(Token::Function {
+                    name: String::from("pi"),
+                    args: vec![],
+                    return_type: Some(Type::Real),
+                    body: vec![Some(Token::Expression {
+                        source: String::from("return 2.5"),
+                        token: Box::new(Token::Return {
+                            value: Some(Box::new(Token::Arith {
+                                left: Box::new(Token::Term {
+                                    left: Box::new(Token::Factor {
+                                        value: Box::new(Token::Real { value: 2.5 })
+                                    }),
+                                    right: vec![]
+                                }),
+                                right: vec![]
+                            }))
+                        })
+                    })]
+                })
+            ))
+        );
+    }
+
+    #[test]
+    fn test_lambda() {
+        let expected = Token::Lambda {
+            args: vec![Token::Arg {
+                ident: String::from("a"),
+                typ: Type::Real,
+            }],
+            body: vec![Some(Token::Expression {
+                source: String::from("return a"),
+                token: Box::new(Token::Return {
+                    value: Some(Box::new(Token::Arith {
+                        left: Box::new(Token::Term {
+                            left: Box::new(Token::Factor {
+                                value: Box::new(Token::Identifier {
+                                    name: String::from("a"),
+                                }),
+                            }),
+                            right: vec![],
+                        }),
+                        right: vec![],
+                    })),
+                }),
+            })],
+        };
+        assert_eq!(lambda("func(a) { return a }"), Ok(("", expected)));
+
+        let result = lambda("func (a, b) {\n  c = a + b\n  return c\n}");
+        assert!(result.is_ok());
+        let (rest, token) = result.unwrap();
+        assert_eq!(rest, "");
+        match token {
+            Token::Lambda { args, body } => {
+                assert_eq!(args.len(), 2);
+                assert_eq!(body.len(), 2);
+            }
+            _ => panic!("expected a lambda"),
+        }
+
+        assert!(lambda("func(a) { }").is_err());
+        assert!(lambda("func foo(a) { return a }").is_err());
+    }
+
+    #[test]
+    fn test_call() {
+        assert_eq!(
+            call("foobar()"),
+            Ok((
+                "",
+                Token::Call {
+                    name: String::from("foobar"),
+                    args: vec![]
+                }
+            ))
+        );
+
+        assert_eq!(
+            call("foobar(baz, 1)"),
+            Ok((
+                "",
+                Token::Call {
+                    name: String::from("foobar"),
+                    args: vec![
+                        Token::Arith {
+                            left: Box::new(Token::Term {
+                                left: Box::new(Token::Factor {
+                                    value: Box::new(Token::Identifier {
+                                        name: String::from("baz")
+                                    })
+                                }),
+                                right: vec![]
+                            }),
+                            right: vec![]
+                        },
+                        Token::Arith {
+                            left: Box::new(Token::Term {
+                                left: Box::new(Token::Factor {
+                                    value: Box::new(Token::Integer { value: 1 })
+                                }),
+                                right: vec![]
+                            }),
+                            right: vec![]
+                        }
+                    ]
+                }
+            ))
+        );
+
+        assert!(call("do(write, 1)").is_err());
+    }
 }
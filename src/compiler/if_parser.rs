@@ -0,0 +1,128 @@
+use nom::{
+    bytes::complete::tag,
+    character::complete::{newline, space0, space1},
+    combinator::{map_res, opt},
+    multi::many0,
+    sequence::{delimited, pair, preceded, tuple},
+    IResult,
+};
+
+use super::{expression_parsers::condition, program_parser::block_statement, tokens::Token};
+
+type Branch = (Token, Vec<Option<Token>>);
+
+fn branch<'a>(keyword: &'static str) -> impl FnMut(&'a str) -> IResult<&'a str, Branch> {
+    pair(
+        delimited(
+            tuple((space0, tag(keyword), space1)),
+            condition,
+            pair(space0, newline),
+        ),
+        many0(block_statement),
+    )
+}
+
+fn otherwise(i: &str) -> IResult<&str, Vec<Option<Token>>> {
+    preceded(
+        tuple((space0, tag("else"), space0, newline)),
+        many0(block_statement),
+    )(i)
+}
+
+pub fn done(i: &str) -> IResult<&str, ()> {
+    map_res(
+        tuple((space0, tag("done"), space0, newline)),
+        |_| -> Result<(), nom::error::Error<&str>> { Ok(()) },
+    )(i)
+}
+
+pub fn if_statement(i: &str) -> IResult<&str, Option<Token>> {
+    log::debug!("[if] parsing '{}'", i);
+    map_res(
+        tuple((branch("if"), many0(branch("elif")), opt(otherwise), done)),
+        |(first, elifs, otherwise, _)| -> Result<Option<Token>, nom::error::Error<&str>> {
+            let mut branches = vec![first];
+            branches.extend(elifs);
+            Ok(Some(Token::If {
+                branches,
+                otherwise: otherwise.unwrap_or_default(),
+            }))
+        },
+    )(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ident(name: &str) -> Token {
+        Token::Arith {
+            left: Box::new(Token::Term {
+                left: Box::new(Token::Factor {
+                    value: Box::new(Token::Identifier {
+                        name: String::from(name),
+                    }),
+                }),
+                right: vec![],
+            }),
+            right: vec![],
+        }
+    }
+
+    fn assign(name: &str, value: i32) -> Option<Token> {
+        Some(Token::Expression {
+            source: format!("{} = {}", name, value),
+            token: Box::new(Token::Assign {
+                ident: String::from(name),
+                expr: Box::new(Token::Arith {
+                    left: Box::new(Token::Term {
+                        left: Box::new(Token::Factor {
+                            value: Box::new(Token::Integer { value }),
+                        }),
+                        right: vec![],
+                    }),
+                    right: vec![],
+                }),
+            }),
+        })
+    }
+
+    #[test]
+    fn test_if() {
+        let result = if_statement("if a\n\tb = 1\ndone\n");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Some(Token::If {
+                    branches: vec![(ident("a"), vec![assign("b", 1)])],
+                    otherwise: vec![],
+                })
+            ))
+        );
+    }
+
+    #[test]
+    fn test_if_elif_else() {
+        let result = if_statement("if a\n  b = 1\nelif c\n  b = 2\n\nelse\n  b = 3\ndone\n");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Some(Token::If {
+                    branches: vec![
+                        (ident("a"), vec![assign("b", 1)]),
+                        (ident("c"), vec![assign("b", 2), None]),
+                    ],
+                    otherwise: vec![assign("b", 3)],
+                })
+            ))
+        );
+    }
+
+    #[test]
+    fn test_if_missing_done() {
+        assert!(if_statement("if a\n  b = 1\n").is_err());
+        assert!(if_statement("iffy\n").is_err());
+    }
+}
@@ -1,33 +1,51 @@
+use crate::asm::escape;
 use crate::asm::syscalls::Syscall;
 use crate::compiler::{
-    builtin::Builtin, error::Error, expression_parsers::expression, program_parser::program,
-    tokens::Token, visitor::Visitor,
+    builtin::Builtin, error::Error, expression_parsers::expression, folder::fold_constants,
+    function_parser::function, if_parser::if_statement, operand_parsers::identifier,
+    peephole::optimise, program_parser::program, tokens::Token, type_checker::TypeChecker,
+    visitor::Visitor, while_parser::while_statement,
 };
 use crate::vm::register::Register as VmRegister;
 
-use std::collections::HashMap;
+use nom::bytes::complete::tag;
+use nom::character::complete::space0;
+use nom::combinator::not;
+use nom::sequence::{terminated, tuple};
+use std::collections::{HashMap, HashSet};
 use std::mem::size_of;
 
 use self::r#type::Type;
 
+pub use self::error::Span;
+pub use self::formatter::format;
+
 mod builtin;
 mod builtin_parsers;
 mod error;
 mod expression_parsers;
 mod factor_parsers;
+mod folder;
+mod formatter;
 mod function_parser;
+mod if_parser;
 mod operand_parsers;
 mod operator_parsers;
+mod peephole;
 mod program_parser;
 mod term_parsers;
 mod tokens;
 mod r#type;
+mod type_checker;
 mod visitor;
+mod while_parser;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 struct Register {
     idx: u8,
     reg: VmRegister,
+    // Set for vector registers holding a seq rather than a coll.
+    ordered: bool,
 }
 
 impl Register {
@@ -36,15 +54,50 @@ impl Register {
             VmRegister::I(_) => 'i',
             VmRegister::R(_) => 'r',
             VmRegister::V(_) => 'v',
+            VmRegister::D(_) => 'd',
+            VmRegister::N(_) => 'n',
+        }
+    }
+
+    pub fn get_type(&self) -> Type {
+        match self.reg {
+            VmRegister::I(_) => Type::Integer,
+            VmRegister::R(_) => Type::Real,
+            VmRegister::V(_) if self.ordered => Type::Seq,
+            VmRegister::V(_) => Type::Coll,
+            VmRegister::D(_) => Type::Dict,
+            VmRegister::N(_) => Type::Nested,
         }
     }
 }
 
+// Where a variable lives on the heap once it has been spilled.
+#[derive(Clone, Copy, Debug)]
+struct Slot {
+    address: usize,
+    typ: Type,
+}
+
+// Bytes per spill slot, enough for a real or the address of a collection.
+const SLOT_SIZE: usize = 8;
+
+// New variables are spilled once fewer than this many registers of their type
+// are free, leaving the rest for evaluating expressions.
+const SPILL_THRESHOLD: usize = 8;
+
+#[derive(Clone, Debug)]
+struct Signature {
+    args: Vec<Type>,
+    return_type: Option<Type>,
+}
+
 #[derive(Debug)]
 pub struct Compiler {
     free_int_reg: Vec<Register>,
     free_real_reg: Vec<Register>,
     free_vec_reg: Vec<Register>,
+    free_dict_reg: Vec<Register>,
+    free_nested_reg: Vec<Register>,
     used_reg: Vec<Register>,
     rodata: Vec<String>,
     assembly: Vec<String>,
@@ -52,6 +105,39 @@ pub struct Compiler {
     // Maps from a name to an index into `used_reg`.
     variables: HashMap<String, usize>,
     local_variables: Vec<String>,
+
+    // Variables kept on the heap instead of in a register, and the number of
+    // slots handed out.  Only whole programs can spill as the slots are
+    // allocated when the program starts.
+    spilled: HashMap<String, Slot>,
+    spill_slots: Option<usize>,
+
+    functions: HashMap<String, Signature>,
+    // The name of the function whose body is being compiled, if any.
+    current_function: Option<String>,
+
+    // Used to generate unique labels for control flow.
+    next_block_id: usize,
+    // The registers used so far in the outermost loop being compiled, if
+    // any.  Values left in them are overwritten the next time round.
+    loop_registers: Option<HashSet<(char, u8)>>,
+
+    // The source being compiled and how far through it the statements being
+    // visited have reached, for finding where errors are.
+    source: String,
+    source_offset: usize,
+
+    // Errors found in statements so far.  Statements with errors are skipped
+    // so that every error can be reported at once.
+    errors: Vec<Error>,
+
+    // 0 writes code as it's parsed, 1 folds constants first and 2 also
+    // removes redundant instructions from the assembly.
+    opt_level: u8,
+
+    // Whether to write where each statement came from, and which register
+    // holds each variable, as debug info.
+    debug_info: bool,
 }
 
 impl Compiler {
@@ -61,18 +147,35 @@ impl Compiler {
                 .map(|i| Register {
                     idx: i,
                     reg: VmRegister::I(0),
+                    ordered: false,
                 })
                 .collect(),
             free_real_reg: (0..32)
                 .map(|i| Register {
                     idx: i,
                     reg: VmRegister::R(0.0),
+                    ordered: false,
                 })
                 .collect(),
             free_vec_reg: (0..32)
                 .map(|i| Register {
                     idx: i,
                     reg: VmRegister::V(vec![]),
+                    ordered: false,
+                })
+                .collect(),
+            free_dict_reg: (0..32)
+                .map(|i| Register {
+                    idx: i,
+                    reg: VmRegister::D(vec![]),
+                    ordered: false,
+                })
+                .collect(),
+            free_nested_reg: (0..32)
+                .map(|i| Register {
+                    idx: i,
+                    reg: VmRegister::N(vec![]),
+                    ordered: false,
                 })
                 .collect(),
             used_reg: vec![],
@@ -80,69 +183,290 @@ impl Compiler {
             assembly: vec![],
             variables: HashMap::new(),
             local_variables: vec![],
+            spilled: HashMap::new(),
+            spill_slots: None,
+            functions: HashMap::new(),
+            current_function: None,
+            next_block_id: 0,
+            loop_registers: None,
+            source: String::new(),
+            source_offset: 0,
+            errors: vec![],
+            opt_level: 1,
+            debug_info: false,
+        }
+    }
+
+    pub fn set_opt_level(&mut self, level: u8) {
+        self.opt_level = level;
+    }
+
+    pub fn set_debug_info(&mut self, debug_info: bool) {
+        self.debug_info = debug_info;
+    }
+
+    fn optimise_tree(&self, tree: Token) -> Token {
+        match self.opt_level {
+            0 => tree,
+            _ => fold_constants(tree),
         }
     }
 
-    pub fn compile(&mut self, source: &str) -> Result<String, Error> {
+    pub fn compile(&mut self, source: &str) -> Result<String, Vec<Error>> {
         self.assembly.clear();
-        let (_, tree) = program(source).unwrap();
-        self.visit_token(&tree)?;
+        let source = &with_final_newline(source);
+        let (rest, tree) = match program(source) {
+            Ok((rest, tree)) => (rest, Some(tree)),
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) => (e.input, None),
+            Err(nom::Err::Incomplete(_)) => ("", None),
+        };
+        self.source = source.to_string();
+        self.source_offset = 0;
+        let parsed = tree.is_some();
+        // Code is only written for programs that are well typed.
+        if let Some(tree) = tree {
+            let mut checker =
+                TypeChecker::new(source, self.variable_types(), self.functions.clone());
+            match checker.check(&tree) {
+                Ok(()) => {
+                    if let Err(e) = self.visit_token(&self.optimise_tree(tree)) {
+                        self.errors.insert(0, e);
+                    }
+                }
+                Err(errors) => self.errors = errors,
+            }
+        }
+        if !parsed || !rest.trim().is_empty() {
+            self.errors.push(syntax_error(source, rest));
+        }
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
+        }
+        if self.opt_level >= 2 {
+            self.assembly = optimise(&self.assembly);
+        }
         Ok([self.rodata.join("\n"), self.assembly.join("\n")].join("\n"))
     }
 
     // NOTE: public for the repl
-    pub fn compile_expr(&mut self, source: &str) -> Result<&Vec<String>, Error> {
+    pub fn compile_expr(&mut self, source: &str) -> Result<&Vec<String>, Vec<Error>> {
         self.assembly.clear();
-        let (_, tree) = expression(source).map_err(|e| Error::new(e.to_string()))?;
+        let tree = match expression(source) {
+            Ok((rest, tree)) if rest.trim().is_empty() => tree,
+            Ok((rest, _)) => return Err(vec![syntax_error(source, rest)]),
+            Err(nom::Err::Error(e) | nom::Err::Failure(e)) => {
+                return Err(vec![syntax_error(source, e.input)])
+            }
+            Err(nom::Err::Incomplete(_)) => return Err(vec![syntax_error(source, "")]),
+        };
+        self.source = source.to_string();
+        self.source_offset = 0;
         if let Some(valid_tree) = tree {
-            self.visit_token(&valid_tree)?;
+            let mut checker =
+                TypeChecker::new(source, self.variable_types(), self.functions.clone());
+            checker.check(&valid_tree)?;
+            if let Err(e) = self.visit_token(&self.optimise_tree(valid_tree)) {
+                self.errors.push(e);
+            }
+        }
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
         }
         Ok(&self.assembly)
     }
 
+    // The types of the variables defined so far, which further source can
+    // use.
+    fn variable_types(&self) -> HashMap<String, Type> {
+        self.variables
+            .iter()
+            .map(|(name, &index)| (name.clone(), self.used_reg[index].get_type()))
+            .chain(
+                self.spilled
+                    .iter()
+                    .map(|(name, slot)| (name.clone(), slot.typ)),
+            )
+            .collect()
+    }
+
     fn integrity_check(&self) {
         for used_reg in &self.used_reg {
             let free_reg = match used_reg.reg {
                 VmRegister::I(_) => &self.free_int_reg,
                 VmRegister::R(_) => &self.free_real_reg,
                 VmRegister::V(_) => &self.free_vec_reg,
+                VmRegister::D(_) => &self.free_dict_reg,
+                VmRegister::N(_) => &self.free_nested_reg,
             };
-            if free_reg.contains(used_reg) {
+            if free_reg.iter().any(|reg| reg.idx == used_reg.idx) {
                 panic!("Integrity check failed");
             }
         }
     }
 
-    fn push_free_reg(&mut self, reg: Register) {
+    fn push_free_reg(&mut self, mut reg: Register) {
+        reg.ordered = false;
         match reg.reg {
             VmRegister::I(_) => self.free_int_reg.push(reg),
             VmRegister::R(_) => self.free_real_reg.push(reg),
             VmRegister::V(_) => self.free_vec_reg.push(reg),
+            VmRegister::D(_) => self.free_dict_reg.push(reg),
+            VmRegister::N(_) => self.free_nested_reg.push(reg),
         };
     }
 
-    fn get_binop_result_reg(&mut self, left: &Register, right: &Register) -> Register {
-        match left.reg {
-            VmRegister::I(_) => match right.reg {
-                VmRegister::I(_) => self.free_int_reg.pop().unwrap(),
-                VmRegister::R(_) => self.free_real_reg.pop().unwrap(),
-                VmRegister::V(_) => self.free_vec_reg.pop().unwrap(),
-            },
-            VmRegister::R(_) => match right.reg {
-                // Promote to a real register.
-                VmRegister::I(_) => self.free_real_reg.pop().unwrap(),
-                VmRegister::R(_) => self.free_real_reg.pop().unwrap(),
-                VmRegister::V(_) => self.free_vec_reg.pop().unwrap(),
-            },
-            VmRegister::V(_) => self.free_vec_reg.pop().unwrap(),
+    fn pop_free_reg(&mut self, typ: Type) -> Result<Register, Error> {
+        let free_reg = match typ {
+            Type::Real => &mut self.free_real_reg,
+            Type::Integer => &mut self.free_int_reg,
+            Type::Coll | Type::Seq => &mut self.free_vec_reg,
+            Type::Dict => &mut self.free_dict_reg,
+            Type::Nested => &mut self.free_nested_reg,
+        };
+        match free_reg.pop() {
+            Some(mut reg) => {
+                reg.ordered = typ == Type::Seq;
+                if let Some(used) = &mut self.loop_registers {
+                    used.insert((reg.get_char(), reg.idx));
+                }
+                Ok(reg)
+            }
+            None => Err(Error::new(format!("Ran out of {} registers", typ))),
         }
     }
 
-    fn add_arith_instruction(&mut self, op: &str) {
+    // Moves the value of a variable first assigned in a loop out of any
+    // register used earlier in the loop, which would overwrite it the next
+    // time round.
+    fn add_loop_variable(&mut self, reg: Register) -> Result<Register, Error> {
+        let used = match &self.loop_registers {
+            Some(used) if used.contains(&(reg.get_char(), reg.idx)) => used,
+            _ => return Ok(reg),
+        };
+        let free_reg = match reg.get_type() {
+            Type::Real => &mut self.free_real_reg,
+            Type::Integer => &mut self.free_int_reg,
+            Type::Coll | Type::Seq => &mut self.free_vec_reg,
+            Type::Dict => &mut self.free_dict_reg,
+            Type::Nested => &mut self.free_nested_reg,
+        };
+        let mut var_reg = match free_reg
+            .iter()
+            .rposition(|r| !used.contains(&(r.get_char(), r.idx)))
+        {
+            Some(idx) => free_reg.remove(idx),
+            None => {
+                return Err(Error::new(format!(
+                    "Ran out of {} registers",
+                    reg.get_type()
+                )))
+            }
+        };
+        var_reg.ordered = reg.ordered;
+        self.assembly.push(format!(
+            "copy ${}{} ${}{}",
+            var_reg.get_char(),
+            var_reg.idx,
+            reg.get_char(),
+            reg.idx
+        ));
+        self.push_free_reg(reg);
+        Ok(var_reg)
+    }
+
+    fn is_variable(&self, ident: &str) -> bool {
+        self.variables.contains_key(ident) || self.spilled.contains_key(ident)
+    }
+
+    // Whether a new variable of the type should be spilled rather than tie up
+    // one of the few remaining registers.
+    fn should_spill(&self, typ: Type) -> bool {
+        let free = match typ {
+            Type::Real => self.free_real_reg.len(),
+            Type::Integer => self.free_int_reg.len(),
+            Type::Coll | Type::Seq => self.free_vec_reg.len(),
+            Type::Dict => self.free_dict_reg.len(),
+            Type::Nested => self.free_nested_reg.len(),
+        };
+        self.spill_slots.is_some() && free < SPILL_THRESHOLD
+    }
+
+    fn new_slot(&mut self, ident: &str, typ: Type) -> Slot {
+        let slots = self.spill_slots.get_or_insert(0);
+        let slot = Slot {
+            address: *slots * SLOT_SIZE,
+            typ,
+        };
+        *slots += 1;
+        self.spilled.insert(ident.to_string(), slot);
+        slot
+    }
+
+    // Stores the register to the slot, leaving the register free.
+    fn add_store(&mut self, slot: Slot, reg: Register) -> Result<(), Error> {
+        let address_reg = self.pop_free_reg(Type::Integer)?;
+        self.assembly
+            .push(format!("load $i{} #{}", address_reg.idx, slot.address));
+        self.assembly.push(format!(
+            "sw $i{} ${}{}",
+            address_reg.idx,
+            reg.get_char(),
+            reg.idx
+        ));
+        self.free_int_reg.push(address_reg);
+        self.push_free_reg(reg);
+        Ok(())
+    }
+
+    // Loads the slot into a new register.
+    fn add_reload(&mut self, slot: Slot) -> Result<Register, Error> {
+        let reg = self.pop_free_reg(slot.typ)?;
+        let address_reg = self.pop_free_reg(Type::Integer)?;
+        self.assembly
+            .push(format!("load $i{} #{}", address_reg.idx, slot.address));
+        self.assembly.push(format!(
+            "lw ${}{} $i{}",
+            reg.get_char(),
+            reg.idx,
+            address_reg.idx
+        ));
+        self.free_int_reg.push(address_reg);
+        Ok(reg)
+    }
+
+    fn get_binop_result_reg(
+        &mut self,
+        op: &str,
+        left: &Register,
+        right: &Register,
+    ) -> Result<Register, Error> {
+        Ok(match (left.get_type(), right.get_type()) {
+            (Type::Dict, _)
+            | (_, Type::Dict)
+            | (Type::Seq, Type::Coll | Type::Nested)
+            | (Type::Coll | Type::Nested, Type::Seq) => {
+                return Err(Error::unchecked(format!(
+                    "{} of {} and {}",
+                    op,
+                    left.get_type(),
+                    right.get_type()
+                )))
+            }
+            // Scalars and colls are broadcast across nested colls.
+            (Type::Nested, _) | (_, Type::Nested) => self.pop_free_reg(Type::Nested)?,
+            (Type::Seq, _) | (_, Type::Seq) => self.pop_free_reg(Type::Seq)?,
+            (Type::Coll, _) | (_, Type::Coll) => self.pop_free_reg(Type::Coll)?,
+            // Promote to a real register.
+            (Type::Real, _) | (_, Type::Real) => self.pop_free_reg(Type::Real)?,
+            (Type::Integer, Type::Integer) => self.pop_free_reg(Type::Integer)?,
+        })
+    }
+
+    fn add_arith_instruction(&mut self, op: &str) -> Result<(), Error> {
         let right_reg = self.used_reg.pop().unwrap();
         let left_reg = self.used_reg.pop().unwrap();
 
-        let result_reg = self.get_binop_result_reg(&left_reg, &right_reg);
+        let result_reg = self.get_binop_result_reg(op, &left_reg, &right_reg)?;
 
         let result_char = result_reg.get_char();
         let left_char = left_reg.get_char();
@@ -157,13 +481,15 @@ impl Compiler {
 
         self.push_free_reg(left_reg);
         self.push_free_reg(right_reg);
+        Ok(())
     }
 
-    fn add_compare_instruction(&mut self, op: &str) {
+    fn add_compare_instruction(&mut self, op: &str) -> Result<(), Error> {
         let right_reg = self.used_reg.pop().unwrap();
         let left_reg = self.used_reg.pop().unwrap();
+        check_comparable(&left_reg, &right_reg)?;
 
-        let result_reg = self.free_int_reg.pop().unwrap();
+        let result_reg = self.pop_free_reg(Type::Integer)?;
 
         let result_char = result_reg.get_char();
         let left_char = left_reg.get_char();
@@ -178,12 +504,521 @@ impl Compiler {
 
         self.push_free_reg(left_reg);
         self.push_free_reg(right_reg);
+        Ok(())
+    }
+
+    fn new_block_id(&mut self) -> usize {
+        let id = self.next_block_id;
+        self.next_block_id += 1;
+        id
+    }
+
+    fn add_jump(&mut self, label: &str) {
+        self.assembly.push(format!("jmp @{}", label));
+    }
+
+    // Evaluates the condition and jumps to the label if it is false.
+    // Jumps to the label if the condition on the line starting with `keyword`
+    // is false, marking where the condition is for debug info and errors.
+    fn add_condition(&mut self, keyword: &str, cond: &Token, label: &str) -> Result<(), Error> {
+        let found = find_condition(&self.source, self.source_offset, keyword);
+        if let (true, Some((offset, len))) = (self.debug_info, found) {
+            let span = Span::new(&self.source, offset, len);
+            self.assembly
+                .push(format!(".loc #{} #{}", span.line, span.column));
+        }
+        let result = self.add_jump_if_false(cond, label);
+        match found {
+            Some((offset, len)) => {
+                self.source_offset = offset + len;
+                result.map_err(|e| e.with_span(Span::new(&self.source, offset, len)))
+            }
+            None => result,
+        }
+    }
+
+    fn add_jump_if_false(&mut self, cond: &Token, label: &str) -> Result<(), Error> {
+        // Comparisons jump on the opposite comparison instead of testing the
+        // result.
+        if let Token::BinOp { left, op, right } = cond {
+            let jump = match **op {
+                Token::EqualsOp => Some("jne"),
+                Token::NotEqualsOp => Some("jeq"),
+                Token::GreaterThanOp => Some("jlte"),
+                Token::GreaterThanEqualsOp => Some("jlt"),
+                Token::LessThanOp => Some("jgte"),
+                Token::LessThanEqualsOp => Some("jgt"),
+                _ => None,
+            };
+            if let Some(jump) = jump {
+                self.visit_token(left)?;
+                self.visit_token(right)?;
+                let right_reg = self.used_reg.pop().unwrap();
+                let left_reg = self.used_reg.pop().unwrap();
+                check_comparable(&left_reg, &right_reg)?;
+                self.assembly.push(format!(
+                    "{} @{} ${}{} ${}{}",
+                    jump,
+                    label,
+                    left_reg.get_char(),
+                    left_reg.idx,
+                    right_reg.get_char(),
+                    right_reg.idx
+                ));
+                self.push_free_reg(left_reg);
+                self.push_free_reg(right_reg);
+                return Ok(());
+            }
+        }
+
+        self.visit_token(cond)?;
+        let cond_reg = self.used_reg.pop().unwrap();
+        if !cond_reg.get_type().is_scalar() {
+            return Err(Error::unchecked(format!(
+                "{} condition",
+                cond_reg.get_type()
+            )));
+        }
+
+        self.assembly.push(format!(
+            "jz @{} ${}{}",
+            label,
+            cond_reg.get_char(),
+            cond_reg.idx
+        ));
+        self.push_free_reg(cond_reg);
+        Ok(())
+    }
+
+    // Writes the function out of line so it only runs when called.  The
+    // function is registered before its body so it can call itself.
+    fn add_function(
+        &mut self,
+        name: &str,
+        args: &[Token],
+        return_type: Option<Type>,
+        body: &[Option<Token>],
+    ) -> Result<(), Error> {
+        if self.functions.contains_key(name) {
+            return Err(Error::unchecked(format!("redefinition of '{}'", name)));
+        }
+        let arg_types = args
+            .iter()
+            .map(|arg| match arg {
+                Token::Arg { typ, .. } => Ok(*typ),
+                _ => Err(Error::new(format!("Invalid argument to '{}'", name))),
+            })
+            .collect::<Result<Vec<Type>, Error>>()?;
+
+        // Added before the body so functions can call themselves.
+        self.functions.insert(
+            name.to_string(),
+            Signature {
+                args: arg_types,
+                return_type,
+            },
+        );
+        let outer_function = self.current_function.replace(name.to_string());
+
+        let end_label = format!("func_{}_end", name);
+        self.assembly.push(format!("; [start func] {}", name));
+        // The body only runs when called, so skip over it.
+        self.add_jump(&end_label);
+        self.assembly.push(format!("func_{}:", name));
+
+        // Outer variables can be read, but not assigned, in the body.
+        let outer_variables = self.variables.clone();
+        let outer_spilled = self.spilled.clone();
+        let outer_local_variables = std::mem::take(&mut self.local_variables);
+
+        for arg in args {
+            self.visit_token(arg)?;
+        }
+
+        // The caller pushes arguments in order so pop them in reverse.
+        for arg in args.iter().rev() {
+            if let Token::Arg { ident, .. } = arg {
+                if let Some(&slot) = self.spilled.get(ident) {
+                    let reg = self.pop_free_reg(slot.typ)?;
+                    self.assembly
+                        .push(format!("pop ${}{}", reg.get_char(), reg.idx));
+                    self.add_store(slot, reg)?;
+                } else {
+                    let reg = &self.used_reg[self.variables[ident]];
+                    self.assembly
+                        .push(format!("pop ${}{}", reg.get_char(), reg.idx));
+                }
+            }
+        }
+
+        self.visit_block(body)?;
+
+        // Functions with a return type always return explicitly.
+        if return_type.is_none() {
+            self.assembly.push("ret".into());
+        }
+
+        log::debug!("{:#?}", self.variables);
+        log::debug!("{:#?}", self.local_variables);
+        log::debug!("{:#?}", self.used_reg);
+
+        // clean up any local variables (in reverse order)
+        let mut free_regs = vec![];
+        for var in self.local_variables.iter().rev() {
+            if self.spilled.contains_key(var) {
+                continue;
+            }
+            let old_used_reg = self.used_reg.remove(self.variables[var]);
+            free_regs.push(old_used_reg);
+        }
+        for reg in free_regs {
+            self.push_free_reg(reg);
+        }
+        self.variables = outer_variables;
+        self.spilled = outer_spilled;
+        self.local_variables = outer_local_variables;
+        self.current_function = outer_function;
+
+        self.assembly.push(format!("{}:", end_label));
+        self.assembly.push(format!("; [end func] {}", name));
+        Ok(())
+    }
+
+    // Finds the function passed to a builtin, writing it out first if it's
+    // anonymous.  Values are passed in as reals and the function must take
+    // `arity` of them and return a single integer or real.
+    fn function_arg(
+        &mut self,
+        builtin: Builtin,
+        arg: &Token,
+        arity: usize,
+    ) -> Result<String, Error> {
+        if let Token::Lambda { args, body } = arg {
+            if args.len() != arity {
+                return Err(unchecked_args(builtin));
+            }
+            let name = format!("lambda_{}", self.new_block_id());
+            self.add_function(&name, args, Some(Type::Real), body)?;
+            return Ok(name);
+        }
+
+        let name = match function_name(arg) {
+            Some(name) => name,
+            None => return Err(unchecked_args(builtin)),
+        };
+        let signature = match self.functions.get(name) {
+            Some(signature) => signature,
+            None => return Err(unchecked_args(builtin)),
+        };
+        if signature.args.len() != arity
+            || !signature.args.iter().all(Type::is_scalar)
+            || !matches!(signature.return_type, Some(Type::Integer | Type::Real))
+        {
+            return Err(unchecked_args(builtin));
+        }
+        Ok(name.to_string())
+    }
+
+    // Evaluates the collection passed to a builtin.
+    fn collection_arg(&mut self, builtin: Builtin, arg: &Token) -> Result<Register, Error> {
+        self.visit_token(arg)?;
+        let reg = self.used_reg.pop().unwrap();
+        // Nested collections are flattened by joining their rows.
+        if builtin == Builtin::Flatten && reg.get_type() == Type::Nested {
+            let out_reg = self.pop_free_reg(Type::Coll)?;
+            self.assembly
+                .push(format!("flat $v{} $n{}", out_reg.idx, reg.idx));
+            self.push_free_reg(reg);
+            return Ok(out_reg);
+        }
+        if !reg.get_type().is_collection() {
+            return Err(unchecked_args(builtin));
+        }
+        Ok(reg)
+    }
+
+    // Loads the syscall number into a register and makes the call.
+    fn add_syscall(&mut self, call: Syscall, operand: &str) -> Result<(), Error> {
+        let call_reg = self.pop_free_reg(Type::Integer)?;
+        self.assembly
+            .push(format!("load $i{} #{}", call_reg.idx, call as u8));
+        self.assembly
+            .push(format!("syscall $i{} {}", call_reg.idx, operand));
+        self.free_int_reg.push(call_reg);
+        Ok(())
+    }
+
+    // Prints each part of the string in turn, with a newline after the last.
+    fn add_print(&mut self, parts: &[Token]) -> Result<(), Error> {
+        let empty = [Token::Text {
+            value: String::new(),
+        }];
+        let parts: Vec<&Token> = parts
+            .iter()
+            .filter(|part| !matches!(part, Token::Text { value } if value.is_empty()))
+            .collect();
+        let parts = if parts.is_empty() {
+            empty.iter().collect()
+        } else {
+            parts
+        };
+
+        for (n, part) in parts.iter().enumerate() {
+            let last = n + 1 == parts.len();
+            match part {
+                Token::Text { value } => {
+                    let label = format!("str_{}", self.new_block_id());
+                    self.rodata
+                        .push(format!("{}: .str '{}'", label, escape(value)));
+                    let call = if last {
+                        Syscall::PrintStr
+                    } else {
+                        Syscall::PrintStrInline
+                    };
+                    self.add_syscall(call, &format!("@{}", label))?;
+                }
+                value => {
+                    self.visit_token(value)?;
+                    let reg = self.used_reg.pop().unwrap();
+                    let call = if last {
+                        Syscall::PrintReg
+                    } else {
+                        Syscall::PrintRegInline
+                    };
+                    self.add_syscall(call, &format!("${}{}", reg.get_char(), reg.idx))?;
+                    self.push_free_reg(reg);
+                }
+            }
+        }
+        Ok(())
+    }
+
+    // Evaluates the dict passed to a builtin.
+    fn dict_arg(&mut self, builtin: Builtin, arg: &Token) -> Result<Register, Error> {
+        self.visit_token(arg)?;
+        let reg = self.used_reg.pop().unwrap();
+        if reg.get_type() != Type::Dict {
+            return Err(unchecked_args(builtin));
+        }
+        Ok(reg)
+    }
+
+    // Pushes the arguments and calls the function.  If `use_result` is set,
+    // the return value is left in a register.
+    fn add_call(&mut self, name: &str, args: &[Token], use_result: bool) -> Result<(), Error> {
+        let signature = match self.functions.get(name) {
+            Some(signature) => signature.clone(),
+            None => return Err(Error::unchecked(format!("call to '{}'", name))),
+        };
+        if args.len() != signature.args.len() {
+            return Err(Error::unchecked(format!("call to '{}'", name)));
+        }
+
+        // Spilled locals live at the same address for every call, so save them
+        // in case this function is called again before it returns.
+        let saved: Vec<Slot> = match self.current_function {
+            Some(_) => self
+                .local_variables
+                .iter()
+                .filter_map(|var| self.spilled.get(var).copied())
+                .collect(),
+            None => vec![],
+        };
+        for slot in &saved {
+            let reg = self.add_reload(*slot)?;
+            self.assembly
+                .push(format!("push ${}{}", reg.get_char(), reg.idx));
+            self.push_free_reg(reg);
+        }
+
+        for (arg, typ) in args.iter().zip(signature.args) {
+            self.visit_token(arg)?;
+            let reg = self.used_reg.pop().unwrap();
+
+            // Integers and reals are converted as they're popped by
+            // the callee, but nothing else can be.
+            if typ != reg.get_type() && !(typ.is_scalar() && reg.get_type().is_scalar()) {
+                return Err(Error::unchecked(format!("call to '{}'", name)));
+            }
+            self.assembly
+                .push(format!("push ${}{}", reg.get_char(), reg.idx));
+            self.push_free_reg(reg);
+        }
+        self.assembly.push(format!("call @func_{}", name));
+
+        let result_reg = match signature.return_type {
+            Some(return_type) => {
+                let result_reg = self.pop_free_reg(return_type)?;
+                self.assembly
+                    .push(format!("pop ${}{}", result_reg.get_char(), result_reg.idx));
+                Some(result_reg)
+            }
+            None => {
+                if use_result {
+                    return Err(Error::unchecked(format!("call to '{}'", name)));
+                }
+                None
+            }
+        };
+
+        for slot in saved.iter().rev() {
+            let reg = self.pop_free_reg(slot.typ)?;
+            self.assembly
+                .push(format!("pop ${}{}", reg.get_char(), reg.idx));
+            self.add_store(*slot, reg)?;
+        }
+
+        if let Some(result_reg) = result_reg {
+            if use_result {
+                self.used_reg.push(result_reg);
+            } else {
+                self.push_free_reg(result_reg);
+            }
+        }
+        Ok(())
+    }
+
+    // Writes the values to the heap and loads them into a vector register.
+    fn add_coll(&mut self, values: &[&Token]) -> Result<(), Error> {
+        // A collection of collections doesn't need the heap, so gather the
+        // rows instead.
+        if self.holds_colls(values) {
+            return self.add_nested_coll(values);
+        }
+
+        // Allocate memory for the heap and put the base address into a register.
+        let alloc_reg = self.pop_free_reg(Type::Integer)?;
+        self.assembly
+            .push(format!("alloc $i{} #{}", alloc_reg.idx, values.len() * 8));
+
+        // Go through the collection and store each generated real to the heap.
+        let vec_base_reg = self.pop_free_reg(Type::Integer)?;
+        self.assembly
+            .push(format!("copy $i{} $i{}", vec_base_reg.idx, alloc_reg.idx));
+
+        let mut value_it = values.iter().peekable();
+        while let Some(v) = value_it.next() {
+            self.visit_token(v)?;
+            let mut used_reg = self.used_reg.pop().unwrap();
+            match used_reg.reg {
+                VmRegister::R(_) => {}
+                VmRegister::I(_) => {
+                    // promote an integer to a real for storage in the collection
+                    let real_reg = self.pop_free_reg(Type::Real)?;
+                    self.assembly
+                        .push(format!("copy $r{} $i{}", real_reg.idx, used_reg.idx));
+                    self.free_int_reg.push(used_reg);
+                    used_reg = real_reg;
+                }
+                VmRegister::V(_) | VmRegister::D(_) | VmRegister::N(_) => {
+                    return Err(Error::unchecked(format!(
+                        "{} in a collection",
+                        used_reg.get_type()
+                    )));
+                }
+            };
+            self.assembly
+                .push(format!("sw $i{} $r{}", vec_base_reg.idx, used_reg.idx));
+            self.free_real_reg.push(used_reg);
+
+            // If we will be going round the loop again, increment the base index.
+            if value_it.peek().is_some() {
+                let inc_reg = self.pop_free_reg(Type::Integer)?;
+                self.assembly
+                    .push(format!("load $i{} #{}", inc_reg.idx, size_of::<f64>()));
+                self.assembly.push(format!(
+                    "add $i{} $i{} $i{}",
+                    vec_base_reg.idx, vec_base_reg.idx, inc_reg.idx
+                ));
+                self.free_int_reg.push(inc_reg);
+            }
+        }
+        self.free_int_reg.push(vec_base_reg);
+
+        // And finally load the heap info into a vector register.
+        let vec_reg = self.pop_free_reg(Type::Coll)?;
+        self.assembly.push(format!(
+            "load $v{} $i{} #{}",
+            vec_reg.idx,
+            alloc_reg.idx,
+            values.len() * size_of::<f64>()
+        ));
+        self.free_int_reg.push(alloc_reg);
+        self.used_reg.push(vec_reg);
+        Ok(())
+    }
+
+    // Whether the values of a collection are collections themselves, which
+    // the first one decides.
+    fn holds_colls(&self, values: &[&Token]) -> bool {
+        let Some(first) = values.first() else {
+            return false;
+        };
+        let mut checker =
+            TypeChecker::new(&self.source, self.variable_types(), self.functions.clone());
+        matches!(checker.value(first), Ok(Type::Coll))
+    }
+
+    // Pushes each row onto the stack and gathers them into a nested register.
+    fn add_nested_coll(&mut self, values: &[&Token]) -> Result<(), Error> {
+        for v in values {
+            self.visit_token(v)?;
+            let row_reg = self.used_reg.pop().unwrap();
+            if row_reg.get_type() != Type::Coll {
+                return Err(Error::unchecked(format!(
+                    "{} in a collection of collections",
+                    row_reg.get_type()
+                )));
+            }
+            self.assembly.push(format!("push $v{}", row_reg.idx));
+            self.push_free_reg(row_reg);
+        }
+
+        let nested_reg = self.pop_free_reg(Type::Nested)?;
+        self.assembly
+            .push(format!("nest $n{} #{}", nested_reg.idx, values.len()));
+        self.used_reg.push(nested_reg);
+        Ok(())
+    }
+
+    // Visits each statement in turn.  Any that fail are recorded and their
+    // registers given back so the rest can still be checked.
+    fn visit_block(&mut self, body: &[Option<Token>]) -> Result<(), Error> {
+        for statement in body.iter().flatten() {
+            let used = self.used_reg.len();
+            let free = [
+                self.free_int_reg.clone(),
+                self.free_real_reg.clone(),
+                self.free_vec_reg.clone(),
+                self.free_dict_reg.clone(),
+                self.free_nested_reg.clone(),
+            ];
+            if let Err(e) = self.visit_token(statement) {
+                self.errors.push(e);
+                self.used_reg.truncate(used);
+                [
+                    self.free_int_reg,
+                    self.free_real_reg,
+                    self.free_vec_reg,
+                    self.free_dict_reg,
+                    self.free_nested_reg,
+                ] = free;
+            }
+        }
+        Ok(())
     }
 
-    fn add_logical_instruction(&mut self, op: &str) {
+    fn add_logical_instruction(&mut self, op: &str) -> Result<(), Error> {
         if op == "not" {
             let right_reg = self.used_reg.pop().unwrap();
-            let result_reg = self.free_int_reg.pop().unwrap();
+            if matches!(right_reg.get_type(), Type::Dict | Type::Nested) {
+                return Err(Error::unchecked(format!(
+                    "{} of {}",
+                    op,
+                    right_reg.get_type()
+                )));
+            }
+            let result_reg = self.pop_free_reg(Type::Integer)?;
 
             let result_char = result_reg.get_char();
             let right_char = right_reg.get_char();
@@ -198,7 +1033,18 @@ impl Compiler {
         } else {
             let right_reg = self.used_reg.pop().unwrap();
             let left_reg = self.used_reg.pop().unwrap();
-            let result_reg = self.free_int_reg.pop().unwrap();
+            if [&left_reg, &right_reg]
+                .iter()
+                .any(|reg| matches!(reg.get_type(), Type::Dict | Type::Nested))
+            {
+                return Err(Error::unchecked(format!(
+                    "{} of {} and {}",
+                    op,
+                    left_reg.get_type(),
+                    right_reg.get_type()
+                )));
+            }
+            let result_reg = self.pop_free_reg(Type::Integer)?;
 
             let result_char = result_reg.get_char();
             let left_char = left_reg.get_char();
@@ -213,6 +1059,135 @@ impl Compiler {
             self.push_free_reg(left_reg);
             self.push_free_reg(right_reg);
         }
+        Ok(())
+    }
+}
+
+// Finds the statement in the source, searching on from `start` as statements
+// are visited in the order they're written.  Statements usually start a line,
+// but those in anonymous functions may not.
+fn find_statement(source: &str, start: usize, statement: &str) -> Option<usize> {
+    let mut offsets = source[start..]
+        .match_indices(statement)
+        .map(|(i, _)| start + i);
+    let first = offsets.clone().next()?;
+    Some(offsets.find(|&i| starts_line(source, i)).unwrap_or(first))
+}
+
+// Finds the line starting with `keyword` that holds a condition, searching on
+// from `start`, and gives its offset and length.
+fn find_condition(source: &str, start: usize, keyword: &str) -> Option<(usize, usize)> {
+    let offset = source[start..]
+        .match_indices(keyword)
+        .map(|(i, _)| start + i)
+        .find(|&i| {
+            starts_line(source, i) && source[i + keyword.len()..].starts_with([' ', '\t'])
+        })?;
+    let line = source[offset..].lines().next().unwrap_or_default();
+    Some((offset, line.trim_end().len()))
+}
+
+// Finds the line declaring the function, searching on from `start`, and gives
+// its offset and length.
+fn find_function(source: &str, start: usize, name: &str) -> Option<(usize, usize)> {
+    let offset = source[start..]
+        .match_indices("func")
+        .map(|(i, _)| start + i)
+        .find(|&i| {
+            starts_line(source, i)
+                && source[i + "func".len()..]
+                    .trim_start()
+                    .strip_prefix(name)
+                    .is_some_and(|rest| rest.starts_with(['(', ' ', '\t']))
+        })?;
+    let line = source[offset..].lines().next().unwrap_or_default();
+    Some((offset, line.trim_end().len()))
+}
+
+// Statements end with a newline, which the last line of a file may not have.
+fn with_final_newline(source: &str) -> String {
+    match source.ends_with('\n') {
+        true => source.to_string(),
+        false => format!("{}\n", source),
+    }
+}
+
+// Whether only spaces come before the offset on its line.
+fn starts_line(source: &str, offset: usize) -> bool {
+    let line = source[..offset].trim_end_matches([' ', '\t']);
+    line.is_empty() || line.ends_with('\n')
+}
+
+// The name of the function if the rvalue is nothing but an identifier.
+fn function_name(rvalue: &Token) -> Option<&str> {
+    match rvalue {
+        Token::Arith { left, right } if right.is_empty() => function_name(left),
+        Token::Term { left, right } if right.is_empty() => function_name(left),
+        Token::Factor { value } => function_name(value),
+        Token::Identifier { name } => Some(name),
+        _ => None,
+    }
+}
+
+// Reports where parsing stopped.  Statements backtrack when they fail, so
+// look inside the one that stopped it to see how far it got.
+fn syntax_error(source: &str, rest: &str) -> Error {
+    let rest = rest.trim_start_matches([' ', '\t']);
+    let reached = [
+        function(rest).err(),
+        if_statement(rest).err(),
+        while_statement(rest).err(),
+        expression(rest).err(),
+    ]
+    .into_iter()
+    .filter_map(|e| match e {
+        Some(nom::Err::Error(e) | nom::Err::Failure(e)) => Some(e.input.len()),
+        _ => None,
+    })
+    .min()
+    .unwrap_or(rest.len());
+    let rest = &rest[rest.len() - reached..];
+    let offset = source.len() - rest.trim_start_matches([' ', '\t']).len();
+    Error::new("Invalid syntax".to_string()).with_span(Span::new(source, offset, 1))
+}
+
+// The error for a builtin given arguments the type checker should have
+// rejected.
+fn unchecked_args(builtin: Builtin) -> Error {
+    Error::unchecked(format!(
+        "arguments to '{}'",
+        builtin.to_string().to_lowercase()
+    ))
+}
+
+fn check_comparable(left: &Register, right: &Register) -> Result<(), Error> {
+    if [left, right]
+        .iter()
+        .any(|reg| matches!(reg.get_type(), Type::Dict | Type::Nested))
+    {
+        return Err(Error::unchecked(format!(
+            "comparison of {} and {}",
+            left.get_type(),
+            right.get_type()
+        )));
+    }
+    Ok(())
+}
+
+// The variable the statement assigns to, if any, including lines that don't
+// parse but look like assignments.
+fn assigned_variable(statement: &Token) -> Option<&str> {
+    match statement {
+        Token::Expression { token, .. } => match &**token {
+            Token::Assign { ident, .. } => Some(ident),
+            _ => None,
+        },
+        Token::Invalid { source } => {
+            terminated(identifier, tuple((space0, tag("="), not(tag("=")))))(source.as_str())
+                .ok()
+                .map(|(_, ident)| ident)
+        }
+        _ => None,
     }
 }
 
@@ -226,23 +1201,23 @@ impl Visitor for Compiler {
             }
 
             // Arithmetic
-            Token::AdditionOp => self.add_arith_instruction("add"),
-            Token::SubtractionOp => self.add_arith_instruction("sub"),
-            Token::MultiplicationOp => self.add_arith_instruction("mul"),
-            Token::DivisionOp => self.add_arith_instruction("div"),
+            Token::AdditionOp => self.add_arith_instruction("add")?,
+            Token::SubtractionOp => self.add_arith_instruction("sub")?,
+            Token::MultiplicationOp => self.add_arith_instruction("mul")?,
+            Token::DivisionOp => self.add_arith_instruction("div")?,
 
             // Comparative
-            Token::EqualsOp => self.add_compare_instruction("eq"),
-            Token::NotEqualsOp => self.add_compare_instruction("neq"),
-            Token::GreaterThanOp => self.add_compare_instruction("gt"),
-            Token::GreaterThanEqualsOp => self.add_compare_instruction("gte"),
-            Token::LessThanOp => self.add_compare_instruction("lt"),
-            Token::LessThanEqualsOp => self.add_compare_instruction("lte"),
+            Token::EqualsOp => self.add_compare_instruction("eq")?,
+            Token::NotEqualsOp => self.add_compare_instruction("neq")?,
+            Token::GreaterThanOp => self.add_compare_instruction("gt")?,
+            Token::GreaterThanEqualsOp => self.add_compare_instruction("gte")?,
+            Token::LessThanOp => self.add_compare_instruction("lt")?,
+            Token::LessThanEqualsOp => self.add_compare_instruction("lte")?,
 
             // Logical
-            Token::AndOp => self.add_logical_instruction("and"),
-            Token::OrOp => self.add_logical_instruction("or"),
-            Token::NotOp => self.add_logical_instruction("not"),
+            Token::AndOp => self.add_logical_instruction("and")?,
+            Token::OrOp => self.add_logical_instruction("or")?,
+            Token::NotOp => self.add_logical_instruction("not")?,
 
             Token::UnaryOp { op, right } => {
                 self.visit_token(right)?;
@@ -262,10 +1237,10 @@ impl Visitor for Compiler {
                 let result_reg = self.used_reg.pop().unwrap();
 
                 if !self.local_variables.contains(ident) {
-                    if self.variables.contains_key(ident) {
+                    if self.is_variable(ident) {
                         // do not allow shadowing.  any variables needed must be passed in.
-                        return Err(Error::new(format!(
-                            "Cannot assign to global variable '{}' in function scope.",
+                        return Err(Error::unchecked(format!(
+                            "assignment to '{}' in a function",
                             ident
                         )));
                     }
@@ -274,222 +1249,555 @@ impl Visitor for Compiler {
                     self.local_variables.push(ident.clone());
                 }
 
-                // Ensure result reg remains 'used' and map name to result reg.
-                // 'unassign' old result reg if the variable already exists.
-                if self.variables.contains_key(ident) {
-                    let old_used_reg = self.used_reg.remove(self.variables[ident]);
-                    if result_reg.reg != old_used_reg.reg {
-                        return Err(Error::new(format!(
-                            "Variable '{}' was {:?} and is now {:?}",
-                            ident, old_used_reg.reg, result_reg.reg
-                        )));
+                // If the variable already exists, copy the result into its
+                // register so it stays put regardless of which path through
+                // the program assigned it.  Otherwise the result reg remains
+                // 'used' and the name maps to it.
+                if let Some(&slot) = self.spilled.get(ident) {
+                    if result_reg.get_type() != slot.typ {
+                        return Err(Error::unchecked(format!("change of type of '{}'", ident)));
+                    }
+                    self.add_store(slot, result_reg)?;
+                } else if self.variables.contains_key(ident) {
+                    let var_reg = &self.used_reg[self.variables[ident]];
+                    if result_reg.get_type() != var_reg.get_type() {
+                        return Err(Error::unchecked(format!("change of type of '{}'", ident)));
+                    }
+                    self.assembly.push(format!(
+                        "copy ${}{} ${}{}",
+                        var_reg.get_char(),
+                        var_reg.idx,
+                        result_reg.get_char(),
+                        result_reg.idx
+                    ));
+                    self.push_free_reg(result_reg);
+                } else if self.should_spill(result_reg.get_type()) {
+                    let slot = self.new_slot(ident, result_reg.get_type());
+                    self.add_store(slot, result_reg)?;
+                } else {
+                    let result_reg = self.add_loop_variable(result_reg)?;
+                    // Only variables outside functions keep their register.
+                    if self.debug_info && self.current_function.is_none() {
+                        self.assembly.push(format!(
+                            ".var '{}' ${}{}",
+                            ident,
+                            result_reg.get_char(),
+                            result_reg.idx
+                        ));
                     }
-                    self.push_free_reg(old_used_reg);
+                    self.variables
+                        .insert(ident.to_string(), self.used_reg.len());
+                    self.used_reg.push(result_reg);
                 }
-
-                self.variables
-                    .insert(ident.to_string(), self.used_reg.len());
-
-                /*                 println!(
-                    "inserted variable '{}': {:?}",
-                    ident.to_string(),
-                    &result_reg,
-                ); */
-
-                self.used_reg.push(result_reg);
             }
 
             Token::Builtin { builtin, args } => {
                 match builtin {
                     Builtin::Write => {
                         if args.len() != 1 {
-                            return Err(Error::new(
-                                "'write' expects a single argument".to_string(),
-                            ));
+                            return Err(unchecked_args(*builtin));
                         }
                         self.visit_token(&args[0])?;
                         let reg = self.used_reg.pop().unwrap();
-                        let call_reg = self.free_int_reg.pop().unwrap();
-
-                        self.assembly.push(format!(
-                            "load $i{} #{}",
-                            call_reg.idx,
-                            Syscall::PrintReg as u8
+                        self.add_syscall(
+                            Syscall::PrintReg,
+                            &format!("${}{}", reg.get_char(), reg.idx),
+                        )?;
+                        self.push_free_reg(reg);
+                    }
+                    Builtin::Print => match args.as_slice() {
+                        [Token::Str { parts }] => self.add_print(parts)?,
+                        _ => return Err(unchecked_args(*builtin)),
+                    },
+                    Builtin::Read => {
+                        // Collections by default, otherwise the named type.
+                        let typ = match args.as_slice() {
+                            [] => Type::Coll,
+                            [arg] => match function_name(arg).map(Type::try_from) {
+                                Some(Ok(typ)) => typ,
+                                _ => return Err(unchecked_args(*builtin)),
+                            },
+                            _ => return Err(unchecked_args(*builtin)),
+                        };
+                        let reg = self.pop_free_reg(typ)?;
+                        self.add_syscall(
+                            Syscall::ReadReg,
+                            &format!("${}{}", reg.get_char(), reg.idx),
+                        )?;
+                        self.used_reg.push(reg);
+                    }
+                    Builtin::Get => {
+                        if args.len() != 2 {
+                            return Err(unchecked_args(*builtin));
+                        }
+                        let dict_reg = self.dict_arg(*builtin, &args[0])?;
+                        self.visit_token(&args[1])?;
+                        let key_reg = self.used_reg.pop().unwrap();
+                        if !key_reg.get_type().is_scalar() {
+                            return Err(unchecked_args(*builtin));
+                        }
+
+                        let out_reg = self.pop_free_reg(Type::Real)?;
+                        self.assembly.push(format!(
+                            "get $r{} $d{} ${}{}",
+                            out_reg.idx,
+                            dict_reg.idx,
+                            key_reg.get_char(),
+                            key_reg.idx
                         ));
 
+                        self.push_free_reg(key_reg);
+                        self.push_free_reg(dict_reg);
+                        self.used_reg.push(out_reg);
+                    }
+                    Builtin::Keys | Builtin::Values => {
+                        if args.len() != 1 {
+                            return Err(unchecked_args(*builtin));
+                        }
+                        let dict_reg = self.dict_arg(*builtin, &args[0])?;
+
+                        let out_reg = self.pop_free_reg(Type::Coll)?;
+                        let opcode = if *builtin == Builtin::Keys {
+                            "keys"
+                        } else {
+                            "vals"
+                        };
+                        self.assembly
+                            .push(format!("{} $v{} $d{}", opcode, out_reg.idx, dict_reg.idx));
+
+                        self.push_free_reg(dict_reg);
+                        self.used_reg.push(out_reg);
+                    }
+                    Builtin::Seq | Builtin::Coll => {
+                        // The elements stay where they are; only the type
+                        // changes.
+                        if args.len() != 1 {
+                            return Err(unchecked_args(*builtin));
+                        }
+                        let mut reg = self.collection_arg(*builtin, &args[0])?;
+                        reg.ordered = *builtin == Builtin::Seq;
+                        self.used_reg.push(reg);
+                    }
+                    Builtin::Sort => {
+                        if args.is_empty() || args.len() > 2 {
+                            return Err(unchecked_args(*builtin));
+                        }
+                        // Ascending unless told otherwise.
+                        let order = match args.get(1).map(function_name) {
+                            None | Some(Some("lt" | "lte")) => 0,
+                            Some(Some("gt" | "gte")) => 1,
+                            Some(_) => return Err(unchecked_args(*builtin)),
+                        };
+                        let in_reg = self.collection_arg(*builtin, &args[0])?;
+
+                        // Sorted elements have an order, so are always a seq.
+                        let out_reg = self.pop_free_reg(Type::Seq)?;
                         self.assembly.push(format!(
-                            "syscall $i{} ${}{}",
-                            call_reg.idx,
-                            reg.get_char(),
-                            reg.idx,
+                            "sort $v{} $v{} #{}",
+                            out_reg.idx, in_reg.idx, order
                         ));
 
-                        self.free_int_reg.push(call_reg);
+                        self.push_free_reg(in_reg);
+                        self.used_reg.push(out_reg);
+                    }
+                    Builtin::First => {
+                        if args.len() != 2 {
+                            return Err(unchecked_args(*builtin));
+                        }
+                        let in_reg = self.collection_arg(*builtin, &args[0])?;
+                        self.visit_token(&args[1])?;
+                        let n_reg = self.used_reg.pop().unwrap();
+                        if !n_reg.get_type().is_scalar() {
+                            return Err(unchecked_args(*builtin));
+                        }
+
+                        let out_reg = self.pop_free_reg(in_reg.get_type())?;
+                        self.assembly.push(format!(
+                            "first $v{} $v{} ${}{}",
+                            out_reg.idx,
+                            in_reg.idx,
+                            n_reg.get_char(),
+                            n_reg.idx
+                        ));
 
-                        self.push_free_reg(reg);
+                        self.push_free_reg(n_reg);
+                        self.push_free_reg(in_reg);
+                        self.used_reg.push(out_reg);
+                    }
+                    Builtin::Flatten | Builtin::Zip => {
+                        let opcode = match builtin {
+                            Builtin::Flatten if args.is_empty() => {
+                                return Err(unchecked_args(*builtin))
+                            }
+                            Builtin::Flatten => "cat",
+                            _ if args.len() != 2 => return Err(unchecked_args(*builtin)),
+                            _ => "zip",
+                        };
+
+                        // Combine the collections pairwise from the left.
+                        let mut acc_reg = self.collection_arg(*builtin, &args[0])?;
+                        for arg in &args[1..] {
+                            let in_reg = self.collection_arg(*builtin, arg)?;
+                            if in_reg.get_type() != acc_reg.get_type() {
+                                return Err(unchecked_args(*builtin));
+                            }
+                            // Zipping pairs up the elements as the rows of a
+                            // nested collection.
+                            let out_reg = match builtin {
+                                Builtin::Zip => self.pop_free_reg(Type::Nested)?,
+                                _ => self.pop_free_reg(in_reg.get_type())?,
+                            };
+                            self.assembly.push(format!(
+                                "{} ${}{} $v{} $v{}",
+                                opcode,
+                                out_reg.get_char(),
+                                out_reg.idx,
+                                acc_reg.idx,
+                                in_reg.idx
+                            ));
+                            self.push_free_reg(in_reg);
+                            self.push_free_reg(acc_reg);
+                            acc_reg = out_reg;
+                        }
+                        self.used_reg.push(acc_reg);
+                    }
+                    Builtin::Length => {
+                        if args.len() != 1 {
+                            return Err(unchecked_args(*builtin));
+                        }
+                        // Nested collections count their rows.
+                        self.visit_token(&args[0])?;
+                        let in_reg = self.used_reg.pop().unwrap();
+                        if !in_reg.get_type().is_collection() && in_reg.get_type() != Type::Nested {
+                            return Err(unchecked_args(*builtin));
+                        }
+
+                        let out_reg = self.pop_free_reg(Type::Integer)?;
+                        self.assembly.push(format!(
+                            "len $i{} ${}{}",
+                            out_reg.idx,
+                            in_reg.get_char(),
+                            in_reg.idx
+                        ));
+
+                        self.push_free_reg(in_reg);
+                        self.used_reg.push(out_reg);
                     }
-                    // TODO: string parser to create a constant and print it using
-                    // a syscall.
-                    /*
-                    "print" => {
-                        self.rodata.push(format!(
-                            "somestr: .str 'reg ${}{}'",
-                            reg.1.get_char(),
-                            reg.0
+                    Builtin::At => {
+                        if args.len() != 2 {
+                            return Err(unchecked_args(*builtin));
+                        }
+                        self.visit_token(&args[0])?;
+                        let in_reg = self.used_reg.pop().unwrap();
+                        // Elements of a nested collection are collections.
+                        let out_type = match in_reg.get_type() {
+                            Type::Coll | Type::Seq => Type::Real,
+                            Type::Nested => Type::Coll,
+                            _ => return Err(unchecked_args(*builtin)),
+                        };
+                        self.visit_token(&args[1])?;
+                        let index_reg = self.used_reg.pop().unwrap();
+                        if !index_reg.get_type().is_scalar() {
+                            return Err(unchecked_args(*builtin));
+                        }
+
+                        let out_reg = self.pop_free_reg(out_type)?;
+                        self.assembly.push(format!(
+                            "at ${}{} ${}{} ${}{}",
+                            out_reg.get_char(),
+                            out_reg.idx,
+                            in_reg.get_char(),
+                            in_reg.idx,
+                            index_reg.get_char(),
+                            index_reg.idx
                         ));
-                        self.assembly.push("print @somestr".to_string());
-                    }*/
-                    _ => return Err(Error::new(format!("Unknown builtin: {}", builtin))),
-                };
-            }
 
-            Token::Function { name, args, body } => {
-                self.assembly.push(format!("; [start func] {}", name));
-                self.assembly.push(format!("func_{}:", name));
+                        self.push_free_reg(index_reg);
+                        self.push_free_reg(in_reg);
+                        self.used_reg.push(out_reg);
+                    }
+                    Builtin::Map => {
+                        if args.len() != 2 {
+                            return Err(unchecked_args(*builtin));
+                        }
+                        let func = self.function_arg(*builtin, &args[0], 1)?;
+                        let in_reg = self.collection_arg(*builtin, &args[1])?;
+                        let out_reg = self.pop_free_reg(in_reg.get_type())?;
 
-                for arg in args {
-                    self.visit_token(arg)?;
-                }
+                        self.assembly.push(format!(
+                            "map $v{} $v{} @func_{}",
+                            out_reg.idx, in_reg.idx, func
+                        ));
 
-                body.iter()
-                    .flatten()
-                    .try_for_each(|expr| self.visit_token(expr))?;
+                        self.push_free_reg(in_reg);
+                        self.used_reg.push(out_reg);
+                    }
+                    Builtin::Filter => {
+                        if args.len() != 2 {
+                            return Err(unchecked_args(*builtin));
+                        }
+                        let func = self.function_arg(*builtin, &args[0], 1)?;
+                        let in_reg = self.collection_arg(*builtin, &args[1])?;
 
-                // TODO: return?
+                        // Find which elements to keep, then keep them.
+                        let mask_reg = self.pop_free_reg(Type::Coll)?;
+                        self.assembly.push(format!(
+                            "map $v{} $v{} @func_{}",
+                            mask_reg.idx, in_reg.idx, func
+                        ));
+                        let out_reg = self.pop_free_reg(in_reg.get_type())?;
+                        self.assembly.push(format!(
+                            "mask $v{} $v{} $v{}",
+                            out_reg.idx, in_reg.idx, mask_reg.idx
+                        ));
 
-                log::debug!("{:#?}", self.variables);
-                log::debug!("{:#?}", self.local_variables);
-                log::debug!("{:#?}", self.used_reg);
-
-                // clean up any local variables (in reverse order)
-                let mut free_regs = vec![];
-                for var in self.local_variables.iter().rev() {
-                    let old_used_reg = self.used_reg.remove(self.variables[var]);
-                    free_regs.push(old_used_reg);
-                    self.variables.remove(var);
-                }
-                for reg in free_regs {
-                    self.push_free_reg(reg);
+                        self.push_free_reg(mask_reg);
+                        self.push_free_reg(in_reg);
+                        self.used_reg.push(out_reg);
+                    }
+                    Builtin::Fold => {
+                        if args.len() != 3 {
+                            return Err(unchecked_args(*builtin));
+                        }
+
+                        // Common reducers have their own opcodes, unless
+                        // they've been replaced by a function of the same
+                        // name.
+                        let reducer = function_name(&args[0])
+                            .filter(|name| !self.functions.contains_key(*name))
+                            .and_then(|name| match name {
+                                "sum" => Some("sum"),
+                                "product" => Some("prod"),
+                                "min" => Some("min"),
+                                "max" => Some("max"),
+                                _ => None,
+                            });
+                        let (opcode, target) = match reducer {
+                            Some(opcode) => (opcode, String::new()),
+                            None => (
+                                "fold",
+                                format!(" @func_{}", self.function_arg(*builtin, &args[0], 2)?),
+                            ),
+                        };
+                        let in_reg = self.collection_arg(*builtin, &args[1])?;
+
+                        self.visit_token(&args[2])?;
+                        let init_reg = self.used_reg.pop().unwrap();
+                        if !init_reg.get_type().is_scalar() {
+                            return Err(unchecked_args(*builtin));
+                        }
+
+                        // The result accumulates in place of the initial value.
+                        let out_reg = self.pop_free_reg(Type::Real)?;
+                        self.assembly.push(format!(
+                            "copy $r{} ${}{}",
+                            out_reg.idx,
+                            init_reg.get_char(),
+                            init_reg.idx
+                        ));
+                        self.assembly.push(format!(
+                            "{} $r{} $v{}{}",
+                            opcode, out_reg.idx, in_reg.idx, target
+                        ));
+
+                        self.push_free_reg(init_reg);
+                        self.push_free_reg(in_reg);
+                        self.used_reg.push(out_reg);
+                    }
+                };
+            }
+
+            Token::Function {
+                name,
+                args,
+                return_type,
+                body,
+            } => {
+                if self.current_function.is_some() {
+                    return Err(Error::unchecked(format!(
+                        "function '{}' inside another function",
+                        name
+                    )));
                 }
-                self.assembly.push(format!("; [end func] {}", name));
+                self.add_function(name, args, *return_type, body)?;
+            }
+
+            Token::Str { .. } | Token::Text { .. } => {
+                return Err(Error::unchecked("string outside 'print'"))
+            }
+
+            Token::Lambda { .. } => {
+                return Err(Error::unchecked("anonymous function outside a builtin"))
             }
 
             Token::Arg { ident, typ } => {
-                let reg = match typ {
-                    Type::Real => self.free_real_reg.pop().unwrap(),
-                    Type::Integer => self.free_int_reg.pop().unwrap(),
-                    Type::Coll => self.free_vec_reg.pop().unwrap(),
-                };
-                self.variables.insert(ident.clone(), self.used_reg.len());
-                log::debug!("{:#?}", self.variables);
+                if self.local_variables.contains(ident) {
+                    return Err(Error::unchecked(format!("repeated argument '{}'", ident)));
+                }
+                if self.should_spill(*typ) {
+                    self.new_slot(ident, *typ);
+                } else {
+                    let reg = self.pop_free_reg(*typ)?;
+                    self.variables.insert(ident.clone(), self.used_reg.len());
+                    log::debug!("{:#?}", self.variables);
+                    self.used_reg.push(reg);
+                    log::debug!("{:#?}", self.used_reg);
+                }
                 self.local_variables.push(ident.clone());
                 log::debug!("{:#?}", self.local_variables);
-                self.used_reg.push(reg);
-                log::debug!("{:#?}", self.used_reg);
             }
 
-            Token::Identifier { name } => {
-                // println!("referencing variable '{}'", name.to_string());
-                if !self.variables.contains_key(name) {
-                    return Err(Error::new(format!("Unknown variable '{}'", name)));
-                }
+            Token::Call { name, args } => self.add_call(name, args, true)?,
 
-                let index = self.variables[name];
+            Token::Return { value } => {
+                let return_type = match &self.current_function {
+                    Some(name) => self.functions[name].return_type,
+                    None => return Err(Error::unchecked("return from outside a function")),
+                };
 
-                // println!(".. found at {}", index);
+                match (value, return_type) {
+                    (Some(value), Some(return_type)) => {
+                        self.visit_token(value)?;
+                        let reg = self.used_reg.pop().unwrap();
+                        // Integers are promoted to reals as they're popped.
+                        let typ = reg.get_type();
+                        if typ != return_type
+                            && !(typ == Type::Integer && return_type == Type::Real)
+                        {
+                            return Err(Error::unchecked(format!(
+                                "return of {} from function returning {}",
+                                typ, return_type
+                            )));
+                        }
+                        self.assembly
+                            .push(format!("push ${}{}", reg.get_char(), reg.idx));
+                        self.push_free_reg(reg);
+                    }
+                    (None, None) => {}
+                    _ => return Err(Error::unchecked("return of the wrong type")),
+                }
+                self.assembly.push("ret".into());
+            }
 
-                let copy_reg = match &self.used_reg[index].reg {
-                    VmRegister::I(_) => self.free_int_reg.pop().unwrap(),
-                    VmRegister::R(_) => self.free_real_reg.pop().unwrap(),
-                    VmRegister::V(_) => self.free_vec_reg.pop().unwrap(),
-                };
+            Token::If {
+                branches,
+                otherwise,
+            } => {
+                let id = self.new_block_id();
+                let end_label = format!("if_{}_end", id);
+                self.assembly.push(format!("; [start if] if_{}", id));
+
+                for (n, (cond, body)) in branches.iter().enumerate() {
+                    // Each failed condition falls through to the next branch.
+                    let next_label = format!("if_{}_{}", id, n + 1);
+                    let keyword = if n == 0 { "if" } else { "elif" };
+                    self.add_condition(keyword, cond, &next_label)?;
+                    self.visit_block(body)?;
+                    if n + 1 < branches.len() || !otherwise.is_empty() {
+                        self.add_jump(&end_label);
+                    }
+                    self.assembly.push(format!("{}:", next_label));
+                }
 
-                // Copy the value of the current identifier into the new reg
-                self.assembly.push(format!(
-                    "copy ${}{} ${}{}",
-                    copy_reg.get_char(),
-                    copy_reg.idx,
-                    self.used_reg[index].get_char(),
-                    self.used_reg[index].idx
-                ));
+                self.visit_block(otherwise)?;
+                self.assembly.push(format!("{}:", end_label));
+                self.assembly.push(format!("; [end if] if_{}", id));
+            }
+
+            Token::While { cond, body } => {
+                let id = self.new_block_id();
+                let start_label = format!("while_{}_start", id);
+                let end_label = format!("while_{}_end", id);
+                self.assembly.push(format!("; [start while] while_{}", id));
+                self.assembly.push(format!("{}:", start_label));
+                let outermost = self.loop_registers.is_none();
+                if outermost {
+                    self.loop_registers = Some(HashSet::new());
+                }
+                let result = self
+                    .add_condition("while", cond, &end_label)
+                    .and_then(|_| self.visit_block(body));
+                if outermost {
+                    self.loop_registers = None;
+                }
+                result?;
+                self.add_jump(&start_label);
+                self.assembly.push(format!("{}:", end_label));
+                self.assembly.push(format!("; [end while] while_{}", id));
+            }
 
-                self.used_reg.push(copy_reg);
+            Token::Identifier { name } => {
+                // println!("referencing variable '{}'", name.to_string());
+                if let Some(&slot) = self.spilled.get(name) {
+                    let reg = self.add_reload(slot)?;
+                    self.used_reg.push(reg);
+                } else if let Some(&index) = self.variables.get(name) {
+                    // println!(".. found at {}", index);
+
+                    let copy_reg = self.pop_free_reg(self.used_reg[index].get_type())?;
+
+                    // Copy the value of the current identifier into the new reg
+                    self.assembly.push(format!(
+                        "copy ${}{} ${}{}",
+                        copy_reg.get_char(),
+                        copy_reg.idx,
+                        self.used_reg[index].get_char(),
+                        self.used_reg[index].idx
+                    ));
+
+                    self.used_reg.push(copy_reg);
+                } else {
+                    return Err(Error::unchecked(format!("unknown variable '{}'", name)));
+                }
             }
 
             Token::Real { value } => {
-                let next_reg = self.free_real_reg.pop().unwrap();
+                let next_reg = self.pop_free_reg(Type::Real)?;
+                // Two decimal places unless more are needed, as they may be
+                // for folded constants.
+                let mut operand = format!("{:.2}", value);
+                if operand.parse::<f64>() != Ok(*value) {
+                    operand = value.to_string();
+                }
                 self.assembly
-                    .push(format!("load $r{} #{:.2}", next_reg.idx, value));
+                    .push(format!("load $r{} #{}", next_reg.idx, operand));
                 self.used_reg.push(next_reg);
             }
 
             Token::Integer { value } => {
-                let next_reg = self.free_int_reg.pop().unwrap();
+                let next_reg = self.pop_free_reg(Type::Integer)?;
                 self.assembly
                     .push(format!("load $i{} #{}", next_reg.idx, value));
                 self.used_reg.push(next_reg);
             }
 
-            Token::Coll { values } => {
-                // Allocate memory for the heap and put the base address into a register.
-                let alloc_reg = self.free_int_reg.pop().unwrap();
-                self.assembly
-                    .push(format!("alloc $i{} #{}", alloc_reg.idx, values.len() * 8));
+            Token::Coll { values } => self.add_coll(&values.iter().collect::<Vec<&Token>>())?,
 
-                // Go through the collection and store each generated real to the heap.
-                let vec_base_reg = self.free_int_reg.pop().unwrap();
-                self.assembly
-                    .push(format!("copy $i{} $i{}", vec_base_reg.idx, alloc_reg.idx));
-
-                let mut value_it = values.iter().peekable();
-                while let Some(v) = value_it.next() {
-                    self.visit_token(v)?;
-                    let mut used_reg = self.used_reg.pop().unwrap();
-                    match used_reg.reg {
-                        VmRegister::R(_) => {}
-                        VmRegister::I(_) => {
-                            // promote an integer to a real for storage in the collection
-                            let real_reg = self.free_real_reg.pop().unwrap();
-                            self.assembly
-                                .push(format!("copy $r{} $i{}", real_reg.idx, used_reg.idx));
-                            self.free_int_reg.push(used_reg);
-                            used_reg = real_reg;
-                        }
-                        // TODO: nested collections
-                        VmRegister::V(_) => {
-                            return Err(Error::new(
-                                "Unable to put collection into a collection".to_string(),
-                            ));
-                        }
-                    };
-                    self.assembly
-                        .push(format!("sw $i{} $r{}", vec_base_reg.idx, used_reg.idx));
-                    self.free_real_reg.push(used_reg);
+            Token::Seq { values } => {
+                self.add_coll(&values.iter().collect::<Vec<&Token>>())?;
+                self.used_reg.last_mut().unwrap().ordered = true;
+            }
 
-                    // If we will be going round the loop again, increment the base index.
-                    if value_it.peek().is_some() {
-                        let inc_reg = self.free_int_reg.pop().unwrap();
-                        self.assembly
-                            .push(format!("load $i{} #{}", inc_reg.idx, size_of::<f64>()));
-                        self.assembly.push(format!(
-                            "add $i{} $i{} $i{}",
-                            vec_base_reg.idx, vec_base_reg.idx, inc_reg.idx
-                        ));
-                        self.free_int_reg.push(inc_reg);
-                    }
+            Token::Dict { entries } => {
+                // Build collections of the keys and values and zip them up.
+                let keys: Vec<&Token> = entries.iter().map(|(k, _)| k).collect();
+                let values: Vec<&Token> = entries.iter().map(|(_, v)| v).collect();
+                self.add_coll(&keys)?;
+                self.add_coll(&values)?;
+                let values_reg = self.used_reg.pop().unwrap();
+                let keys_reg = self.used_reg.pop().unwrap();
+                if keys_reg.get_type() != Type::Coll || values_reg.get_type() != Type::Coll {
+                    return Err(Error::unchecked("dict of collections"));
                 }
-                self.free_int_reg.push(vec_base_reg);
 
-                // And finally load the heap info into a vector register.
-                let vec_reg = self.free_vec_reg.pop().unwrap();
+                let dict_reg = self.pop_free_reg(Type::Dict)?;
                 self.assembly.push(format!(
-                    "load $v{} $i{} #{}",
-                    vec_reg.idx,
-                    alloc_reg.idx,
-                    values.len() * size_of::<f64>()
+                    "dict $d{} $v{} $v{}",
+                    dict_reg.idx, keys_reg.idx, values_reg.idx
                 ));
-                self.free_int_reg.push(alloc_reg);
-                self.used_reg.push(vec_reg);
+                self.push_free_reg(values_reg);
+                self.push_free_reg(keys_reg);
+                self.used_reg.push(dict_reg);
             }
 
             Token::Factor { ref value } => self.visit_token(value)?,
@@ -517,17 +1825,53 @@ impl Visitor for Compiler {
                 ref source,
                 ref token,
             } => {
-                self.assembly.push(format!("; {}", source));
+                // Lambdas may spread an expression over several lines.
+                for line in source.lines() {
+                    self.assembly.push(format!("; {}", line));
+                }
                 log::debug!("writing assembly for '{}'", source);
-                self.visit_token(token)?;
+                let offset = find_statement(&self.source, self.source_offset, source);
+                if let (true, Some(offset)) = (self.debug_info, offset) {
+                    let span = Span::new(&self.source, offset, source.len());
+                    self.assembly
+                        .push(format!(".loc #{} #{}", span.line, span.column));
+                }
+                let result = match **token {
+                    Token::Call { ref name, ref args } => self.add_call(name, args, false),
+                    _ => self.visit_token(token),
+                };
+                if let Some(offset) = offset {
+                    self.source_offset = offset + source.len();
+                    if let Err(e) = result {
+                        return Err(e.with_span(Span::new(&self.source, offset, source.len())));
+                    }
+                }
+                result?;
+            }
+            Token::Invalid { ref source } => {
+                let offset = find_statement(&self.source, self.source_offset, source)
+                    .unwrap_or(self.source_offset);
+                self.source_offset = offset + source.len();
+                return Err(syntax_error(&self.source, &self.source[offset..]));
             }
+
             Token::Program { ref statements } => {
                 self.rodata.push(".data".into());
                 self.assembly.push(".code".into());
-                statements
-                    .iter()
-                    .flatten()
-                    .try_for_each(|expr| self.visit_token(expr))?;
+                let code_start = self.assembly.len();
+                self.spill_slots = Some(0);
+                self.visit_block(statements)?;
+                // The first error fails the program, leaving the rest.
+                if !self.errors.is_empty() {
+                    return Err(self.errors.remove(0));
+                }
+
+                // The heap is empty when the program starts so the slots
+                // begin at address zero.
+                if let Some(slots @ 1..) = self.spill_slots {
+                    self.assembly
+                        .insert(code_start, format!("alloc $i0 #{}", slots * SLOT_SIZE));
+                }
 
                 self.assembly.push("halt\n".into());
             }
@@ -550,12 +1894,15 @@ mod tests {
     use nom::IResult;
 
     use super::*;
+    use crate::asm::Assembler;
+    use crate::vm::VM;
 
     #[test]
     fn test_get_char() {
         let i: char = Register {
             idx: 0,
             reg: VmRegister::I(42),
+            ordered: false,
         }
         .get_char();
         assert_eq!(i, 'i');
@@ -563,6 +1910,7 @@ mod tests {
         let r: char = Register {
             idx: 0,
             reg: VmRegister::R(42.0),
+            ordered: false,
         }
         .get_char();
         assert_eq!(r, 'r');
@@ -570,6 +1918,7 @@ mod tests {
         let v: char = Register {
             idx: 0,
             reg: VmRegister::V(vec![]),
+            ordered: false,
         }
         .get_char();
         assert_eq!(v, 'v');
@@ -577,6 +1926,18 @@ mod tests {
 
     fn generate_test_program(listing: &str) -> IResult<&str, Token> {
         match program(listing) {
+            // Lines that don't parse are kept so the rest can be compiled,
+            // but count as failures here.
+            Ok((rest, Token::Program { statements }))
+                if statements
+                    .iter()
+                    .any(|s| matches!(s, Some(Token::Invalid { .. }))) =>
+            {
+                Err(nom::Err::Error(nom::error::Error::new(
+                    rest,
+                    nom::error::ErrorKind::Verify,
+                )))
+            }
             Ok((rest, tree)) => {
                 assert!(rest.is_empty());
                 Ok((rest, tree))
@@ -611,7 +1972,8 @@ mod tests {
             compiler.used_reg,
             vec![Register {
                 idx: 29,
-                reg: VmRegister::R(0.0)
+                reg: VmRegister::R(0.0),
+                ordered: false,
             }]
         );
     }
@@ -639,7 +2001,8 @@ mod tests {
             compiler.used_reg,
             vec![Register {
                 idx: 29,
-                reg: VmRegister::I(0)
+                reg: VmRegister::I(0),
+                ordered: false,
             }]
         );
     }
@@ -683,7 +2046,8 @@ mod tests {
             compiler.used_reg,
             vec![Register {
                 idx: 29,
-                reg: VmRegister::V(vec![])
+                reg: VmRegister::V(vec![]),
+                ordered: false,
             }]
         );
     }
@@ -711,7 +2075,8 @@ mod tests {
             compiler.used_reg,
             vec![Register {
                 idx: 29,
-                reg: VmRegister::R(0.0)
+                reg: VmRegister::R(0.0),
+                ordered: false,
             }]
         );
     }
@@ -739,7 +2104,8 @@ mod tests {
             compiler.used_reg,
             vec![Register {
                 idx: 29,
-                reg: VmRegister::R(0.0)
+                reg: VmRegister::R(0.0),
+                ordered: false,
             }]
         );
     }
@@ -767,7 +2133,8 @@ mod tests {
             compiler.used_reg,
             vec![Register {
                 idx: 29,
-                reg: VmRegister::R(0.0)
+                reg: VmRegister::R(0.0),
+                ordered: false,
             }]
         );
     }
@@ -797,7 +2164,8 @@ mod tests {
             compiler.used_reg,
             vec![Register {
                 idx: 31,
-                reg: VmRegister::I(0)
+                reg: VmRegister::I(0),
+                ordered: false,
             }]
         );
     }
@@ -825,7 +2193,8 @@ mod tests {
             compiler.used_reg,
             vec![Register {
                 idx: 31,
-                reg: VmRegister::I(0)
+                reg: VmRegister::I(0),
+                ordered: false,
             }]
         );
     }
@@ -853,7 +2222,8 @@ mod tests {
             compiler.used_reg,
             vec![Register {
                 idx: 31,
-                reg: VmRegister::I(0)
+                reg: VmRegister::I(0),
+                ordered: false,
             }]
         );
     }
@@ -881,7 +2251,8 @@ mod tests {
             compiler.used_reg,
             vec![Register {
                 idx: 31,
-                reg: VmRegister::I(0)
+                reg: VmRegister::I(0),
+                ordered: false,
             }]
         );
     }
@@ -909,7 +2280,8 @@ mod tests {
             compiler.used_reg,
             vec![Register {
                 idx: 31,
-                reg: VmRegister::I(0)
+                reg: VmRegister::I(0),
+                ordered: false,
             }]
         );
     }
@@ -937,7 +2309,8 @@ mod tests {
             compiler.used_reg,
             vec![Register {
                 idx: 31,
-                reg: VmRegister::I(0)
+                reg: VmRegister::I(0),
+                ordered: false,
             }]
         );
     }
@@ -958,7 +2331,8 @@ mod tests {
             compiler.used_reg,
             vec![Register {
                 idx: 31,
-                reg: VmRegister::I(0)
+                reg: VmRegister::I(0),
+                ordered: false,
             }]
         );
         assert_eq!(
@@ -996,11 +2370,13 @@ mod tests {
             vec![
                 Register {
                     idx: 31,
-                    reg: VmRegister::R(0.0)
+                    reg: VmRegister::R(0.0),
+                    ordered: false,
                 },
                 Register {
                     idx: 30,
-                    reg: VmRegister::R(0.0)
+                    reg: VmRegister::R(0.0),
+                    ordered: false,
                 }
             ]
         );
@@ -1042,7 +2418,8 @@ mod tests {
             compiler.used_reg,
             vec![Register {
                 idx: 31,
-                reg: VmRegister::V(vec![])
+                reg: VmRegister::V(vec![]),
+                ordered: false,
             }]
         );
     }
@@ -1073,39 +2450,1143 @@ mod tests {
     }
 
     #[test]
-    fn test_function() {
+    fn test_read() {
         let mut compiler = Compiler::new();
-        let (_, test_program) = generate_test_program(
-            "func foobar(i: integer, r: real) {\nr = r + i * 2\ndo(write, r)\n}\n",
-        )
-        .unwrap();
-        //assert!(compiler.visit_token(&test_program).is_ok());
-        println!("{:#?}", compiler.visit_token(&test_program));
+        let (_, test_program) = generate_test_program("a = do(read)\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
         assert_eq!(
             compiler.assembly,
             vec![
                 ".code",
-                "; [start func] foobar",
-                "func_foobar:",
-                "; r = r + i * 2",
-                "copy $r30 $r31",
-                "copy $i30 $i31",
-                "load $i29 #2",
-                "mul $i28 $i30 $i29",
-                "add $r29 $r30 $i28",
-                "; do(write, r)",
-                "copy $r31 $r29",
-                "load $i28 #0",
-                "syscall $i28 $r31",
-                "; [end func] foobar",
+                "; a = do(read)",
+                "load $i31 #3",
+                "syscall $i31 $v31",
                 "halt\n"
             ]
         );
         assert_eq!(compiler.free_int_reg.len(), 32);
-        assert_eq!(compiler.free_real_reg.len(), 32);
+        assert_eq!(compiler.free_vec_reg.len(), 31);
+
+        for (typ, reg) in [("real", "$r31"), ("integer", "$i31"), ("coll", "$v31")] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) =
+                generate_test_program(&format!("a = do(read, {})\n", typ)).unwrap();
+            assert!(compiler.visit_token(&test_program).is_ok());
+            // the syscall register is taken after the destination.
+            let call_reg = if typ == "integer" { "$i30" } else { "$i31" };
+            assert_eq!(
+                compiler.assembly[compiler.assembly.len() - 2],
+                format!("syscall {} {}", call_reg, reg)
+            );
+        }
+
+        for program in ["a = do(read, foo)\n", "a = do(read, real, real)\n"] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) = generate_test_program(program).unwrap();
+            assert!(compiler.visit_token(&test_program).is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn test_dict() {
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program("d = {1: 2.5}\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.assembly,
+            vec![
+                ".code",
+                "; d = {1: 2.5}",
+                "alloc $i31 #8",
+                "copy $i30 $i31",
+                "load $i29 #1",
+                "copy $r31 $i29",
+                "sw $i30 $r31",
+                "load $v31 $i31 #8",
+                "alloc $i31 #8",
+                "copy $i30 $i31",
+                "load $r31 #2.50",
+                "sw $i30 $r31",
+                "load $v30 $i31 #8",
+                "dict $d31 $v31 $v30",
+                "halt\n"
+            ]
+        );
+        assert_eq!(compiler.free_dict_reg.len(), 31);
         assert_eq!(compiler.free_vec_reg.len(), 32);
-        assert_eq!(compiler.used_reg, vec![]);
 
-        // TODO: test shadowing
+        for (program, expected) in [
+            ("k = do(get, {1: 2}, 1)\n", "get $r31 $d31 $i31"),
+            ("k = do(keys, {1: 2})\n", "keys $v31 $d31"),
+            ("k = do(values, {1: 2})\n", "vals $v31 $d31"),
+        ] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) = generate_test_program(program).unwrap();
+            assert!(compiler.visit_token(&test_program).is_ok(), "{}", program);
+            assert_eq!(
+                compiler.assembly[compiler.assembly.len() - 2],
+                expected,
+                "{}",
+                program
+            );
+        }
+
+        for program in [
+            "a = {1: 2} + 1\n",
+            "{1: 2} eq {1: 2}\n",
+            "a = [{1: 2}]\n",
+            "a = do(get, [1], 1)\n",
+            "a = do(get, {1: 2}, [1])\n",
+            "a = do(get, {1: 2})\n",
+            "a = do(keys, 1)\n",
+            "a = do(values, {1: 2}, {1: 2})\n",
+        ] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) = generate_test_program(program).unwrap();
+            assert!(compiler.visit_token(&test_program).is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn test_seq() {
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program("s = |1|\nt = do(coll, s)\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.assembly,
+            vec![
+                ".code",
+                "; s = |1|",
+                "alloc $i31 #8",
+                "copy $i30 $i31",
+                "load $i29 #1",
+                "copy $r31 $i29",
+                "sw $i30 $r31",
+                "load $v31 $i31 #8",
+                "; t = do(coll, s)",
+                "copy $v30 $v31",
+                "halt\n"
+            ]
+        );
+        assert_eq!(compiler.used_reg[0].get_type(), Type::Seq);
+        assert_eq!(compiler.used_reg[1].get_type(), Type::Coll);
+
+        // seqs stay seqs through map, filter, and arithmetic.
+        for program in [
+            "a = do(map, func(x) { return x }, |1|)\n",
+            "a = do(filter, func(x) { return x }, |1|)\n",
+            "a = |1| * 2\n",
+            "a = |1| + |2|\n",
+            "a = do(seq, [1])\n",
+            "a = do(read, seq)\n",
+        ] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) = generate_test_program(program).unwrap();
+            assert!(compiler.visit_token(&test_program).is_ok(), "{}", program);
+            assert_eq!(compiler.used_reg[0].get_type(), Type::Seq, "{}", program);
+        }
+
+        for program in [
+            "a = |1| + [1]\n",
+            "a = |1|\na = [1]\n",
+            "a = [|1|]\n",
+            "a = do(seq, 1)\n",
+            "a = do(coll, |1|, |2|)\n",
+            "func f(a: seq) -> real {\nreturn 1\n}\nb = f([1])\n",
+            "if |1|\na = 1\ndone\n",
+        ] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) = generate_test_program(program).unwrap();
+            assert!(compiler.visit_token(&test_program).is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn test_collection_builtins() {
+        for (program, expected, typ) in [
+            ("a = do(sort, [1])\n", "sort $v30 $v31 #0", Type::Seq),
+            ("a = do(sort, [1], lte)\n", "sort $v30 $v31 #0", Type::Seq),
+            ("a = do(sort, [1], gt)\n", "sort $v30 $v31 #1", Type::Seq),
+            ("a = do(first, |1|, 1)\n", "first $v30 $v31 $i31", Type::Seq),
+            (
+                "a = do(first, [1], 1.5)\n",
+                "first $v30 $v31 $r31",
+                Type::Coll,
+            ),
+            (
+                "a = do(flatten, [1], [2])\n",
+                "cat $v29 $v31 $v30",
+                Type::Coll,
+            ),
+            (
+                "a = do(zip, |1|, |2|)\n",
+                "zip $n31 $v31 $v30",
+                Type::Nested,
+            ),
+            ("a = do(length, [1])\n", "len $i31 $v31", Type::Integer),
+        ] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) = generate_test_program(program).unwrap();
+            assert!(compiler.visit_token(&test_program).is_ok(), "{}", program);
+            assert_eq!(
+                compiler.assembly[compiler.assembly.len() - 2],
+                expected,
+                "{}",
+                program
+            );
+            assert_eq!(compiler.used_reg[0].get_type(), typ, "{}", program);
+        }
+
+        // flattening many collections concatenates them in turn.
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program("a = do(flatten, [1], [2], [3])\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler
+                .assembly
+                .iter()
+                .filter(|line| line.starts_with("cat"))
+                .collect::<Vec<_>>(),
+            vec!["cat $v29 $v31 $v30", "cat $v30 $v29 $v31"]
+        );
+        assert_eq!(compiler.free_vec_reg.len(), 31);
+
+        // nested collections are flattened by joining their rows.
+        let mut compiler = Compiler::new();
+        let assembly = compiler
+            .compile("a = do(flatten, [[1, 2], [3]])\nb = do(flatten, [0], [[1], [2]])\n")
+            .unwrap();
+        assert_eq!(assembly.matches("flat $v").count(), 2);
+        let bytecode = Assembler::new().assemble(&assembly).unwrap();
+        let mut vm = VM::new();
+        vm.set_bytecode(&bytecode).unwrap();
+        vm.run().unwrap();
+        for (name, expected) in [("a", vec![1.0, 2.0, 3.0]), ("b", vec![0.0, 1.0, 2.0])] {
+            let reg = &compiler.used_reg[compiler.variables[name]];
+            assert_eq!(reg.get_type(), Type::Coll);
+            assert_eq!(vm.vregisters[reg.idx as usize], expected);
+        }
+
+        for program in [
+            "a = do(sort)\n",
+            "a = do(sort, 1)\n",
+            "a = do(sort, [1], eq)\n",
+            "a = do(first, [1])\n",
+            "a = do(first, [1], [1])\n",
+            "a = do(flatten)\n",
+            "a = do(flatten, [1], |1|)\n",
+            "a = do(flatten, [[1]], |1|)\n",
+            "a = do(zip, [1])\n",
+            "a = do(zip, [1], 1)\n",
+            "a = do(length, 1)\n",
+            "a = do(length, [1], [2])\n",
+        ] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) = generate_test_program(program).unwrap();
+            assert!(compiler.visit_token(&test_program).is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn test_print() {
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program("do(print, \"a {1} b\")\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.rodata,
+            vec![".data", "str_0: .str 'a '", "str_1: .str ' b'"]
+        );
+        assert_eq!(
+            compiler.assembly,
+            vec![
+                ".code",
+                "; do(print, \"a {1} b\")",
+                "load $i31 #5",
+                "syscall $i31 @str_0",
+                "load $i31 #1",
+                "load $i30 #4",
+                "syscall $i30 $i31",
+                "load $i31 #2",
+                "syscall $i31 @str_1",
+                "halt\n"
+            ]
+        );
+        assert_eq!(compiler.free_int_reg.len(), 32);
+
+        // a trailing value ends the line itself.
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program("do(print, \"{1.5}\")\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(compiler.rodata, vec![".data"]);
+        assert_eq!(
+            compiler.assembly[compiler.assembly.len() - 2],
+            "syscall $i31 $r31"
+        );
+
+        // an empty string still prints a line.
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program("do(print, \"\")\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(compiler.rodata, vec![".data", "str_0: .str ''"]);
+
+        // quotes and backslashes are escaped for the assembler.
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program("do(print, \"it's \\o/\")\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.rodata,
+            vec![".data", "str_0: .str 'it\\'s \\\\o/'"]
+        );
+
+        for program in [
+            "do(print, 1)\n",
+            "do(print, \"a\", \"b\")\n",
+            "do(print, \"{foo}\")\n",
+            "do(write, \"a\")\n",
+        ] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) = generate_test_program(program).unwrap();
+            assert!(compiler.visit_token(&test_program).is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn test_nested_coll() {
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program("m = [[1], [2]]\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.assembly,
+            vec![
+                ".code",
+                "; m = [[1], [2]]",
+                "alloc $i31 #8",
+                "copy $i30 $i31",
+                "load $i29 #1",
+                "copy $r31 $i29",
+                "sw $i30 $r31",
+                "load $v31 $i31 #8",
+                "push $v31",
+                "alloc $i31 #8",
+                "copy $i30 $i31",
+                "load $i29 #2",
+                "copy $r31 $i29",
+                "sw $i30 $r31",
+                "load $v31 $i31 #8",
+                "push $v31",
+                "nest $n31 #2",
+                "halt\n"
+            ]
+        );
+        assert_eq!(compiler.used_reg[0].get_type(), Type::Nested);
+        assert_eq!(compiler.free_int_reg.len(), 32);
+        assert_eq!(compiler.free_vec_reg.len(), 32);
+        assert_eq!(compiler.free_nested_reg.len(), 31);
+
+        for (program, expected, typ) in [
+            ("a = [[1]] * 2\n", "mul $n30 $n31 $i31", Type::Nested),
+            ("a = [1] + [[1]]\n", "add $n30 $v31 $n31", Type::Nested),
+            ("a = do(at, [[1]], 0)\n", "at $v31 $n31 $i31", Type::Coll),
+            ("a = do(at, |1|, 0.5)\n", "at $r30 $v31 $r31", Type::Real),
+            ("a = do(length, [[1]])\n", "len $i31 $n31", Type::Integer),
+        ] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) = generate_test_program(program).unwrap();
+            assert!(compiler.visit_token(&test_program).is_ok(), "{}", program);
+            assert_eq!(
+                compiler.assembly[compiler.assembly.len() - 2],
+                expected,
+                "{}",
+                program
+            );
+            assert_eq!(compiler.used_reg[0].get_type(), typ, "{}", program);
+        }
+
+        for program in [
+            "a = [[1], 2]\n",
+            "a = [1, [2]]\n",
+            "a = [[[1]]]\n",
+            "a = [|1|]\n",
+            "a = [[1], |2|]\n",
+            "a = [[1]] + |1|\n",
+            "[[1]] eq [[1]]\n",
+            "a = do(map, func(x) { return x }, [[1]])\n",
+            "a = do(at, 1, 0)\n",
+            "a = do(at, [1], [0])\n",
+            "a = {[1]: 2}\n",
+        ] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) = generate_test_program(program).unwrap();
+            assert!(compiler.visit_token(&test_program).is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn test_compile_error_span() {
+        for (program, line, column, len) in [
+            // syntax errors mark where parsing stopped.
+            ("a = 1\nb = a +* 2\n", 2, 7, 1),
+            ("x = 1 +\n", 1, 7, 1),
+            ("func f(a: real) {\n    b = a $ 2\n}\n", 2, 11, 1),
+            ("a = 1\nif a $\n  b = 1\nelse\n  b = 2\ndone\n", 2, 6, 1),
+            // other errors mark the whole statement.
+            ("a = 1\n  b = c + 1\n", 2, 3, 9),
+            ("b = 1\nb = 1\nb = 1.5\n", 3, 1, 7),
+            // and errors in a function the line declaring it.
+            (
+                "func f() {\n  do(write, 1)\n}\nfunc f() {\n  do(write, 2)\n}\n",
+                4,
+                1,
+                10,
+            ),
+        ] {
+            let mut compiler = Compiler::new();
+            let errors = compiler.compile(program).unwrap_err();
+            assert_eq!(errors.len(), 1);
+            let span = errors[0].span().unwrap();
+            assert_eq!((span.line, span.column, span.len), (line, column, len));
+        }
+
+        // trailing blank lines are fine, as is a last line without a newline
+        // or no lines at all.
+        assert!(Compiler::new().compile("a = 1\n\n").is_ok());
+        assert!(Compiler::new().compile("a = 1").is_ok());
+        assert!(Compiler::new().compile("").unwrap().contains(".code"));
+    }
+
+    #[test]
+    fn test_compile_errors() {
+        let listing = concat!(
+            "a = 1\n",
+            "b = c\n",
+            "a = 1.5\n",
+            "func f(x: real) {\n",
+            "  y = x $ 2\n",
+            "}\n",
+            "while a\n",
+            "  g = 1 2\n",
+            "done\n",
+            "b = c\n",
+            "d = b + 1\n",
+        );
+        let errors = Compiler::new().compile(listing).unwrap_err();
+        assert_eq!(
+            errors
+                .iter()
+                .map(|e| {
+                    let span = e.span().unwrap();
+                    (span.line, span.column)
+                })
+                .collect::<Vec<_>>(),
+            vec![(2, 1), (3, 1), (5, 9), (8, 9), (10, 1)]
+        );
+        assert_eq!(
+            errors[0].to_string().lines().next(),
+            Some("2:1: Unknown variable 'c'")
+        );
+        // 'b' couldn't be assigned so isn't reported as unknown.
+        assert_eq!(errors.len(), 5);
+
+        // registers used by statements with errors are given back.  Code
+        // isn't written for ill-typed programs, so write it here.
+        let mut compiler = Compiler::new();
+        compiler.source = listing.to_string();
+        let (_, tree) = program(listing).unwrap();
+        assert!(compiler.visit_token(&tree).is_err());
+        assert_eq!(compiler.free_int_reg.len(), 31);
+        assert_eq!(compiler.free_real_reg.len(), 32);
+    }
+
+    #[test]
+    fn test_type_errors() {
+        // caught before any code is written, rather than when run.
+        let errors = Compiler::new()
+            .compile("a = [1, 2]\nb = 2 - a\n")
+            .unwrap_err();
+        assert_eq!(
+            errors[0].to_string().lines().next(),
+            Some("2:1: Cannot subtract coll from integer")
+        );
+    }
+
+    #[test]
+    fn test_constant_folding() {
+        let mut compiler = Compiler::new();
+        let assembly = compiler
+            .compile("a = (3.4 + 1.0) - 2.8\nb = [1, 2] * 2\nc = a * 1\n")
+            .unwrap();
+        assert_eq!(
+            assembly
+                .lines()
+                .filter(|l| !l.starts_with(';'))
+                .collect::<Vec<_>>(),
+            vec![
+                ".data",
+                ".code",
+                "load $r31 #1.6000000000000005",
+                "alloc $i31 #16",
+                "copy $i30 $i31",
+                "load $r30 #2.00",
+                "sw $i30 $r30",
+                "load $i29 #8",
+                "add $i30 $i30 $i29",
+                "load $r30 #4.00",
+                "sw $i30 $r30",
+                "load $v31 $i31 #16",
+                "copy $r30 $r31",
+                "halt",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_spill() {
+        let mut listing: String = (0..26).map(|i| format!("a{} = {}\n", i, i)).collect();
+        listing.push_str("a25 = a25 + a0\n");
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program(&listing).unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(compiler.assembly[1], "alloc $i0 #16");
+        assert_eq!(
+            compiler.assembly[compiler.assembly.len() - 16..],
+            vec![
+                "; a24 = 24",
+                "load $i7 #24",
+                "load $i6 #0",
+                "sw $i6 $i7",
+                "; a25 = 25",
+                "load $i7 #25",
+                "load $i6 #8",
+                "sw $i6 $i7",
+                "; a25 = a25 + a0",
+                "load $i6 #8",
+                "lw $i7 $i6",
+                "copy $i6 $i31",
+                "add $i5 $i7 $i6",
+                "load $i6 #8",
+                "sw $i6 $i5",
+                "halt\n"
+            ]
+        );
+        assert_eq!(compiler.spilled.len(), 2);
+        assert_eq!(compiler.free_int_reg.len(), 8);
+
+        // values part way through an expression aren't spilled, so deep
+        // nesting runs out of the registers left by the variables.
+        let nested = |depth: usize| {
+            let mut listing: String = (0..24).map(|i| format!("a{} = {}\n", i, i)).collect();
+            listing.push_str(&format!(
+                "b = {}1{}\n",
+                "(a0 + ".repeat(depth),
+                ")".repeat(depth)
+            ));
+            listing
+        };
+        assert!(Compiler::new().compile(&nested(6)).is_ok());
+        let errors = Compiler::new().compile(&nested(8)).unwrap_err();
+        assert_eq!(
+            errors[0].to_string().lines().next(),
+            Some("25:1: Ran out of Integer registers")
+        );
+
+        // spilled locals are saved across calls.
+        let mut listing = String::from("func f(n: integer) -> integer {\n");
+        for i in 0..26 {
+            listing.push_str(&format!("    x{} = n\n", i));
+        }
+        listing.push_str("    return f(x25)\n}\n");
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program(&listing).unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        let call = compiler
+            .assembly
+            .iter()
+            .position(|line| line == "call @func_f")
+            .unwrap();
+        assert_eq!(
+            compiler.assembly[call - 12..call + 11],
+            vec![
+                "load $i6 #0",
+                "lw $i7 $i6",
+                "push $i7",
+                "load $i6 #8",
+                "lw $i7 $i6",
+                "push $i7",
+                "load $i6 #16",
+                "lw $i7 $i6",
+                "push $i7",
+                "load $i6 #16",
+                "lw $i7 $i6",
+                "push $i7",
+                "call @func_f",
+                "pop $i7",
+                "pop $i6",
+                "load $i5 #16",
+                "sw $i5 $i6",
+                "pop $i6",
+                "load $i5 #8",
+                "sw $i5 $i6",
+                "pop $i6",
+                "load $i5 #0",
+                "sw $i5 $i6",
+            ]
+        );
+
+        // temporaries are never spilled.
+        let mut compiler = Compiler::new();
+        compiler.free_int_reg.truncate(2);
+        let (_, test_program) = generate_test_program("a = 1 + (1 + 1)\n").unwrap();
+        assert_eq!(
+            compiler.visit_token(&test_program).unwrap_err().to_string(),
+            "Ran out of Integer registers"
+        );
+    }
+
+    #[test]
+    fn test_map() {
+        let mut compiler = Compiler::new();
+        let (_, test_program) =
+            generate_test_program("a = do(map, func(x) { return x * x }, [1])\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.assembly,
+            vec![
+                ".code",
+                "; a = do(map, func(x) { return x * x }, [1])",
+                "; [start func] lambda_0",
+                "jmp @func_lambda_0_end",
+                "func_lambda_0:",
+                "pop $r31",
+                "; return x * x",
+                "copy $r30 $r31",
+                "copy $r29 $r31",
+                "mul $r28 $r30 $r29",
+                "push $r28",
+                "ret",
+                "func_lambda_0_end:",
+                "; [end func] lambda_0",
+                "alloc $i31 #8",
+                "copy $i30 $i31",
+                "load $i29 #1",
+                "copy $r31 $i29",
+                "sw $i30 $r31",
+                "load $v31 $i31 #8",
+                "map $v30 $v31 @func_lambda_0",
+                "halt\n"
+            ]
+        );
+        assert_eq!(compiler.free_vec_reg.len(), 31);
+
+        // named functions.
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program(
+            "func half(a: real) -> real {\nreturn a / 2\n}\nb = do(map, half, [1])\n",
+        )
+        .unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert!(compiler
+            .assembly
+            .contains(&"map $v30 $v31 @func_half".to_string()));
+
+        for program in [
+            // not a function.
+            "a = do(map, 1, [1])\n",
+            // unknown function.
+            "a = do(map, half, [1])\n",
+            // not a collection.
+            "a = do(map, func(x) { return x }, 1)\n",
+            // lambdas must take a single value.
+            "a = do(map, func(x, y) { return x }, [1])\n",
+            // wrong number of arguments.
+            "a = do(map, [1])\n",
+            // functions must take and return a single value.
+            "func f(a: real, b: real) -> real {\nreturn a\n}\nc = do(map, f, [1])\n",
+            "func f(a: coll) -> real {\nreturn 1\n}\nc = do(map, f, [1])\n",
+            "func f(a: real) {\ndo(write, a)\n}\nc = do(map, f, [1])\n",
+            "func f(a: real) -> coll {\nreturn [a]\n}\nc = do(map, f, [1])\n",
+        ] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) = generate_test_program(program).unwrap();
+            assert!(compiler.visit_token(&test_program).is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn test_filter() {
+        let mut compiler = Compiler::new();
+        let (_, test_program) =
+            generate_test_program("a = do(filter, func(x) { return x > 2 }, [1])\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.assembly[compiler.assembly.len() - 3..],
+            vec![
+                "map $v30 $v31 @func_lambda_0",
+                "mask $v29 $v31 $v30",
+                "halt\n"
+            ]
+        );
+        assert_eq!(compiler.free_vec_reg.len(), 31);
+        assert_eq!(compiler.used_reg.len(), 1);
+
+        for program in [
+            "a = do(filter, func(x) { return x > 2 }, 1)\n",
+            "a = do(filter, [1])\n",
+            "a = do(filter, 2, [1])\n",
+        ] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) = generate_test_program(program).unwrap();
+            assert!(compiler.visit_token(&test_program).is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn test_fold() {
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program("a = do(fold, sum, [1], 2)\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.assembly[compiler.assembly.len() - 4..],
+            vec!["load $i31 #2", "copy $r31 $i31", "sum $r31 $v31", "halt\n"]
+        );
+        assert_eq!(compiler.free_int_reg.len(), 32);
+        assert_eq!(compiler.free_real_reg.len(), 31);
+        assert_eq!(compiler.free_vec_reg.len(), 32);
+
+        for (reducer, opcode) in [("product", "prod"), ("min", "min"), ("max", "max")] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) =
+                generate_test_program(&format!("a = do(fold, {}, [1], 2)\n", reducer)).unwrap();
+            assert!(compiler.visit_token(&test_program).is_ok());
+            assert_eq!(
+                compiler.assembly[compiler.assembly.len() - 2],
+                format!("{} $r31 $v31", opcode)
+            );
+        }
+
+        // anonymous functions.
+        let mut compiler = Compiler::new();
+        let (_, test_program) =
+            generate_test_program("a = do(fold, func(acc, x) { return acc * x }, [1], 2.5)\n")
+                .unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.assembly[compiler.assembly.len() - 4..],
+            vec![
+                "load $r31 #2.50",
+                "copy $r30 $r31",
+                "fold $r30 $v31 @func_lambda_0",
+                "halt\n"
+            ]
+        );
+
+        // functions named after reducers replace them.
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program(
+            "func sum(a: real, b: integer) -> real {\nreturn a - b\n}\nc = do(fold, sum, [1], 0)\n",
+        )
+        .unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.assembly[compiler.assembly.len() - 2],
+            "fold $r31 $v31 @func_sum"
+        );
+
+        for program in [
+            // missing initial value.
+            "a = do(fold, sum, [1])\n",
+            // not a collection.
+            "a = do(fold, sum, 1, 0)\n",
+            // collections aren't initial values.
+            "a = do(fold, sum, [1], [0])\n",
+            // functions must take two values.
+            "a = do(fold, func(x) { return x }, [1], 0)\n",
+            "func f(a: real) -> real {\nreturn a\n}\nc = do(fold, f, [1], 0)\n",
+            // unknown function.
+            "a = do(fold, total, [1], 0)\n",
+        ] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) = generate_test_program(program).unwrap();
+            assert!(compiler.visit_token(&test_program).is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn test_if() {
+        let mut compiler = Compiler::new();
+        let (_, test_program) =
+            generate_test_program("a = 1\nif a\n  a = 2\nelse\n  a = 3\ndone\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.assembly,
+            vec![
+                ".code",
+                "; a = 1",
+                "load $i31 #1",
+                "; [start if] if_0",
+                "copy $i30 $i31",
+                "jz @if_0_1 $i30",
+                "; a = 2",
+                "load $i30 #2",
+                "copy $i31 $i30",
+                "jmp @if_0_end",
+                "if_0_1:",
+                "; a = 3",
+                "load $i30 #3",
+                "copy $i31 $i30",
+                "if_0_end:",
+                "; [end if] if_0",
+                "halt\n"
+            ]
+        );
+        assert_eq!(compiler.free_int_reg.len(), 31);
+        assert_eq!(
+            compiler.variables,
+            [("a".to_string(), 0)].iter().cloned().collect()
+        );
+
+        // comparisons jump when they're false.
+        let mut compiler = Compiler::new();
+        let (_, test_program) =
+            generate_test_program("a = 1\nif a lt 2.5\n  a = 2\ndone\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.assembly[3..7],
+            vec![
+                "; [start if] if_0",
+                "copy $i30 $i31",
+                "load $r31 #2.50",
+                "jgte @if_0_1 $i30 $r31",
+            ]
+        );
+
+        // collections can't be conditions.
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program("if [1]\n  a = 2\ndone\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_err());
+    }
+
+    #[test]
+    fn test_while() {
+        let mut compiler = Compiler::new();
+        let (_, test_program) =
+            generate_test_program("a = 3\nwhile a\n  a = a - 1\n  b = a\ndone\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.assembly,
+            vec![
+                ".code",
+                "; a = 3",
+                "load $i31 #3",
+                "; [start while] while_0",
+                "while_0_start:",
+                "copy $i30 $i31",
+                "jz @while_0_end $i30",
+                "; a = a - 1",
+                "copy $i30 $i31",
+                "load $i29 #1",
+                "sub $i28 $i30 $i29",
+                "copy $i31 $i28",
+                "; b = a",
+                "copy $i28 $i31",
+                "copy $i27 $i28",
+                "jmp @while_0_start",
+                "while_0_end:",
+                "; [end while] while_0",
+                "halt\n"
+            ]
+        );
+        // variables keep their registers across iterations, and aren't given
+        // those used earlier in the loop.
+        assert_eq!(
+            compiler.used_reg,
+            vec![
+                Register {
+                    idx: 31,
+                    reg: VmRegister::I(0),
+                    ordered: false,
+                },
+                Register {
+                    idx: 27,
+                    reg: VmRegister::I(0),
+                    ordered: false,
+                }
+            ]
+        );
+        assert_eq!(
+            compiler.variables,
+            [("a".to_string(), 0), ("b".to_string(), 1)]
+                .iter()
+                .cloned()
+                .collect()
+        );
+    }
+
+    #[test]
+    fn test_while_variables_survive_condition() {
+        // 'b' would otherwise be given the register the condition is
+        // evaluated in, and be overwritten when the loop ends.
+        let listing = concat!(
+            "a = 3\n",
+            "while a\n",
+            "  c = a * 2\n",
+            "  b = a\n",
+            "  a = a - 1\n",
+            "done\n",
+        );
+        // -O 2 drops values that are never read.
+        for level in 0..=1 {
+            let mut compiler = Compiler::new();
+            compiler.set_opt_level(level);
+            let assembly = compiler.compile(listing).unwrap();
+            let bytecode = Assembler::new().assemble(&assembly).unwrap();
+            let mut vm = VM::new();
+            vm.set_bytecode(&bytecode).unwrap();
+            vm.run().unwrap();
+            for (name, expected) in [("b", 1), ("c", 2)] {
+                let reg = &compiler.used_reg[compiler.variables[name]];
+                assert_eq!(vm.iregisters[reg.idx as usize], expected, "-O {}", level);
+            }
+        }
+    }
+
+    #[test]
+    fn test_function() {
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program(
+            "func foobar(i: integer, r: real) {\nr = r + i * 2\ndo(write, r)\n}\n",
+        )
+        .unwrap();
+        //assert!(compiler.visit_token(&test_program).is_ok());
+        println!("{:#?}", compiler.visit_token(&test_program));
+        assert_eq!(
+            compiler.assembly,
+            vec![
+                ".code",
+                "; [start func] foobar",
+                "jmp @func_foobar_end",
+                "func_foobar:",
+                "pop $r31",
+                "pop $i31",
+                "; r = r + i * 2",
+                "copy $r30 $r31",
+                "copy $i30 $i31",
+                "load $i29 #2",
+                "mul $i28 $i30 $i29",
+                "add $r29 $r30 $i28",
+                "copy $r31 $r29",
+                "; do(write, r)",
+                "copy $r29 $r31",
+                "load $i28 #0",
+                "syscall $i28 $r29",
+                "ret",
+                "func_foobar_end:",
+                "; [end func] foobar",
+                "halt\n"
+            ]
+        );
+        assert_eq!(compiler.free_int_reg.len(), 32);
+        assert_eq!(compiler.free_real_reg.len(), 32);
+        assert_eq!(compiler.free_vec_reg.len(), 32);
+        assert_eq!(compiler.used_reg, vec![]);
+
+        // arguments may shadow outer variables, which survive the function.
+        let mut compiler = Compiler::new();
+        let (_, test_program) =
+            generate_test_program("r = 1.5\nfunc foobar(r: real) {\ndo(write, r)\n}\nq = r\n")
+                .unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.variables,
+            [("r".to_string(), 0), ("q".to_string(), 1)]
+                .iter()
+                .cloned()
+                .collect()
+        );
+
+        // but outer variables can't be assigned.
+        let mut compiler = Compiler::new();
+        let (_, test_program) =
+            generate_test_program("r = 1.5\nfunc foobar(q: real) {\nr = q\n}\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_err());
+    }
+
+    #[test]
+    fn test_call() {
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program(
+            "func foobar(c: coll, r: real) {\ndo(write, r)\n}\nfoobar([1], 2)\n",
+        )
+        .unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.assembly[13..],
+            vec![
+                "; foobar([1], 2)",
+                "alloc $i31 #8",
+                "copy $i30 $i31",
+                "load $i29 #1",
+                "copy $r31 $i29",
+                "sw $i30 $r31",
+                "load $v31 $i31 #8",
+                "push $v31",
+                "load $i31 #2",
+                "push $i31",
+                "call @func_foobar",
+                "halt\n"
+            ]
+        );
+        assert_eq!(compiler.free_int_reg.len(), 32);
+        assert_eq!(compiler.free_real_reg.len(), 32);
+        assert_eq!(compiler.free_vec_reg.len(), 32);
+
+        // unknown functions.
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program("foobar(1)\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_err());
+
+        // wrong number of arguments.
+        let mut compiler = Compiler::new();
+        let (_, test_program) =
+            generate_test_program("func foobar(r: real) {\ndo(write, r)\n}\nfoobar(1, 2)\n")
+                .unwrap();
+        assert!(compiler.visit_token(&test_program).is_err());
+
+        // collections can't be passed as reals.
+        let mut compiler = Compiler::new();
+        let (_, test_program) =
+            generate_test_program("func foobar(r: real) {\ndo(write, r)\n}\nfoobar([1])\n")
+                .unwrap();
+        assert!(compiler.visit_token(&test_program).is_err());
+    }
+
+    #[test]
+    fn test_return() {
+        let mut compiler = Compiler::new();
+        let (_, test_program) =
+            generate_test_program("func sq(a: integer) -> integer {\nreturn a * a\n}\nb = sq(3)\n")
+                .unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.assembly[2..],
+            vec![
+                "jmp @func_sq_end",
+                "func_sq:",
+                "pop $i31",
+                "; return a * a",
+                "copy $i30 $i31",
+                "copy $i29 $i31",
+                "mul $i28 $i30 $i29",
+                "push $i28",
+                "ret",
+                "func_sq_end:",
+                "; [end func] sq",
+                "; b = sq(3)",
+                "load $i31 #3",
+                "push $i31",
+                "call @func_sq",
+                "pop $i31",
+                "halt\n"
+            ]
+        );
+        assert_eq!(compiler.free_int_reg.len(), 31);
+
+        // integers are promoted to reals.
+        let mut compiler = Compiler::new();
+        let (_, test_program) =
+            generate_test_program("func half() -> real {\nreturn 1\n}\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+
+        // the result of a statement call is discarded.
+        let mut compiler = Compiler::new();
+        let (_, test_program) =
+            generate_test_program("func one() -> integer {\nreturn 1\n}\none()\n").unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+        assert_eq!(
+            compiler.assembly[compiler.assembly.len() - 3..],
+            vec!["call @func_one", "pop $i31", "halt\n"]
+        );
+        assert_eq!(compiler.free_int_reg.len(), 32);
+
+        let mut compiler = Compiler::new();
+        let (_, test_program) = generate_test_program(
+            "func sign(a: integer) -> integer {\nif a lt 0\nreturn -1\nelse\nreturn 1\ndone\n}\n",
+        )
+        .unwrap();
+        assert!(compiler.visit_token(&test_program).is_ok());
+
+        // wrong return types.
+        for program in [
+            "func f() -> integer {\nreturn 1.5\n}\n",
+            "func f() -> coll {\nreturn 1\n}\n",
+            "func f() -> integer {\nreturn\n}\n",
+            "func f() {\nreturn 1\n}\n",
+            "func f() {\nreturn\n}\na = f()\n",
+            "return 1\n",
+        ] {
+            let mut compiler = Compiler::new();
+            let (_, test_program) = generate_test_program(program).unwrap();
+            assert!(compiler.visit_token(&test_program).is_err(), "{}", program);
+        }
+    }
+
+    #[test]
+    fn test_debug_info() {
+        let source = "a = 1\nfunc f(x: real) -> real {\n    y = x * 2\n    return y\n}\nb = f(a)\n";
+        let mut compiler = Compiler::new();
+        compiler.set_debug_info(true);
+        let assembly = compiler.compile(source).unwrap();
+        let debug_info: Vec<&str> = assembly
+            .lines()
+            .filter(|line| line.starts_with(".loc") || line.starts_with(".var"))
+            .collect();
+        assert_eq!(
+            debug_info,
+            vec![
+                ".loc #1 #1",
+                ".var 'a' $i31",
+                ".loc #3 #5",
+                ".loc #4 #5",
+                ".loc #6 #1",
+                ".var 'b' $r31",
+            ]
+        );
+
+        let assembly = Compiler::new().compile(source).unwrap();
+        assert!(!assembly.contains(".loc"));
+    }
+
+    #[test]
+    fn test_debug_info_conditions() {
+        let source =
+            "a = 1\nif a > 1\n  a = 2\nelif a < 0\n  a = 3\ndone\nwhile a\n  a = a - 1\ndone\n";
+        let mut compiler = Compiler::new();
+        compiler.set_debug_info(true);
+        let assembly = compiler.compile(source).unwrap();
+        let locs: Vec<&str> = assembly
+            .lines()
+            .filter(|line| line.starts_with(".loc"))
+            .collect();
+        assert_eq!(
+            locs,
+            vec![
+                ".loc #1 #1",
+                ".loc #2 #1",
+                ".loc #3 #3",
+                ".loc #4 #1",
+                ".loc #5 #3",
+                ".loc #7 #1",
+                ".loc #8 #3",
+            ]
+        );
+
+        // errors in conditions are reported at the condition.
+        let source = "c = [1, 2]\nx = 1\n\nif do(at, c, 5) > 1\n  x = 2\ndone\n";
+        let mut compiler = Compiler::new();
+        compiler.set_debug_info(true);
+        let assembly = compiler.compile(source).unwrap();
+        let bytecode = Assembler::new().assemble(&assembly).unwrap();
+        let mut vm = VM::new();
+        vm.set_bytecode(&bytecode).unwrap();
+        assert_eq!(vm.run().unwrap_err().location(), Some((4, 1)));
     }
 }
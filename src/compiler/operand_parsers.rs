@@ -2,12 +2,13 @@ use crate::compiler::expression_parsers::*;
 use crate::compiler::tokens::Token;
 
 use nom::{
-    bytes::complete::tag,
-    character::complete::{alpha1, multispace0},
-    combinator::map_res,
-    multi::separated_list0,
+    branch::alt,
+    bytes::complete::{is_not, tag},
+    character::complete::{alpha1, alphanumeric1, multispace0},
+    combinator::{map, map_res, recognize, verify},
+    multi::{many0, separated_list0},
     number::complete::double,
-    sequence::delimited,
+    sequence::{delimited, pair, preceded, separated_pair, terminated},
     IResult,
 };
 
@@ -23,12 +24,28 @@ pub fn num(i: &str) -> IResult<&str, Token> {
     })(i)
 }
 
+// Words that form part of the language and can't be used as identifiers.
+const KEYWORDS: [&str; 8] = [
+    "do", "func", "return", "if", "elif", "else", "while", "done",
+];
+
+// A letter followed by any number of letters, digits and underscores.
+pub fn identifier(i: &str) -> IResult<&str, &str> {
+    verify(
+        recognize(pair(alpha1, many0(alt((alphanumeric1, tag("_")))))),
+        |name: &str| !KEYWORDS.contains(&name),
+    )(i)
+}
+
 pub fn ident(i: &str) -> IResult<&str, Token> {
-    map_res(alpha1, |name| -> Result<Token, nom::error::Error<&str>> {
-        Ok(Token::Identifier {
-            name: String::from(name),
-        })
-    })(i)
+    map_res(
+        identifier,
+        |name| -> Result<Token, nom::error::Error<&str>> {
+            Ok(Token::Identifier {
+                name: String::from(name),
+            })
+        },
+    )(i)
 }
 
 pub fn coll(i: &str) -> IResult<&str, Token> {
@@ -42,6 +59,59 @@ pub fn coll(i: &str) -> IResult<&str, Token> {
     )(i)
 }
 
+// A seq literal, such as `|1, 2, 3|`.
+pub fn seq(i: &str) -> IResult<&str, Token> {
+    map_res(
+        delimited(
+            tag("|"),
+            separated_list0(delimited(multispace0, tag(","), multispace0), rvalue),
+            tag("|"),
+        ),
+        |values| -> Result<Token, nom::error::Error<&str>> { Ok(Token::Seq { values }) },
+    )(i)
+}
+
+// A string literal, such as `"x is {x}"`, with values to interpolate between
+// braces.
+pub fn string(i: &str) -> IResult<&str, Token> {
+    map_res(
+        delimited(
+            tag("\""),
+            many0(alt((
+                map(is_not("\"{\n"), |value: &str| Token::Text {
+                    value: String::from(value),
+                }),
+                delimited(
+                    terminated(tag("{"), multispace0),
+                    rvalue,
+                    preceded(multispace0, tag("}")),
+                ),
+            ))),
+            tag("\""),
+        ),
+        |parts| -> Result<Token, nom::error::Error<&str>> { Ok(Token::Str { parts }) },
+    )(i)
+}
+
+// A dict literal, such as `{1: 2.5, 3: 4}`.
+pub fn dict(i: &str) -> IResult<&str, Token> {
+    map_res(
+        delimited(
+            terminated(tag("{"), multispace0),
+            separated_list0(
+                delimited(multispace0, tag(","), multispace0),
+                separated_pair(
+                    rvalue,
+                    delimited(multispace0, tag(":"), multispace0),
+                    rvalue,
+                ),
+            ),
+            preceded(multispace0, tag("}")),
+        ),
+        |entries| -> Result<Token, nom::error::Error<&str>> { Ok(Token::Dict { entries }) },
+    )(i)
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -81,6 +151,30 @@ mod tests {
                 name: "foo".to_string()
             }
         );
+
+        let result = ident("done");
+        assert!(result.is_err());
+
+        assert_eq!(
+            ident("gt2"),
+            Ok((
+                "",
+                Token::Identifier {
+                    name: "gt2".to_string()
+                }
+            ))
+        );
+        assert_eq!(
+            ident("sum_fn "),
+            Ok((
+                " ",
+                Token::Identifier {
+                    name: "sum_fn".to_string()
+                }
+            ))
+        );
+        assert!(ident("2gt").is_err());
+        assert!(ident("_foo").is_err());
     }
 
     #[test]
@@ -136,4 +230,121 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_seq() {
+        let result = seq("||");
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().1, Token::Seq { values: vec![] });
+
+        let result = seq("|3, foo|");
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap().1,
+            Token::Seq {
+                values: vec![
+                    Token::Arith {
+                        left: Box::new(Token::Term {
+                            left: Box::new(Token::Factor {
+                                value: Box::new(Token::Integer { value: 3 })
+                            }),
+                            right: vec![]
+                        }),
+                        right: vec![],
+                    },
+                    Token::Arith {
+                        left: Box::new(Token::Term {
+                            left: Box::new(Token::Factor {
+                                value: Box::new(Token::Identifier {
+                                    name: String::from("foo")
+                                })
+                            }),
+                            right: vec![]
+                        }),
+                        right: vec![],
+                    },
+                ]
+            }
+        );
+
+        assert!(seq("|1, 2").is_err());
+    }
+
+    #[test]
+    fn test_string() {
+        let result = string("\"\"");
+        assert!(result.is_ok());
+        assert_eq!(result.unwrap().1, Token::Str { parts: vec![] });
+
+        let result = string("\"x is { x }!\"");
+        assert!(result.is_ok());
+        assert_eq!(
+            result.unwrap().1,
+            Token::Str {
+                parts: vec![
+                    Token::Text {
+                        value: String::from("x is ")
+                    },
+                    Token::Arith {
+                        left: Box::new(Token::Term {
+                            left: Box::new(Token::Factor {
+                                value: Box::new(Token::Identifier {
+                                    name: String::from("x")
+                                })
+                            }),
+                            right: vec![]
+                        }),
+                        right: vec![],
+                    },
+                    Token::Text {
+                        value: String::from("!")
+                    },
+                ]
+            }
+        );
+
+        assert!(string("\"unterminated").is_err());
+        assert!(string("\"two\nlines\"").is_err());
+        assert!(string("\"{x\"").is_err());
+    }
+
+    #[test]
+    fn test_dict() {
+        assert_eq!(dict("{}"), Ok(("", Token::Dict { entries: vec![] })));
+        assert_eq!(dict("{ }"), Ok(("", Token::Dict { entries: vec![] })));
+
+        let result = dict("{1: 2.5, foo : 3}");
+        assert!(result.is_ok());
+        let (rest, token) = result.unwrap();
+        assert_eq!(rest, "");
+        let factor = |value| Token::Arith {
+            left: Box::new(Token::Term {
+                left: Box::new(Token::Factor {
+                    value: Box::new(value),
+                }),
+                right: vec![],
+            }),
+            right: vec![],
+        };
+        assert_eq!(
+            token,
+            Token::Dict {
+                entries: vec![
+                    (
+                        factor(Token::Integer { value: 1 }),
+                        factor(Token::Real { value: 2.5 })
+                    ),
+                    (
+                        factor(Token::Identifier {
+                            name: String::from("foo")
+                        }),
+                        factor(Token::Integer { value: 3 })
+                    ),
+                ]
+            }
+        );
+
+        assert!(dict("{1}").is_err());
+        assert!(dict("{1: 2").is_err());
+    }
 }
@@ -1,4 +1,4 @@
-use nom::{bytes::complete::tag, combinator::map_res, IResult};
+use nom::{branch::alt, bytes::complete::tag, combinator::map_res, IResult};
 
 use crate::compiler::tokens::Token;
 
@@ -63,9 +63,10 @@ pub fn division_op(i: &str) -> IResult<&str, Token> {
 //    )
 //);
 pub fn eq_op(i: &str) -> IResult<&str, Token> {
-    map_res(tag("eq"), |_| -> Result<Token, nom::error::Error<&str>> {
-        Ok(Token::EqualsOp)
-    })(i)
+    map_res(
+        alt((tag("eq"), tag("=="))),
+        |_| -> Result<Token, nom::error::Error<&str>> { Ok(Token::EqualsOp) },
+    )(i)
 }
 
 //named!(pub neq_op<CompleteStr, Token>,
@@ -76,9 +77,10 @@ pub fn eq_op(i: &str) -> IResult<&str, Token> {
 //    )
 //);
 pub fn neq_op(i: &str) -> IResult<&str, Token> {
-    map_res(tag("neq"), |_| -> Result<Token, nom::error::Error<&str>> {
-        Ok(Token::NotEqualsOp)
-    })(i)
+    map_res(
+        alt((tag("neq"), tag("!="))),
+        |_| -> Result<Token, nom::error::Error<&str>> { Ok(Token::NotEqualsOp) },
+    )(i)
 }
 
 //named!(pub gt_op<CompleteStr, Token>,
@@ -89,9 +91,10 @@ pub fn neq_op(i: &str) -> IResult<&str, Token> {
 //    )
 //);
 pub fn gt_op(i: &str) -> IResult<&str, Token> {
-    map_res(tag("gt"), |_| -> Result<Token, nom::error::Error<&str>> {
-        Ok(Token::GreaterThanOp)
-    })(i)
+    map_res(
+        alt((tag("gt"), tag(">"))),
+        |_| -> Result<Token, nom::error::Error<&str>> { Ok(Token::GreaterThanOp) },
+    )(i)
 }
 //
 //named!(pub gte_op<CompleteStr, Token>,
@@ -102,9 +105,10 @@ pub fn gt_op(i: &str) -> IResult<&str, Token> {
 //    )
 //);
 pub fn gte_op(i: &str) -> IResult<&str, Token> {
-    map_res(tag("gte"), |_| -> Result<Token, nom::error::Error<&str>> {
-        Ok(Token::GreaterThanEqualsOp)
-    })(i)
+    map_res(
+        alt((tag("gte"), tag(">="))),
+        |_| -> Result<Token, nom::error::Error<&str>> { Ok(Token::GreaterThanEqualsOp) },
+    )(i)
 }
 //
 //named!(pub lt_op<CompleteStr, Token>,
@@ -115,9 +119,10 @@ pub fn gte_op(i: &str) -> IResult<&str, Token> {
 //    )
 //);
 pub fn lt_op(i: &str) -> IResult<&str, Token> {
-    map_res(tag("lt"), |_| -> Result<Token, nom::error::Error<&str>> {
-        Ok(Token::LessThanOp)
-    })(i)
+    map_res(
+        alt((tag("lt"), tag("<"))),
+        |_| -> Result<Token, nom::error::Error<&str>> { Ok(Token::LessThanOp) },
+    )(i)
 }
 //
 //named!(pub lte_op<CompleteStr, Token>,
@@ -128,9 +133,10 @@ pub fn lt_op(i: &str) -> IResult<&str, Token> {
 //    )
 //);
 pub fn lte_op(i: &str) -> IResult<&str, Token> {
-    map_res(tag("lte"), |_| -> Result<Token, nom::error::Error<&str>> {
-        Ok(Token::LessThanEqualsOp)
-    })(i)
+    map_res(
+        alt((tag("lte"), tag("<="))),
+        |_| -> Result<Token, nom::error::Error<&str>> { Ok(Token::LessThanEqualsOp) },
+    )(i)
 }
 //
 //named!(pub and_op<CompleteStr, Token>,
@@ -228,4 +234,14 @@ mod tests {
         let (_, token) = result.unwrap();
         assert_eq!(token, Token::LessThanEqualsOp);
     }
+
+    #[test]
+    fn test_symbolic_operator() {
+        assert_eq!(eq_op("=="), Ok(("", Token::EqualsOp)));
+        assert_eq!(neq_op("!="), Ok(("", Token::NotEqualsOp)));
+        assert_eq!(gt_op(">"), Ok(("", Token::GreaterThanOp)));
+        assert_eq!(gte_op(">="), Ok(("", Token::GreaterThanEqualsOp)));
+        assert_eq!(lt_op("<"), Ok(("", Token::LessThanOp)));
+        assert_eq!(lte_op("<="), Ok(("", Token::LessThanEqualsOp)));
+    }
 }
@@ -0,0 +1,457 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::asm::syscalls::Syscall;
+
+// An instruction from the code section and the line it's on.
+#[derive(Debug)]
+struct Instruction {
+    line: usize,
+    opcode: String,
+    operands: Vec<String>,
+}
+
+// What an instruction does with its register operands, by index.
+#[derive(Debug, Default)]
+struct Effect {
+    // Read, so a copy of the register may be read instead.
+    reads: Vec<usize>,
+    // Read and then written.
+    updates: Vec<usize>,
+    writes: Vec<usize>,
+    // Control may pass to code that reads or writes any register.
+    calls: bool,
+    // Nothing but the writes happens, so it can go if they're unused.
+    pure: bool,
+}
+
+impl Effect {
+    fn new(reads: &[usize], writes: &[usize], pure: bool) -> Effect {
+        Effect {
+            reads: reads.to_vec(),
+            writes: writes.to_vec(),
+            pure,
+            ..Effect::default()
+        }
+    }
+
+    fn calls(mut self) -> Effect {
+        self.calls = true;
+        self
+    }
+}
+
+impl Instruction {
+    fn parse(line: usize, text: &str) -> Option<Instruction> {
+        let text = text.trim();
+        if text.is_empty() || text.starts_with(';') || text.starts_with('.') || is_label(text) {
+            return None;
+        }
+        let mut words = text.split_whitespace().map(str::to_string);
+        Some(Instruction {
+            line,
+            opcode: words.next()?,
+            operands: words.collect(),
+        })
+    }
+
+    fn to_line(&self) -> String {
+        std::iter::once(self.opcode.as_str())
+            .chain(self.operands.iter().map(String::as_str))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn register(&self, idx: usize) -> Option<&str> {
+        self.operands
+            .get(idx)
+            .map(String::as_str)
+            .filter(|operand| operand.starts_with('$'))
+    }
+
+    fn on_scalars(&self) -> bool {
+        self.operands
+            .iter()
+            .all(|operand| operand.starts_with("$i") || operand.starts_with("$r"))
+    }
+
+    // `known` holds the constants known to be in registers, which tell what
+    // a syscall does.
+    fn effect(&self, known: &HashMap<String, String>) -> Effect {
+        match self.opcode.as_str() {
+            "load" if self.operands.len() == 3 => Effect::new(&[1], &[0], true),
+            "load" => Effect::new(&[], &[0], true),
+            "copy" => Effect::new(&[1], &[0], true),
+            // Collections of unequal lengths, or a collection and a scalar,
+            // fail, so only operations on scalars are pure.
+            "not" => Effect::new(&[1], &[0], self.on_scalars()),
+            "add" | "sub" | "mul" | "eq" | "neq" | "gt" | "gte" | "lt" | "lte" | "and" | "or" => {
+                Effect::new(&[1, 2], &[0], self.on_scalars())
+            }
+            // Kept for dividing by zero.
+            "div" => Effect::new(&[1, 2], &[0], false),
+            "lw" | "keys" | "vals" | "sort" | "len" | "flat" => Effect::new(&[1], &[0], false),
+            "mask" | "dict" | "get" | "first" | "cat" | "zip" | "at" => {
+                Effect::new(&[1, 2], &[0], false)
+            }
+            "alloc" | "pop" | "nest" => Effect::new(&[], &[0], false),
+            "sw" => Effect::new(&[0, 1], &[], false),
+            "push" | "jmp" => Effect::new(&[0], &[], false),
+            "jz" | "jnz" => Effect::new(&[0, 1], &[], false),
+            "jeq" | "jne" | "jgt" | "jlt" | "jgte" | "jlte" => Effect::new(&[0, 1, 2], &[], false),
+            "halt" => Effect::new(&[], &[], false),
+            "map" => Effect::new(&[1], &[0], false).calls(),
+            "fold" | "sum" | "prod" | "min" | "max" => Effect {
+                reads: vec![1],
+                updates: vec![0],
+                calls: self.opcode == "fold",
+                ..Effect::default()
+            },
+            "syscall" => {
+                let call = self
+                    .register(0)
+                    .and_then(|reg| known.get(reg))
+                    .and_then(|value| value.strip_prefix('#')?.parse::<i32>().ok())
+                    .and_then(|call| Syscall::try_from(call).ok());
+                match call {
+                    Some(Syscall::ReadReg) => Effect::new(&[0], &[1], false),
+                    Some(_) => Effect::new(&[0, 1], &[], false),
+                    None => Effect {
+                        reads: vec![0],
+                        updates: vec![1],
+                        ..Effect::default()
+                    },
+                }
+            }
+            // Including call and ret.
+            _ => Effect::default().calls(),
+        }
+    }
+}
+
+fn is_jump(opcode: &str) -> bool {
+    matches!(
+        opcode,
+        "jmp" | "jeq" | "jne" | "jgt" | "jlt" | "jgte" | "jlte" | "jz" | "jnz"
+    )
+}
+
+fn is_label(text: &str) -> bool {
+    text.ends_with(':') && !text.contains(char::is_whitespace)
+}
+
+// Registers hold values of their own type, so copies between types convert.
+fn same_type(a: &str, b: &str) -> bool {
+    a.get(..2) == b.get(..2)
+}
+
+// Removes redundant instructions from the assembly.  Registers are shared
+// with functions, so nothing is assumed about them across calls, and every
+// register is assumed to be read after a return.
+pub fn optimise(assembly: &[String]) -> Vec<String> {
+    let mut lines: Vec<Option<String>> = assembly.iter().cloned().map(Some).collect();
+    while propagate(&mut lines) | remove_unused(&mut lines) {}
+    lines.into_iter().flatten().collect()
+}
+
+fn instructions(lines: &[Option<String>]) -> impl Iterator<Item = (usize, &str)> {
+    let code = lines
+        .iter()
+        .position(|line| line.as_deref() == Some(".code"))
+        .unwrap_or(0);
+    lines
+        .iter()
+        .enumerate()
+        .skip(code)
+        .filter_map(|(n, line)| Some((n, line.as_deref()?)))
+}
+
+// Reads copied registers instead of their copies, and drops loads of values
+// that are already loaded, as long as nothing has been written in between.
+fn propagate(lines: &mut [Option<String>]) -> bool {
+    let mut changed = false;
+    let mut known: HashMap<String, String> = HashMap::new();
+    let mut copies: HashMap<String, String> = HashMap::new();
+    let code: Vec<(usize, String)> = instructions(lines)
+        .map(|(n, text)| (n, text.to_string()))
+        .collect();
+
+    for (n, text) in code {
+        if is_label(text.trim()) {
+            known.clear();
+            copies.clear();
+            continue;
+        }
+        let mut instruction = match Instruction::parse(n, &text) {
+            Some(instruction) => instruction,
+            None => continue,
+        };
+        let effect = instruction.effect(&known);
+
+        for &idx in &effect.reads {
+            if let Some(source) = instruction.register(idx).and_then(|reg| copies.get(reg)) {
+                instruction.operands[idx] = source.clone();
+                lines[n] = Some(instruction.to_line());
+                changed = true;
+            }
+        }
+
+        if let ("load" | "copy", [dest, source]) =
+            (instruction.opcode.as_str(), instruction.operands.as_slice())
+        {
+            let redundant = match instruction.opcode.as_str() {
+                "load" => known.get(dest) == Some(source),
+                _ => dest == source || copies.get(dest) == Some(source),
+            };
+            if redundant {
+                lines[n] = None;
+                changed = true;
+                continue;
+            }
+        }
+
+        if effect.calls {
+            known.clear();
+            copies.clear();
+        }
+        for &idx in effect.writes.iter().chain(&effect.updates) {
+            if let Some(reg) = instruction.register(idx) {
+                known.remove(reg);
+                copies.retain(|dest, source| dest != reg && source != reg);
+            }
+        }
+
+        match (instruction.opcode.as_str(), instruction.operands.as_slice()) {
+            ("load", [dest, value]) => {
+                known.insert(dest.clone(), value.clone());
+            }
+            ("copy", [dest, source]) if same_type(dest, source) => {
+                copies.insert(dest.clone(), source.clone());
+            }
+            _ => {}
+        }
+    }
+    changed
+}
+
+// Drops instructions whose results are never read.
+fn remove_unused(lines: &mut [Option<String>]) -> bool {
+    let mut program = vec![];
+    let mut labels = HashMap::new();
+    let mut known: HashMap<String, String> = HashMap::new();
+    for (n, text) in instructions(lines) {
+        let text = text.trim();
+        if is_label(text) {
+            labels.insert(text.trim_end_matches(':').to_string(), program.len());
+            known.clear();
+            continue;
+        }
+        if let Some(instruction) = Instruction::parse(n, text) {
+            let effect = instruction.effect(&known);
+            // Jumps go to labels, or to labels loaded into registers beforehand.
+            let target = instruction
+                .operands
+                .first()
+                .filter(|_| is_jump(&instruction.opcode))
+                .and_then(|target| known.get(target).or(Some(target)))
+                .and_then(|value| value.strip_prefix('@'))
+                .map(str::to_string);
+            if effect.calls {
+                known.clear();
+            }
+            for &idx in effect.writes.iter().chain(&effect.updates) {
+                if let Some(reg) = instruction.register(idx) {
+                    known.remove(reg);
+                }
+            }
+            if let ("load", [dest, value]) =
+                (instruction.opcode.as_str(), instruction.operands.as_slice())
+            {
+                known.insert(dest.clone(), value.clone());
+            }
+            program.push((instruction, effect, target));
+        }
+    }
+
+    let all: HashSet<String> = program
+        .iter()
+        .flat_map(|(instruction, ..)| instruction.operands.iter())
+        .filter(|operand| operand.starts_with('$'))
+        .cloned()
+        .collect();
+
+    // What each instruction reads and writes, and where control goes next.
+    let mut uses = vec![];
+    let mut defs = vec![];
+    let mut successors = vec![];
+    for (n, (instruction, effect, target)) in program.iter().enumerate() {
+        let target = target.as_ref().and_then(|label| labels.get(label)).copied();
+        let registers = |indices: &[usize]| -> HashSet<String> {
+            indices
+                .iter()
+                .filter_map(|&idx| instruction.register(idx))
+                .map(str::to_string)
+                .collect()
+        };
+        let jumps_anywhere = is_jump(&instruction.opcode) && target.is_none();
+        uses.push(match effect.calls || jumps_anywhere {
+            true => all.clone(),
+            false => &registers(&effect.reads) | &registers(&effect.updates),
+        });
+        defs.push(registers(&effect.writes));
+        successors.push(match instruction.opcode.as_str() {
+            "halt" | "ret" => vec![],
+            "jmp" => target.into_iter().collect(),
+            opcode if is_jump(opcode) => std::iter::once(n + 1).chain(target).collect(),
+            _ => vec![n + 1],
+        });
+    }
+
+    // Work back from the end until nothing changes.
+    let mut live_in: Vec<HashSet<String>> = vec![HashSet::new(); program.len() + 1];
+    let mut live_out: Vec<HashSet<String>> = vec![HashSet::new(); program.len()];
+    let mut changed = true;
+    while changed {
+        changed = false;
+        for n in (0..program.len()).rev() {
+            let out: HashSet<String> = successors[n]
+                .iter()
+                .flat_map(|&s| live_in[s].iter().cloned())
+                .collect();
+            let mut live: HashSet<String> = &out - &defs[n];
+            live.extend(uses[n].iter().cloned());
+            if live != live_in[n] {
+                live_in[n] = live;
+                changed = true;
+            }
+            live_out[n] = out;
+        }
+    }
+
+    let mut removed = false;
+    for (n, (instruction, effect, _)) in program.iter().enumerate() {
+        if effect.pure && !defs[n].is_empty() && defs[n].is_disjoint(&live_out[n]) {
+            lines[instruction.line] = None;
+            removed = true;
+        }
+    }
+    removed
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn optimised(assembly: &str) -> Vec<String> {
+        let lines: Vec<String> = assembly.lines().map(str::to_string).collect();
+        optimise(&lines)
+    }
+
+    #[test]
+    fn test_copies() {
+        // the copy is read in place of the variable, then unused.
+        assert_eq!(
+            optimised(
+                ".code\nload $r31 #1.50\ncopy $r30 $r31\nload $i31 #0\nsyscall $i31 $r30\nhalt"
+            ),
+            vec![
+                ".code",
+                "load $r31 #1.50",
+                "load $i31 #0",
+                "syscall $i31 $r31",
+                "halt"
+            ]
+        );
+
+        // not if the source changes first.
+        let assembly = ".code\nload $i30 #1\ncopy $i29 $i30\nload $i30 #2\nadd $i28 $i29 $i30\npush $i28\nhalt";
+        assert_eq!(
+            optimised(assembly),
+            vec![
+                ".code",
+                "load $i30 #1",
+                "copy $i29 $i30",
+                "load $i30 #2",
+                "add $i28 $i29 $i30",
+                "push $i28",
+                "halt"
+            ]
+        );
+
+        // or between types, which converts.
+        let assembly = ".code\nload $i30 #1\ncopy $r29 $i30\npush $r29\nhalt";
+        assert!(optimised(assembly).contains(&"copy $r29 $i30".to_string()));
+    }
+
+    #[test]
+    fn test_loads() {
+        assert_eq!(
+            optimised(concat!(
+                ".code\n",
+                "load $i29 #8\n",
+                "add $i30 $i30 $i29\n",
+                "load $i29 #8\n",
+                "add $i30 $i30 $i29\n",
+                "push $i30\n",
+                "halt"
+            )),
+            vec![
+                ".code",
+                "load $i29 #8",
+                "add $i30 $i30 $i29",
+                "add $i30 $i30 $i29",
+                "push $i30",
+                "halt"
+            ]
+        );
+
+        // values aren't known after a label or a call.
+        for between in ["start:", "call @func_f"] {
+            let assembly = format!(
+                ".code\nload $i29 #8\npush $i29\n{}\nload $i29 #8\npush $i29\nhalt",
+                between
+            );
+            assert_eq!(optimised(&assembly).len(), 7, "{}", between);
+        }
+    }
+
+    #[test]
+    fn test_unused() {
+        assert_eq!(
+            optimised(".code\nload $i31 #1\nload $i30 #2\nadd $i29 $i31 $i30\nhalt"),
+            vec![".code", "halt"]
+        );
+
+        // arithmetic on vectors may fail, so it stays.
+        let assembly = ".code\nadd $v2 $v0 $v1\nhalt";
+        assert_eq!(optimised(assembly).len(), 3);
+
+        // values read in a loop are used.
+        let assembly = concat!(
+            ".code\n",
+            "load $i31 #0\n",
+            "start:\n",
+            "load $i30 #1\n",
+            "add $i31 $i31 $i30\n",
+            "load $i29 @start\n",
+            "jeq $i29 $i31 $i31\n",
+            "halt"
+        );
+        assert_eq!(optimised(assembly).len(), 8);
+
+        // as are values read after jumping straight to a label.
+        let assembly = concat!(
+            ".code\n",
+            "load $i31 #1\n",
+            "jmp @end\n",
+            "load $i31 #2\n",
+            "end:\n",
+            "push $i31\n",
+            "halt"
+        );
+        assert_eq!(optimised(assembly).len(), 7);
+
+        // functions may read any register.
+        let assembly = ".code\nload $i31 #1\ncall @func_f\nhalt";
+        assert_eq!(optimised(assembly).len(), 4);
+    }
+}
@@ -1,16 +1,103 @@
 use nom::branch::alt;
-use nom::combinator::map_res;
+use nom::bytes::complete::{tag, take_until};
+use nom::character::complete::{newline, not_line_ending, space0, space1};
+use nom::combinator::{map, map_res, not, recognize};
+use nom::error::ErrorKind;
 use nom::multi::many1;
+use nom::sequence::{pair, preceded, tuple};
 use nom::IResult;
 
 use crate::compiler::expression_parsers::expression;
 use crate::compiler::tokens::Token;
 
 use super::function_parser::function;
+use super::if_parser::if_statement;
+use super::while_parser::while_statement;
+
+// The lines that close a block, which are left for the block to parse.
+fn block_end(i: &str) -> IResult<&str, &str> {
+    preceded(
+        space0,
+        alt((tag("}"), tag("done"), tag("else"), tag("elif"))),
+    )(i)
+}
+
+// A line that doesn't parse.
+fn invalid(i: &str) -> IResult<&str, Option<Token>> {
+    map(recognize(pair(not_line_ending, newline)), |source: &str| {
+        Some(Token::Invalid {
+            source: source.trim().to_string(),
+        })
+    })(i)
+}
+
+// A function that doesn't parse, skipped up to its closing brace.
+fn invalid_function(i: &str) -> IResult<&str, Option<Token>> {
+    map(
+        recognize(tuple((
+            pair(tag("func"), space1),
+            take_until("\n}"),
+            pair(newline, not_line_ending),
+            newline,
+        ))),
+        |source: &str| {
+            Some(Token::Invalid {
+                source: source.trim().to_string(),
+            })
+        },
+    )(i)
+}
+
+// A conditional block or loop whose first line doesn't parse, skipped up to
+// its matching `done` so that the lines ending its branches aren't reported
+// as well.
+fn invalid_block(i: &str) -> IResult<&str, Option<Token>> {
+    let mut depth = 0;
+    let mut rest = i;
+    loop {
+        let (next, line) = recognize(pair(not_line_ending, newline))(rest)?;
+        match line.split_whitespace().next() {
+            Some("if" | "while") => depth += 1,
+            Some("done") if depth > 0 => depth -= 1,
+            _ if depth == 0 => {
+                return Err(nom::Err::Error(nom::error::Error::new(i, ErrorKind::Tag)))
+            }
+            _ => {}
+        }
+        rest = next;
+        if depth == 0 {
+            break;
+        }
+    }
+    Ok((
+        rest,
+        Some(Token::Invalid {
+            source: i[..i.len() - rest.len()].trim().to_string(),
+        }),
+    ))
+}
+
+// A single statement, which may be indented, as found in programs and blocks.
+pub fn statement(i: &str) -> IResult<&str, Option<Token>> {
+    preceded(space0, alt((if_statement, while_statement, expression)))(i)
+}
+
+// A statement in a block, or a line that doesn't parse so the rest of the
+// block can be.
+pub fn block_statement(i: &str) -> IResult<&str, Option<Token>> {
+    alt((statement, invalid_block, preceded(not(block_end), invalid)))(i)
+}
 
 pub fn program(i: &str) -> IResult<&str, Token> {
     map_res(
-        many1(alt((function, expression))),
+        many1(alt((
+            function,
+            invalid_function,
+            statement,
+            invalid_block,
+            // Including the ends of blocks that couldn't be parsed.
+            invalid,
+        ))),
         |funcs_or_exprs| -> Result<Token, nom::error::Error<&str>> {
             Ok(Token::Program {
                 statements: funcs_or_exprs,
@@ -74,4 +161,47 @@ mod tests {
             }
         );
     }
+
+    #[test]
+    fn test_program_recovery() {
+        let (rest, token) =
+            program("a = 1 2\nfunc f( {\n  b = 1\n}\nif a\n  b = $\ndone\ndone\nc = 1\n").unwrap();
+        assert!(rest.is_empty());
+        let invalid = |source: &str| {
+            Some(Token::Invalid {
+                source: source.to_string(),
+            })
+        };
+        match token {
+            Token::Program { statements } => {
+                assert_eq!(statements.len(), 5);
+                assert_eq!(statements[0], invalid("a = 1 2"));
+                assert_eq!(statements[1], invalid("func f( {\n  b = 1\n}"));
+                match &statements[2] {
+                    Some(Token::If { branches, .. }) => {
+                        assert_eq!(branches[0].1, vec![invalid("b = $")])
+                    }
+                    _ => panic!("expected an if"),
+                }
+                // a stray block end.
+                assert_eq!(statements[3], invalid("done"));
+                assert!(matches!(statements[4], Some(Token::Expression { .. })));
+            }
+            _ => panic!("expected a program"),
+        }
+
+        // blocks whose first line doesn't parse are skipped to their `done`,
+        // including any blocks inside them.
+        let listing = "if a $\n  b = 1\nelif a\n  while b\n  done\nelse\ndone\nc = 1\n";
+        let (rest, token) = program(listing).unwrap();
+        assert!(rest.is_empty());
+        match token {
+            Token::Program { statements } => {
+                assert_eq!(statements.len(), 2);
+                assert_eq!(statements[0], invalid(listing[..49].trim()));
+                assert!(matches!(statements[1], Some(Token::Expression { .. })));
+            }
+            _ => panic!("expected a program"),
+        }
+    }
 }
@@ -49,6 +49,22 @@ pub enum Token {
 
     Function {
         name: String,
+        args: Vec<Token>, // Args
+        return_type: Option<Type>,
+        body: Vec<Option<Token>>, // Expressions
+    },
+
+    Return {
+        value: Option<Box<Token>>,
+    },
+
+    Str {
+        parts: Vec<Token>, // Text and values to interpolate
+    },
+    Text {
+        value: String,
+    },
+    Lambda {
         args: Vec<Token>,         // Args
         body: Vec<Option<Token>>, // Expressions
     },
@@ -58,12 +74,33 @@ pub enum Token {
         typ: Type,
     },
 
+    Call {
+        name: String,
+        args: Vec<Token>, // Rvalues
+    },
+
+    If {
+        branches: Vec<(Token, Vec<Option<Token>>)>, // Conditions and their bodies
+        otherwise: Vec<Option<Token>>,              // Expressions
+    },
+
+    While {
+        cond: Box<Token>,
+        body: Vec<Option<Token>>, // Expressions
+    },
+
     Identifier {
         name: String,
     },
     Coll {
         values: Vec<Token>,
     },
+    Seq {
+        values: Vec<Token>,
+    },
+    Dict {
+        entries: Vec<(Token, Token)>, // Keys and values
+    },
     Integer {
         value: i32,
     },
@@ -88,4 +125,8 @@ pub enum Token {
     Program {
         statements: Vec<Option<Token>>,
     },
+    // Source that couldn't be parsed, skipped so the rest can be.
+    Invalid {
+        source: String,
+    },
 }
@@ -7,6 +7,21 @@ pub enum Type {
     Real,
     Integer,
     Coll,
+    Seq,
+    Dict,
+    Nested,
+}
+
+impl Type {
+    // Integers and reals, which can be converted to each other.
+    pub fn is_scalar(&self) -> bool {
+        matches!(self, Type::Real | Type::Integer)
+    }
+
+    // Colls and seqs, which share a representation.
+    pub fn is_collection(&self) -> bool {
+        matches!(self, Type::Coll | Type::Seq)
+    }
 }
 
 impl fmt::Display for Type {
@@ -29,5 +44,14 @@ impl<'a> TryFrom<&'a str> for Type {
 }
 
 fn iterator() -> impl Iterator<Item = Type> {
-    [Type::Real, Type::Integer, Type::Coll].iter().copied()
+    [
+        Type::Real,
+        Type::Integer,
+        Type::Coll,
+        Type::Seq,
+        Type::Dict,
+        Type::Nested,
+    ]
+    .iter()
+    .copied()
 }
@@ -0,0 +1,1260 @@
+use std::collections::{HashMap, HashSet};
+
+use crate::compiler::{
+    builtin::Builtin,
+    error::{Error, Span},
+    r#type::Type,
+    tokens::Token,
+    visitor::Visitor,
+};
+
+use super::{
+    assigned_variable, find_condition, find_function, find_statement, function_name, syntax_error,
+    Signature,
+};
+
+// Works out the type of every value before any code is written, so that
+// programs which would fail in the VM are rejected when they're compiled.
+#[derive(Debug)]
+pub struct TypeChecker {
+    // The types of the values being evaluated, in place of registers.
+    types: Vec<Type>,
+
+    variables: HashMap<String, Type>,
+    // Variables only assigned on some paths through an `if`, which can't be
+    // read until they're assigned again.
+    unassigned: HashSet<String>,
+    // Variables from outside the function being checked, which it can read
+    // but not assign.
+    read_only: HashSet<String>,
+    functions: HashMap<String, Signature>,
+    // The name of the function whose body is being checked, if any.
+    current_function: Option<String>,
+    next_lambda_id: usize,
+
+    // As for the compiler, to find where errors are.
+    source: String,
+    source_offset: usize,
+
+    errors: Vec<Error>,
+    failed_variables: HashSet<String>,
+}
+
+impl TypeChecker {
+    // Variables and functions already defined, by the repl, can be used.
+    pub(super) fn new(
+        source: &str,
+        variables: HashMap<String, Type>,
+        functions: HashMap<String, Signature>,
+    ) -> TypeChecker {
+        TypeChecker {
+            types: vec![],
+            variables,
+            unassigned: HashSet::new(),
+            read_only: HashSet::new(),
+            functions,
+            current_function: None,
+            next_lambda_id: 0,
+            source: source.to_string(),
+            source_offset: 0,
+            errors: vec![],
+            failed_variables: HashSet::new(),
+        }
+    }
+
+    pub fn check(&mut self, tree: &Token) -> Result<(), Vec<Error>> {
+        if let Err(e) = self.visit_token(tree) {
+            self.errors.insert(0, e);
+        }
+        if !self.errors.is_empty() {
+            return Err(std::mem::take(&mut self.errors));
+        }
+        Ok(())
+    }
+
+    fn pop(&mut self) -> Type {
+        self.types.pop().unwrap()
+    }
+
+    // The type of the value, or an error if it has none.
+    pub(super) fn value(&mut self, token: &Token) -> Result<Type, Error> {
+        self.visit_token(token)?;
+        Ok(self.pop())
+    }
+
+    fn check_arith(&mut self, op: &str) -> Result<(), Error> {
+        let right = self.pop();
+        let left = self.pop();
+        let result = match (left, right) {
+            (Type::Dict, _) | (_, Type::Dict) => {
+                return Err(Error::new(format!("Cannot {} dicts", op)))
+            }
+            (Type::Seq, Type::Coll | Type::Nested) | (Type::Coll | Type::Nested, Type::Seq) => {
+                return Err(Error::new(format!("Cannot {} a seq and a coll", op)))
+            }
+            // The VM only broadcasts scalars from the right of these.
+            (left, right) if left.is_scalar() && right.is_collection() && op == "sub" => {
+                return Err(Error::new(format!(
+                    "Cannot subtract {} from {}",
+                    lowercase(right),
+                    lowercase(left)
+                )))
+            }
+            (left, right) if left.is_scalar() && right.is_collection() && op == "div" => {
+                return Err(Error::new(format!(
+                    "Cannot divide {} by {}",
+                    lowercase(left),
+                    lowercase(right)
+                )))
+            }
+            (Type::Nested, _) | (_, Type::Nested) => Type::Nested,
+            (Type::Seq, _) | (_, Type::Seq) => Type::Seq,
+            (Type::Coll, _) | (_, Type::Coll) => Type::Coll,
+            (Type::Real, _) | (_, Type::Real) => Type::Real,
+            (Type::Integer, Type::Integer) => Type::Integer,
+        };
+        self.types.push(result);
+        Ok(())
+    }
+
+    // Comparisons always give an integer, so both sides must be scalars or
+    // both collections.
+    fn check_compare(&mut self) -> Result<(), Error> {
+        let right = self.pop();
+        let left = self.pop();
+        if left == Type::Dict || right == Type::Dict {
+            return Err(Error::new("Cannot compare dicts".to_string()));
+        }
+        if left == Type::Nested || right == Type::Nested {
+            return Err(Error::new("Cannot compare nested colls".to_string()));
+        }
+        if left.is_scalar() != right.is_scalar() {
+            return Err(Error::new(format!(
+                "Cannot compare {} with {}",
+                lowercase(left),
+                lowercase(right)
+            )));
+        }
+        self.types.push(Type::Integer);
+        Ok(())
+    }
+
+    // Logical operators also give an integer, so only work on scalars.
+    fn check_logical(&mut self, op: &str) -> Result<(), Error> {
+        let operands = match op {
+            "not" => vec![self.pop()],
+            _ => vec![self.pop(), self.pop()],
+        };
+        for typ in operands {
+            match typ {
+                Type::Dict => return Err(Error::new(format!("Cannot {} dicts", op))),
+                Type::Nested => return Err(Error::new(format!("Cannot {} nested colls", op))),
+                Type::Coll | Type::Seq => {
+                    return Err(Error::new(format!("Cannot {} collections", op)))
+                }
+                Type::Integer | Type::Real => {}
+            }
+        }
+        self.types.push(Type::Integer);
+        Ok(())
+    }
+
+    // Checks the condition on the line starting with `keyword`, marking the
+    // line in any error.
+    fn check_condition(&mut self, keyword: &str, cond: &Token) -> Result<(), Error> {
+        let found = find_condition(&self.source, self.source_offset, keyword);
+        let result = self.check_condition_type(cond);
+        match found {
+            Some((offset, len)) => {
+                self.source_offset = offset + len;
+                result.map_err(|e| e.with_span(Span::new(&self.source, offset, len)))
+            }
+            None => result,
+        }
+    }
+
+    fn check_condition_type(&mut self, cond: &Token) -> Result<(), Error> {
+        let kind = match self.value(cond)? {
+            Type::Coll => "a collection",
+            Type::Seq => "a seq",
+            Type::Dict => "a dict",
+            Type::Nested => "a nested collection",
+            Type::Integer | Type::Real => return Ok(()),
+        };
+        Err(Error::new(format!(
+            "Condition must be an integer or real, not {}",
+            kind
+        )))
+    }
+
+    fn check_function(
+        &mut self,
+        name: &str,
+        args: &[Token],
+        return_type: Option<Type>,
+        body: &[Option<Token>],
+    ) -> Result<(), Error> {
+        if self.functions.contains_key(name) {
+            return Err(Error::new(format!(
+                "Function '{}' is already defined",
+                name
+            )));
+        }
+        let arg_types = args
+            .iter()
+            .filter_map(|arg| match arg {
+                Token::Arg { typ, .. } => Some(*typ),
+                _ => None,
+            })
+            .collect();
+
+        // Added before the body so functions can call themselves.
+        self.functions.insert(
+            name.to_string(),
+            Signature {
+                args: arg_types,
+                return_type,
+            },
+        );
+        let mut seen = HashSet::new();
+        for arg in args {
+            if let Token::Arg { ident, .. } = arg {
+                if !seen.insert(ident) {
+                    return Err(Error::new(format!(
+                        "Argument '{}' is declared more than once",
+                        ident
+                    )));
+                }
+            }
+        }
+
+        let outer_function = self.current_function.replace(name.to_string());
+        let outer_variables = self.variables.clone();
+        let outer_unassigned = self.unassigned.clone();
+        let outer_read_only = std::mem::replace(
+            &mut self.read_only,
+            self.variables.keys().cloned().collect(),
+        );
+
+        for arg in args {
+            self.visit_token(arg)?;
+        }
+        self.check_block(body);
+
+        self.variables = outer_variables;
+        self.unassigned = outer_unassigned;
+        self.read_only = outer_read_only;
+        self.current_function = outer_function;
+
+        match return_type {
+            Some(return_type) if !always_returns(body) => Err(Error::new(format!(
+                "Not every path through '{}' returns a {}",
+                name, return_type
+            ))),
+            _ => Ok(()),
+        }
+    }
+
+    // Checks the function passed to a builtin, which must take `arity` reals
+    // and return a single integer or real.
+    fn check_function_arg(
+        &mut self,
+        builtin: Builtin,
+        arg: &Token,
+        arity: usize,
+    ) -> Result<(), Error> {
+        if let Token::Lambda { args, body } = arg {
+            if args.len() != arity {
+                return Err(Error::new(format!(
+                    "'{}' expects a function of {} arguments but was given {}",
+                    lowercase(builtin),
+                    arity,
+                    args.len()
+                )));
+            }
+            let name = format!("lambda_{}", self.next_lambda_id);
+            self.next_lambda_id += 1;
+            return self.check_function(&name, args, Some(Type::Real), body);
+        }
+
+        let name = match function_name(arg) {
+            Some(name) => name,
+            None => {
+                return Err(Error::new(format!(
+                    "'{}' expects a function",
+                    lowercase(builtin)
+                )))
+            }
+        };
+        let signature = match self.functions.get(name) {
+            Some(signature) => signature,
+            None => return Err(Error::new(format!("Unknown function '{}'", name))),
+        };
+        if signature.args.len() != arity
+            || !signature.args.iter().all(Type::is_scalar)
+            || !matches!(signature.return_type, Some(Type::Integer | Type::Real))
+        {
+            return Err(Error::new(format!(
+                "'{}' must take {} and return a single integer or real",
+                name,
+                if arity == 1 {
+                    "a single integer or real".to_string()
+                } else {
+                    format!("{} integers or reals", arity)
+                }
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_collection_arg(&mut self, builtin: Builtin, arg: &Token) -> Result<Type, Error> {
+        let typ = self.value(arg)?;
+        // Nested collections are flattened by joining their rows.
+        if builtin == Builtin::Flatten && typ == Type::Nested {
+            return Ok(Type::Coll);
+        }
+        if !typ.is_collection() {
+            return Err(Error::new(format!(
+                "'{}' expects a collection but was given {}",
+                lowercase(builtin),
+                typ
+            )));
+        }
+        Ok(typ)
+    }
+
+    fn check_dict_arg(&mut self, builtin: Builtin, arg: &Token) -> Result<(), Error> {
+        let typ = self.value(arg)?;
+        if typ != Type::Dict {
+            return Err(Error::new(format!(
+                "'{}' expects a dict but was given {}",
+                lowercase(builtin),
+                typ
+            )));
+        }
+        Ok(())
+    }
+
+    fn check_scalar_arg(&mut self, arg: &Token, message: &str) -> Result<(), Error> {
+        if !self.value(arg)?.is_scalar() {
+            return Err(Error::new(message.to_string()));
+        }
+        Ok(())
+    }
+
+    // The type the builtin gives, if any.
+    fn check_builtin(&mut self, builtin: Builtin, args: &[Token]) -> Result<Option<Type>, Error> {
+        Ok(match builtin {
+            Builtin::Write => {
+                if args.len() != 1 {
+                    return Err(Error::new("'write' expects a single argument".to_string()));
+                }
+                self.value(&args[0])?;
+                None
+            }
+            Builtin::Print => match args {
+                [Token::Str { parts }] => {
+                    for part in parts {
+                        if !matches!(part, Token::Text { .. }) {
+                            self.value(part)?;
+                        }
+                    }
+                    None
+                }
+                _ => return Err(Error::new("'print' expects a single string".to_string())),
+            },
+            Builtin::Read => match args {
+                [] => Some(Type::Coll),
+                [arg] => match function_name(arg).map(Type::try_from) {
+                    Some(Ok(typ)) => Some(typ),
+                    _ => {
+                        return Err(Error::new(
+                            "'read' expects a type of real, integer, coll, or seq".to_string(),
+                        ))
+                    }
+                },
+                _ => {
+                    return Err(Error::new(
+                        "'read' expects at most one argument".to_string(),
+                    ))
+                }
+            },
+            Builtin::Get => {
+                if args.len() != 2 {
+                    return Err(Error::new("'get' expects a dict and a key".to_string()));
+                }
+                self.check_dict_arg(builtin, &args[0])?;
+                self.check_scalar_arg(&args[1], "'get' expects an integer or real key")?;
+                Some(Type::Real)
+            }
+            Builtin::Keys | Builtin::Values => {
+                if args.len() != 1 {
+                    return Err(Error::new(format!(
+                        "'{}' expects a single dict",
+                        lowercase(builtin)
+                    )));
+                }
+                self.check_dict_arg(builtin, &args[0])?;
+                Some(Type::Coll)
+            }
+            Builtin::Seq | Builtin::Coll => {
+                if args.len() != 1 {
+                    return Err(Error::new(format!(
+                        "'{}' expects a single collection",
+                        lowercase(builtin)
+                    )));
+                }
+                self.check_collection_arg(builtin, &args[0])?;
+                Some(match builtin {
+                    Builtin::Seq => Type::Seq,
+                    _ => Type::Coll,
+                })
+            }
+            Builtin::Sort => {
+                if args.is_empty() || args.len() > 2 {
+                    return Err(Error::new(
+                        "'sort' expects a collection and an optional comparison".to_string(),
+                    ));
+                }
+                if !matches!(
+                    args.get(1).map(function_name),
+                    None | Some(Some("lt" | "lte" | "gt" | "gte"))
+                ) {
+                    return Err(Error::new(
+                        "'sort' expects a comparison of lt, lte, gt, or gte".to_string(),
+                    ));
+                }
+                self.check_collection_arg(builtin, &args[0])?;
+                Some(Type::Seq)
+            }
+            Builtin::First => {
+                if args.len() != 2 {
+                    return Err(Error::new(
+                        "'first' expects a collection and a number of elements".to_string(),
+                    ));
+                }
+                let typ = self.check_collection_arg(builtin, &args[0])?;
+                self.check_scalar_arg(
+                    &args[1],
+                    "'first' expects an integer or real number of elements",
+                )?;
+                Some(typ)
+            }
+            Builtin::Flatten | Builtin::Zip => {
+                match builtin {
+                    Builtin::Flatten if args.is_empty() => {
+                        return Err(Error::new(
+                            "'flatten' expects one or more collections".to_string(),
+                        ))
+                    }
+                    Builtin::Zip if args.len() != 2 => {
+                        return Err(Error::new("'zip' expects two collections".to_string()))
+                    }
+                    _ => {}
+                }
+                let typ = self.check_collection_arg(builtin, &args[0])?;
+                for arg in &args[1..] {
+                    if self.check_collection_arg(builtin, arg)? != typ {
+                        return Err(Error::new(format!(
+                            "Cannot {} a seq and a coll",
+                            lowercase(builtin)
+                        )));
+                    }
+                }
+                match builtin {
+                    Builtin::Zip => Some(Type::Nested),
+                    _ => Some(typ),
+                }
+            }
+            Builtin::Length => {
+                if args.len() != 1 {
+                    return Err(Error::new(
+                        "'length' expects a single collection".to_string(),
+                    ));
+                }
+                let typ = self.value(&args[0])?;
+                if !typ.is_collection() && typ != Type::Nested {
+                    return Err(Error::new(format!(
+                        "'length' expects a collection but was given {}",
+                        typ
+                    )));
+                }
+                Some(Type::Integer)
+            }
+            Builtin::At => {
+                if args.len() != 2 {
+                    return Err(Error::new(
+                        "'at' expects a collection and an index".to_string(),
+                    ));
+                }
+                let typ = match self.value(&args[0])? {
+                    Type::Coll | Type::Seq => Type::Real,
+                    Type::Nested => Type::Coll,
+                    typ => {
+                        return Err(Error::new(format!(
+                            "'at' expects a collection but was given {}",
+                            typ
+                        )))
+                    }
+                };
+                self.check_scalar_arg(&args[1], "'at' expects an integer or real index")?;
+                Some(typ)
+            }
+            Builtin::Map | Builtin::Filter => {
+                if args.len() != 2 {
+                    return Err(Error::new(match builtin {
+                        Builtin::Map => "'map' expects a function and a collection".to_string(),
+                        _ => "'filter' expects a predicate and a collection".to_string(),
+                    }));
+                }
+                self.check_function_arg(builtin, &args[0], 1)?;
+                Some(self.check_collection_arg(builtin, &args[1])?)
+            }
+            Builtin::Fold => {
+                if args.len() != 3 {
+                    return Err(Error::new(
+                        "'fold' expects a function, a collection, and an initial value".to_string(),
+                    ));
+                }
+                let reducer = function_name(&args[0])
+                    .filter(|name| !self.functions.contains_key(*name))
+                    .is_some_and(|name| matches!(name, "sum" | "product" | "min" | "max"));
+                if !reducer {
+                    self.check_function_arg(builtin, &args[0], 2)?;
+                }
+                self.check_collection_arg(builtin, &args[1])?;
+                self.check_scalar_arg(&args[2], "'fold' expects an integer or real initial value")?;
+                Some(Type::Real)
+            }
+        })
+    }
+
+    // The type the function returns, if any.
+    fn check_call(&mut self, name: &str, args: &[Token]) -> Result<Option<Type>, Error> {
+        let signature = match self.functions.get(name) {
+            Some(signature) => signature.clone(),
+            None => return Err(Error::new(format!("Unknown function '{}'", name))),
+        };
+        if args.len() != signature.args.len() {
+            return Err(Error::new(format!(
+                "'{}' expects {} arguments but was given {}",
+                name,
+                signature.args.len(),
+                args.len()
+            )));
+        }
+        for (arg, typ) in args.iter().zip(signature.args) {
+            let arg_type = self.value(arg)?;
+            if typ != arg_type && !(typ.is_scalar() && arg_type.is_scalar()) {
+                return Err(Error::new(format!(
+                    "'{}' expects {} argument but was given {}",
+                    name, typ, arg_type
+                )));
+            }
+        }
+        Ok(signature.return_type)
+    }
+
+    fn check_coll(&mut self, values: &[Token]) -> Result<Type, Error> {
+        let mut coll_type = Type::Coll;
+        for (n, value) in values.iter().enumerate() {
+            let typ = self.value(value)?;
+            if coll_type == Type::Nested {
+                if typ != Type::Coll {
+                    return Err(Error::new(format!(
+                        "Unable to put {} into a collection of collections",
+                        lowercase(typ)
+                    )));
+                }
+                continue;
+            }
+            match typ {
+                Type::Integer | Type::Real => {}
+                Type::Coll if n == 0 => coll_type = Type::Nested,
+                Type::Coll => {
+                    return Err(Error::new(
+                        "Unable to mix collections and reals in a collection".to_string(),
+                    ))
+                }
+                Type::Seq => {
+                    return Err(Error::new(
+                        "Unable to put seq into a collection".to_string(),
+                    ))
+                }
+                Type::Dict => {
+                    return Err(Error::new(
+                        "Unable to put dict into a collection".to_string(),
+                    ))
+                }
+                Type::Nested => {
+                    return Err(Error::new(
+                        "Unable to put nested collection into a collection".to_string(),
+                    ))
+                }
+            }
+        }
+        Ok(coll_type)
+    }
+
+    // The variables that hold a value on every path to this point.
+    fn assigned(&self) -> HashSet<String> {
+        self.variables
+            .keys()
+            .filter(|name| !self.unassigned.contains(*name))
+            .cloned()
+            .collect()
+    }
+
+    // Marks every other variable as unassigned.
+    fn assign_only(&mut self, assigned: &HashSet<String>) {
+        self.unassigned = self
+            .variables
+            .keys()
+            .filter(|name| !assigned.contains(*name))
+            .cloned()
+            .collect();
+    }
+
+    fn record(&mut self, e: Error) {
+        if !e.is_cascaded() {
+            self.errors.push(e);
+        }
+    }
+
+    // Checks each statement in turn, recording any errors so the rest can
+    // still be checked.
+    fn check_block(&mut self, body: &[Option<Token>]) {
+        for statement in body.iter().flatten() {
+            let depth = self.types.len();
+            if let Err(e) = self.visit_token(statement) {
+                if let Some(ident) = assigned_variable(statement) {
+                    self.failed_variables.insert(ident.to_string());
+                }
+                self.record(e);
+                self.types.truncate(depth);
+            }
+        }
+    }
+}
+
+fn lowercase(value: impl ToString) -> String {
+    value.to_string().to_lowercase()
+}
+
+// Whether every path through the statements ends in a return.
+fn always_returns(body: &[Option<Token>]) -> bool {
+    body.iter().flatten().any(|statement| match statement {
+        Token::Expression { token, .. } => matches!(**token, Token::Return { .. }),
+        Token::If {
+            branches,
+            otherwise,
+        } => branches.iter().all(|(_, body)| always_returns(body)) && always_returns(otherwise),
+        _ => false,
+    })
+}
+
+impl Visitor for TypeChecker {
+    fn visit_token(&mut self, node: &Token) -> Result<(), Error> {
+        match node {
+            Token::Comment { .. } => {}
+
+            // Arithmetic
+            Token::AdditionOp => self.check_arith("add")?,
+            Token::SubtractionOp => self.check_arith("sub")?,
+            Token::MultiplicationOp => self.check_arith("mul")?,
+            Token::DivisionOp => self.check_arith("div")?,
+
+            // Comparative
+            Token::EqualsOp
+            | Token::NotEqualsOp
+            | Token::GreaterThanOp
+            | Token::GreaterThanEqualsOp
+            | Token::LessThanOp
+            | Token::LessThanEqualsOp => self.check_compare()?,
+
+            // Logical
+            Token::AndOp => self.check_logical("and")?,
+            Token::OrOp => self.check_logical("or")?,
+            Token::NotOp => self.check_logical("not")?,
+
+            Token::UnaryOp { op, right } => {
+                self.visit_token(right)?;
+                self.visit_token(op)?;
+            }
+
+            Token::BinOp { left, op, right } => {
+                self.visit_token(left)?;
+                self.visit_token(right)?;
+                self.visit_token(op)?;
+            }
+
+            Token::Assign { ident, expr } => {
+                let typ = self.value(expr)?;
+                if self.read_only.contains(ident) {
+                    // Any variables needed must be passed in.
+                    return Err(Error::new(format!(
+                        "Cannot assign to global variable '{}' in function scope.",
+                        ident
+                    )));
+                }
+                match self.variables.get(ident) {
+                    Some(&was) if was != typ => {
+                        return Err(Error::new(format!(
+                            "Variable '{}' was {} and is now {}",
+                            ident, was, typ
+                        )))
+                    }
+                    Some(_) => {}
+                    None => {
+                        self.variables.insert(ident.clone(), typ);
+                    }
+                }
+                self.unassigned.remove(ident);
+            }
+
+            Token::Builtin { builtin, args } => match self.check_builtin(*builtin, args)? {
+                Some(typ) => self.types.push(typ),
+                None => {
+                    return Err(Error::new(format!(
+                        "'{}' does not return a value",
+                        lowercase(builtin)
+                    )))
+                }
+            },
+
+            Token::Function {
+                name,
+                args,
+                return_type,
+                body,
+            } => {
+                // Errors in the function itself mark the line declaring it.
+                let found = find_function(&self.source, self.source_offset, name);
+                if let Some((offset, len)) = found {
+                    self.source_offset = offset + len;
+                }
+                self.check_function(name, args, *return_type, body)
+                    .map_err(|e| match found {
+                        Some((offset, len)) => e.with_span(Span::new(&self.source, offset, len)),
+                        None => e,
+                    })?
+            }
+
+            Token::Str { .. } | Token::Text { .. } => {
+                return Err(Error::new(
+                    "Strings can only be passed to 'print'".to_string(),
+                ))
+            }
+
+            Token::Lambda { .. } => {
+                return Err(Error::new(
+                    "Anonymous functions can only be passed to builtins".to_string(),
+                ))
+            }
+
+            Token::Arg { ident, typ } => {
+                // Arguments hide any variable of the same name outside.
+                self.read_only.remove(ident);
+                self.variables.insert(ident.clone(), *typ);
+            }
+
+            Token::Call { name, args } => match self.check_call(name, args)? {
+                Some(typ) => self.types.push(typ),
+                None => return Err(Error::new(format!("'{}' does not return a value", name))),
+            },
+
+            Token::Return { value } => {
+                let return_type = match &self.current_function {
+                    Some(name) => self.functions[name].return_type,
+                    None => {
+                        return Err(Error::new(
+                            "Cannot return from outside a function".to_string(),
+                        ))
+                    }
+                };
+                match (value, return_type) {
+                    (Some(value), Some(return_type)) => {
+                        // Integers are promoted to reals as they're returned.
+                        let typ = self.value(value)?;
+                        if typ != return_type
+                            && !(typ == Type::Integer && return_type == Type::Real)
+                        {
+                            return Err(Error::new(format!(
+                                "Cannot return {} from function returning {}",
+                                typ, return_type
+                            )));
+                        }
+                    }
+                    (None, None) => {}
+                    (Some(_), None) => {
+                        return Err(Error::new(
+                            "Cannot return a value from function without a return type".to_string(),
+                        ))
+                    }
+                    (None, Some(return_type)) => {
+                        return Err(Error::new(format!(
+                            "Must return a {} from function",
+                            return_type
+                        )))
+                    }
+                }
+            }
+
+            Token::If {
+                branches,
+                otherwise,
+            } => {
+                // The bodies are still worth checking if a condition isn't.
+                // Only variables assigned in every branch, including an empty
+                // `else`, can be read after `done`.
+                let before = self.assigned();
+                let mut paths = vec![];
+                for (n, (cond, body)) in branches.iter().enumerate() {
+                    let keyword = if n == 0 { "if" } else { "elif" };
+                    if let Err(e) = self.check_condition(keyword, cond) {
+                        self.record(e);
+                    }
+                    self.check_block(body);
+                    paths.push(self.assigned());
+                    self.assign_only(&before);
+                }
+                self.check_block(otherwise);
+                let assigned = paths.iter().fold(self.assigned(), |all, path| &all & path);
+                self.assign_only(&assigned);
+            }
+
+            Token::While { cond, body } => {
+                // The body may not run, so variables it first assigns can't be
+                // read after `done`.
+                let before = self.assigned();
+                if let Err(e) = self.check_condition("while", cond) {
+                    self.record(e);
+                }
+                self.check_block(body);
+                self.assign_only(&before);
+            }
+
+            Token::Identifier { name } => match self.variables.get(name) {
+                Some(_) if self.unassigned.contains(name) => {
+                    let message =
+                        format!("Variable '{}' is not assigned on every path to here", name);
+                    // A path may only have missed it because its assignment
+                    // had an error.
+                    return Err(match self.failed_variables.contains(name) {
+                        true => Error::cascaded(message),
+                        false => Error::new(message),
+                    });
+                }
+                Some(&typ) => self.types.push(typ),
+                None => {
+                    let message = format!("Unknown variable '{}'", name);
+                    return Err(match self.failed_variables.contains(name) {
+                        true => Error::cascaded(message),
+                        false => Error::new(message),
+                    });
+                }
+            },
+
+            Token::Real { .. } => self.types.push(Type::Real),
+            Token::Integer { .. } => self.types.push(Type::Integer),
+
+            Token::Coll { values } => {
+                let typ = self.check_coll(values)?;
+                self.types.push(typ);
+            }
+
+            Token::Seq { values } => {
+                // Seqs of collections are still nested collections.
+                let typ = match self.check_coll(values)? {
+                    Type::Coll => Type::Seq,
+                    typ => typ,
+                };
+                self.types.push(typ);
+            }
+
+            Token::Dict { entries } => {
+                for (key, value) in entries {
+                    if !self.value(key)?.is_scalar() || !self.value(value)?.is_scalar() {
+                        return Err(Error::new(
+                            "Dict keys and values must be integers or reals".to_string(),
+                        ));
+                    }
+                }
+                self.types.push(Type::Dict);
+            }
+
+            Token::Factor { value } => self.visit_token(value)?,
+            Token::Term { left, right } | Token::Arith { left, right } => {
+                self.visit_token(left)?;
+                for (op, operand) in right {
+                    self.visit_token(operand)?;
+                    self.visit_token(op)?;
+                }
+            }
+
+            Token::Expression { source, token } => {
+                let offset = find_statement(&self.source, self.source_offset, source);
+                // Statements may call functions and builtins for their
+                // effects alone, and any value left is dropped.
+                let depth = self.types.len();
+                let result = match **token {
+                    Token::Builtin { builtin, ref args } => {
+                        self.check_builtin(builtin, args).map(|_| ())
+                    }
+                    Token::Call { ref name, ref args } => self.check_call(name, args).map(|_| ()),
+                    _ => self.visit_token(token),
+                };
+                self.types.truncate(depth);
+                if let Some(offset) = offset {
+                    self.source_offset = offset + source.len();
+                    if let Err(e) = result {
+                        return Err(e.with_span(Span::new(&self.source, offset, source.len())));
+                    }
+                }
+                result?;
+            }
+            Token::Invalid { source } => {
+                let offset = find_statement(&self.source, self.source_offset, source)
+                    .unwrap_or(self.source_offset);
+                self.source_offset = offset + source.len();
+                return Err(syntax_error(&self.source, &self.source[offset..]));
+            }
+
+            Token::Program { statements } => {
+                self.check_block(statements);
+                if !self.errors.is_empty() {
+                    return Err(self.errors.remove(0));
+                }
+            }
+        };
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::compiler::program_parser::program;
+
+    fn check(listing: &str) -> Result<(), Vec<String>> {
+        let (rest, tree) = program(listing).unwrap();
+        assert!(rest.is_empty());
+        TypeChecker::new(listing, HashMap::new(), HashMap::new())
+            .check(&tree)
+            .map_err(|errors| {
+                errors
+                    .iter()
+                    .map(|e| e.to_string().lines().next().unwrap().to_string())
+                    .collect()
+            })
+    }
+
+    fn check_error(listing: &str) -> String {
+        let errors = check(listing).unwrap_err();
+        assert_eq!(errors.len(), 1, "{:?}", errors);
+        errors[0].clone()
+    }
+
+    #[test]
+    fn test_arith() {
+        for listing in [
+            "a = 1 + 2\nb = a * 2\n",
+            "a = [1, 2] - 1\n",
+            "a = 1 + [1, 2]\n",
+            "a = [1, 2] / [3, 4]\n",
+            "a = 1 - [[1, 2], [3, 4]]\n",
+            "a = 2 / (1 + 1)\n",
+        ] {
+            assert_eq!(check(listing), Ok(()), "{}", listing);
+        }
+
+        // integers are promoted to reals, and scalars to collections.
+        assert_eq!(
+            check_error("a = 1\na = a + 1.5\n"),
+            "2:1: Variable 'a' was Integer and is now Real"
+        );
+        assert_eq!(
+            check_error("a = 1.5\na = a * [1]\n"),
+            "2:1: Variable 'a' was Real and is now Coll"
+        );
+
+        assert_eq!(
+            check_error("a = 1 - [1, 2]\n"),
+            "1:1: Cannot subtract coll from integer"
+        );
+        assert_eq!(
+            check_error("b = [1, 2]\na = 1.5 / b\n"),
+            "2:1: Cannot divide real by coll"
+        );
+        assert_eq!(check_error("a = 1 + {1: 2}\n"), "1:1: Cannot add dicts");
+        assert_eq!(
+            check_error("a = |1, 2| + [1, 2]\n"),
+            "1:1: Cannot add a seq and a coll"
+        );
+    }
+
+    #[test]
+    fn test_compare() {
+        assert_eq!(check("if [1, 2] == [1, 2]\n  a = 1\ndone\n"), Ok(()));
+        assert_eq!(
+            check_error(concat!(
+                "func f(a: coll) -> coll {\n",
+                "  return a > [1]\n",
+                "}\n",
+            )),
+            "2:3: Cannot return Integer from function returning Coll"
+        );
+        assert_eq!(
+            check_error("if [1, 2] > 1\n  a = 1\ndone\n"),
+            "1:1: Cannot compare coll with integer"
+        );
+        assert_eq!(
+            check_error("while 1 == {1: 2}\ndone\n"),
+            "1:1: Cannot compare dicts"
+        );
+        assert_eq!(check_error("[1] and 1\n"), "1:1: Cannot and collections");
+        assert_eq!(
+            check_error("not [[1], [2]]\n"),
+            "1:1: Cannot not nested colls"
+        );
+    }
+
+    #[test]
+    fn test_builtins() {
+        for listing in [
+            "a = do(length, [1, 2])\nb = a + 1\n",
+            "a = do(flatten, [[1], [2]], [3])\nb = a + [1, 2, 3]\n",
+            "a = do(zip, |1|, |2|)\nb = do(at, a, 0)\nc = b + [1, 1]\n",
+            "a = do(at, [[1], [2]], 0)\nb = a + [1]\n",
+            "a = do(map, func(x) { return x * 2 }, |1, 2|)\nb = a + |1, 1|\n",
+            "a = do(fold, sum, [1, 2], 0)\nb = a * 2.5\n",
+            "do(print, \"{do(get, {1: 2}, 1)}\")\n",
+        ] {
+            assert_eq!(check(listing), Ok(()), "{}", listing);
+        }
+
+        assert_eq!(
+            check_error("a = do(length, 1)\n"),
+            "1:1: 'length' expects a collection but was given Integer"
+        );
+        assert_eq!(
+            check_error("a = do(get, [1], 1)\n"),
+            "1:1: 'get' expects a dict but was given Coll"
+        );
+        assert_eq!(
+            check_error("a = do(write, 1)\n"),
+            "1:1: 'write' does not return a value"
+        );
+        assert_eq!(
+            check_error("a = do(sort, [1, 2])\nb = a + [1, 2]\n"),
+            "2:1: Cannot add a seq and a coll"
+        );
+        assert_eq!(
+            check_error("a = do(map, func(x) { return [x] }, [1])\n"),
+            "1:23: Cannot return Coll from function returning Real"
+        );
+    }
+
+    #[test]
+    fn test_functions() {
+        assert_eq!(
+            check(concat!(
+                "func f(a: real, b: coll) -> coll {\n",
+                "  return b * a\n",
+                "}\n",
+                "c = f(2, [1, 2]) + [1, 1]\n",
+            )),
+            Ok(())
+        );
+        assert_eq!(
+            check_error(concat!(
+                "func f(a: coll) -> real {\n",
+                "  return 1.5\n",
+                "}\n",
+                "c = f(1)\n",
+            )),
+            "4:1: 'f' expects Coll argument but was given Integer"
+        );
+        assert_eq!(
+            check_error(concat!(
+                "func f(a: coll) -> integer {\n",
+                "  return a\n",
+                "}\n",
+            )),
+            "2:3: Cannot return Coll from function returning Integer"
+        );
+        assert_eq!(
+            check_error(concat!(
+                "func f(a: coll) {\n",
+                "  do(write, a)\n",
+                "}\n",
+                "b = f([1])\n",
+            )),
+            "4:1: 'f' does not return a value"
+        );
+
+        // arguments go out of scope with the function.
+        assert_eq!(
+            check_error(concat!(
+                "func f(a: coll) {\n",
+                "  do(write, a)\n",
+                "}\n",
+                "do(write, a)\n",
+            )),
+            "4:1: Unknown variable 'a'"
+        );
+
+        // every path must return, and the function can still be called.
+        assert_eq!(
+            check(concat!(
+                "func sign(a: integer) -> integer {\n",
+                "  if a lt 0\n",
+                "    return -1\n",
+                "  elif a gt 0\n",
+                "    return 1\n",
+                "  done\n",
+                "}\n",
+                "b = sign(1)\n",
+            )),
+            Err(vec![
+                "1:1: Not every path through 'sign' returns a Integer".to_string()
+            ])
+        );
+        assert_eq!(
+            check_error(concat!(
+                "func f(n: integer) -> integer {\n",
+                "  while n gt 0\n",
+                "    return 1\n",
+                "  done\n",
+                "}\n",
+            )),
+            "1:1: Not every path through 'f' returns a Integer"
+        );
+
+        assert_eq!(
+            check_error("func f() {\n  do(write, 1)\n}\nfunc f() {\n  do(write, 2)\n}\n"),
+            "4:1: Function 'f' is already defined"
+        );
+        assert_eq!(
+            check_error("func f(a: real, a: real) {\n  do(write, a)\n}\n"),
+            "1:1: Argument 'a' is declared more than once"
+        );
+
+        // outer variables can be read but not assigned, unless an argument
+        // hides them.
+        assert_eq!(
+            check_error("a = 1\nfunc f() {\n  a = 2\n}\n"),
+            "3:3: Cannot assign to global variable 'a' in function scope."
+        );
+        assert_eq!(
+            check("a = 1\nfunc f(a: integer) {\n  a = 2\n}\nfunc g() {\n  b = a\n}\n"),
+            Ok(())
+        );
+    }
+
+    #[test]
+    fn test_conditions() {
+        assert_eq!(check("a = 1\nwhile a < 3\n  a = a + 1\ndone\n"), Ok(()));
+        assert_eq!(
+            check_error("a = [1]\nif a\n  a = a + 1\ndone\n"),
+            "2:1: Condition must be an integer or real, not a collection"
+        );
+        assert_eq!(
+            check_error("a = 1\nif a\n  a = 2\n  elif [1]\n  a = 3\ndone\n"),
+            "4:3: Condition must be an integer or real, not a collection"
+        );
+    }
+
+    #[test]
+    fn test_assigned_on_every_path() {
+        for listing in [
+            "a = 0\nif a\n  b = 5\nelse\n  b = 6\ndone\ndo(write, b)\n",
+            "a = 0\nif a\n  b = 5\nelif a > 1\n  b = 6\nelse\n  b = 7\ndone\nc = b\n",
+            "a = 0\nif a\n  b = 5\ndone\nb = 6\ndo(write, b)\n",
+            "a = 0\nif a\n  b = 5\n  do(write, b)\ndone\n",
+        ] {
+            assert_eq!(check(listing), Ok(()), "{}", listing);
+        }
+
+        assert_eq!(
+            check_error("a = 0\nif a\n  b = 5\ndone\ndo(write, b)\n"),
+            "5:1: Variable 'b' is not assigned on every path to here"
+        );
+        assert_eq!(
+            check_error("a = 0\nif a\n  b = 5\nelif a > 1\n  b = 6\ndone\nc = b\n"),
+            "7:1: Variable 'b' is not assigned on every path to here"
+        );
+        assert_eq!(
+            check_error("a = 0\nif a\n  b = 5\nelse\n  c = b\ndone\n"),
+            "5:3: Variable 'b' is not assigned on every path to here"
+        );
+        // errors in a branch's assignment aren't reported again.
+        assert_eq!(
+            check("a = 0\nif a\n  b = [1] + {1: 2}\nelse\n  b = [2]\ndone\nc = b\n"),
+            Err(vec!["3:3: Cannot add dicts".to_string()])
+        );
+        // the type is still known, so can't change.
+        assert_eq!(
+            check_error("a = 0\nif a\n  b = 5\ndone\nb = 1.5\n"),
+            "5:1: Variable 'b' was Integer and is now Real"
+        );
+    }
+
+    #[test]
+    fn test_assigned_after_loop() {
+        for listing in [
+            "a = 1
+while a gt 0
+  b = 5
+  a = a - b
+done
+",
+            "a = 1
+b = 0
+while a gt 0
+  b = 5
+  a = a - 1
+done
+do(write, b)
+",
+        ] {
+            assert_eq!(check(listing), Ok(()), "{}", listing);
+        }
+
+        assert_eq!(
+            check_error(
+                "a = 0
+while a gt 0
+  b = 5
+  a = a - 1
+done
+do(write, b)
+"
+            ),
+            "6:1: Variable 'b' is not assigned on every path to here"
+        );
+    }
+
+    #[test]
+    fn test_errors() {
+        assert_eq!(
+            check(concat!(
+                "a = 1 - [1]\n",
+                "b = a + 1\n",
+                "if [1] > 1\n",
+                "  c = 1 - |1|\n",
+                "  d = 1 +* 2\n",
+                "done\n",
+                "[1] and [1]\n",
+            )),
+            Err(vec![
+                "1:1: Cannot subtract coll from integer".to_string(),
+                "3:1: Cannot compare coll with integer".to_string(),
+                "4:3: Cannot subtract seq from integer".to_string(),
+                "5:9: Invalid syntax".to_string(),
+                "7:1: Cannot and collections".to_string(),
+            ])
+        );
+    }
+}
@@ -0,0 +1,79 @@
+use nom::{
+    bytes::complete::tag,
+    character::complete::{newline, space0, space1},
+    combinator::map_res,
+    multi::many0,
+    sequence::{delimited, pair, tuple},
+    IResult,
+};
+
+use super::{
+    expression_parsers::condition, if_parser::done, program_parser::block_statement, tokens::Token,
+};
+
+pub fn while_statement(i: &str) -> IResult<&str, Option<Token>> {
+    log::debug!("[while] parsing '{}'", i);
+    map_res(
+        tuple((
+            delimited(pair(tag("while"), space1), condition, pair(space0, newline)),
+            many0(block_statement),
+            done,
+        )),
+        |(cond, body, _)| -> Result<Option<Token>, nom::error::Error<&str>> {
+            Ok(Some(Token::While {
+                cond: Box::new(cond),
+                body,
+            }))
+        },
+    )(i)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_while() {
+        let result = while_statement("while a\n\ta = 0\ndone\n");
+        assert_eq!(
+            result,
+            Ok((
+                "",
+                Some(Token::While {
+                    cond: Box::new(Token::Arith {
+                        left: Box::new(Token::Term {
+                            left: Box::new(Token::Factor {
+                                value: Box::new(Token::Identifier {
+                                    name: String::from("a"),
+                                }),
+                            }),
+                            right: vec![],
+                        }),
+                        right: vec![],
+                    }),
+                    body: vec![Some(Token::Expression {
+                        source: String::from("a = 0"),
+                        token: Box::new(Token::Assign {
+                            ident: String::from("a"),
+                            expr: Box::new(Token::Arith {
+                                left: Box::new(Token::Term {
+                                    left: Box::new(Token::Factor {
+                                        value: Box::new(Token::Integer { value: 0 }),
+                                    }),
+                                    right: vec![],
+                                }),
+                                right: vec![],
+                            }),
+                        }),
+                    })],
+                })
+            ))
+        );
+    }
+
+    #[test]
+    fn test_while_missing_done() {
+        assert!(while_statement("while a\n  a = 0\n").is_err());
+        assert!(while_statement("whilea\n").is_err());
+    }
+}
@@ -1,7 +1,7 @@
-use crate::asm::Assembler;
-use crate::compiler::Compiler;
+use crate::asm::{disassemble, Assembler, DO_HEADER_PREFIX};
+use crate::compiler::{Compiler, Span};
 use crate::repl::REPL;
-use crate::vm::{is_valid_bytecode, VM};
+use crate::vm::VM;
 
 use clap::Parser;
 use log::LevelFilter;
@@ -19,6 +19,8 @@ pub mod vm;
 #[command(bin_name = "mrdo")]
 enum Cli {
     Args(Args),
+    Fmt(FmtArgs),
+    Disasm(DisasmArgs),
 }
 
 #[derive(clap::Args)]
@@ -41,13 +43,40 @@ struct Args {
 
     #[arg(short = 'r', long)]
     list_reg: bool,
+
+    #[arg(short = 'O', long, default_value_t = 1, value_parser = clap::value_parser!(u8).range(0..=2))]
+    opt_level: u8,
     // TODO: implement this.
     //#[structopt(short, long)]
     //threads: Option<u32>,
 }
 
+/// Rewrites programs in the canonical style.
+#[derive(clap::Args)]
+#[command(author, version, long_about = None)]
+struct FmtArgs {
+    #[arg(required = true, value_hint = clap::ValueHint::FilePath, value_name = "INPUT_FILE")]
+    programs: Vec<std::path::PathBuf>,
+
+    /// List the programs that aren't formatted instead of rewriting them.
+    #[arg(long)]
+    check: bool,
+}
+
+/// Lists bytecode as assembly.
+#[derive(clap::Args)]
+#[command(author, version, long_about = None)]
+struct DisasmArgs {
+    #[arg(value_hint = clap::ValueHint::FilePath, value_name = "INPUT_FILE")]
+    program: std::path::PathBuf,
+}
+
 fn main() {
-    let Cli::Args(args) = Cli::parse();
+    let args = match Cli::parse() {
+        Cli::Args(args) => args,
+        Cli::Fmt(args) => format(&args.programs, args.check),
+        Cli::Disasm(args) => disasm(&args.program),
+    };
 
     if args.debug {
         pretty_env_logger::formatted_timed_builder()
@@ -62,21 +91,82 @@ fn main() {
     match args.program {
         Some(p) => {
             let bytecode = read_bytecode(&p);
-            let bc = match bytecode {
-                Some(bc) => bc,
-                None => compile(&p, args.output, args.list_asm),
+            // The source is kept to show where any runtime error is.
+            let (bc, source) = match bytecode {
+                Some(bc) => (bc, None),
+                None => {
+                    let source = read_assembly(&p);
+                    let bc = compile(&source, args.output, args.list_asm, args.opt_level);
+                    (bc, Some(source))
+                }
             };
             log::info!("Running...");
-            run_bytecode(&bc, args.list_bc, args.list_reg);
+            run_bytecode(&bc, source.as_deref(), args.list_bc, args.list_reg);
         }
         None => run_repl(),
     }
 }
 
+fn format(programs: &[std::path::PathBuf], check: bool) -> ! {
+    let mut failed = false;
+    for program in programs {
+        let source = read_assembly(program);
+        let formatted = match compiler::format(&source) {
+            Ok(formatted) => formatted,
+            Err(errors) => {
+                for e in &errors {
+                    eprintln!("error: {}: {}\n", program.display(), e);
+                }
+                failed = true;
+                continue;
+            }
+        };
+        if formatted == source {
+            continue;
+        }
+        if check {
+            println!("{} is not formatted", program.display());
+            failed = true;
+        } else if let Err(e) = fs::write(program, formatted) {
+            eprintln!("Error writing file {}: {}", program.display(), e);
+            failed = true;
+        }
+    }
+    std::process::exit(if failed { 1 } else { 0 });
+}
+
+fn disasm(program: &std::path::PathBuf) -> ! {
+    let bytecode = match read_bytecode(program) {
+        Some(bc) => bc,
+        None => {
+            eprintln!("error: {} is not bytecode", program.display());
+            std::process::exit(1);
+        }
+    };
+    match disassemble(&bytecode) {
+        Ok(listing) => {
+            print!("{}", listing);
+            std::process::exit(0);
+        }
+        Err(e) => {
+            eprintln!("error: {}", e);
+            std::process::exit(1);
+        }
+    }
+}
+
 fn read_bytecode(tmp: &std::path::PathBuf) -> Option<Vec<u8>> {
-    let bytecode = fs::read(tmp).unwrap();
+    let bytecode = match fs::read(tmp) {
+        Ok(bytecode) => bytecode,
+        Err(e) => {
+            eprintln!("error: {}: {}", tmp.display(), e);
+            std::process::exit(1);
+        }
+    };
 
-    match is_valid_bytecode(&bytecode) {
+    // Anything with the prefix is bytecode, so that bytecode the VM can't run
+    // is reported as such rather than compiled.
+    match bytecode.starts_with(&DO_HEADER_PREFIX) {
         true => Some(bytecode),
         false => None,
     }
@@ -98,24 +188,18 @@ fn run_repl() {
     repl.run();
 }
 
-fn run_bytecode(bytecode: &[u8], list_bc: bool, list_reg: bool) {
+fn run_bytecode(bytecode: &[u8], source: Option<&str>, list_bc: bool, list_reg: bool) {
     let mut vm = VM::new();
     if let Err(e) = vm.set_bytecode(bytecode) {
         println!("vmerror: {}", e);
+        std::process::exit(1);
     }
 
     if list_bc {
-        println!("Listing readonly:");
-        for data in vm.ro_data.chunks(4) {
-            println!("  {:?}", data);
+        match disassemble(bytecode) {
+            Ok(listing) => println!("bytecode\n{}EOF", listing),
+            Err(e) => println!("disassembler error: {}", e),
         }
-        println!("EOF");
-
-        println!("Listing instructions:");
-        for instr in vm.program.chunks(4) {
-            println!("  {:?}", instr);
-        }
-        println!("EOF");
     }
 
     let result = vm.run();
@@ -128,23 +212,33 @@ fn run_bytecode(bytecode: &[u8], list_bc: bool, list_reg: bool) {
         }
         Err(e) => {
             println!("vmerror: {}", e);
+            if let (Some((line, column)), Some(source)) = (e.location(), source) {
+                if let Some(span) = Span::at(source, line as usize, column as usize) {
+                    println!("{}", span);
+                }
+            }
             std::process::exit(1);
         }
     }
 }
 
 fn compile(
-    assembly: &std::path::PathBuf,
+    source: &str,
     output: Option<std::path::PathBuf>,
     list_asm: bool,
+    opt_level: u8,
 ) -> Vec<u8> {
     log::info!("Compiling...");
     let mut compiler = Compiler::new();
+    compiler.set_opt_level(opt_level);
+    compiler.set_debug_info(true);
 
-    let source = read_assembly(assembly);
-    let assembly = compiler.compile(&source);
-    if let Err(e) = assembly {
-        log::error!("compiler error: {}", e);
+    let assembly = compiler.compile(source);
+    if let Err(errors) = assembly {
+        for e in &errors {
+            eprintln!("error: {}\n", e);
+        }
+        eprintln!("{} error(s) found", errors.len());
         std::process::exit(1);
     }
 
@@ -1,8 +1,8 @@
 use crate::asm::program_parsers::program;
-use crate::asm::Assembler;
+use crate::asm::{disassemble, disassemble_code, Assembler};
 use crate::compiler::Compiler;
 use crate::repl::command_parser::CommandParser;
-use crate::vm::VM;
+use crate::vm::{is_valid_bytecode, VM};
 
 use std::fs;
 use std::io;
@@ -66,8 +66,10 @@ impl REPL {
                     Mode::Assembly => vec![buffer],
                     Mode::Highlevel => match self.compiler.compile_expr(buffer.as_str()) {
                         Ok(compiled) => compiled.to_vec(),
-                        Err(e) => {
-                            println!("{} Unable to compile input: {}", ERROR_TAG, e);
+                        Err(errors) => {
+                            for e in errors {
+                                println!("{} Unable to compile input: {}", ERROR_TAG, e);
+                            }
                             continue;
                         }
                     },
@@ -145,11 +147,23 @@ impl REPL {
             }
             println!("{} EOF", INFO_TAG);
         } else if args[0] == "bc" {
-            println!("{} Listing bytecode:", INFO_TAG);
-            for instr in self.vm.program.chunks(4) {
-                println!("  {:?}", instr);
+            // Programs loaded from a file have a header, but those typed in
+            // don't.
+            let listing = if is_valid_bytecode(&self.vm.program) {
+                disassemble(&self.vm.program)
+            } else {
+                disassemble_code(&self.vm.program)
+            };
+            match listing {
+                Ok(listing) => {
+                    println!("{} Listing bytecode:", INFO_TAG);
+                    for line in listing.lines() {
+                        println!("  {}", line);
+                    }
+                    println!("{} EOF", INFO_TAG);
+                }
+                Err(e) => println!("{} {}", ERROR_TAG, e),
             }
-            println!("{} EOF", INFO_TAG);
         }
     }
 
@@ -187,9 +201,8 @@ impl REPL {
                 self.vm = VM::new();
                 self.vm.set_bytecode(&assembled).unwrap();
                 self.list_program(&[]);
-                let result = self.vm.run();
-                if result.is_err() {
-                    println!("{} Runtime error: {}", ERROR_TAG, result.unwrap_err());
+                if let Err(e) = self.vm.run() {
+                    println!("{} Runtime error: {}", ERROR_TAG, e);
                 }
             }
             Err(errors) => {
@@ -1,4 +1,5 @@
 use crate::vm::error::Error;
+use crate::vm::nested_opcode::broadcast;
 use crate::vm::register::*;
 use crate::vm::VM;
 
@@ -15,6 +16,11 @@ impl VM {
         let b_reg = self.get_register(b_idx)?;
 
         match self.get_register(out_idx)? {
+            Register::D(_) => return Err(Error::new("Cannot add into a dict register")),
+            Register::N(_) => {
+                self.nregisters[idx_from_nested_register(out_idx) as usize] =
+                    broadcast(a_reg, b_reg, "add", |a, b| a + b)?;
+            }
             Register::I(_) => {
                 let a: i32 = a_reg.try_into()?;
                 let b: i32 = b_reg.try_into()?;
@@ -66,6 +72,11 @@ impl VM {
         let b_reg = self.get_register(b_idx)?;
 
         match self.get_register(out_idx)? {
+            Register::D(_) => return Err(Error::new("Cannot sub into a dict register")),
+            Register::N(_) => {
+                self.nregisters[idx_from_nested_register(out_idx) as usize] =
+                    broadcast(a_reg, b_reg, "sub", |a, b| a - b)?;
+            }
             Register::I(_) => {
                 let a: i32 = a_reg.try_into()?;
                 let b: i32 = b_reg.try_into()?;
@@ -114,6 +125,11 @@ impl VM {
         let b_reg = self.get_register(b_idx)?;
 
         match self.get_register(out_idx)? {
+            Register::D(_) => return Err(Error::new("Cannot mul into a dict register")),
+            Register::N(_) => {
+                self.nregisters[idx_from_nested_register(out_idx) as usize] =
+                    broadcast(a_reg, b_reg, "mul", |a, b| a * b)?;
+            }
             Register::I(_) => {
                 let a: i32 = a_reg.try_into()?;
                 let b: i32 = b_reg.try_into()?;
@@ -165,6 +181,11 @@ impl VM {
         let b_reg = self.get_register(b_idx)?;
 
         match self.get_register(out_idx)? {
+            Register::D(_) => return Err(Error::new("Cannot div into a dict register")),
+            Register::N(_) => {
+                self.nregisters[idx_from_nested_register(out_idx) as usize] =
+                    broadcast(a_reg, b_reg, "div", |a, b| a / b)?;
+            }
             Register::I(_) => {
                 let a: i32 = a_reg.try_into()?;
                 let b: i32 = b_reg.try_into()?;
@@ -320,6 +341,23 @@ mod tests {
         assert!(exit.is_err());
     }
 
+    #[test]
+    fn test_opcode_add_vector_to_nested() {
+        let mut vm = VM::new();
+        vm.nregisters[0] = vec![vec![1.0, 2.0], vec![3.0, 4.0]];
+        vm.vregisters[0] = vec![10.0, 20.0];
+        vm.program = vec![
+            Opcode::ADD as u8,
+            nested_register_to_idx(1),
+            nested_register_to_idx(0),
+            vector_register_to_idx(0),
+        ];
+        let exit = vm.step();
+        assert!(exit.is_ok());
+        assert!(!exit.unwrap());
+        assert_eq!(vm.nregisters[1], vec![vec![11.0, 22.0], vec![13.0, 24.0]]);
+    }
+
     #[test]
     fn test_opcode_sub_integer_from_real_to_integer() {
         let mut vm = VM::new();
@@ -363,7 +401,7 @@ mod tests {
         let exit = vm.step();
         assert!(exit.is_ok());
         assert!(!exit.unwrap());
-        for (i, vreg) in vec![-1.0, -1.0, -0.9].iter().enumerate() {
+        for (i, vreg) in [-1.0, -1.0, -0.9].iter().enumerate() {
             assert_approx_eq!(vm.vregisters[0][i], vreg);
         }
     }
@@ -382,7 +420,7 @@ mod tests {
         let exit = vm.step();
         assert!(exit.is_ok());
         assert!(!exit.unwrap());
-        for (i, vreg) in vec![-0.2, 0.8, 1.9].iter().enumerate() {
+        for (i, vreg) in [-0.2, 0.8, 1.9].iter().enumerate() {
             assert_approx_eq!(vm.vregisters[0][i], vreg);
         }
     }
@@ -474,7 +512,7 @@ mod tests {
         let exit = vm.step();
         assert!(exit.is_ok());
         assert!(!exit.unwrap());
-        for (i, vreg) in vec![1.2, 2.4, 3.72].iter().enumerate() {
+        for (i, vreg) in [1.2, 2.4, 3.72].iter().enumerate() {
             assert_approx_eq!(vm.vregisters[0][i], vreg);
         }
     }
@@ -493,7 +531,7 @@ mod tests {
         let exit = vm.step();
         assert!(exit.is_ok());
         assert!(!exit.unwrap());
-        for (i, vreg) in vec![1.2, 2.4, 3.72].iter().enumerate() {
+        for (i, vreg) in [1.2, 2.4, 3.72].iter().enumerate() {
             assert_approx_eq!(vm.vregisters[0][i], vreg);
         }
     }
@@ -512,7 +550,7 @@ mod tests {
         let exit = vm.step();
         assert!(exit.is_ok());
         assert!(!exit.unwrap());
-        for (i, vreg) in vec![2.0, 4.0, 6.2].iter().enumerate() {
+        for (i, vreg) in [2.0, 4.0, 6.2].iter().enumerate() {
             assert_approx_eq!(vm.vregisters[0][i], vreg);
         }
     }
@@ -531,7 +569,7 @@ mod tests {
         let exit = vm.step();
         assert!(exit.is_ok());
         assert!(!exit.unwrap());
-        for (i, vreg) in vec![2.0, 4.0, 6.2].iter().enumerate() {
+        for (i, vreg) in [2.0, 4.0, 6.2].iter().enumerate() {
             assert_approx_eq!(vm.vregisters[0][i], vreg);
         }
     }
@@ -600,7 +638,7 @@ mod tests {
         let exit = vm.step();
         assert!(exit.is_ok());
         assert!(!exit.unwrap());
-        for (i, vreg) in vec![0.5, 0.6666666, 0.775].iter().enumerate() {
+        for (i, vreg) in [0.5, 0.6666666, 0.775].iter().enumerate() {
             assert_approx_eq!(vm.vregisters[0][i], vreg);
         }
 
@@ -617,7 +655,7 @@ mod tests {
         let exit = vm.step();
         assert!(exit.is_ok());
         assert!(!exit.unwrap());
-        for (i, vreg) in vec![0.8333333, 1.6666666, 2.5833333].iter().enumerate() {
+        for (i, vreg) in [0.8333333, 1.6666666, 2.5833333].iter().enumerate() {
             assert_approx_eq!(vm.vregisters[0][i], vreg);
         }
 
@@ -0,0 +1,237 @@
+use crate::vm::error::Error;
+use crate::vm::register::Register;
+use crate::vm::VM;
+
+// The most calls that can be in progress at once, so runaway recursion fails
+// rather than using up all the memory.  Functions applied by builtins also
+// recurse in the VM itself, which this keeps within its stack.
+const MAX_CALL_DEPTH: usize = 512;
+
+// The state of the caller, restored when the callee returns.
+pub struct Frame {
+    return_pc: usize,
+    iregisters: [i32; 32],
+    rregisters: [f64; 32],
+    vregisters: [Vec<f64>; 32],
+    dregisters: [Vec<(f64, f64)>; 32],
+    nregisters: [Vec<Vec<f64>>; 32],
+}
+
+impl VM {
+    pub fn call(&mut self) -> Result<(), Error> {
+        let target = self.next_u32() as usize;
+        self.enter(target)
+    }
+
+    // Saves the caller's state and jumps to the function at `target`.
+    pub(super) fn enter(&mut self, target: usize) -> Result<(), Error> {
+        self.check_target(target)?;
+        if self.call_stack.len() >= MAX_CALL_DEPTH {
+            return Err(Error::new(&format!(
+                "Too many nested calls (more than {})",
+                MAX_CALL_DEPTH
+            )));
+        }
+        self.call_stack.push(Frame {
+            return_pc: self.pc,
+            iregisters: self.iregisters,
+            rregisters: self.rregisters,
+            vregisters: self.vregisters.clone(),
+            dregisters: self.dregisters.clone(),
+            nregisters: self.nregisters.clone(),
+        });
+        self.pc = target;
+        Ok(())
+    }
+
+    // Calls the function at `target` with the arguments and runs it until it
+    // returns, giving back the value it returned.
+    pub(super) fn apply(&mut self, target: usize, args: &[Register]) -> Result<Register, Error> {
+        let depth = self.call_stack.len();
+        self.stack.extend_from_slice(args);
+        self.enter(target)?;
+        while self.call_stack.len() > depth {
+            if self.step()? {
+                return Err(Error::new("Cannot halt inside an applied function"));
+            }
+        }
+        match self.stack.pop() {
+            Some(value) => Ok(value),
+            None => Err(Error::new("Applied function did not return a value")),
+        }
+    }
+
+    pub fn ret(&mut self) -> Result<(), Error> {
+        let frame = match self.call_stack.pop() {
+            Some(frame) => frame,
+            None => return Err(Error::new("Cannot return from outside a function")),
+        };
+
+        self.iregisters = frame.iregisters;
+        self.rregisters = frame.rregisters;
+        self.vregisters = frame.vregisters;
+        self.dregisters = frame.dregisters;
+        self.nregisters = frame.nregisters;
+        self.pc = frame.return_pc;
+        Ok(())
+    }
+
+    pub fn push(&mut self) -> Result<(), Error> {
+        let reg_idx = self.next_u8();
+        let reg = self.get_register(reg_idx)?;
+        self.stack.push(reg);
+
+        // swallow the padding bytes.
+        self.next_u8();
+        self.next_u8();
+        Ok(())
+    }
+
+    pub fn pop(&mut self) -> Result<(), Error> {
+        let reg_idx = self.next_u8();
+        let value: Register = match self.stack.pop() {
+            Some(value) => value,
+            None => return Err(Error::new("Cannot pop from an empty stack")),
+        };
+        self.set_register(reg_idx, value)?;
+
+        // swallow the padding bytes.
+        self.next_u8();
+        self.next_u8();
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::asm::opcode::Opcode;
+    use crate::vm::register::*;
+
+    #[test]
+    fn test_opcode_call_ret() {
+        let mut vm = VM::new();
+        vm.iregisters[0] = 42;
+        vm.rregisters[1] = 4.2;
+        vm.program = vec![
+            Opcode::CALL as u8,
+            0,
+            0,
+            0,
+            9,
+            Opcode::HLT as u8,
+            0,
+            0,
+            0,
+            Opcode::LOAD as u8,
+            0,
+            0,
+            0,
+            0,
+            7,
+            Opcode::RET as u8,
+            0,
+            0,
+            0,
+        ];
+        let exit = vm.step();
+        assert!(exit.is_ok());
+        assert!(!exit.unwrap());
+        assert_eq!(vm.pc, 9);
+        assert_eq!(vm.call_stack.len(), 1);
+
+        // the callee can use registers freely...
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.iregisters[0], 7);
+
+        // ... and they are restored on return.
+        let exit = vm.step();
+        assert!(exit.is_ok());
+        assert!(!exit.unwrap());
+        assert_eq!(vm.pc, 5);
+        assert_eq!(vm.iregisters[0], 42);
+        assert_eq!(vm.rregisters[1], 4.2);
+        assert!(vm.call_stack.is_empty());
+    }
+
+    #[test]
+    fn test_opcode_call_depth() {
+        // a function that calls itself forever.
+        let mut vm = VM::new();
+        vm.program = vec![Opcode::CALL as u8, 0, 0, 0, 0];
+        for _ in 0..MAX_CALL_DEPTH {
+            assert!(vm.step().is_ok());
+        }
+        assert_eq!(
+            vm.step().unwrap_err().to_string(),
+            "\u{2620} Too many nested calls (more than 512)"
+        );
+    }
+
+    #[test]
+    fn test_opcode_ret_outside_function() {
+        let mut vm = VM::new();
+        vm.program = vec![Opcode::RET as u8, 0, 0, 0];
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_opcode_push_pop() {
+        let mut vm = VM::new();
+        vm.iregisters[0] = 42;
+        vm.vregisters[1] = vec![1.0, 2.0];
+        vm.program = vec![
+            Opcode::PUSH as u8,
+            0,
+            0,
+            0,
+            Opcode::PUSH as u8,
+            vector_register_to_idx(1),
+            0,
+            0,
+            Opcode::POP as u8,
+            vector_register_to_idx(2),
+            0,
+            0,
+            Opcode::POP as u8,
+            real_register_to_idx(3),
+            0,
+            0,
+        ];
+        assert!(vm.step().is_ok());
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.stack.len(), 2);
+
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.vregisters[2], vec![1.0, 2.0]);
+
+        // integers are converted to reals when popped into a real register.
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.rregisters[3], 42.0);
+        assert!(vm.stack.is_empty());
+        assert_eq!(vm.pc, 16);
+    }
+
+    #[test]
+    fn test_opcode_pop_empty() {
+        let mut vm = VM::new();
+        vm.program = vec![Opcode::POP as u8, 0, 0, 0];
+        assert!(vm.step().is_err());
+
+        let mut vm = VM::new();
+        vm.vregisters[0] = vec![1.0];
+        vm.program = vec![
+            Opcode::PUSH as u8,
+            vector_register_to_idx(0),
+            0,
+            0,
+            Opcode::POP as u8,
+            0,
+            0,
+            0,
+        ];
+        assert!(vm.step().is_ok());
+        assert!(vm.step().is_err());
+    }
+}
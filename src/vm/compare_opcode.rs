@@ -4,233 +4,56 @@ use crate::vm::VM;
 
 use std::convert::TryInto;
 
-impl VM {
-    pub fn eq(&mut self) -> Result<(), Error> {
-        let out_idx = self.next_u8();
-
-        if !is_int_register(out_idx) {
-            return Err(Error::new(
-                "Comparison operators require integer output registers",
-            ));
-        }
-
-        let a_idx = self.next_u8();
-        let b_idx = self.next_u8();
+// The comparisons made by the compare and conditional jump opcodes.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Comparison {
+    Eq,
+    Neq,
+    Gt,
+    Lt,
+    Gte,
+    Lte,
+}
 
-        if self.are_register_contents_equal(a_idx, b_idx)? {
-            self.iregisters[out_idx as usize] = 1;
-        } else {
-            self.iregisters[out_idx as usize] = 0;
+impl Comparison {
+    fn test<T: PartialOrd>(self, a: T, b: T) -> bool {
+        match self {
+            Comparison::Eq => a == b,
+            Comparison::Neq => a != b,
+            Comparison::Gt => a > b,
+            Comparison::Lt => a < b,
+            Comparison::Gte => a >= b,
+            Comparison::Lte => a <= b,
         }
+    }
+}
 
-        Ok(())
+impl VM {
+    pub fn eq(&mut self) -> Result<(), Error> {
+        self.compare(Comparison::Eq)
     }
 
     pub fn neq(&mut self) -> Result<(), Error> {
-        let out_idx = self.next_u8();
-
-        if !is_int_register(out_idx) {
-            return Err(Error::new(
-                "Comparison operators require integer output registers",
-            ));
-        }
-
-        let a_idx = self.next_u8();
-        let b_idx = self.next_u8();
-
-        let a_reg = self.get_register(a_idx)?;
-        let b_reg = self.get_register(b_idx)?;
-
-        match self.get_register(a_idx)? {
-            Register::I(_) => {
-                let a: i32 = a_reg.try_into()?;
-                let b: i32 = b_reg.try_into()?;
-
-                if a != b {
-                    self.iregisters[out_idx as usize] = 1;
-                } else {
-                    self.iregisters[out_idx as usize] = 0;
-                }
-            }
-            Register::R(_) => {
-                let a: f64 = a_reg.try_into()?;
-                let b: f64 = b_reg.try_into()?;
-
-                if (a - b).abs() > f64::EPSILON {
-                    self.iregisters[out_idx as usize] = 1;
-                } else {
-                    self.iregisters[out_idx as usize] = 0;
-                }
-            }
-            Register::V(va) => {
-                if let Register::V(vb) = b_reg {
-                    if va != vb {
-                        self.iregisters[out_idx as usize] = 1;
-                    } else {
-                        self.iregisters[out_idx as usize] = 0;
-                    }
-                } else {
-                    return Err(Error::new("Cannot compare vectors with integers or reals"));
-                }
-            }
-        }
-        Ok(())
+        self.compare(Comparison::Neq)
     }
 
     pub fn gt(&mut self) -> Result<(), Error> {
-        let out_idx = self.next_u8();
-
-        if !is_int_register(out_idx) {
-            return Err(Error::new(
-                "Comparison operators require integer output registers",
-            ));
-        }
-
-        let a_idx = self.next_u8();
-        let b_idx = self.next_u8();
-
-        let a_reg = self.get_register(a_idx)?;
-        let b_reg = self.get_register(b_idx)?;
-
-        match self.get_register(a_idx)? {
-            Register::I(_) => {
-                let a: i32 = a_reg.try_into()?;
-                let b: i32 = b_reg.try_into()?;
-
-                if a > b {
-                    self.iregisters[out_idx as usize] = 1;
-                } else {
-                    self.iregisters[out_idx as usize] = 0;
-                }
-            }
-            Register::R(_) => {
-                let a: f64 = a_reg.try_into()?;
-                let b: f64 = b_reg.try_into()?;
-
-                if a > b {
-                    self.iregisters[out_idx as usize] = 1;
-                } else {
-                    self.iregisters[out_idx as usize] = 0;
-                }
-            }
-            Register::V(va) => {
-                if let Register::V(vb) = b_reg {
-                    if va > vb {
-                        self.iregisters[out_idx as usize] = 1;
-                    } else {
-                        self.iregisters[out_idx as usize] = 0;
-                    }
-                } else {
-                    return Err(Error::new("Cannot compare vectors with integers or reals"));
-                }
-            }
-        }
-        Ok(())
+        self.compare(Comparison::Gt)
     }
 
     pub fn lt(&mut self) -> Result<(), Error> {
-        let out_idx = self.next_u8();
-
-        if !is_int_register(out_idx) {
-            return Err(Error::new(
-                "Comparison operators require integer output registers",
-            ));
-        }
-
-        let a_idx = self.next_u8();
-        let b_idx = self.next_u8();
-
-        let a_reg = self.get_register(a_idx)?;
-        let b_reg = self.get_register(b_idx)?;
-
-        match self.get_register(a_idx)? {
-            Register::I(_) => {
-                let a: i32 = a_reg.try_into()?;
-                let b: i32 = b_reg.try_into()?;
-
-                if a < b {
-                    self.iregisters[out_idx as usize] = 1;
-                } else {
-                    self.iregisters[out_idx as usize] = 0;
-                }
-            }
-            Register::R(_) => {
-                let a: f64 = a_reg.try_into()?;
-                let b: f64 = b_reg.try_into()?;
-
-                if a < b {
-                    self.iregisters[out_idx as usize] = 1;
-                } else {
-                    self.iregisters[out_idx as usize] = 0;
-                }
-            }
-            Register::V(va) => {
-                if let Register::V(vb) = b_reg {
-                    if va < vb {
-                        self.iregisters[out_idx as usize] = 1;
-                    } else {
-                        self.iregisters[out_idx as usize] = 0;
-                    }
-                } else {
-                    return Err(Error::new("Cannot compare vectors with integers or reals"));
-                }
-            }
-        }
-        Ok(())
+        self.compare(Comparison::Lt)
     }
 
     pub fn gte(&mut self) -> Result<(), Error> {
-        let out_idx = self.next_u8();
-
-        if !is_int_register(out_idx) {
-            return Err(Error::new(
-                "Comparison operators require integer output registers",
-            ));
-        }
-
-        let a_idx = self.next_u8();
-        let b_idx = self.next_u8();
-
-        let a_reg = self.get_register(a_idx)?;
-        let b_reg = self.get_register(b_idx)?;
-
-        match self.get_register(a_idx)? {
-            Register::I(_) => {
-                let a: i32 = a_reg.try_into()?;
-                let b: i32 = b_reg.try_into()?;
-
-                if a >= b {
-                    self.iregisters[out_idx as usize] = 1;
-                } else {
-                    self.iregisters[out_idx as usize] = 0;
-                }
-            }
-            Register::R(_) => {
-                let a: f64 = a_reg.try_into()?;
-                let b: f64 = b_reg.try_into()?;
-
-                if a >= b {
-                    self.iregisters[out_idx as usize] = 1;
-                } else {
-                    self.iregisters[out_idx as usize] = 0;
-                }
-            }
-            Register::V(va) => {
-                if let Register::V(vb) = b_reg {
-                    if va >= vb {
-                        self.iregisters[out_idx as usize] = 1;
-                    } else {
-                        self.iregisters[out_idx as usize] = 0;
-                    }
-                } else {
-                    return Err(Error::new("Cannot compare vectors with integers or reals"));
-                }
-            }
-        }
-        Ok(())
+        self.compare(Comparison::Gte)
     }
 
     pub fn lte(&mut self) -> Result<(), Error> {
+        self.compare(Comparison::Lte)
+    }
+
+    fn compare(&mut self, comparison: Comparison) -> Result<(), Error> {
         let out_idx = self.next_u8();
 
         if !is_int_register(out_idx) {
@@ -242,65 +65,53 @@ impl VM {
         let a_idx = self.next_u8();
         let b_idx = self.next_u8();
 
-        let a_reg = self.get_register(a_idx)?;
-        let b_reg = self.get_register(b_idx)?;
-
-        match self.get_register(a_idx)? {
-            Register::I(_) => {
-                let a: i32 = a_reg.try_into()?;
-                let b: i32 = b_reg.try_into()?;
-
-                if a <= b {
-                    self.iregisters[out_idx as usize] = 1;
-                } else {
-                    self.iregisters[out_idx as usize] = 0;
-                }
-            }
-            Register::R(_) => {
-                let a: f64 = a_reg.try_into()?;
-                let b: f64 = b_reg.try_into()?;
-
-                if a <= b {
-                    self.iregisters[out_idx as usize] = 1;
-                } else {
-                    self.iregisters[out_idx as usize] = 0;
-                }
-            }
-            Register::V(va) => {
-                if let Register::V(vb) = b_reg {
-                    if va <= vb {
-                        self.iregisters[out_idx as usize] = 1;
-                    } else {
-                        self.iregisters[out_idx as usize] = 0;
-                    }
-                } else {
-                    return Err(Error::new("Cannot compare vectors with integers or reals"));
-                }
-            }
-        }
+        let result = self.compare_registers(a_idx, b_idx, comparison)?;
+        self.iregisters[out_idx as usize] = result as i32;
         Ok(())
     }
 
-    pub(super) fn are_register_contents_equal(&self, a_idx: u8, b_idx: u8) -> Result<bool, Error> {
-        let a_reg = self.get_register(a_idx)?;
-        let b_reg = self.get_register(b_idx)?;
+    pub(super) fn compare_registers(
+        &self,
+        a_idx: u8,
+        b_idx: u8,
+        comparison: Comparison,
+    ) -> Result<bool, Error> {
+        VM::compare_values(
+            self.get_register(a_idx)?,
+            self.get_register(b_idx)?,
+            comparison,
+        )
+    }
 
-        match self.get_register(a_idx)? {
-            Register::I(_) => {
-                let a: i32 = a_reg.try_into()?;
+    // Compares the values as the type of the first.  Reals are equal if they
+    // are within epsilon of each other, and vectors are ordered element by
+    // element.
+    pub(super) fn compare_values(
+        a_reg: Register,
+        b_reg: Register,
+        comparison: Comparison,
+    ) -> Result<bool, Error> {
+        match a_reg {
+            Register::D(_) => Err(Error::new("Cannot compare dicts")),
+            Register::N(_) => Err(Error::new("Cannot compare nested vectors")),
+            Register::I(a) => {
                 let b: i32 = b_reg.try_into()?;
 
-                Ok(a == b)
+                Ok(comparison.test(a, b))
             }
-            Register::R(_) => {
-                let a: f64 = a_reg.try_into()?;
+            Register::R(a) => {
                 let b: f64 = b_reg.try_into()?;
 
-                Ok((a - b).abs() < f64::EPSILON)
+                let equal = (a - b).abs() < f64::EPSILON;
+                Ok(match comparison {
+                    Comparison::Eq => equal,
+                    Comparison::Neq => !equal,
+                    _ => comparison.test(a, b),
+                })
             }
             Register::V(va) => {
                 if let Register::V(vb) = b_reg {
-                    Ok(va == vb)
+                    Ok(comparison.test(va, vb))
                 } else {
                     Err(Error::new("Cannot compare vectors with integers or reals"))
                 }
@@ -0,0 +1,184 @@
+use crate::vm::error::Error;
+use crate::vm::register::*;
+use crate::vm::VM;
+
+use std::iter::zip;
+
+impl VM {
+    fn get_dict(&self, reg: u8) -> Result<Vec<(f64, f64)>, Error> {
+        match self.get_register(reg)? {
+            Register::D(d) => Ok(d),
+            _ => Err(Error::new("Expected a dict register")),
+        }
+    }
+
+    // Builds a dict from a vector of keys and a vector of values.
+    pub fn dict(&mut self) -> Result<(), Error> {
+        let out_idx = self.next_u8();
+        let keys_idx = self.next_u8();
+        let vals_idx = self.next_u8();
+
+        let keys: Vec<f64> = self.get_register(keys_idx)?.try_into()?;
+        let vals: Vec<f64> = self.get_register(vals_idx)?.try_into()?;
+        if keys.len() != vals.len() {
+            return Err(Error::new("Cannot build dict from unequal keys and values"));
+        }
+
+        let mut entries: Vec<(f64, f64)> = Vec::with_capacity(keys.len());
+        for (k, v) in zip(keys, vals) {
+            if entries.iter().any(|(key, _)| *key == k) {
+                return Err(Error::new(&format!("Duplicate key {} in dict", k)));
+            }
+            entries.push((k, v));
+        }
+        self.set_register(out_idx, Register::D(entries))
+    }
+
+    // Looks up the value for a key.
+    pub fn get(&mut self) -> Result<(), Error> {
+        let out_idx = self.next_u8();
+        let dict_idx = self.next_u8();
+        let key_idx = self.next_u8();
+
+        let dict = self.get_dict(dict_idx)?;
+        let key: f64 = self.get_register(key_idx)?.try_into()?;
+        match dict.iter().find(|(k, _)| *k == key) {
+            Some((_, v)) => self.set_register(out_idx, Register::R(*v)),
+            None => Err(Error::new(&format!("Key {} not found in dict", key))),
+        }
+    }
+
+    pub fn keys(&mut self) -> Result<(), Error> {
+        let out_idx = self.next_u8();
+        let dict_idx = self.next_u8();
+
+        // swallow the padding byte.
+        self.next_u8();
+
+        let dict = self.get_dict(dict_idx)?;
+        self.set_register(out_idx, Register::V(dict.iter().map(|(k, _)| *k).collect()))
+    }
+
+    pub fn vals(&mut self) -> Result<(), Error> {
+        let out_idx = self.next_u8();
+        let dict_idx = self.next_u8();
+
+        // swallow the padding byte.
+        self.next_u8();
+
+        let dict = self.get_dict(dict_idx)?;
+        self.set_register(out_idx, Register::V(dict.iter().map(|(_, v)| *v).collect()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::asm::opcode::Opcode;
+
+    #[test]
+    fn test_opcode_dict() {
+        let mut vm = VM::new();
+        vm.vregisters[0] = vec![3.0, 1.0];
+        vm.vregisters[1] = vec![4.5, 2.0];
+        vm.program = vec![
+            Opcode::DICT as u8,
+            dict_register_to_idx(0),
+            vector_register_to_idx(0),
+            vector_register_to_idx(1),
+        ];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.dregisters[0], vec![(3.0, 4.5), (1.0, 2.0)]);
+
+        // unequal lengths.
+        let mut vm = VM::new();
+        vm.vregisters[0] = vec![3.0, 1.0];
+        vm.vregisters[1] = vec![4.5];
+        vm.program = vec![
+            Opcode::DICT as u8,
+            dict_register_to_idx(0),
+            vector_register_to_idx(0),
+            vector_register_to_idx(1),
+        ];
+        assert!(vm.step().is_err());
+
+        // duplicate keys.
+        let mut vm = VM::new();
+        vm.vregisters[0] = vec![3.0, 3.0];
+        vm.vregisters[1] = vec![4.5, 2.0];
+        vm.program = vec![
+            Opcode::DICT as u8,
+            dict_register_to_idx(0),
+            vector_register_to_idx(0),
+            vector_register_to_idx(1),
+        ];
+        assert!(vm.step().is_err());
+
+        // non-dict output.
+        let mut vm = VM::new();
+        vm.program = vec![
+            Opcode::DICT as u8,
+            vector_register_to_idx(2),
+            vector_register_to_idx(0),
+            vector_register_to_idx(1),
+        ];
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_opcode_get() {
+        let mut vm = VM::new();
+        vm.dregisters[1] = vec![(3.0, 4.5), (1.0, 2.0)];
+        vm.iregisters[2] = 1;
+        vm.program = vec![
+            Opcode::GET as u8,
+            real_register_to_idx(0),
+            dict_register_to_idx(1),
+            2,
+        ];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.rregisters[0], 2.0);
+
+        // missing keys.
+        let mut vm = VM::new();
+        vm.dregisters[1] = vec![(3.0, 4.5)];
+        vm.program = vec![
+            Opcode::GET as u8,
+            real_register_to_idx(0),
+            dict_register_to_idx(1),
+            2,
+        ];
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_opcode_keys_vals() {
+        let mut vm = VM::new();
+        vm.dregisters[1] = vec![(3.0, 4.5), (1.0, 2.0)];
+        vm.program = vec![
+            Opcode::KEYS as u8,
+            vector_register_to_idx(0),
+            dict_register_to_idx(1),
+            0,
+            Opcode::VALS as u8,
+            vector_register_to_idx(1),
+            dict_register_to_idx(1),
+            0,
+        ];
+        assert!(vm.step().is_ok());
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.vregisters[0], vec![3.0, 1.0]);
+        assert_eq!(vm.vregisters[1], vec![4.5, 2.0]);
+
+        // non-dict input.
+        let mut vm = VM::new();
+        vm.program = vec![
+            Opcode::KEYS as u8,
+            vector_register_to_idx(0),
+            vector_register_to_idx(1),
+            0,
+        ];
+        assert!(vm.step().is_err());
+    }
+}
@@ -3,19 +3,35 @@ use std::fmt;
 #[derive(Debug)]
 pub struct Error {
     details: String,
+    // The line and column of the statement that failed, if known.
+    location: Option<(u32, u32)>,
 }
 
 impl Error {
     pub fn new(msg: &str) -> Error {
         Error {
             details: msg.to_string(),
+            location: None,
         }
     }
+
+    // Marks where the error was found, unless it's already known.
+    pub fn with_location(mut self, location: (u32, u32)) -> Error {
+        self.location.get_or_insert(location);
+        self
+    }
+
+    pub fn location(&self) -> Option<(u32, u32)> {
+        self.location
+    }
 }
 
 impl fmt::Display for Error {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "\u{2620} {}", self.details)
+        match self.location {
+            Some((line, column)) => write!(f, "\u{2620} {}:{}: {}", line, column, self.details),
+            None => write!(f, "\u{2620} {}", self.details),
+        }
     }
 }
 
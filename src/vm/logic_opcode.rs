@@ -52,6 +52,8 @@ impl VM {
         let b_reg = self.get_register(b_idx)?;
 
         match self.get_register(out_idx)? {
+            Register::D(_) => return Err(Error::new("Cannot and into a dict register")),
+            Register::N(_) => return Err(Error::new("Cannot and into a nested register")),
             Register::I(_) => {
                 let a: i32 = a_reg.try_into()?;
                 let b: i32 = b_reg.try_into()?;
@@ -103,6 +105,8 @@ impl VM {
         let b_reg = self.get_register(b_idx)?;
 
         match self.get_register(out_idx)? {
+            Register::D(_) => return Err(Error::new("Cannot or into a dict register")),
+            Register::N(_) => return Err(Error::new("Cannot or into a nested register")),
             Register::I(_) => {
                 let a: i32 = a_reg.try_into()?;
                 let b: i32 = b_reg.try_into()?;
@@ -152,6 +156,8 @@ impl VM {
         let in_reg = self.get_register(in_idx)?;
 
         match self.get_register(out_idx)? {
+            Register::D(_) => return Err(Error::new("Cannot not into a dict register")),
+            Register::N(_) => return Err(Error::new("Cannot not into a nested register")),
             Register::I(_) => {
                 let a: i32 = in_reg.try_into()?;
 
@@ -1,30 +1,135 @@
 use crate::asm::opcode::Opcode;
 use crate::asm::syscalls::Syscall;
-use crate::asm::{DO_HEADER_LEN, DO_HEADER_PREFIX};
+use crate::asm::{
+    DebugInfo, Header, DO_LABEL_TARGET, DO_SECTION_CODE, DO_SECTION_DEBUG, DO_SECTION_READONLY,
+};
+use crate::vm::call_opcode::Frame;
+use crate::vm::compare_opcode::Comparison;
 use crate::vm::error::Error;
 use crate::vm::register::*;
 
+use std::collections::HashMap;
 use std::convert::{TryFrom, TryInto};
 use std::default::Default;
 
 mod arith_opcode;
+mod call_opcode;
 mod compare_opcode;
+mod dict_opcode;
 mod error;
 mod logic_opcode;
+mod nested_opcode;
 pub mod register;
+mod vector_opcode;
 
 pub struct VM {
     pub iregisters: [i32; 32],
     pub rregisters: [f64; 32],
     pub vregisters: [Vec<f64>; 32],
+    pub dregisters: [Vec<(f64, f64)>; 32],
+    pub nregisters: [Vec<Vec<f64>>; 32],
     pub program: Vec<u8>,
     heap: Vec<u8>,
+    // The start and length of the block on the heap that each address holding
+    // a collection points to, so storing there again can reuse the block.
+    blocks: HashMap<usize, (usize, usize)>,
     pc: usize,
+    // Where the code section ends, so the VM never runs the debug info.  Unset
+    // when the program was not loaded from bytecode.
+    code_end: Option<usize>,
     pub ro_data: Vec<u8>,
+    call_stack: Vec<Frame>,
+    stack: Vec<Register>,
+    debug: DebugInfo,
+}
+
+// Formats a vector as, for example, `[ 1, 2.5 ]`.
+fn format_vector(v: &[f64]) -> String {
+    if v.is_empty() {
+        return "[ ]".to_string();
+    }
+    let elements: Vec<String> = v.iter().map(|e| e.to_string()).collect();
+    format!("[ {} ]", elements.join(", "))
+}
+
+// Formats a nested vector as, for example, `[ [ 1, 2 ], [ 3 ] ]`.
+fn format_nested(n: &[Vec<f64>]) -> String {
+    if n.is_empty() {
+        return "[ ]".to_string();
+    }
+    let rows: Vec<String> = n.iter().map(|row| format_vector(row)).collect();
+    format!("[ {} ]", rows.join(", "))
+}
+
+// Parses a line of input as the same type as the register.  Collections are
+// separated by whitespace or commas.
+fn parse_input(line: &str, like: &Register) -> Result<Register, Error> {
+    let parse_real = |s: &str| {
+        s.parse::<f64>()
+            .map_err(|_| Error::new(&format!("Cannot read '{}' as a real", s)))
+    };
+    let line = line.trim();
+    match like {
+        Register::I(_) => line
+            .parse::<i32>()
+            .map(Register::I)
+            .map_err(|_| Error::new(&format!("Cannot read '{}' as an integer", line))),
+        Register::R(_) => parse_real(line).map(Register::R),
+        Register::V(_) => line
+            .split(|c: char| c.is_whitespace() || c == ',')
+            .filter(|s| !s.is_empty())
+            .map(parse_real)
+            .collect::<Result<Vec<f64>, Error>>()
+            .map(Register::V),
+        Register::D(_) => Err(Error::new("Cannot read a dict")),
+        Register::N(_) => Err(Error::new("Cannot read a nested vector")),
+    }
+}
+
+// Formats a dict as, for example, `{ 1: 2, 3: 4.5 }`.
+fn format_dict(d: &[(f64, f64)]) -> String {
+    if d.is_empty() {
+        return "{ }".to_string();
+    }
+    let entries: Vec<String> = d.iter().map(|(k, v)| format!("{}: {}", k, v)).collect();
+    format!("{{ {} }}", entries.join(", "))
+}
+
+// Formats a register as it is written.
+fn format_register(reg: &Register) -> String {
+    match reg {
+        Register::I(i) => i.to_string(),
+        Register::R(r) => r.to_string(),
+        Register::V(v) => format_vector(v),
+        Register::D(d) => format_dict(d),
+        Register::N(n) => format_nested(n),
+    }
+}
+
+// Lays out a collection as runs of reals, each prefixed by its length, for
+// storing on the heap.  Nested vectors are prefixed by their number of rows.
+fn flatten_collection(reg: &Register) -> Vec<f64> {
+    match reg {
+        Register::V(v) => [&[v.len() as f64], v.as_slice()].concat(),
+        Register::D(d) => {
+            let mut values = vec![2.0 * d.len() as f64];
+            values.extend(d.iter().flat_map(|(k, v)| [*k, *v]));
+            values
+        }
+        Register::N(n) => {
+            let mut values = vec![n.len() as f64];
+            for row in n {
+                values.push(row.len() as f64);
+                values.extend(row);
+            }
+            values
+        }
+        Register::I(_) | Register::R(_) => unreachable!(),
+    }
 }
 
 pub fn is_valid_bytecode(bytecode: &[u8]) -> bool {
-    bytecode.len() > DO_HEADER_LEN && bytecode[0..4] == DO_HEADER_PREFIX
+    Header::parse(bytecode).is_ok()
 }
 
 impl VM {
@@ -33,10 +138,17 @@ impl VM {
             iregisters: Default::default(),
             rregisters: Default::default(),
             vregisters: Default::default(),
+            dregisters: Default::default(),
+            nregisters: Default::default(),
             program: vec![],
             heap: vec![],
+            blocks: HashMap::new(),
             pc: 0,
+            code_end: None,
             ro_data: vec![],
+            call_stack: vec![],
+            stack: vec![],
+            debug: DebugInfo::new(),
         }
     }
 
@@ -49,36 +161,43 @@ impl VM {
     }
 
     pub fn set_bytecode(&mut self, bytecode: &[u8]) -> Result<(), Error> {
-        if !is_valid_bytecode(bytecode) {
-            return Err(Error::new(
-                format!("Invalid bytecode '{:#?}'", bytecode).as_str(),
-            ));
-        }
+        let header = Header::parse(bytecode).map_err(|e| Error::new(&e.to_string()))?;
 
         self.program.clear();
         self.program.append(&mut bytecode.to_vec());
 
-        let bytes = &self.program[4..8];
-
-        let ro_len = ((bytes[0] as u32) << 24
-            | (bytes[1] as u32) << 16
-            | (bytes[2] as u32) << 8
-            | (bytes[3] as u32)) as usize;
+        let ro = header.section(DO_SECTION_READONLY);
+        self.ro_data.append(&mut (self.program[ro].to_vec()));
 
-        let ro_end = DO_HEADER_LEN + ro_len;
-
-        if ro_len != 0 {
-            self.ro_data
-                .append(&mut (self.program[DO_HEADER_LEN..ro_end].to_vec()));
-        }
+        let debug = header.section(DO_SECTION_DEBUG);
+        self.debug = DebugInfo::parse(&self.program[debug.clone()], debug.start)
+            .map_err(|e| Error::new(&e.to_string()))?;
 
-        self.pc = ro_end;
+        self.code_end = Some(header.section(DO_SECTION_CODE).end);
+        self.pc = header.entry as usize;
         Ok(())
     }
 
     // Step one instruction. Returns an error or a boolean indicating the program is complete.
     pub fn step(&mut self) -> Result<bool, Error> {
-        if self.pc >= self.program.len() {
+        let pc = self.pc;
+        self.execute().map_err(|e| match self.debug.location(pc) {
+            Some(location) => e.with_location(location),
+            None => e,
+        })
+    }
+
+    // The variables the program recorded as debug info, and their values.
+    pub fn variables(&self) -> Vec<(String, Register)> {
+        self.debug
+            .variables()
+            .into_iter()
+            .filter_map(|(name, reg)| Some((name.to_string(), self.get_register(reg).ok()?)))
+            .collect()
+    }
+
+    fn execute(&mut self) -> Result<bool, Error> {
+        if self.pc >= self.code_end() {
             return Err(Error::new("Ran out of program to run"));
         }
         let opcode = self.decode_opcode();
@@ -94,6 +213,12 @@ impl VM {
                     Register::R(_) => {
                         self.rregisters[idx_from_real_register(register) as usize] = self.next_f64()
                     }
+                    Register::D(_) => {
+                        return Err(Error::new("Cannot load constant into dict register"))
+                    }
+                    Register::N(_) => {
+                        return Err(Error::new("Cannot load constant into nested register"))
+                    }
                     Register::V(_) => {
                         let base_addr_reg = self.next_u8();
                         if !is_int_register(base_addr_reg) {
@@ -132,57 +257,8 @@ impl VM {
                 let dest_reg = self.next_u8();
                 let src_reg = self.next_u8();
 
-                match self.get_register(src_reg)? {
-                    Register::I(si) => {
-                        match self.get_register(dest_reg)? {
-                            Register::I(_) => self.iregisters[dest_reg as usize] = si,
-                            Register::R(_) => {
-                                self.rregisters[idx_from_real_register(dest_reg) as usize] =
-                                    si as f64
-                            }
-                            Register::V(_) => {
-                                return Err(Error::new(
-                                    "Cannot copy from int register to vector register",
-                                ))
-                            }
-                        };
-                    }
-                    Register::R(sr) => {
-                        match self.get_register(dest_reg)? {
-                            Register::I(_) => {
-                                if ((sr as i32) as f64) != sr {
-                                    log::warn!("loss of precision copying {} from real register to integer register", sr)
-                                }
-                                self.iregisters[dest_reg as usize] = sr as i32;
-                            }
-                            Register::R(_) => {
-                                self.rregisters[idx_from_real_register(dest_reg) as usize] = sr
-                            }
-                            Register::V(_) => {
-                                return Err(Error::new(
-                                    "Cannot copy from real register to vector register",
-                                ))
-                            }
-                        };
-                    }
-                    Register::V(sv) => {
-                        match self.get_register(dest_reg)? {
-                            Register::I(_) => {
-                                return Err(Error::new(
-                                    "Cannot copy from vector register to int register",
-                                ))
-                            }
-                            Register::R(_) => {
-                                return Err(Error::new(
-                                    "Cannot copy from vector register to real register",
-                                ))
-                            }
-                            Register::V(_) => {
-                                self.vregisters[idx_from_vector_register(dest_reg) as usize] = sv
-                            }
-                        };
-                    }
-                }
+                let value = self.get_register(src_reg)?;
+                self.set_register(dest_reg, value)?;
 
                 // Throw away the last byte.
                 self.next_u8();
@@ -194,16 +270,24 @@ impl VM {
             Opcode::MUL => self.mul()?,
             Opcode::DIV => self.div()?,
             Opcode::JMP => {
-                let target = self.iregisters[self.next_u8() as usize];
-                self.pc = target as usize;
+                self.pc = self.next_target()?;
             }
+            Opcode::CALL => self.call()?,
+            Opcode::RET => self.ret()?,
             Opcode::EQ => self.eq()?,
             Opcode::NEQ => self.neq()?,
             Opcode::GT => self.gt()?,
             Opcode::LT => self.lt()?,
             Opcode::GTE => self.gte()?,
             Opcode::LTE => self.lte()?,
-            Opcode::JEQ => self.jeq()?,
+            Opcode::JEQ => self.jump_if(Comparison::Eq)?,
+            Opcode::JNE => self.jump_if(Comparison::Neq)?,
+            Opcode::JGT => self.jump_if(Comparison::Gt)?,
+            Opcode::JLT => self.jump_if(Comparison::Lt)?,
+            Opcode::JGTE => self.jump_if(Comparison::Gte)?,
+            Opcode::JLTE => self.jump_if(Comparison::Lte)?,
+            Opcode::JZ => self.jump_if_zero(Comparison::Eq)?,
+            Opcode::JNZ => self.jump_if_zero(Comparison::Neq)?,
             Opcode::AND => self.and()?,
             Opcode::OR => self.or()?,
             Opcode::NOT => self.not()?,
@@ -222,6 +306,27 @@ impl VM {
                 let new_end = self.heap.len() as i32 + bytes;
                 self.heap.resize(new_end as usize, 0);
             }
+            Opcode::PUSH => self.push()?,
+            Opcode::POP => self.pop()?,
+            Opcode::MAP => self.map()?,
+            Opcode::MASK => self.mask()?,
+            Opcode::FOLD => self.fold()?,
+            Opcode::SUM => self.reduce(|acc, e| acc + e)?,
+            Opcode::PROD => self.reduce(|acc, e| acc * e)?,
+            Opcode::MIN => self.reduce(f64::min)?,
+            Opcode::MAX => self.reduce(f64::max)?,
+            Opcode::DICT => self.dict()?,
+            Opcode::GET => self.get()?,
+            Opcode::KEYS => self.keys()?,
+            Opcode::VALS => self.vals()?,
+            Opcode::SORT => self.sort()?,
+            Opcode::FIRST => self.first()?,
+            Opcode::CAT => self.cat()?,
+            Opcode::ZIP => self.zip()?,
+            Opcode::LEN => self.len()?,
+            Opcode::NEST => self.nest()?,
+            Opcode::FLAT => self.flat()?,
+            Opcode::AT => self.at()?,
             Opcode::SYSCALL => {
                 let call_idx = self.next_u8();
                 if !is_int_register(call_idx) {
@@ -232,39 +337,44 @@ impl VM {
                 match Syscall::try_from(call_num) {
                     Ok(call) => {
                         match call {
-                            Syscall::PrintReg => {
+                            Syscall::PrintReg | Syscall::PrintRegInline => {
                                 let reg_idx = self.next_u8();
-                                let reg = self.get_register(reg_idx)?;
-                                match reg {
-                                    Register::I(i) => println!("{}", i),
-                                    Register::R(r) => println!("{}", r),
-                                    Register::V(v) => println!("{:?}", v),
-                                };
+                                let value = format_register(&self.get_register(reg_idx)?);
+                                if call == Syscall::PrintReg {
+                                    println!("{}", value);
+                                } else {
+                                    print!("{}", value);
+                                }
                                 // Swallow the remaining u8.
                                 self.next_u8();
                             }
-                            Syscall::PrintMem => return Err(Error::new("Unimplemented")),
-                            Syscall::PrintStr => {
-                                let offset = self.next_u16() as usize;
-                                let mut end = offset;
-                                let slice = self.ro_data.as_slice();
-
-                                while slice[end] != 0 {
-                                    end += 1;
-                                }
-
-                                let result = std::str::from_utf8(&slice[offset..end]);
-                                match result {
-                                    Ok(s) => {
-                                        println!("{}", s);
-                                    }
+                            Syscall::ReadReg => {
+                                let reg_idx = self.next_u8();
+                                let mut line = String::new();
+                                match std::io::stdin().read_line(&mut line) {
+                                    Ok(0) => return Err(Error::new("Unexpected end of input")),
+                                    Ok(_) => {}
                                     Err(e) => {
                                         return Err(Error::new(&format!(
-                                            "Error decoding string to print: {:#?}",
+                                            "Error reading input: {}",
                                             e
                                         )))
                                     }
                                 };
+                                let value = parse_input(&line, &self.get_register(reg_idx)?)?;
+                                self.set_register(reg_idx, value)?;
+                                // Swallow the remaining u8.
+                                self.next_u8();
+                            }
+                            Syscall::PrintMem => return Err(Error::new("Unimplemented")),
+                            Syscall::PrintStr | Syscall::PrintStrInline => {
+                                let offset = self.next_u16() as usize;
+                                let value = self.read_str(offset)?;
+                                if call == Syscall::PrintStr {
+                                    println!("{}", value);
+                                } else {
+                                    print!("{}", value);
+                                }
                             }
                         }
                     }
@@ -320,6 +430,21 @@ impl VM {
             }
         }
         println!("EOF");
+
+        println!("Listing (non-empty) nested registers:");
+        for (i, reg) in self.nregisters.iter().enumerate() {
+            if !reg.is_empty() {
+                println!("  [{}]\t{:?}", i, reg);
+            }
+        }
+        println!("EOF");
+        if !self.debug.is_empty() {
+            println!("Listing variables:");
+            for (name, value) in self.variables() {
+                println!("  {}\t{}", name, format_register(&value));
+            }
+            println!("EOF");
+        }
     }
 
     fn decode_opcode(&mut self) -> Opcode {
@@ -343,12 +468,74 @@ impl VM {
                 self.vregisters[idx_from_vector_register(reg) as usize].clone(),
             ));
         }
+        if is_nested_register(reg) {
+            return Ok(Register::N(
+                self.nregisters[idx_from_nested_register(reg) as usize].clone(),
+            ));
+        }
+        if is_dict_register(reg) {
+            return Ok(Register::D(
+                self.dregisters[idx_from_dict_register(reg) as usize].clone(),
+            ));
+        }
 
         Err(Error::new(
             format!("Unknown register type {}", reg).as_str(),
         ))
     }
 
+    // Stores the value in the register, converting between integers and reals
+    // as necessary.
+    fn set_register(&mut self, reg: u8, value: Register) -> Result<(), Error> {
+        match (value, self.get_register(reg)?) {
+            (Register::I(si), Register::I(_)) => self.iregisters[reg as usize] = si,
+            (Register::I(si), Register::R(_)) => {
+                self.rregisters[idx_from_real_register(reg) as usize] = si as f64
+            }
+            (Register::R(sr), Register::I(_)) => {
+                if ((sr as i32) as f64) != sr {
+                    log::warn!(
+                        "loss of precision copying {} from real register to integer register",
+                        sr
+                    )
+                }
+                self.iregisters[reg as usize] = sr as i32;
+            }
+            (Register::R(sr), Register::R(_)) => {
+                self.rregisters[idx_from_real_register(reg) as usize] = sr
+            }
+            (Register::V(sv), Register::V(_)) => {
+                self.vregisters[idx_from_vector_register(reg) as usize] = sv
+            }
+            (Register::D(sd), Register::D(_)) => {
+                self.dregisters[idx_from_dict_register(reg) as usize] = sd
+            }
+            (Register::N(sn), Register::N(_)) => {
+                self.nregisters[idx_from_nested_register(reg) as usize] = sn
+            }
+            (value, target) => {
+                return Err(Error::new(&format!(
+                    "Cannot copy from {} register to {} register",
+                    value.class(),
+                    target.class()
+                )))
+            }
+        }
+        Ok(())
+    }
+
+    // Reads the null-terminated string at the offset into the read-only data.
+    fn read_str(&self, offset: usize) -> Result<&str, Error> {
+        let slice = self.ro_data.as_slice();
+        let mut end = offset;
+        while slice[end] != 0 {
+            end += 1;
+        }
+
+        std::str::from_utf8(&slice[offset..end])
+            .map_err(|e| Error::new(&format!("Error decoding string to print: {:#?}", e)))
+    }
+
     fn next_u8(&mut self) -> u8 {
         let result = self.program[self.pc];
         self.pc += 1;
@@ -361,6 +548,12 @@ impl VM {
         u16::from_be_bytes(bytes)
     }
 
+    fn next_u32(&mut self) -> u32 {
+        let bytes: [u8; 4] = self.program[self.pc..self.pc + 4].try_into().unwrap();
+        self.pc += 4;
+        u32::from_be_bytes(bytes)
+    }
+
     fn next_i32(&mut self) -> i32 {
         let bytes: [u8; 4] = self.program[self.pc..self.pc + 4].try_into().unwrap();
         self.pc += 4;
@@ -399,9 +592,22 @@ impl VM {
                 self.rregisters[idx_from_real_register(register) as usize] =
                     f64::from_be_bytes(bytes);
             }
-            Register::V(_) => return Err(Error::new("Cannot load word into vector register")),
+            // Collections are stored elsewhere on the heap with their address
+            // in the word.
+            collection => {
+                let bytes: [u8; 4] = self.load_bytes(address, 4)?.try_into().unwrap();
+                let pointer = i32::from_be_bytes(bytes);
+                if pointer < 0 {
+                    return Err(Error::new("Cannot load collection from negative address"));
+                }
+                let value = self.load_collection(&collection, pointer as usize)?;
+                self.set_register(register, value)?;
+            }
         }
 
+        // swallow the next byte.
+        self.next_u8();
+
         Ok(())
     }
 
@@ -422,8 +628,13 @@ impl VM {
         let bytes = match reg {
             Register::I(i) => i32::to_be_bytes(i).to_vec(),
             Register::R(r) => f64::to_be_bytes(r).to_vec(),
-            Register::V(_) => {
-                return Err(Error::new("Cannot store word from vector register"));
+            collection => {
+                let values: Vec<u8> = flatten_collection(&collection)
+                    .iter()
+                    .flat_map(|value| value.to_be_bytes())
+                    .collect();
+                let pointer = self.store_block(address, &values) as i32;
+                i32::to_be_bytes(pointer).to_vec()
             }
         };
 
@@ -437,18 +648,123 @@ impl VM {
         Ok(())
     }
 
-    fn jeq(&mut self) -> Result<(), Error> {
+    // Writes a collection's values to the block the address points to if they
+    // fit, or it can grow at the end of the heap, and to a new block if not.
+    fn store_block(&mut self, address: usize, values: &[u8]) -> usize {
+        let (start, len) = match self.blocks.get(&address) {
+            Some(&(start, len)) if values.len() <= len => (start, len),
+            Some(&(start, len)) if start + len == self.heap.len() => (start, values.len()),
+            _ => (self.heap.len(), values.len()),
+        };
+        if start + len > self.heap.len() {
+            self.heap.resize(start + len, 0);
+        }
+        self.heap[start..start + values.len()].copy_from_slice(values);
+        self.blocks.insert(address, (start, len));
+        start
+    }
+
+    fn load_bytes(&self, address: usize, count: usize) -> Result<&[u8], Error> {
+        match self.heap.get(address..address.saturating_add(count)) {
+            Some(bytes) => Ok(bytes),
+            None => Err(Error::new("Cannot load beyond the end of the heap")),
+        }
+    }
+
+    fn load_real(&self, address: usize) -> Result<f64, Error> {
+        let bytes: [u8; 8] = self.load_bytes(address, 8)?.try_into().unwrap();
+        Ok(f64::from_be_bytes(bytes))
+    }
+
+    // Reads a run of reals prefixed by its length, giving back the reals and
+    // the address after them.
+    fn load_run(&self, address: usize) -> Result<(Vec<f64>, usize), Error> {
+        let mut values = vec![];
+        let len = self.load_real(address)? as usize;
+        let bytes = self.load_bytes(address + 8, len.saturating_mul(8))?;
+        for chunk in bytes.chunks_exact(8) {
+            values.push(f64::from_be_bytes(chunk.try_into().unwrap()));
+        }
+        Ok((values, address + 8 + bytes.len()))
+    }
+
+    // Reads a collection of the same kind as `register` written by
+    // `flatten_collection`.
+    fn load_collection(&self, register: &Register, address: usize) -> Result<Register, Error> {
+        Ok(match register {
+            Register::V(_) => Register::V(self.load_run(address)?.0),
+            Register::D(_) => {
+                let (values, _) = self.load_run(address)?;
+                Register::D(values.chunks_exact(2).map(|kv| (kv[0], kv[1])).collect())
+            }
+            Register::N(_) => {
+                let rows = self.load_real(address)? as usize;
+                let mut address = address + 8;
+                let mut nested = vec![];
+                for _ in 0..rows {
+                    let (row, next) = self.load_run(address)?;
+                    nested.push(row);
+                    address = next;
+                }
+                Register::N(nested)
+            }
+            Register::I(_) | Register::R(_) => unreachable!(),
+        })
+    }
+
+    fn code_end(&self) -> usize {
+        self.code_end.unwrap_or(self.program.len())
+    }
+
+    // Checks that a jump or call lands inside the code section, when the
+    // program was loaded from bytecode.
+    pub(super) fn check_target(&self, target: usize) -> Result<usize, Error> {
+        if self.code_end.is_some_and(|end| target >= end) {
+            return Err(Error::new(&format!(
+                "Cannot jump to {} outside the program",
+                target
+            )));
+        }
+        Ok(target)
+    }
+
+    // The target of a jump, which is either a label's offset or in a register.
+    fn next_target(&mut self) -> Result<usize, Error> {
         let register = self.next_u8();
+        if register == DO_LABEL_TARGET {
+            let target = self.next_u32() as usize;
+            return self.check_target(target);
+        }
         if !is_int_register(register) {
             return Err(Error::new("Cannot jump to non-integer location"));
         }
-        let target = self.iregisters[register as usize];
+        self.check_target(self.iregisters[register as usize] as usize)
+    }
+
+    fn jump_if(&mut self, comparison: Comparison) -> Result<(), Error> {
+        let target = self.next_target()?;
 
         let a_idx = self.next_u8();
         let b_idx = self.next_u8();
 
-        if self.are_register_contents_equal(a_idx, b_idx)? {
-            self.pc = target as usize;
+        if self.compare_registers(a_idx, b_idx, comparison)? {
+            self.pc = target;
+        }
+        Ok(())
+    }
+
+    // Jumps if the register is, or isn't, equal to zero.
+    fn jump_if_zero(&mut self, comparison: Comparison) -> Result<(), Error> {
+        let start = self.pc;
+        let target = self.next_target()?;
+        let register = self.next_u8();
+        // Skip the padding after a register target.
+        if self.pc - start < 3 {
+            self.next_u8();
+        }
+
+        if VM::compare_values(self.get_register(register)?, Register::I(0), comparison)? {
+            self.pc = target;
         }
         Ok(())
     }
@@ -463,9 +779,46 @@ impl Default for VM {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::asm::{Assembler, Record, DO_HEADER_LEN};
 
     use assert_approx_eq::assert_approx_eq;
 
+    #[test]
+    fn test_parse_input() {
+        assert_eq!(
+            parse_input("42\n", &Register::I(0)).ok(),
+            Some(Register::I(42))
+        );
+        assert!(parse_input("4.2\n", &Register::I(0)).is_err());
+        assert_eq!(
+            parse_input(" 4.2\n", &Register::R(0.0)).ok(),
+            Some(Register::R(4.2))
+        );
+        assert!(parse_input("four\n", &Register::R(0.0)).is_err());
+        assert_eq!(
+            parse_input("1 2, 3,4.5\n", &Register::V(vec![])).ok(),
+            Some(Register::V(vec![1.0, 2.0, 3.0, 4.5]))
+        );
+        assert_eq!(
+            parse_input("\n", &Register::V(vec![])).ok(),
+            Some(Register::V(vec![]))
+        );
+        assert!(parse_input("1 b 3\n", &Register::V(vec![])).is_err());
+    }
+
+    #[test]
+    fn test_format_dict() {
+        assert_eq!(format_dict(&[(1.0, 2.0), (3.0, 4.5)]), "{ 1: 2, 3: 4.5 }");
+        assert_eq!(format_dict(&[]), "{ }");
+    }
+
+    #[test]
+    fn test_format_vector() {
+        assert_eq!(format_vector(&[4.0, 3.0]), "[ 4, 3 ]");
+        assert_eq!(format_vector(&[1.5]), "[ 1.5 ]");
+        assert_eq!(format_vector(&[]), "[ ]");
+    }
+
     #[test]
     fn test_create_vm() {
         let vm = VM::new();
@@ -640,7 +993,7 @@ mod tests {
         let mut vm = VM::new();
         vm.heap = vec![0, 0, 0, 0, 0, 0, 0, 42];
         vm.iregisters[1] = 4;
-        vm.program = vec![Opcode::LW as u8, 0, 1];
+        vm.program = vec![Opcode::LW as u8, 0, 1, 0];
         let exit = vm.step();
         assert!(exit.is_ok());
         assert!(!exit.unwrap());
@@ -649,7 +1002,7 @@ mod tests {
         let mut vm = VM::new();
         vm.heap = vec![64, 16, 204, 204, 204, 204, 204, 255];
         vm.iregisters[0] = 0;
-        vm.program = vec![Opcode::LW as u8, real_register_to_idx(0), 0];
+        vm.program = vec![Opcode::LW as u8, real_register_to_idx(0), 0, 0];
         let exit = vm.step();
         assert!(exit.is_ok());
         assert!(!exit.unwrap());
@@ -658,14 +1011,14 @@ mod tests {
         let mut vm = VM::new();
         vm.heap = vec![0, 0, 0, 0, 0, 0, 0, 42];
         vm.rregisters[1] = 4.0;
-        vm.program = vec![Opcode::LW as u8, 0, real_register_to_idx(1)];
+        vm.program = vec![Opcode::LW as u8, 0, real_register_to_idx(1), 0];
         let exit = vm.step();
         assert!(exit.is_err());
 
         let mut vm = VM::new();
         vm.heap = vec![0, 0, 0, 42];
         vm.iregisters[1] = 0;
-        vm.program = vec![Opcode::LW as u8, vector_register_to_idx(0), 1];
+        vm.program = vec![Opcode::LW as u8, vector_register_to_idx(0), 1, 0];
         let exit = vm.step();
         assert!(exit.is_err());
     }
@@ -695,6 +1048,73 @@ mod tests {
         assert!(vm.step().is_err());
     }
 
+    #[test]
+    fn test_opcode_sw_lw_collections() {
+        let values = [
+            Register::V(vec![1.0, 2.5]),
+            Register::D(vec![(1.0, 2.0), (3.0, 4.0)]),
+            Register::N(vec![vec![1.0], vec![], vec![2.0, 3.0]]),
+        ];
+        for value in values {
+            let to_idx = match value {
+                Register::V(_) => vector_register_to_idx,
+                Register::D(_) => dict_register_to_idx,
+                _ => nested_register_to_idx,
+            };
+            let mut vm = VM::new();
+            vm.heap = vec![0; 8];
+            vm.iregisters[0] = 4;
+            vm.set_register(to_idx(1), value.clone()).unwrap();
+            vm.program = vec![
+                Opcode::SW as u8,
+                0,
+                to_idx(1),
+                0,
+                Opcode::LW as u8,
+                to_idx(2),
+                0,
+                0,
+            ];
+            assert!(vm.step().is_ok());
+            assert_eq!(vm.heap[4..8], [0, 0, 0, 8]);
+            assert!(vm.step().is_ok());
+            assert_eq!(vm.get_register(to_idx(2)).unwrap(), value);
+        }
+
+        // storing again reuses the block when the collection fits, or grows
+        // it at the end of the heap.
+        let mut vm = VM::new();
+        vm.heap = vec![0; 8];
+        vm.program = vec![Opcode::SW as u8, 0, vector_register_to_idx(1), 0];
+        for n in [3, 2, 3, 1, 4] {
+            vm.vregisters[1] = vec![1.5; n];
+            vm.pc = 0;
+            assert!(vm.step().is_ok());
+            assert_eq!(vm.heap[..4], [0, 0, 0, 8]);
+            assert_eq!(vm.load_run(8).unwrap().0, vec![1.5; n]);
+        }
+        assert_eq!(vm.heap.len(), 8 + 5 * 8);
+
+        // a block that can't grow is left for a new one.
+        vm.iregisters[0] = 4;
+        vm.vregisters[1] = vec![2.5];
+        vm.pc = 0;
+        assert!(vm.step().is_ok());
+        vm.iregisters[0] = 0;
+        vm.vregisters[1] = vec![1.5; 5];
+        vm.pc = 0;
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.heap[..4], [0, 0, 0, 64]);
+        assert_eq!(vm.load_run(64).unwrap().0, vec![1.5; 5]);
+        assert_eq!(vm.load_run(48).unwrap().0, vec![2.5]);
+
+        // the stored address is beyond the heap.
+        let mut vm = VM::new();
+        vm.heap = vec![0, 0, 0, 42];
+        vm.program = vec![Opcode::LW as u8, nested_register_to_idx(0), 0, 0];
+        assert!(vm.step().is_err());
+    }
+
     #[test]
     fn test_opcode_jmp() {
         let mut vm = VM::new();
@@ -704,6 +1124,134 @@ mod tests {
         assert!(exit.is_ok());
         assert!(!exit.unwrap());
         assert_eq!(vm.pc, 100);
+
+        // to a label.
+        let mut vm = VM::new();
+        vm.program = vec![Opcode::JMP as u8, DO_LABEL_TARGET, 0, 0, 1, 2];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.pc, 258);
+
+        // past where two bytes reach.
+        let mut vm = VM::new();
+        vm.program = vec![Opcode::JMP as u8, DO_LABEL_TARGET, 0, 1, 0, 2];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.pc, 65538);
+
+        let mut vm = VM::new();
+        vm.program = vec![Opcode::JMP as u8, real_register_to_idx(0), 0, 0];
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_opcode_jeq_label() {
+        let mut vm = VM::new();
+        vm.iregisters[1] = 3;
+        vm.iregisters[2] = 3;
+        vm.program = vec![Opcode::JEQ as u8, DO_LABEL_TARGET, 0, 0, 0, 100, 1, 2];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.pc, 100);
+
+        vm.iregisters[2] = 5;
+        vm.pc = 0;
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.pc, 8);
+    }
+
+    #[test]
+    fn test_opcode_conditional_jumps() {
+        let jumps = |a: Register, b: Register| -> Vec<Opcode> {
+            [
+                Opcode::JEQ,
+                Opcode::JNE,
+                Opcode::JGT,
+                Opcode::JLT,
+                Opcode::JGTE,
+                Opcode::JLTE,
+            ]
+            .into_iter()
+            .filter(|&opcode| {
+                let mut vm = VM::new();
+                vm.set_register(vector_register_to_idx(1), Register::V(vec![]))
+                    .unwrap();
+                let (a_idx, b_idx) = match (&a, &b) {
+                    (Register::V(_), _) => (vector_register_to_idx(0), vector_register_to_idx(1)),
+                    (Register::R(_), _) => (real_register_to_idx(0), real_register_to_idx(1)),
+                    _ => (0, 1),
+                };
+                vm.set_register(a_idx, a.clone()).unwrap();
+                vm.set_register(b_idx, b.clone()).unwrap();
+                vm.program = vec![opcode as u8, DO_LABEL_TARGET, 0, 0, 0, 100, a_idx, b_idx];
+                vm.step().unwrap();
+                vm.pc == 100
+            })
+            .collect()
+        };
+
+        assert_eq!(
+            jumps(Register::I(3), Register::I(3)),
+            vec![Opcode::JEQ, Opcode::JGTE, Opcode::JLTE]
+        );
+        assert_eq!(
+            jumps(Register::I(2), Register::I(3)),
+            vec![Opcode::JNE, Opcode::JLT, Opcode::JLTE]
+        );
+        assert_eq!(
+            jumps(Register::R(3.5), Register::R(2.5)),
+            vec![Opcode::JNE, Opcode::JGT, Opcode::JGTE]
+        );
+        assert_eq!(
+            jumps(Register::R(0.1 + 0.2), Register::R(0.3)),
+            vec![Opcode::JEQ, Opcode::JGT, Opcode::JGTE]
+        );
+        assert_eq!(
+            jumps(
+                Register::V(vec![1.0, 3.0]),
+                Register::V(vec![1.0, 2.0, 4.0])
+            ),
+            vec![Opcode::JNE, Opcode::JGT, Opcode::JGTE]
+        );
+
+        // vectors can only be compared with vectors.
+        let mut vm = VM::new();
+        vm.program = vec![Opcode::JLT as u8, 0, vector_register_to_idx(0), 1];
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_opcode_jz() {
+        let mut vm = VM::new();
+        vm.iregisters[0] = 100;
+        vm.program = vec![Opcode::JZ as u8, 0, 1, 0];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.pc, 100);
+
+        vm.pc = 0;
+        vm.iregisters[1] = 2;
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.pc, 4);
+
+        let mut vm = VM::new();
+        vm.rregisters[0] = 0.5;
+        vm.program = vec![
+            Opcode::JNZ as u8,
+            DO_LABEL_TARGET,
+            0,
+            0,
+            0,
+            100,
+            real_register_to_idx(0),
+        ];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.pc, 100);
+
+        vm.pc = 0;
+        vm.rregisters[0] = 0.0;
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.pc, 7);
+
+        let mut vm = VM::new();
+        vm.program = vec![Opcode::JZ as u8, 0, dict_register_to_idx(0), 0];
+        assert!(vm.step().is_err());
     }
 
     #[test]
@@ -820,6 +1368,29 @@ mod tests {
         assert!(!exit.unwrap());
     }
 
+    #[test]
+    fn test_opcode_syscall_print_inline() {
+        let mut vm = VM::new();
+        vm.ro_data.append(&mut vec![72, 105, 0]);
+        vm.iregisters[0] = Syscall::PrintStrInline as i32;
+        vm.program = vec![Opcode::SYSCALL as u8, 0, 0, 0];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.read_str(0).unwrap(), "Hi");
+
+        let mut vm = VM::new();
+        vm.iregisters[0] = Syscall::PrintRegInline as i32;
+        vm.program = vec![Opcode::SYSCALL as u8, 0, vector_register_to_idx(0), 0];
+        assert!(vm.step().is_ok());
+    }
+
+    #[test]
+    fn test_format_register() {
+        assert_eq!(format_register(&Register::I(42)), "42");
+        assert_eq!(format_register(&Register::R(2.5)), "2.5");
+        assert_eq!(format_register(&Register::V(vec![1.0, 2.5])), "[ 1, 2.5 ]");
+        assert_eq!(format_register(&Register::D(vec![(1.0, 2.0)])), "{ 1: 2 }");
+    }
+
     #[test]
     fn test_opcode_syscall_printreg() {
         let mut vm = VM::new();
@@ -846,15 +1417,82 @@ mod tests {
         let result = vm.set_bytecode(&[1, 2, 3, 4, 0, 0, 0, 0]);
         assert!(result.is_err());
 
-        let mut bytecode = vec![];
-        bytecode.append(&mut DO_HEADER_PREFIX.to_vec());
-        while bytecode.len() < DO_HEADER_LEN {
-            bytecode.push(0);
-        }
-        bytecode.append(&mut vec![1, 2, 3, 4]);
+        let mut bytecode = Header::new(2, 4).to_bytes();
+        bytecode.append(&mut vec![5, 0, 1, 2, 3, 4]);
         let result = vm.set_bytecode(&bytecode);
         assert!(result.is_ok());
-        assert_eq!(vm.ro_data, vec![]);
-        assert_eq!(vm.pc, DO_HEADER_LEN);
+        assert_eq!(vm.ro_data, vec![5, 0]);
+        assert_eq!(vm.pc, DO_HEADER_LEN + 2);
+
+        // Bytecode from before the header was versioned.
+        bytecode[4..12].fill(0);
+        let result = vm.set_bytecode(&bytecode);
+        assert_eq!(
+            result.unwrap_err().to_string(),
+            "\u{2620} Unsupported bytecode version 0 (expected 2)"
+        );
+    }
+
+    #[test]
+    fn test_error_location() {
+        let code = vec![
+            Opcode::LOAD as u8,
+            0,
+            0,
+            0,
+            0,
+            99,
+            Opcode::SYSCALL as u8,
+            0,
+            1,
+            0,
+        ];
+        let start = DO_HEADER_LEN as u32;
+        let debug = DebugInfo {
+            records: vec![
+                Record::Location {
+                    pc: start,
+                    line: 1,
+                    column: 1,
+                },
+                Record::Location {
+                    pc: start + 6,
+                    line: 2,
+                    column: 3,
+                },
+            ],
+        }
+        .to_bytes();
+        let mut header = Header::new(0, code.len());
+        header.sections[DO_SECTION_DEBUG] = debug.len() as u32;
+        let bytecode = [header.to_bytes(), code, debug].concat();
+
+        let mut vm = VM::new();
+        vm.set_bytecode(&bytecode).unwrap();
+        let e = vm.run().unwrap_err();
+        assert_eq!(e.location(), Some((2, 3)));
+        assert_eq!(e.to_string(), "\u{2620} 2:3: Unknown syscall 99");
+    }
+
+    #[test]
+    fn test_code_end() {
+        // running off the end of the code stops before the debug info.
+        let bytecode = Assembler::new()
+            .assemble(".data\n.code\n.loc #1 #1\nload $i0 #1\n.var 'a' $i0\n")
+            .unwrap();
+        let mut vm = VM::new();
+        vm.set_bytecode(&bytecode).unwrap();
+        let e = vm.run().unwrap_err();
+        assert_eq!(e.to_string(), "\u{2620} 1:1: Ran out of program to run");
+
+        // jumps and calls may not leave the code either.
+        for jump in ["jmp $i0", "call @end"] {
+            let source = format!(".data\n.code\nload $i0 #1000\n{}\nhlt\nend:\n", jump);
+            let bytecode = Assembler::new().assemble(&source).unwrap();
+            let mut vm = VM::new();
+            vm.set_bytecode(&bytecode).unwrap();
+            let e = vm.run().unwrap_err();
+            assert!(e.to_string().contains("outside the program"), "{}", e);
+        }
     }
 }
@@ -0,0 +1,264 @@
+use crate::vm::error::Error;
+use crate::vm::register::*;
+use crate::vm::VM;
+
+use std::convert::TryInto;
+use std::iter::zip;
+
+// One side of an arithmetic operation on a row of a nested vector.
+enum Operand<'a> {
+    Scalar(f64),
+    Row(&'a [f64]),
+}
+
+fn operand(reg: &Register, row: usize) -> Result<Operand<'_>, Error> {
+    match reg {
+        Register::I(i) => Ok(Operand::Scalar(*i as f64)),
+        Register::R(r) => Ok(Operand::Scalar(*r)),
+        Register::V(v) => Ok(Operand::Row(v)),
+        Register::N(n) => Ok(Operand::Row(&n[row])),
+        Register::D(_) => Err(Error::new("Cannot use a dict in nested arithmetic")),
+    }
+}
+
+// Applies the operation element by element to each row of a nested vector.
+// Scalars are applied to every element and vectors to every row.
+pub fn broadcast(
+    a: Register,
+    b: Register,
+    op: &str,
+    f: fn(f64, f64) -> f64,
+) -> Result<Vec<Vec<f64>>, Error> {
+    let rows = match (&a, &b) {
+        (Register::N(na), Register::N(nb)) if na.len() != nb.len() => {
+            return Err(Error::new(&format!(
+                "Cannot {} nested vectors with unequal lengths",
+                op
+            )))
+        }
+        (Register::N(n), _) | (_, Register::N(n)) => n.len(),
+        _ => {
+            return Err(Error::new(&format!(
+                "Cannot {} two non-nested registers into a nested register",
+                op
+            )))
+        }
+    };
+
+    let mut output = Vec::with_capacity(rows);
+    for row in 0..rows {
+        output.push(match (operand(&a, row)?, operand(&b, row)?) {
+            (Operand::Row(ra), Operand::Row(rb)) => {
+                if ra.len() != rb.len() {
+                    return Err(Error::new(&format!(
+                        "Cannot {} rows with unequal lengths",
+                        op
+                    )));
+                }
+                zip(ra, rb).map(|(a, b)| f(*a, *b)).collect()
+            }
+            (Operand::Row(ra), Operand::Scalar(b)) => ra.iter().map(|a| f(*a, b)).collect(),
+            (Operand::Scalar(a), Operand::Row(rb)) => rb.iter().map(|b| f(a, *b)).collect(),
+            // At least one side is nested, so has rows.
+            (Operand::Scalar(_), Operand::Scalar(_)) => unreachable!(),
+        });
+    }
+    Ok(output)
+}
+
+impl VM {
+    // Pops the given number of vectors from the stack, in the order they were
+    // pushed, as the rows of a nested vector.
+    pub fn nest(&mut self) -> Result<(), Error> {
+        let out_idx = self.next_u8();
+        let count = self.next_i32();
+        if count < 0 || count as usize > self.stack.len() {
+            return Err(Error::new("Not enough vectors on the stack to nest"));
+        }
+
+        let start = self.stack.len() - count as usize;
+        let mut rows = Vec::with_capacity(count as usize);
+        for value in self.stack.split_off(start) {
+            rows.push(value.try_into()?);
+        }
+        if !is_nested_register(out_idx) {
+            return Err(Error::new("Cannot nest vectors into a non-nested register"));
+        }
+        self.set_register(out_idx, Register::N(rows))
+    }
+
+    // Joins the rows of a nested vector, in turn, into a single vector.
+    pub fn flat(&mut self) -> Result<(), Error> {
+        let out_idx = self.next_u8();
+        let in_idx = self.next_u8();
+
+        // swallow the padding byte.
+        self.next_u8();
+
+        let rows = match self.get_register(in_idx)? {
+            Register::N(n) => n,
+            _ => return Err(Error::new("Expected a nested register")),
+        };
+        self.set_register(out_idx, Register::V(rows.concat()))
+    }
+
+    // Takes the element of a vector, or the row of a nested vector, at an
+    // index.
+    pub fn at(&mut self) -> Result<(), Error> {
+        let out_idx = self.next_u8();
+        let in_idx = self.next_u8();
+        let index_idx = self.next_u8();
+
+        let index: f64 = self.get_register(index_idx)?.try_into()?;
+        let (len, value) = match self.get_register(in_idx)? {
+            Register::V(v) => (v.len(), v.get(index as usize).map(|e| Register::R(*e))),
+            Register::N(n) => (n.len(), n.get(index as usize).cloned().map(Register::V)),
+            _ => return Err(Error::new("Expected a vector or nested register")),
+        };
+        match value {
+            Some(value) if index >= 0.0 => self.set_register(out_idx, value),
+            _ => Err(Error::new(&format!(
+                "Index {} out of range for length {}",
+                index, len
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::asm::opcode::Opcode;
+
+    #[test]
+    fn test_broadcast() {
+        let n = Register::N(vec![vec![1.0, 2.0], vec![3.0, 4.0]]);
+        assert_eq!(
+            broadcast(n.clone(), Register::I(1), "add", |a, b| a + b).unwrap(),
+            vec![vec![2.0, 3.0], vec![4.0, 5.0]]
+        );
+        assert_eq!(
+            broadcast(Register::R(10.0), n.clone(), "sub", |a, b| a - b).unwrap(),
+            vec![vec![9.0, 8.0], vec![7.0, 6.0]]
+        );
+        assert_eq!(
+            broadcast(n.clone(), Register::V(vec![2.0, 3.0]), "mul", |a, b| a * b).unwrap(),
+            vec![vec![2.0, 6.0], vec![6.0, 12.0]]
+        );
+        assert_eq!(
+            broadcast(n.clone(), n.clone(), "add", |a, b| a + b).unwrap(),
+            vec![vec![2.0, 4.0], vec![6.0, 8.0]]
+        );
+
+        // mismatched shapes.
+        assert!(broadcast(n.clone(), Register::V(vec![1.0]), "add", |a, b| a + b).is_err());
+        assert!(broadcast(
+            n.clone(),
+            Register::N(vec![vec![1.0, 2.0]]),
+            "add",
+            |a, b| a + b
+        )
+        .is_err());
+        assert!(broadcast(n, Register::D(vec![]), "add", |a, b| a + b).is_err());
+        assert!(broadcast(Register::I(1), Register::I(1), "add", |a, b| a + b).is_err());
+    }
+
+    #[test]
+    fn test_opcode_nest() {
+        let mut vm = VM::new();
+        vm.stack.push(Register::R(4.2));
+        vm.stack.push(Register::V(vec![1.0, 2.0]));
+        vm.stack.push(Register::V(vec![3.0]));
+        vm.program = vec![Opcode::NEST as u8, nested_register_to_idx(0), 0, 0, 0, 2];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.nregisters[0], vec![vec![1.0, 2.0], vec![3.0]]);
+        assert_eq!(vm.stack, vec![Register::R(4.2)]);
+
+        // a non-vector row.
+        vm.pc = 0;
+        vm.program = vec![Opcode::NEST as u8, nested_register_to_idx(0), 0, 0, 0, 1];
+        assert!(vm.step().is_err());
+
+        // not enough rows.
+        let mut vm = VM::new();
+        vm.program = vec![Opcode::NEST as u8, nested_register_to_idx(0), 0, 0, 0, 1];
+        assert!(vm.step().is_err());
+
+        // a non-nested destination.
+        let mut vm = VM::new();
+        vm.stack.push(Register::V(vec![1.0]));
+        vm.program = vec![Opcode::NEST as u8, vector_register_to_idx(0), 0, 0, 0, 1];
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_opcode_flat() {
+        let mut vm = VM::new();
+        vm.nregisters[1] = vec![vec![1.0, 2.0], vec![], vec![3.0]];
+        vm.program = vec![
+            Opcode::FLAT as u8,
+            vector_register_to_idx(0),
+            nested_register_to_idx(1),
+            0,
+        ];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.vregisters[0], vec![1.0, 2.0, 3.0]);
+
+        let mut vm = VM::new();
+        vm.program = vec![
+            Opcode::FLAT as u8,
+            vector_register_to_idx(0),
+            vector_register_to_idx(1),
+            0,
+        ];
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_opcode_at() {
+        let mut vm = VM::new();
+        vm.vregisters[1] = vec![1.0, 2.0];
+        vm.nregisters[1] = vec![vec![1.0], vec![2.0, 3.0]];
+        vm.iregisters[0] = 1;
+        vm.program = vec![
+            Opcode::AT as u8,
+            real_register_to_idx(0),
+            vector_register_to_idx(1),
+            0,
+            Opcode::AT as u8,
+            vector_register_to_idx(0),
+            nested_register_to_idx(1),
+            0,
+        ];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.rregisters[0], 2.0);
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.vregisters[0], vec![2.0, 3.0]);
+
+        // out of range.
+        for index in [2.0, -1.0] {
+            let mut vm = VM::new();
+            vm.vregisters[1] = vec![1.0, 2.0];
+            vm.rregisters[0] = index;
+            vm.program = vec![
+                Opcode::AT as u8,
+                real_register_to_idx(1),
+                vector_register_to_idx(1),
+                real_register_to_idx(0),
+            ];
+            assert!(vm.step().is_err(), "{}", index);
+        }
+
+        // a row into a real register.
+        let mut vm = VM::new();
+        vm.nregisters[1] = vec![vec![1.0]];
+        vm.program = vec![
+            Opcode::AT as u8,
+            real_register_to_idx(0),
+            nested_register_to_idx(1),
+            0,
+        ];
+        assert!(vm.step().is_err());
+    }
+}
@@ -7,6 +7,21 @@ pub enum Register {
     I(i32),
     R(f64),
     V(Vec<f64>),
+    D(Vec<(f64, f64)>),
+    N(Vec<Vec<f64>>),
+}
+
+impl Register {
+    // The name of the class of register that holds this value.
+    pub fn class(&self) -> &'static str {
+        match self {
+            Register::I(_) => "int",
+            Register::R(_) => "real",
+            Register::V(_) => "vector",
+            Register::D(_) => "dict",
+            Register::N(_) => "nested",
+        }
+    }
 }
 
 impl TryInto<i32> for Register {
@@ -20,6 +35,8 @@ impl TryInto<i32> for Register {
                 Ok(r as i32)
             }
             Register::V(_) => Err(Error::new("Cannot convert vector register into i32")),
+            Register::D(_) => Err(Error::new("Cannot convert dict register into i32")),
+            Register::N(_) => Err(Error::new("Cannot convert nested register into i32")),
         }
     }
 }
@@ -32,6 +49,8 @@ impl TryInto<f64> for Register {
             Register::I(i) => Ok(i as f64),
             Register::R(r) => Ok(r),
             Register::V(_) => Err(Error::new("Cannot convert vector register into f64")),
+            Register::D(_) => Err(Error::new("Cannot convert dict register into f64")),
+            Register::N(_) => Err(Error::new("Cannot convert nested register into f64")),
         }
     }
 }
@@ -44,20 +63,36 @@ impl TryInto<Vec<f64>> for Register {
             Register::I(_) => Err(Error::new("Cannot convert integer register into vector")),
             Register::R(_) => Err(Error::new("Cannot convert real register into vector")),
             Register::V(v) => Ok(v),
+            Register::D(_) => Err(Error::new("Cannot convert dict register into vector")),
+            Register::N(_) => Err(Error::new("Cannot convert nested register into vector")),
         }
     }
 }
 
+// The top two bits of a register hold its class.
+const CLASS_MASK: u8 = 0b11000000;
+
+// Vector registers with this bit set hold nested vectors.
+const NESTED_BIT: u8 = 0b00100000;
+
 pub fn is_int_register(reg: u8) -> bool {
-    !is_real_register(reg) && !is_vector_register(reg)
+    (reg & CLASS_MASK) == 0
 }
 
 pub fn is_real_register(reg: u8) -> bool {
-    (reg & 0b10000000) == 0b10000000
+    (reg & CLASS_MASK) == 0b10000000
 }
 
 pub fn is_vector_register(reg: u8) -> bool {
-    (reg & 0b01000000) == 0b01000000
+    (reg & CLASS_MASK) == 0b01000000 && (reg & NESTED_BIT) == 0
+}
+
+pub fn is_nested_register(reg: u8) -> bool {
+    (reg & CLASS_MASK) == 0b01000000 && (reg & NESTED_BIT) != 0
+}
+
+pub fn is_dict_register(reg: u8) -> bool {
+    (reg & CLASS_MASK) == 0b11000000
 }
 
 pub fn idx_from_int_register(reg: u8) -> u8 {
@@ -72,6 +107,14 @@ pub fn idx_from_vector_register(reg: u8) -> u8 {
     reg & 0b10111111
 }
 
+pub fn idx_from_nested_register(reg: u8) -> u8 {
+    reg & 0b00011111
+}
+
+pub fn idx_from_dict_register(reg: u8) -> u8 {
+    reg & 0b00111111
+}
+
 pub fn int_register_to_idx(reg: u8) -> u8 {
     reg
 }
@@ -84,6 +127,14 @@ pub fn vector_register_to_idx(reg: u8) -> u8 {
     reg | 0b01000000
 }
 
+pub fn nested_register_to_idx(reg: u8) -> u8 {
+    reg | 0b01100000
+}
+
+pub fn dict_register_to_idx(reg: u8) -> u8 {
+    reg | 0b11000000
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -119,6 +170,7 @@ mod tests {
         assert!(is_int_register(reg));
         assert!(!is_real_register(reg));
         assert!(!is_vector_register(reg));
+        assert!(!is_dict_register(reg));
         assert_eq!(idx_from_int_register(reg), 24);
     }
 
@@ -128,6 +180,7 @@ mod tests {
         assert!(!is_int_register(reg));
         assert!(is_real_register(reg));
         assert!(!is_vector_register(reg));
+        assert!(!is_dict_register(reg));
         assert_eq!(idx_from_real_register(reg), 24);
     }
 
@@ -137,6 +190,30 @@ mod tests {
         assert!(!is_int_register(reg));
         assert!(!is_real_register(reg));
         assert!(is_vector_register(reg));
+        assert!(!is_dict_register(reg));
         assert_eq!(idx_from_vector_register(reg), 24);
     }
+
+    #[test]
+    fn test_is_dict_register() {
+        let reg = dict_register_to_idx(24);
+        assert!(!is_int_register(reg));
+        assert!(!is_real_register(reg));
+        assert!(!is_vector_register(reg));
+        assert!(is_dict_register(reg));
+        assert_eq!(idx_from_dict_register(reg), 24);
+    }
+
+    #[test]
+    fn test_is_nested_register() {
+        let reg = nested_register_to_idx(24);
+        assert!(!is_int_register(reg));
+        assert!(!is_real_register(reg));
+        assert!(!is_vector_register(reg));
+        assert!(!is_dict_register(reg));
+        assert!(is_nested_register(reg));
+        assert_eq!(idx_from_nested_register(reg), 24);
+
+        assert!(!is_nested_register(vector_register_to_idx(24)));
+    }
 }
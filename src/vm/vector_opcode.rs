@@ -0,0 +1,506 @@
+use crate::vm::error::Error;
+use crate::vm::register::*;
+use crate::vm::VM;
+
+use std::convert::TryInto;
+use std::iter::zip;
+
+impl VM {
+    fn get_vector(&self, reg: u8) -> Result<Vec<f64>, Error> {
+        match self.get_register(reg)? {
+            Register::V(v) => Ok(v),
+            _ => Err(Error::new("Expected a vector register")),
+        }
+    }
+
+    fn set_vector(&mut self, reg: u8, value: Vec<f64>) -> Result<(), Error> {
+        if !is_vector_register(reg) {
+            return Err(Error::new("Cannot write a vector to a non-vector register"));
+        }
+        self.vregisters[idx_from_vector_register(reg) as usize] = value;
+        Ok(())
+    }
+
+    // Applies the function to every element of the input vector.
+    pub fn map(&mut self) -> Result<(), Error> {
+        let out_idx = self.next_u8();
+        let in_idx = self.next_u8();
+        let target = self.next_u32() as usize;
+
+        let input = self.get_vector(in_idx)?;
+        let mut output = Vec::with_capacity(input.len());
+        for value in input {
+            output.push(self.apply(target, &[Register::R(value)])?.try_into()?);
+        }
+        self.set_vector(out_idx, output)
+    }
+
+    // Keeps the elements of the input vector where the mask is true.
+    pub fn mask(&mut self) -> Result<(), Error> {
+        let out_idx = self.next_u8();
+        let in_idx = self.next_u8();
+        let mask_idx = self.next_u8();
+
+        let input = self.get_vector(in_idx)?;
+        let mask = self.get_vector(mask_idx)?;
+        if input.len() != mask.len() {
+            return Err(Error::new("Cannot mask vectors with unequal lengths"));
+        }
+        let output = zip(input, mask)
+            .filter(|(_, m)| *m != 0.0)
+            .map(|(v, _)| v)
+            .collect();
+        self.set_vector(out_idx, output)
+    }
+
+    // Accumulates the input vector into the accumulator register, which holds
+    // the initial value, by applying the function to the accumulator and each
+    // element in turn.
+    pub fn fold(&mut self) -> Result<(), Error> {
+        let acc_idx = self.next_u8();
+        let in_idx = self.next_u8();
+        let target = self.next_u32() as usize;
+
+        let input = self.get_vector(in_idx)?;
+        let mut acc: f64 = self.get_register(acc_idx)?.try_into()?;
+        for value in input {
+            acc = self
+                .apply(target, &[Register::R(acc), Register::R(value)])?
+                .try_into()?;
+        }
+        self.set_register(acc_idx, Register::R(acc))
+    }
+
+    // As `fold`, for reducers built in to the VM.
+    pub fn reduce(&mut self, f: fn(f64, f64) -> f64) -> Result<(), Error> {
+        let acc_idx = self.next_u8();
+        let in_idx = self.next_u8();
+
+        // swallow the padding byte.
+        self.next_u8();
+
+        let input = self.get_vector(in_idx)?;
+        let init: f64 = self.get_register(acc_idx)?.try_into()?;
+        self.set_register(acc_idx, Register::R(input.into_iter().fold(init, f)))
+    }
+
+    // Sorts the input vector in ascending (0) or descending (1) order.
+    pub fn sort(&mut self) -> Result<(), Error> {
+        let out_idx = self.next_u8();
+        let in_idx = self.next_u8();
+        let order = self.next_i32();
+
+        let mut output = self.get_vector(in_idx)?;
+        match order {
+            0 => output.sort_by(f64::total_cmp),
+            1 => output.sort_by(|a, b| b.total_cmp(a)),
+            _ => return Err(Error::new("Unknown sort order")),
+        }
+        self.set_vector(out_idx, output)
+    }
+
+    // Takes the first n elements of the input vector, or all of them if
+    // there are fewer than n.
+    pub fn first(&mut self) -> Result<(), Error> {
+        let out_idx = self.next_u8();
+        let in_idx = self.next_u8();
+        let n_idx = self.next_u8();
+
+        let mut output = self.get_vector(in_idx)?;
+        let n: f64 = self.get_register(n_idx)?.try_into()?;
+        if n < 0.0 {
+            return Err(Error::new("Cannot take a negative number of elements"));
+        }
+        output.truncate(n as usize);
+        self.set_vector(out_idx, output)
+    }
+
+    // Concatenates the two input vectors.
+    pub fn cat(&mut self) -> Result<(), Error> {
+        let out_idx = self.next_u8();
+        let left_idx = self.next_u8();
+        let right_idx = self.next_u8();
+
+        let mut output = self.get_vector(left_idx)?;
+        output.extend(self.get_vector(right_idx)?);
+        self.set_vector(out_idx, output)
+    }
+
+    // Pairs up the elements of the two input vectors as the rows of a nested
+    // vector.
+    pub fn zip(&mut self) -> Result<(), Error> {
+        let out_idx = self.next_u8();
+        let left_idx = self.next_u8();
+        let right_idx = self.next_u8();
+
+        let left = self.get_vector(left_idx)?;
+        let right = self.get_vector(right_idx)?;
+        if left.len() != right.len() {
+            return Err(Error::new("Cannot zip vectors with unequal lengths"));
+        }
+        if !is_nested_register(out_idx) {
+            return Err(Error::new("Cannot zip vectors into a non-nested register"));
+        }
+        let output = zip(left, right).map(|(l, r)| vec![l, r]).collect();
+        self.set_register(out_idx, Register::N(output))
+    }
+
+    // Writes the number of elements in the input vector to an integer
+    // register.
+    pub fn len(&mut self) -> Result<(), Error> {
+        let out_idx = self.next_u8();
+        let in_idx = self.next_u8();
+
+        // swallow the padding byte.
+        self.next_u8();
+
+        let len = match self.get_register(in_idx)? {
+            Register::V(v) => v.len(),
+            Register::N(n) => n.len(),
+            _ => return Err(Error::new("Expected a vector or nested register")),
+        };
+        if !is_int_register(out_idx) {
+            return Err(Error::new("Expected integer register for length"));
+        }
+        self.set_register(out_idx, Register::I(len as i32))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::asm::opcode::Opcode;
+
+    // A function at offset 8 that squares its argument.
+    fn square_program() -> Vec<u8> {
+        vec![
+            Opcode::MAP as u8,
+            vector_register_to_idx(0),
+            vector_register_to_idx(1),
+            0,
+            0,
+            0,
+            8,
+            Opcode::HLT as u8,
+            Opcode::POP as u8,
+            real_register_to_idx(0),
+            0,
+            0,
+            Opcode::MUL as u8,
+            real_register_to_idx(0),
+            real_register_to_idx(0),
+            real_register_to_idx(0),
+            Opcode::PUSH as u8,
+            real_register_to_idx(0),
+            0,
+            0,
+            Opcode::RET as u8,
+            0,
+            0,
+            0,
+        ]
+    }
+
+    #[test]
+    fn test_opcode_map() {
+        let mut vm = VM::new();
+        vm.rregisters[0] = 4.2;
+        vm.vregisters[1] = vec![1.0, 2.0, 3.0];
+        vm.program = square_program();
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.vregisters[0], vec![1.0, 4.0, 9.0]);
+        assert_eq!(vm.rregisters[0], 4.2);
+        assert!(vm.stack.is_empty());
+        assert!(vm.call_stack.is_empty());
+    }
+
+    #[test]
+    fn test_opcode_map_empty() {
+        let mut vm = VM::new();
+        vm.vregisters[0] = vec![1.0];
+        vm.program = square_program();
+        assert!(vm.run().is_ok());
+        assert!(vm.vregisters[0].is_empty());
+    }
+
+    #[test]
+    fn test_opcode_map_non_vector_is_error() {
+        let mut vm = VM::new();
+        vm.program = square_program();
+        vm.program[2] = 0;
+        assert!(vm.step().is_err());
+
+        let mut vm = VM::new();
+        vm.program = square_program();
+        vm.program[1] = 0;
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_opcode_map_no_return_value_is_error() {
+        let mut vm = VM::new();
+        vm.vregisters[1] = vec![1.0];
+        vm.program = square_program();
+        vm.program[16] = Opcode::RET as u8;
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_opcode_mask() {
+        let mut vm = VM::new();
+        vm.vregisters[1] = vec![4.0, 3.0, 2.0, 1.0];
+        vm.vregisters[2] = vec![1.0, 0.0, 0.5, 0.0];
+        vm.program = vec![
+            Opcode::MASK as u8,
+            vector_register_to_idx(0),
+            vector_register_to_idx(1),
+            vector_register_to_idx(2),
+        ];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.vregisters[0], vec![4.0, 2.0]);
+
+        // unequal lengths.
+        let mut vm = VM::new();
+        vm.vregisters[1] = vec![4.0, 3.0];
+        vm.vregisters[2] = vec![1.0];
+        vm.program = vec![
+            Opcode::MASK as u8,
+            vector_register_to_idx(0),
+            vector_register_to_idx(1),
+            vector_register_to_idx(2),
+        ];
+        assert!(vm.step().is_err());
+
+        // non-vector mask.
+        let mut vm = VM::new();
+        vm.program = vec![
+            Opcode::MASK as u8,
+            vector_register_to_idx(0),
+            vector_register_to_idx(1),
+            2,
+        ];
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_opcode_fold() {
+        // A function at offset 8 that subtracts its second argument from its
+        // first.
+        let mut vm = VM::new();
+        vm.vregisters[0] = vec![1.0, 2.0, 3.0];
+        vm.rregisters[0] = 10.0;
+        vm.program = vec![
+            Opcode::FOLD as u8,
+            real_register_to_idx(0),
+            vector_register_to_idx(0),
+            0,
+            0,
+            0,
+            8,
+            Opcode::HLT as u8,
+            Opcode::POP as u8,
+            real_register_to_idx(2),
+            0,
+            0,
+            Opcode::POP as u8,
+            real_register_to_idx(1),
+            0,
+            0,
+            Opcode::SUB as u8,
+            real_register_to_idx(1),
+            real_register_to_idx(1),
+            real_register_to_idx(2),
+            Opcode::PUSH as u8,
+            real_register_to_idx(1),
+            0,
+            0,
+            Opcode::RET as u8,
+            0,
+            0,
+            0,
+        ];
+        assert!(vm.run().is_ok());
+        assert_eq!(vm.rregisters[0], 4.0);
+        assert!(vm.stack.is_empty());
+
+        // a vector accumulator.
+        let mut vm = VM::new();
+        vm.program = vec![
+            Opcode::FOLD as u8,
+            vector_register_to_idx(1),
+            vector_register_to_idx(0),
+            0,
+            0,
+            0,
+            8,
+        ];
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_opcode_reduce() {
+        for (opcode, expected) in [
+            (Opcode::SUM, 8.0),
+            (Opcode::PROD, -24.0),
+            (Opcode::MIN, -1.0),
+            (Opcode::MAX, 4.0),
+        ] {
+            let mut vm = VM::new();
+            vm.vregisters[0] = vec![4.0, -1.0, 3.0];
+            vm.rregisters[0] = 2.0;
+            vm.program = vec![
+                opcode as u8,
+                real_register_to_idx(0),
+                vector_register_to_idx(0),
+                0,
+            ];
+            assert!(vm.step().is_ok());
+            assert_eq!(vm.rregisters[0], expected, "{:?}", opcode);
+        }
+
+        // empty vectors reduce to the initial value.
+        let mut vm = VM::new();
+        vm.iregisters[0] = 7;
+        vm.program = vec![Opcode::MIN as u8, 0, vector_register_to_idx(0), 0];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.iregisters[0], 7);
+
+        // non-vector input.
+        let mut vm = VM::new();
+        vm.program = vec![Opcode::SUM as u8, 0, 1, 0];
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_opcode_sort() {
+        for (order, expected) in [(0, vec![-1.0, 3.0, 4.0]), (1, vec![4.0, 3.0, -1.0])] {
+            let mut vm = VM::new();
+            vm.vregisters[1] = vec![4.0, -1.0, 3.0];
+            vm.program = vec![
+                Opcode::SORT as u8,
+                vector_register_to_idx(0),
+                vector_register_to_idx(1),
+                0,
+                0,
+                0,
+                order,
+            ];
+            assert!(vm.step().is_ok());
+            assert_eq!(vm.vregisters[0], expected);
+            assert_eq!(vm.vregisters[1], vec![4.0, -1.0, 3.0]);
+        }
+
+        // unknown order.
+        let mut vm = VM::new();
+        vm.program = vec![
+            Opcode::SORT as u8,
+            vector_register_to_idx(0),
+            vector_register_to_idx(1),
+            0,
+            0,
+            0,
+            2,
+        ];
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_opcode_first() {
+        for (n, expected) in [(2, vec![4.0, -1.0]), (5, vec![4.0, -1.0, 3.0]), (0, vec![])] {
+            let mut vm = VM::new();
+            vm.vregisters[1] = vec![4.0, -1.0, 3.0];
+            vm.iregisters[0] = n;
+            vm.program = vec![
+                Opcode::FIRST as u8,
+                vector_register_to_idx(0),
+                vector_register_to_idx(1),
+                0,
+            ];
+            assert!(vm.step().is_ok());
+            assert_eq!(vm.vregisters[0], expected);
+        }
+
+        // negative n.
+        let mut vm = VM::new();
+        vm.rregisters[0] = -1.0;
+        vm.program = vec![
+            Opcode::FIRST as u8,
+            vector_register_to_idx(0),
+            vector_register_to_idx(1),
+            real_register_to_idx(0),
+        ];
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_opcode_cat() {
+        let mut vm = VM::new();
+        vm.vregisters[1] = vec![1.0, 2.0];
+        vm.vregisters[2] = vec![3.0];
+        vm.program = vec![
+            Opcode::CAT as u8,
+            vector_register_to_idx(0),
+            vector_register_to_idx(1),
+            vector_register_to_idx(2),
+        ];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.vregisters[0], vec![1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn test_opcode_zip() {
+        let mut vm = VM::new();
+        vm.vregisters[1] = vec![1.0, 2.0];
+        vm.vregisters[2] = vec![3.0, 4.0];
+        vm.program = vec![
+            Opcode::ZIP as u8,
+            nested_register_to_idx(0),
+            vector_register_to_idx(1),
+            vector_register_to_idx(2),
+        ];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.nregisters[0], vec![vec![1.0, 3.0], vec![2.0, 4.0]]);
+
+        // a non-nested destination.
+        vm.pc = 0;
+        vm.program[1] = vector_register_to_idx(0);
+        assert!(vm.step().is_err());
+
+        // unequal lengths.
+        let mut vm = VM::new();
+        vm.vregisters[1] = vec![1.0, 2.0];
+        vm.program = vec![
+            Opcode::ZIP as u8,
+            nested_register_to_idx(0),
+            vector_register_to_idx(1),
+            vector_register_to_idx(2),
+        ];
+        assert!(vm.step().is_err());
+    }
+
+    #[test]
+    fn test_opcode_len() {
+        let mut vm = VM::new();
+        vm.vregisters[1] = vec![1.0, 2.0];
+        vm.program = vec![Opcode::LEN as u8, 3, vector_register_to_idx(1), 0];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.iregisters[3], 2);
+
+        let mut vm = VM::new();
+        vm.nregisters[1] = vec![vec![1.0, 2.0], vec![], vec![3.0]];
+        vm.program = vec![Opcode::LEN as u8, 3, nested_register_to_idx(1), 0];
+        assert!(vm.step().is_ok());
+        assert_eq!(vm.iregisters[3], 3);
+
+        // non-integer destination.
+        let mut vm = VM::new();
+        vm.program = vec![
+            Opcode::LEN as u8,
+            real_register_to_idx(3),
+            vector_register_to_idx(1),
+            0,
+        ];
+        assert!(vm.step().is_err());
+    }
+}
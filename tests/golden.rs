@@ -0,0 +1,87 @@
+// Runs programs from `old/test` and compares their output, or the errors for
+// programs that don't compile, to the expected output alongside them.
+
+use std::fs;
+use std::fs::File;
+use std::path::PathBuf;
+use std::process::{Command, Stdio};
+
+fn run_golden(name: &str) {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("old/test");
+    let program = dir.join(format!("{}.do", name));
+    let input = dir.join(format!("{}.in", name));
+    let expected = fs::read_to_string(dir.join(format!("{}.out", name))).unwrap();
+
+    let stdin = match File::open(input) {
+        Ok(f) => Stdio::from(f),
+        Err(_) => Stdio::null(),
+    };
+    let output = Command::new(env!("CARGO_BIN_EXE_mrdo"))
+        .arg("args")
+        .arg(&program)
+        .stdin(stdin)
+        .output()
+        .unwrap();
+
+    assert!(
+        output.status.success(),
+        "{} failed: {}",
+        name,
+        String::from_utf8_lossy(&output.stderr)
+    );
+    assert_eq!(String::from_utf8_lossy(&output.stdout), expected);
+}
+
+fn run_failing(name: &str) {
+    let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("old/test");
+    let program = dir.join(format!("{}.do", name));
+    let expected = fs::read_to_string(dir.join(format!("{}.err", name))).unwrap();
+
+    let output = Command::new(env!("CARGO_BIN_EXE_mrdo"))
+        .arg("args")
+        .arg(&program)
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+
+    assert_eq!(output.status.code(), Some(1), "{} didn't fail", name);
+    assert_eq!(String::from_utf8_lossy(&output.stderr), expected);
+}
+
+#[test]
+fn test_filter() {
+    run_golden("filter");
+}
+
+#[test]
+fn test_io() {
+    run_golden("io");
+}
+
+#[test]
+fn test_print() {
+    run_golden("print");
+}
+
+#[test]
+fn test_syntax_error() {
+    run_failing("syntax");
+}
+
+#[test]
+fn test_missing_file() {
+    let program = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("old/test/missing.do");
+    for command in ["args", "disasm"] {
+        let output = Command::new(env!("CARGO_BIN_EXE_mrdo"))
+            .arg(command)
+            .arg(&program)
+            .stdin(Stdio::null())
+            .output()
+            .unwrap();
+
+        assert_eq!(output.status.code(), Some(1), "{} didn't fail", command);
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        assert!(stderr.starts_with("error: "), "{}", stderr);
+        assert!(stderr.contains("missing.do"), "{}", stderr);
+    }
+}